@@ -0,0 +1,98 @@
+//! Transcripts of `--agent` runs: the task, and every step with its tool calls and their
+//! results, then the final answer, written to a Markdown file as the run goes
+
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+use crate::model::{Message, ToolCall};
+use crate::{history, paths, warning};
+
+/// Tool results longer than this are cut in transcripts
+const MAX_RESULT_CHARS: usize = 4000;
+
+/// A transcript being written. Failures to write are warned about once, and the run goes on.
+pub struct Transcript {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Transcript {
+    /// Create the transcript at the path, or in `transcripts` of the data directory
+    pub fn create(path: Option<&str>, model: &str, task: &str) -> Result<Self> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => paths::transcripts_dir()?.join(format!("{now}-{}.md", std::process::id())),
+        };
+        paths::ensure_parent_dir(&path)?;
+        let file = File::create(&path)
+            .map_err(|e| anyhow!("Failed to create transcript {}: {e}", path.display()))?;
+        let mut transcript = Self {
+            path,
+            file: Some(file),
+        };
+        transcript.write(&format!(
+            "# Agent run\n\nStarted at {} UTC with `{model}`.\n\n## Task\n\n{task}\n",
+            history::format_time(now)
+        ));
+        Ok(transcript)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Record a step answered with tool calls
+    pub fn step(&mut self, step: usize, message: &Message) {
+        self.write(&format!("\n## Step {step}\n"));
+        if !message.content.is_empty() {
+            self.write(&format!("\n{}\n", message.content));
+        }
+    }
+
+    pub fn tool_result(&mut self, call: &ToolCall, result: &str) {
+        let result = match result.char_indices().nth(MAX_RESULT_CHARS) {
+            Some((end, _)) => format!("{}\n[cut]", &result[..end]),
+            None => result.to_owned(),
+        };
+        let fence = "`".repeat(3.max(longest_backtick_run(&result) + 1));
+        self.write(&format!(
+            "\nCalled `{}` with `{}`:\n\n{fence}\n{result}\n{fence}\n",
+            call.function.name, call.function.arguments
+        ));
+    }
+
+    pub fn final_answer(&mut self, step: usize, message: &Message) {
+        self.write(&format!(
+            "\n## Final answer (step {step})\n\n{}\n",
+            message.content
+        ));
+    }
+
+    /// Record why the run stopped without a final answer
+    pub fn failed(&mut self, err: &anyhow::Error) {
+        self.write(&format!("\n## Stopped\n\n{err}\n"));
+    }
+
+    fn write(&mut self, text: &str) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        if let Err(err) = file.write_all(text.as_bytes()) {
+            warning(format!(
+                "Failed to write transcript {}: {err}",
+                self.path.display()
+            ));
+            self.file = None;
+        }
+    }
+}
+
+fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
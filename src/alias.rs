@@ -0,0 +1,52 @@
+//! Aliases in `[aliases]` of the config file: `heygpt NAME ...` asks with preset options, and
+//! `\NAME` runs a sequence of commands in interactive mode
+
+use serde::{Deserialize, Serialize};
+
+use crate::Options;
+
+/// An alias, e.g.
+///
+/// ```toml
+/// [aliases.tr]
+/// system = "Translate the text into English."
+/// model = "gpt-4o-mini"
+///
+/// [aliases.x]
+/// commands = ["\\reset", "\\set model gpt-4o"]
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Alias {
+    pub system: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    /// Text put before the prompt, e.g. `Explain this error:`
+    pub prompt: Option<String>,
+    /// Commands run by `\NAME` in interactive mode
+    #[serde(default)]
+    pub commands: Vec<String>,
+}
+
+impl Alias {
+    /// Apply the options of the alias, which are overridden by those on command line
+    pub fn apply(&self, options: &mut Options) {
+        if self.system.is_some() {
+            options.system.clone_from(&self.system);
+        }
+        if let Some(model) = &self.model {
+            options.model.clone_from(model);
+        }
+        if self.temperature.is_some() {
+            options.temperature = self.temperature;
+        }
+    }
+}
+
+/// Take the alias named by the first word of the prompt out of it, if any
+pub fn take(options: &Options, prompt: &mut Option<Vec<String>>) -> Option<Alias> {
+    let words = prompt.as_mut()?;
+    let alias = options.aliases.get(words.first()?)?.clone();
+    words.remove(0);
+    Some(alias)
+}
@@ -0,0 +1,341 @@
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+
+use crate::{error, html, paths, token};
+
+/// Files larger than this are refused, as they are hardly meant to be read by the model
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Read a text file and wrap it in a fenced code block labeled with its path, e.g.
+///
+/// ````text
+/// ```src/main.rs
+/// fn main() {}
+/// ```
+/// ````
+pub fn read_file(path: &str) -> Result<String> {
+    let file = paths::expand_home(path);
+    let metadata =
+        std::fs::metadata(&file).map_err(|e| anyhow!("Failed to read file `{path}`: {e}"))?;
+    if !metadata.is_file() {
+        bail!("`{path}` is not a file");
+    }
+    if metadata.len() > MAX_FILE_SIZE {
+        bail!(
+            "File `{path}` is too large ({} KiB), at most {} KiB can be attached",
+            metadata.len() / 1024,
+            MAX_FILE_SIZE / 1024
+        );
+    }
+    let bytes = std::fs::read(&file).map_err(|e| anyhow!("Failed to read file `{path}`: {e}"))?;
+    let content =
+        String::from_utf8(bytes).map_err(|_| anyhow!("File `{path}` is not a UTF-8 text file"))?;
+    Ok(fence(path, &content))
+}
+
+/// Wrap the content in a code block whose fence is longer than any backtick run in it
+pub fn fence(label: &str, content: &str) -> String {
+    let longest = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    let newline = if content.ends_with('\n') { "" } else { "\n" };
+    format!("{fence}{label}\n{content}{newline}{fence}")
+}
+
+/// A file of a directory, packed into the context in full or in part
+struct PackedFile {
+    path: String,
+    content: String,
+    /// Number of lines kept, or `None` if kept in full
+    kept_lines: Option<usize>,
+    total_lines: usize,
+}
+
+/// Paths and contents of text files in the directory matching `include`, skipping ignored,
+/// binary and too large files, sorted by path
+pub fn text_files(dir: &str, include: &[String]) -> Result<Vec<(String, String)>> {
+    let root = &paths::expand_home(dir);
+    if !root.is_dir() {
+        bail!("`{dir}` is not a directory");
+    }
+    let include = include_set(include)?;
+    // Ignored as by git, also outside a repository, but hidden files are not skipped
+    let walker = WalkBuilder::new(root)
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build();
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry.map_err(|e| anyhow!("Failed to read directory `{dir}`: {e}"))?;
+        // Symbolic links are not followed
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let path = entry.path();
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        if include.as_ref().is_some_and(|set| !set.is_match(rel)) {
+            continue;
+        }
+        let size = entry.metadata().map(|m| m.len()).unwrap_or(u64::MAX);
+        if size > MAX_FILE_SIZE {
+            continue;
+        }
+        // Binary files are skipped
+        if let Ok(content) = std::fs::read_to_string(path) {
+            files.push((path.display().to_string(), content));
+        }
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(files)
+}
+
+/// Walk a directory, skipping files ignored by git, and pack text files whose paths match any of
+/// `include` (all if empty) into code blocks after a manifest of them. When the files exceed
+/// `max_tokens`, the budget is shared evenly: small files are kept in full and the larger ones
+/// are truncated, or omitted if not even a line of them fits.
+pub fn read_dir(dir: &str, include: &[String], max_tokens: usize) -> Result<String> {
+    let files = text_files(dir, include)?;
+    if files.is_empty() {
+        bail!("No files to attach in `{dir}`");
+    }
+
+    // The manifest and fences take a few tokens per file
+    let overhead = files.len() * 10;
+    let mut budget = max_tokens.saturating_sub(overhead);
+    let mut order: Vec<_> = (0..files.len()).collect();
+    order.sort_by_key(|&i| token::estimate_tokens(&files[i].1));
+    let mut packed: Vec<Option<PackedFile>> = files.iter().map(|_| None).collect();
+    for (n, &i) in order.iter().enumerate() {
+        let (path, content) = &files[i];
+        let share = budget / (files.len() - n);
+        let file = truncate(path, content, share);
+        budget -= token::estimate_tokens(&file.content).min(budget);
+        packed[i] = Some(file);
+    }
+
+    let packed: Vec<_> = packed.into_iter().flatten().collect();
+    let truncated = packed
+        .iter()
+        .filter(|f| f.kept_lines.is_some_and(|n| n > 0))
+        .count();
+    let omitted = packed.iter().filter(|f| f.kept_lines == Some(0)).count();
+    let s = if packed.len() == 1 { "" } else { "s" };
+    let mut manifest = format!("Files in `{dir}` ({} file{s}", packed.len());
+    if truncated > 0 {
+        manifest.push_str(&format!(", {truncated} truncated"));
+    }
+    if omitted > 0 {
+        manifest.push_str(&format!(", {omitted} omitted"));
+    }
+    manifest.push_str("):\n");
+    let mut blocks = Vec::new();
+    for file in &packed {
+        let note = match file.kept_lines {
+            None => String::new(),
+            Some(0) => " (omitted)".to_owned(),
+            Some(n) => format!(" (first {n} of {} lines)", file.total_lines),
+        };
+        manifest.push_str(&format!("- {}{note}\n", file.path));
+        if file.kept_lines != Some(0) {
+            blocks.push(fence(&file.path, &file.content));
+        }
+    }
+    Ok(std::iter::once(manifest.trim_end().to_owned())
+        .chain(blocks)
+        .collect::<Vec<_>>()
+        .join("\n\n"))
+}
+
+/// Keep leading lines of the content within the number of tokens
+fn truncate(path: &str, content: &str, max_tokens: usize) -> PackedFile {
+    let total_lines = content.lines().count();
+    if token::estimate_tokens(content) <= max_tokens {
+        return PackedFile {
+            path: path.to_owned(),
+            content: content.to_owned(),
+            kept_lines: None,
+            total_lines,
+        };
+    }
+    let mut kept = String::new();
+    let mut tokens = 0;
+    let mut kept_lines = 0;
+    for line in content.split_inclusive('\n') {
+        tokens += token::estimate_tokens(line);
+        if tokens > max_tokens {
+            break;
+        }
+        kept.push_str(line);
+        kept_lines += 1;
+    }
+    if kept_lines > 0 {
+        kept.push_str(&format!("... ({} more lines)\n", total_lines - kept_lines));
+    }
+    PackedFile {
+        path: path.to_owned(),
+        content: kept,
+        kept_lines: Some(kept_lines),
+        total_lines,
+    }
+}
+
+/// Globs of `--include`, or `None` to include all files. Patterns with a slash are anchored to
+/// the root, others match the file name, and only `**` matches across `/`.
+fn include_set(include: &[String]) -> Result<Option<GlobSet>> {
+    if include.is_empty() {
+        return Ok(None);
+    }
+    let mut set = GlobSetBuilder::new();
+    for pattern in include {
+        let glob = match pattern.strip_prefix('/') {
+            Some(anchored) => anchored.to_owned(),
+            None if pattern.contains('/') => pattern.clone(),
+            None => format!("**/{pattern}"),
+        };
+        let glob = GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| error::usage(format!("Invalid pattern `{pattern}`: {e}")))?;
+        set.add(glob);
+    }
+    Ok(Some(set.build()?))
+}
+
+/// If the data is a PNG, JPEG, GIF or WebP image, encode it as a `data:` URL
+pub fn image_data_url(data: &[u8]) -> Option<String> {
+    let mime = match data {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => "image/png",
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        _ => return None,
+    };
+    let encoded = base64::engine::general_purpose::STANDARD.encode(data);
+    Some(format!("data:{mime};base64,{encoded}"))
+}
+
+/// Download a page and wrap it in a code block labeled with its URL. HTML pages are converted
+/// to readable text.
+pub async fn fetch_url(client: &Client, url: &str) -> Result<String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| anyhow!("Failed to fetch {url}: {e}"))?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("text/html")
+        .to_ascii_lowercase();
+    let is_text = ["text/", "json", "xml", "javascript"]
+        .iter()
+        .any(|t| content_type.contains(t));
+    if !is_text {
+        bail!("{url} is not a text page but `{content_type}`");
+    }
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| anyhow!("Failed to fetch {url}: {e}"))?;
+    if body.len() as u64 > MAX_FILE_SIZE {
+        bail!(
+            "Page {url} is too large ({} KiB), at most {} KiB can be attached",
+            body.len() / 1024,
+            MAX_FILE_SIZE / 1024
+        );
+    }
+    let body = String::from_utf8_lossy(&body);
+    let text = if content_type.contains("html") {
+        html::to_text(&body)
+    } else {
+        body.into_owned()
+    };
+    Ok(fence(url, &text))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn include_patterns() {
+        let matches = |pattern: &str, path: &str| {
+            include_set(&[pattern.to_owned()])
+                .unwrap()
+                .unwrap()
+                .is_match(path)
+        };
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", "src/main.rs"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(matches("/src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/bin/main.rs"));
+        assert!(!matches("src/*.rs", "lib/src/main.rs"));
+        assert!(matches("src/**", "src/bin/main.rs"));
+        assert!(matches("?.md", "a.md"));
+        assert!(!matches("?.md", "ab.md"));
+        assert!(matches("[ab].md", "docs/b.md"));
+        assert!(!matches("[ab].md", "c.md"));
+        assert!(include_set(&["[a".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn include_and_ignored_files() {
+        let root = std::env::temp_dir().join(format!("heygpt-attach-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let files = [
+            (".gitignore", "target/\n*.log\n[ab].txt\n"),
+            (".git/info/exclude", "*.tmp\n"),
+            ("README.md", "readme"),
+            ("a.txt", "a"),
+            ("c.txt", "c"),
+            ("notes.tmp", "notes"),
+            ("src/main.rs", "fn main() {}"),
+            ("src/debug.log", "log"),
+            ("src/gen/.gitignore", "*.rs\n!keep.rs\n"),
+            ("src/gen/skip.rs", "skip"),
+            ("src/gen/keep.rs", "keep"),
+            ("target/out.rs", "out"),
+            ("lib/target", "a file, not the ignored directory"),
+        ];
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let list = |include: &[&str]| {
+            let include: Vec<_> = include.iter().map(|p| p.to_string()).collect();
+            text_files(root.to_str().unwrap(), &include)
+                .unwrap()
+                .into_iter()
+                .map(|(path, _)| {
+                    let rel = Path::new(&path).strip_prefix(&root).unwrap();
+                    rel.to_string_lossy().replace('\\', "/")
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(list(&["*.rs"]), ["src/gen/keep.rs", "src/main.rs"]);
+        assert_eq!(list(&["src/*.rs", "*.md"]), ["README.md", "src/main.rs"]);
+        assert_eq!(
+            list(&[]),
+            [
+                ".gitignore",
+                "README.md",
+                "c.txt",
+                "lib/target",
+                "src/gen/.gitignore",
+                "src/gen/keep.rs",
+                "src/main.rs",
+            ]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,277 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, ValueEnum};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Method};
+use tracing::debug;
+
+use crate::model::{SpeechRequest, TranscriptionRequest};
+use crate::{api_request, check_response, http_client, verbose, Options};
+
+#[derive(Args, Debug)]
+pub struct TranscribeArgs {
+    /// The audio file to transcribe, e.g. mp3, m4a, wav or webm
+    pub file: PathBuf,
+
+    /// The transcription model
+    #[arg(long, default_value = "whisper-1")]
+    pub model: String,
+
+    /// Language of the audio in ISO-639-1 format, e.g. `en`. Improves accuracy and latency.
+    #[arg(long)]
+    pub language: Option<String>,
+
+    /// Text to guide the model's style or continue a previous segment
+    #[arg(long)]
+    pub prompt: Option<String>,
+
+    /// Output format of the transcript
+    #[arg(long, value_enum, default_value_t = TranscriptFormat::Text)]
+    pub format: TranscriptFormat,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum TranscriptFormat {
+    Text,
+    Json,
+    Srt,
+    Vtt,
+}
+
+impl TranscriptFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TranscriptFormat::Text => "text",
+            TranscriptFormat::Json => "json",
+            TranscriptFormat::Srt => "srt",
+            TranscriptFormat::Vtt => "vtt",
+        }
+    }
+}
+
+pub async fn transcribe(options: Options, args: TranscribeArgs) -> Result<()> {
+    let audio = std::fs::read(&args.file)
+        .map_err(|e| anyhow!("Failed to read audio file {}: {e}", args.file.display()))?;
+    let file_name = args
+        .file
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "audio".to_owned());
+
+    let data = TranscriptionRequest {
+        model: args.model,
+        language: args.language,
+        prompt: args.prompt,
+        response_format: args.format.as_str().to_owned(),
+    };
+    debug!("Transcription request: {:?}", &data);
+
+    let mut form = Multipart::new();
+    if let serde_json::Value::Object(fields) = serde_json::to_value(&data)? {
+        for (name, value) in fields {
+            match value {
+                serde_json::Value::String(s) => form.text(&name, &s),
+                other => form.text(&name, &other.to_string()),
+            }
+        }
+    }
+    form.file("file", &file_name, &audio);
+
+    let req = api_request(
+        &http_client(&options)?,
+        &options,
+        Method::POST,
+        "/audio/transcriptions",
+    )?
+    .header(CONTENT_TYPE, form.content_type())
+    .body(form.finish());
+    let response = verbose::send(&options, req).await?;
+    let transcript = check_response(response).await?.text().await?;
+
+    print!("{}", transcript);
+    if !transcript.ends_with('\n') {
+        println!();
+    }
+    std::io::stdout().flush()?;
+    Ok(())
+}
+
+#[derive(Args, Debug)]
+pub struct SpeakArgs {
+    /// The text to speak. Reads stdin if omitted.
+    pub text: Vec<String>,
+
+    /// The voice to use, e.g. alloy, echo, fable, onyx, nova or shimmer (default: `--voice` option)
+    #[arg(long)]
+    pub voice: Option<String>,
+
+    /// Speed of the speech, between 0.25 and 4.0 (default: `--speed` option)
+    #[arg(long)]
+    pub speed: Option<f64>,
+
+    /// The text-to-speech model
+    #[arg(long, default_value = "tts-1")]
+    pub model: String,
+
+    /// Audio format
+    #[arg(long, value_enum, default_value_t = SpeechFormat::Mp3)]
+    pub format: SpeechFormat,
+
+    /// Write the audio to a file instead of playing it
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum SpeechFormat {
+    Mp3,
+    Opus,
+    Aac,
+    Flac,
+    Wav,
+}
+
+impl SpeechFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SpeechFormat::Mp3 => "mp3",
+            SpeechFormat::Opus => "opus",
+            SpeechFormat::Aac => "aac",
+            SpeechFormat::Flac => "flac",
+            SpeechFormat::Wav => "wav",
+        }
+    }
+}
+
+pub async fn speak(options: Options, args: SpeakArgs) -> Result<()> {
+    let text = if args.text.is_empty() {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        args.text.join(" ")
+    };
+    let data = SpeechRequest {
+        model: args.model,
+        input: text,
+        voice: args.voice.unwrap_or(options.voice.clone()),
+        response_format: args.format.as_str().to_owned(),
+        speed: args.speed.or(options.speed),
+    };
+    let audio = synthesize(&http_client(&options)?, &options, &data).await?;
+
+    match args.output {
+        Some(path) => std::fs::write(&path, audio)
+            .map_err(|e| anyhow!("Failed to write {}: {e}", path.display())),
+        None => play(&audio, args.format.as_str()),
+    }
+}
+
+/// Speak the text aloud with the voice from options, used by `--speak`
+pub async fn speak_text(client: &Client, options: &Options, text: &str) -> Result<()> {
+    let data = SpeechRequest {
+        model: "tts-1".to_owned(),
+        input: text.to_owned(),
+        voice: options.voice.clone(),
+        response_format: "mp3".to_owned(),
+        speed: options.speed,
+    };
+    let audio = synthesize(client, options, &data).await?;
+    play(&audio, "mp3")
+}
+
+async fn synthesize(client: &Client, options: &Options, data: &SpeechRequest) -> Result<Vec<u8>> {
+    debug!("Speech request: {:?}", data);
+    let req = api_request(client, options, Method::POST, "/audio/speech")?.json(data);
+    let response = verbose::send(options, req).await?;
+    let audio = check_response(response).await?.bytes().await?;
+    Ok(audio.to_vec())
+}
+
+/// Play audio with the first available command-line player
+fn play(audio: &[u8], extension: &str) -> Result<()> {
+    let path =
+        std::env::temp_dir().join(format!("heygpt-speech-{}.{extension}", std::process::id()));
+    std::fs::write(&path, audio)?;
+    let result = play_file(&path);
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+fn play_file(path: &Path) -> Result<()> {
+    let players: &[(&str, &[&str])] = &[
+        ("afplay", &[]),
+        ("mpv", &["--really-quiet", "--no-video"]),
+        ("ffplay", &["-nodisp", "-autoexit", "-loglevel", "quiet"]),
+    ];
+    for (player, args) in players {
+        let status = Command::new(player)
+            .args(*args)
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status();
+        match status {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => bail!("{player} exited with {status}"),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    bail!("No audio player found (tried afplay, mpv and ffplay). Use `--output` to save the audio to a file instead.")
+}
+
+/// A minimal `multipart/form-data` body builder
+struct Multipart {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+impl Multipart {
+    fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        Self {
+            boundary: format!("heygpt-boundary-{nanos:x}"),
+            body: Vec::new(),
+        }
+    }
+
+    fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    fn text(&mut self, name: &str, value: &str) {
+        self.body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                self.boundary, name, value
+            )
+            .as_bytes(),
+        );
+    }
+
+    fn file(&mut self, name: &str, file_name: &str, content: &[u8]) {
+        self.body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                self.boundary,
+                name,
+                file_name.replace('"', "")
+            )
+            .as_bytes(),
+        );
+        self.body.extend_from_slice(content);
+        self.body.extend_from_slice(b"\r\n");
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.body
+            .extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        self.body
+    }
+}
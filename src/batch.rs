@@ -0,0 +1,168 @@
+use std::io::Write;
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, ValueHint};
+use futures::stream::{self, StreamExt};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::model::{Message, ResponseUsage};
+use crate::{http_client, Options, Session};
+
+#[derive(Args, Debug)]
+pub struct BatchArgs {
+    /// Input JSONL file. Each line is a prompt string, a message array,
+    /// or an object with `prompt` or `messages` and an optional `id`. Reads stdin if omitted.
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    pub input: Option<String>,
+
+    /// Output JSONL file. Writes to stdout if omitted.
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    pub output: Option<String>,
+
+    /// Maximum number of requests in flight
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+}
+
+/// A line of batch input
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum BatchInput {
+    Prompt(String),
+    Messages(Vec<Message>),
+    Object {
+        #[serde(default)]
+        id: Option<serde_json::Value>,
+        #[serde(default)]
+        prompt: Option<String>,
+        #[serde(default)]
+        messages: Option<Vec<Message>>,
+    },
+}
+
+/// A line of batch output, in the same order as input
+#[derive(Serialize, Debug, Default)]
+struct BatchOutput {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<ResponseUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub async fn run(options: Options, args: BatchArgs) -> Result<()> {
+    if args.concurrency == 0 {
+        bail!("Concurrency must be at least 1");
+    }
+
+    let input = match &args.input {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read input file `{path}`: {e}"))?,
+        None => std::io::read_to_string(std::io::stdin())?,
+    };
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+
+    let client = http_client(&options)?;
+    let lines = input.lines().filter(|line| !line.trim().is_empty());
+    let mut results = stream::iter(lines.enumerate())
+        .map(|(index, line)| run_one(&client, &options, index, line))
+        .buffered(args.concurrency);
+
+    let mut failed = 0usize;
+    while let Some(result) = results.next().await {
+        if result.error.is_some() {
+            failed += 1;
+        }
+        writeln!(output, "{}", serde_json::to_string(&result)?)?;
+        output.flush()?;
+    }
+
+    if failed > 0 {
+        bail!("{failed} request(s) in batch failed");
+    }
+    Ok(())
+}
+
+async fn run_one(client: &Client, options: &Options, index: usize, line: &str) -> BatchOutput {
+    let mut id = None;
+    let result = async {
+        let input: BatchInput = serde_json::from_str(line)
+            .map_err(|e| anyhow!("Invalid input at line {}: {e}", index + 1))?;
+        let messages = match input {
+            BatchInput::Prompt(prompt) => prompt_messages(options, prompt),
+            BatchInput::Messages(messages) => messages,
+            BatchInput::Object {
+                id: input_id,
+                prompt,
+                messages,
+            } => {
+                id = input_id;
+                match (prompt, messages) {
+                    (_, Some(messages)) => messages,
+                    (Some(prompt), None) => prompt_messages(options, prompt),
+                    (None, None) => bail!("Either `prompt` or `messages` is required"),
+                }
+            }
+        };
+
+        let mut session = Session::new(options.clone(), client.clone(), false, false);
+        session.messages = messages;
+        let mut data = session.build_request()?;
+        data.set_stream(false);
+        session.fetch(&data).await
+    }
+    .await;
+
+    match result {
+        Ok(response) => {
+            debug!("batch response {}: {:?}", index, &response);
+            let choice = response.choices.into_iter().next();
+            BatchOutput {
+                index,
+                id,
+                content: choice.as_ref().map(|c| c.message.content.clone()),
+                model: Some(response.model),
+                finish_reason: choice.and_then(|c| c.finish_reason),
+                usage: Some(response.usage),
+                error: None,
+            }
+        }
+        Err(err) => BatchOutput {
+            index,
+            id,
+            error: Some(err.to_string()),
+            ..Default::default()
+        },
+    }
+}
+
+/// Messages for a plain prompt, prepended with the system prompt if given
+fn prompt_messages(options: &Options, prompt: String) -> Vec<Message> {
+    let mut messages = Vec::new();
+    if let Some(system) = options.system.as_ref().filter(|s| !s.is_empty()) {
+        messages.push(Message {
+            role: "system".to_string(),
+            content: system.clone(),
+            ..Default::default()
+        });
+    }
+    messages.push(Message {
+        role: "user".to_string(),
+        content: prompt,
+        ..Default::default()
+    });
+    messages
+}
@@ -0,0 +1,789 @@
+//! Amazon Bedrock backend, for Claude, Llama and other models hosted there.
+//!
+//! Like recording, this works by pointing the API base URL at a local server. It translates
+//! chat completion requests into requests to the Converse API of Bedrock, signed with AWS
+//! Signature Version 4, and translates the responses back. Streaming responses arrive in the
+//! binary event stream encoding of AWS and are turned into server-sent events. The base URL has
+//! a secret path only known to this process, so that other programs can't use the credentials.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use futures::StreamExt;
+use hyper::body::{Bytes, Sender};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+use tracing::{debug, info};
+
+use crate::history::format_time;
+use crate::model::{Message, Request as ChatRequest};
+use crate::serve::{error_response, missing_secret, proxy_secret, strip_secret};
+use crate::{http_client, warning, Options};
+
+/// AWS credentials, from the environment or a profile of the shared credentials file
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+struct Bedrock {
+    client: Client,
+    /// Path prefix of requests from this process, see `proxy_secret`
+    secret: String,
+    /// Base URL of Bedrock Runtime, e.g. `https://bedrock-runtime.us-east-1.amazonaws.com`
+    upstream: String,
+    region: String,
+    credentials: Credentials,
+}
+
+/// Start the local server translating requests for Bedrock. Returns the base URL to send
+/// requests to instead of the API.
+///
+/// Requests go to Bedrock Runtime in the region of `AWS_REGION`, or to `api_base_url` if it
+/// was changed, e.g. to a VPC endpoint.
+pub fn start(options: &Options) -> Result<String> {
+    let profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_owned());
+    let (upstream, region) = match region_of_url(&options.api_base_url) {
+        Some(region) => (
+            options.api_base_url.trim_end_matches('/').to_owned(),
+            region,
+        ),
+        None => {
+            let region = region(&profile)?;
+            let upstream = if options.api_base_url.starts_with("https://api.openai.com") {
+                format!("https://bedrock-runtime.{region}.amazonaws.com")
+            } else {
+                options.api_base_url.trim_end_matches('/').to_owned()
+            };
+            (upstream, region)
+        }
+    };
+    let secret = proxy_secret()?;
+    let bedrock = Arc::new(Bedrock {
+        client: http_client(options)?,
+        secret: secret.clone(),
+        upstream,
+        region,
+        credentials: credentials(&profile)?,
+    });
+
+    let make_svc = make_service_fn(move |_conn| {
+        let bedrock = bedrock.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let bedrock = bedrock.clone();
+                async move { Ok::<_, Infallible>(bedrock.handle(req).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_svc);
+    debug!("Sending requests to Bedrock via {}", server.local_addr());
+    let base_url = format!("http://{}{secret}", server.local_addr());
+    tokio::spawn(server);
+    Ok(base_url)
+}
+
+impl Bedrock {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let Some(path) = strip_secret(req.uri().path(), &self.secret) else {
+            return missing_secret();
+        };
+        if req.method() != Method::POST || path != "/chat/completions" {
+            return error_response(
+                StatusCode::NOT_FOUND,
+                "Only chat completions are supported with Bedrock",
+            );
+        }
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => body,
+            Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+        };
+        let request: ChatRequest = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+        };
+        let converse = match converse_request(&request) {
+            Ok(converse) => converse,
+            Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+        };
+
+        let action = if request.stream {
+            "converse-stream"
+        } else {
+            "converse"
+        };
+        let path = format!("/model/{}/{action}", uri_encode(&request.model));
+        info!("POST {path}");
+        let body = converse.to_string();
+        let response = match self.send(&path, body).await {
+            Ok(response) => response,
+            Err(err) => return error_response(StatusCode::BAD_GATEWAY, &err.to_string()),
+        };
+        if !response.status().is_success() {
+            return translate_error(response).await;
+        }
+        if request.stream {
+            translate_stream(response, request.model)
+        } else {
+            translate_response(response, &request.model).await
+        }
+    }
+
+    /// Send a signed POST request to Bedrock Runtime
+    async fn send(&self, path: &str, body: String) -> Result<reqwest::Response> {
+        let url = Url::parse(&format!("{}{path}", self.upstream))?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+        let amz_date = amz_date(now());
+        let mut headers = vec![
+            ("content-type", "application/json".to_owned()),
+            ("host", host),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let authorization = authorization(
+            &self.credentials,
+            &self.region,
+            "bedrock",
+            url.path(),
+            &headers,
+            &body,
+            &amz_date,
+        )?;
+
+        let mut builder = self.client.post(url);
+        for (name, value) in &headers {
+            if *name != "host" {
+                builder = builder.header(*name, value);
+            }
+        }
+        Ok(builder
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?)
+    }
+}
+
+/// The `Authorization` header of Signature Version 4 of a POST request without query. The
+/// headers must be sorted by name in lowercase.
+fn authorization(
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    body: &str,
+    amz_date: &str,
+) -> Result<String> {
+    // Services other than S3 encode each segment of the path once more
+    let canonical_path = path
+        .split('/')
+        .map(uri_encode)
+        .collect::<Vec<_>>()
+        .join("/");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "POST\n{canonical_path}\n\n{canonical_headers}\n{signed_headers}\n{}",
+        hex(&openssl::sha::sha256(body.as_bytes()))
+    );
+
+    let date = &amz_date[..8];
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex(&openssl::sha::sha256(canonical_request.as_bytes()))
+    );
+    let key = signing_key(&credentials.secret_access_key, date, region, service)?;
+    let signature = hex(&hmac(&key, &string_to_sign)?);
+    Ok(format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+        credentials.access_key_id
+    ))
+}
+
+/// The key deriving signatures of a day, region and service from the secret access key
+fn signing_key(
+    secret_access_key: &str,
+    date: &str,
+    region: &str,
+    service: &str,
+) -> Result<Vec<u8>> {
+    let secret = format!("AWS4{secret_access_key}");
+    let mut key = hmac(secret.as_bytes(), date)?;
+    for part in [region, service, "aws4_request"] {
+        key = hmac(&key, part)?;
+    }
+    Ok(key)
+}
+
+/// Translate a chat completion request into the body of a Converse request
+fn converse_request(request: &ChatRequest) -> Result<Value> {
+    let mut system = Vec::new();
+    let mut messages = Vec::new();
+    for message in &request.messages {
+        if message.role == "system" {
+            // Bedrock rejects blank text
+            if !message.content.trim().is_empty() {
+                system.push(json!({ "text": message.content }));
+            }
+        } else {
+            messages.push(json!({
+                "role": message.role,
+                "content": content_blocks(message)?,
+            }));
+        }
+    }
+
+    let mut config = serde_json::Map::new();
+    if let Some(max_tokens) = request.max_tokens.or(request.max_completion_tokens) {
+        config.insert("maxTokens".to_owned(), max_tokens.into());
+    }
+    if let Some(temperature) = request.temperature {
+        config.insert("temperature".to_owned(), temperature.into());
+    }
+    if let Some(top_p) = request.top_p {
+        config.insert("topP".to_owned(), top_p.into());
+    }
+
+    let mut body = json!({ "messages": messages });
+    if !system.is_empty() {
+        body["system"] = system.into();
+    }
+    if !config.is_empty() {
+        body["inferenceConfig"] = config.into();
+    }
+    Ok(body)
+}
+
+/// Content blocks of a message. Bedrock only accepts images given inline, as `data:` URLs.
+fn content_blocks(message: &Message) -> Result<Vec<Value>> {
+    let mut blocks = Vec::new();
+    if !message.content.is_empty() {
+        blocks.push(json!({ "text": message.content }));
+    }
+    for url in &message.images {
+        let (format, data) = url
+            .strip_prefix("data:image/")
+            .and_then(|rest| rest.split_once(";base64,"))
+            .ok_or_else(|| anyhow!("Bedrock only accepts images attached from files"))?;
+        let format = if format == "jpg" { "jpeg" } else { format };
+        blocks.push(json!({ "image": { "format": format, "source": { "bytes": data } } }));
+    }
+    Ok(blocks)
+}
+
+/// Translate the stop reason of Bedrock into the finish reason of OpenAI API
+fn finish_reason(stop_reason: &str) -> &str {
+    match stop_reason {
+        "max_tokens" => "length",
+        "content_filtered" | "guardrail_intervened" => "content_filter",
+        _ => "stop",
+    }
+}
+
+fn usage(usage: &Value) -> Value {
+    json!({
+        "prompt_tokens": usage["inputTokens"],
+        "completion_tokens": usage["outputTokens"],
+        "total_tokens": usage["totalTokens"],
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+/// Translate an error response of Bedrock, keeping the status so that it's classified the same
+async fn translate_error(response: reqwest::Response) -> Response<Body> {
+    let status = response.status();
+    // e.g. `ValidationException:http://internal.amazon.com/coral/com.amazon.bedrock/`
+    let error_type = response
+        .headers()
+        .get("x-amzn-errortype")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(':').next())
+        .unwrap_or("BedrockError")
+        .to_owned();
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|v| v["message"].as_str().map(str::to_owned))
+        .unwrap_or(body);
+    json_response(
+        status,
+        &json!({ "error": { "message": message, "type": error_type, "param": null, "code": null } }),
+    )
+}
+
+async fn translate_response(response: reqwest::Response, model: &str) -> Response<Body> {
+    let id = request_id(&response);
+    let output: Value = match response.json().await {
+        Ok(output) => output,
+        Err(err) => return error_response(StatusCode::BAD_GATEWAY, &err.to_string()),
+    };
+    let content: String = output["output"]["message"]["content"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|block| block["text"].as_str())
+        .collect();
+    let body = json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": now(),
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": finish_reason(output["stopReason"].as_str().unwrap_or_default()),
+        }],
+        "usage": usage(&output["usage"]),
+    });
+    json_response(StatusCode::OK, &body)
+}
+
+fn request_id(response: &reqwest::Response) -> String {
+    response
+        .headers()
+        .get("x-amzn-requestid")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("bedrock")
+        .to_owned()
+}
+
+/// Turn the event stream of a Converse stream response into server-sent events of chat
+/// completion chunks
+fn translate_stream(response: reqwest::Response, model: String) -> Response<Body> {
+    let id = request_id(&response);
+    let (mut sender, body) = Body::channel();
+    let mut stream = response.bytes_stream();
+    tokio::spawn(async move {
+        let chunk = |delta: Value, finish_reason: Option<&str>| {
+            json!({
+                "id": id,
+                "object": "chat.completion.chunk",
+                "created": now(),
+                "model": model,
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+            })
+        };
+        let mut buffer = Vec::new();
+        while let Some(bytes) = stream.next().await {
+            let Ok(bytes) = bytes else {
+                sender.abort();
+                return;
+            };
+            buffer.extend_from_slice(&bytes);
+            loop {
+                let event = match next_event(&mut buffer) {
+                    Ok(Some(event)) => event,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warning(format!("Bedrock sent a malformed event: {e}"));
+                        sender.abort();
+                        return;
+                    }
+                };
+                let payload: Value = serde_json::from_slice(&event.payload).unwrap_or_default();
+                let data = match (event.header(":message-type"), event.header(":event-type")) {
+                    ("event", "messageStart") => chunk(json!({ "role": "assistant" }), None),
+                    ("event", "contentBlockDelta") => match payload["delta"]["text"].as_str() {
+                        Some(text) => chunk(json!({ "content": text }), None),
+                        None => continue,
+                    },
+                    ("event", "messageStop") => chunk(
+                        json!({}),
+                        Some(finish_reason(
+                            payload["stopReason"].as_str().unwrap_or_default(),
+                        )),
+                    ),
+                    ("event", "metadata") => {
+                        let mut data = chunk(json!({}), None);
+                        data["choices"] = json!([]);
+                        data["usage"] = usage(&payload["usage"]);
+                        data
+                    }
+                    ("event", _) => continue,
+                    _ => {
+                        // The status was sent already, so the error can only be reported here
+                        warning(format!(
+                            "Bedrock failed with {}: {}",
+                            event.header(":exception-type"),
+                            payload["message"].as_str().unwrap_or_default()
+                        ));
+                        sender.abort();
+                        return;
+                    }
+                };
+                if send_event(&mut sender, &data.to_string()).await.is_err() {
+                    return;
+                }
+            }
+        }
+        let _ = send_event(&mut sender, "[DONE]").await;
+    });
+
+    let mut response = Response::new(body);
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+    response
+}
+
+async fn send_event(sender: &mut Sender, data: &str) -> hyper::Result<()> {
+    sender
+        .send_data(Bytes::from(format!("data: {data}\n\n")))
+        .await
+}
+
+/// A message of the event stream encoding, with the headers of string values
+struct Event {
+    headers: Vec<(String, String)>,
+    payload: Vec<u8>,
+}
+
+impl Event {
+    fn header(&self, name: &str) -> &str {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// Take the next complete message of the event stream encoding out of the buffer. Checksums
+/// are not verified, as TLS protects the stream already.
+///
+/// A message is the total length and the length of the headers as 32-bit integers, the
+/// checksum of these, the headers, the payload, and the checksum of the whole message. Fails on
+/// lengths that don't fit, after which the stream can't be followed any more.
+fn next_event(buffer: &mut Vec<u8>) -> Result<Option<Event>> {
+    let read_u32 = |b: &[u8]| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+    if buffer.len() < 12 {
+        return Ok(None);
+    }
+    let total_len = read_u32(&buffer[0..4]);
+    let headers_len = read_u32(&buffer[4..8]);
+    if total_len < 16 || headers_len > total_len - 16 {
+        bail!("message of {total_len} bytes with {headers_len} bytes of headers");
+    }
+    if buffer.len() < total_len {
+        return Ok(None);
+    }
+    let message: Vec<u8> = buffer.drain(..total_len).collect();
+    let headers_end = 12 + headers_len;
+    let payload_end = total_len - 4;
+
+    let mut headers = Vec::new();
+    let mut rest = &message[12..headers_end];
+    while let Some((&name_len, tail)) = rest.split_first() {
+        let name_len = name_len as usize;
+        if tail.len() < name_len + 1 {
+            break;
+        }
+        let name = String::from_utf8_lossy(&tail[..name_len]).into_owned();
+        let value_type = tail[name_len];
+        let tail = &tail[name_len + 1..];
+        // Lengths of values by type, where strings and byte arrays have a 16-bit length
+        let (value, tail) = match value_type {
+            0 | 1 => (&tail[..0], tail),
+            2 => tail.split_at(1.min(tail.len())),
+            3 => tail.split_at(2.min(tail.len())),
+            4 => tail.split_at(4.min(tail.len())),
+            5 | 8 => tail.split_at(8.min(tail.len())),
+            9 => tail.split_at(16.min(tail.len())),
+            6 | 7 if tail.len() >= 2 => {
+                let len = u16::from_be_bytes([tail[0], tail[1]]) as usize;
+                tail[2..].split_at(len.min(tail.len() - 2))
+            }
+            _ => break,
+        };
+        if value_type == 7 {
+            headers.push((name, String::from_utf8_lossy(value).into_owned()));
+        }
+        rest = tail;
+    }
+    Ok(Some(Event {
+        headers,
+        payload: message[headers_end..payload_end].to_vec(),
+    }))
+}
+
+/// Region in the host name of Bedrock Runtime, e.g. `bedrock-runtime.us-east-1.amazonaws.com`
+pub fn region_of_url(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    let rest = host
+        .strip_prefix("bedrock-runtime.")
+        .or_else(|| host.strip_prefix("bedrock-runtime-fips."))?;
+    let (region, domain) = rest.split_once('.')?;
+    domain.starts_with("amazonaws.").then(|| region.to_owned())
+}
+
+/// Region from `AWS_REGION`, `AWS_DEFAULT_REGION` or the config file of the AWS CLI
+fn region(profile: &str) -> Result<String> {
+    for name in ["AWS_REGION", "AWS_DEFAULT_REGION"] {
+        if let Ok(region) = std::env::var(name) {
+            return Ok(region);
+        }
+    }
+    let section = if profile == "default" {
+        profile.to_owned()
+    } else {
+        format!("profile {profile}")
+    };
+    aws_file("AWS_CONFIG_FILE", "config", &section, "region")?.ok_or_else(|| {
+        anyhow!("No AWS region for Bedrock. Please set it via AWS_REGION environment variable.")
+    })
+}
+
+/// Credentials from the environment, or the profile in the shared credentials file
+fn credentials(profile: &str) -> Result<Credentials> {
+    if let (Ok(access_key_id), Ok(secret_access_key)) = (
+        std::env::var("AWS_ACCESS_KEY_ID"),
+        std::env::var("AWS_SECRET_ACCESS_KEY"),
+    ) {
+        return Ok(Credentials {
+            access_key_id,
+            secret_access_key,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        });
+    }
+    let get = |key| aws_file("AWS_SHARED_CREDENTIALS_FILE", "credentials", profile, key);
+    match (get("aws_access_key_id")?, get("aws_secret_access_key")?) {
+        (Some(access_key_id), Some(secret_access_key)) => Ok(Credentials {
+            access_key_id,
+            secret_access_key,
+            session_token: get("aws_session_token")?,
+        }),
+        _ => Err(crate::error::Failure::new(
+            crate::error::FailureKind::Auth,
+            format!("AWS credentials are required for Bedrock. Please set them via AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment variables, or in profile `{profile}` of ~/.aws/credentials."),
+        )
+        .into()),
+    }
+}
+
+/// Look up a key in a section of an INI file of the AWS CLI, at the path in the environment
+/// variable or in `~/.aws`
+fn aws_file(var: &str, name: &str, section: &str, key: &str) -> Result<Option<String>> {
+    let path = match std::env::var_os(var) {
+        Some(path) => PathBuf::from(path),
+        None => match dirs::home_dir() {
+            Some(home) => home.join(".aws").join(name),
+            None => return Ok(None),
+        },
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(anyhow!("Failed to read {}: {err}", path.display())),
+    };
+    let mut in_section = false;
+    for line in text.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_section = name.trim() == section;
+        } else if let (true, Some((k, v))) = (in_section, line.split_once('=')) {
+            if k.trim() == key {
+                return Ok(Some(v.trim().to_owned()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Timestamp in the basic ISO 8601 format of AWS, e.g. `20240101T120000Z`
+fn amz_date(secs: u64) -> String {
+    format!(
+        "{}Z",
+        format_time(secs).replace(['-', ':'], "").replace(' ', "T")
+    )
+}
+
+/// Percent-encode everything but unreserved characters, as AWS expects
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data.as_bytes())?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Credentials of the examples in the AWS documentation
+    fn example_credentials() -> Credentials {
+        Credentials {
+            access_key_id: "AKIDEXAMPLE".to_owned(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_owned(),
+            session_token: None,
+        }
+    }
+
+    #[test]
+    fn signing_key_of_aws_example() {
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        )
+        .unwrap();
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    /// `post-vanilla` of the Signature Version 4 test suite
+    #[test]
+    fn post_vanilla() {
+        let headers = [
+            ("host", "example.amazonaws.com".to_owned()),
+            ("x-amz-date", "20150830T123600Z".to_owned()),
+        ];
+        let authorization = authorization(
+            &example_credentials(),
+            "us-east-1",
+            "service",
+            "/",
+            &headers,
+            "",
+            "20150830T123600Z",
+        )
+        .unwrap();
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+        );
+    }
+
+    /// `post-x-www-form-urlencoded` of the Signature Version 4 test suite
+    #[test]
+    fn post_with_body() {
+        let headers = [
+            (
+                "content-type",
+                "application/x-www-form-urlencoded".to_owned(),
+            ),
+            ("host", "example.amazonaws.com".to_owned()),
+            ("x-amz-date", "20150830T123600Z".to_owned()),
+        ];
+        let authorization = authorization(
+            &example_credentials(),
+            "us-east-1",
+            "service",
+            "/",
+            &headers,
+            "Param1=value1",
+            "20150830T123600Z",
+        )
+        .unwrap();
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=content-type;host;x-amz-date, Signature=ff11897932ad3f4e8b18135d722051e5ac45fc38421b1da7b9d196a0fe09473a"
+        );
+    }
+
+    /// A message in the event stream encoding with zeroed checksums
+    fn message(headers: &[u8], payload: &[u8]) -> Vec<u8> {
+        let total_len = (16 + headers.len() + payload.len()) as u32;
+        let mut message = total_len.to_be_bytes().to_vec();
+        message.extend((headers.len() as u32).to_be_bytes());
+        message.extend([0; 4]);
+        message.extend(headers);
+        message.extend(payload);
+        message.extend([0; 4]);
+        message
+    }
+
+    #[test]
+    fn event_stream_messages() {
+        let header = b"\x0b:event-type\x07\x00\x11contentBlockDelta";
+        let mut buffer = message(header, b"{}");
+        buffer.extend(message(b"", b""));
+        let event = next_event(&mut buffer).unwrap().unwrap();
+        assert_eq!(event.header(":event-type"), "contentBlockDelta");
+        assert_eq!(event.payload, b"{}");
+        assert!(next_event(&mut buffer).unwrap().unwrap().payload.is_empty());
+        assert!(next_event(&mut buffer).unwrap().is_none());
+
+        // Incomplete
+        let mut buffer = message(header, b"{}")[..20].to_vec();
+        assert!(next_event(&mut buffer).unwrap().is_none());
+
+        // Lengths that don't fit
+        for (total_len, headers_len) in [(0u32, 0u32), (15, 0), (20, 5), (16, u32::MAX)] {
+            let mut buffer = total_len.to_be_bytes().to_vec();
+            buffer.extend(headers_len.to_be_bytes());
+            buffer.resize(32, 0);
+            assert!(
+                next_event(&mut buffer).is_err(),
+                "{total_len} {headers_len}"
+            );
+        }
+    }
+
+    #[test]
+    fn uri_encoding_and_dates() {
+        assert_eq!(
+            uri_encode("us.anthropic.claude-3-5-haiku-20241022-v1:0"),
+            "us.anthropic.claude-3-5-haiku-20241022-v1%3A0"
+        );
+        assert_eq!(amz_date(1440938160), "20150830T123600Z");
+    }
+}
@@ -0,0 +1,181 @@
+//! Client-side guardrails on the number of requests and the spending, e.g. for teams sharing
+//! an API key. Requests and their estimated costs are tracked in a state file in the data
+//! directory, shared by all heygpt processes of the user.
+
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Failure, FailureKind};
+use crate::{paths, Options};
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+/// Requests and spending of the last day
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct Ledger {
+    /// Unix timestamps of requests
+    #[serde(default)]
+    requests: Vec<u64>,
+
+    /// Unix timestamps and estimated costs in USD of responses
+    #[serde(default)]
+    spending: Vec<(u64, f64)>,
+}
+
+/// Whether any budget is configured. Nothing is tracked otherwise.
+pub fn is_enabled(options: &Options) -> bool {
+    options.max_requests_per_hour.is_some() || options.max_cost_per_day.is_some()
+}
+
+/// The ledger locked against other processes from checking the budgets until the request is
+/// counted, so that concurrent requests can't all pass the same budget
+pub struct Guard {
+    _lock: File,
+    ledger: Ledger,
+}
+
+impl Guard {
+    /// Count the request being sent, and release the lock
+    pub fn record_request(mut self) -> Result<()> {
+        self.ledger.requests.push(now());
+        self.ledger.save()
+    }
+}
+
+/// Check whether sending a request costing about `estimated_cost` would exceed a budget, and
+/// describe the exceeded budget if so. A request of unknown cost can't be kept within
+/// `max_cost_per_day`. The returned guard holds the lock on the ledger until dropped or the
+/// request is recorded with it.
+pub fn check(
+    options: &Options,
+    model: &str,
+    estimated_cost: Option<f64>,
+) -> Result<(Guard, Option<Failure>)> {
+    let lock = Ledger::lock()?;
+    let ledger = Ledger::load()?;
+    let failure = exceeded(&ledger, options, model, estimated_cost);
+    Ok((
+        Guard {
+            _lock: lock,
+            ledger,
+        },
+        failure,
+    ))
+}
+
+/// The budget the request would exceed, if any
+fn exceeded(
+    ledger: &Ledger,
+    options: &Options,
+    model: &str,
+    estimated_cost: Option<f64>,
+) -> Option<Failure> {
+    let now = now();
+    if let Some(max) = options.max_requests_per_hour {
+        let requests = ledger.requests.iter().filter(|&&t| t + HOUR > now).count();
+        if requests >= max as usize {
+            return Some(Failure::new(
+                FailureKind::BudgetExceeded,
+                format!("Reached the budget of {max} requests per hour (`max_requests_per_hour`)"),
+            ));
+        }
+    }
+    if let Some(max) = options.max_cost_per_day {
+        let Some(estimated_cost) = estimated_cost else {
+            return Some(Failure::new(
+                FailureKind::BudgetExceeded,
+                format!(
+                    "Pricing of model `{model}` is unknown, so the budget of ${max} per day (`max_cost_per_day`) can't be kept; set `input_price` and `output_price` under `[models.\"{model}\"]` in the config file"
+                ),
+            ));
+        };
+        let spent: f64 = ledger
+            .spending
+            .iter()
+            .filter(|(t, _)| t + DAY > now)
+            .map(|(_, cost)| cost)
+            .sum();
+        if spent + estimated_cost > max {
+            return Some(Failure::new(
+                FailureKind::BudgetExceeded,
+                format!(
+                    "The request would exceed the budget of ${max} per day (`max_cost_per_day`), as ${spent:.4} has been spent in the last 24 hours"
+                ),
+            ));
+        }
+    }
+    None
+}
+
+/// Count a request being sent despite an exceeded budget
+pub fn record_request() -> Result<()> {
+    Ledger::update(|ledger| ledger.requests.push(now()))
+}
+
+/// Add the estimated cost of a response to the spending
+pub fn record_cost(cost: f64) -> Result<()> {
+    Ledger::update(|ledger| ledger.spending.push((now(), cost)))
+}
+
+impl Ledger {
+    fn path() -> Result<PathBuf> {
+        Ok(paths::data_dir()?.join("budget.json"))
+    }
+
+    /// Lock the ledger against other processes until the returned file is dropped
+    fn lock() -> Result<File> {
+        let path = Self::path()?.with_extension("lock");
+        paths::ensure_parent_dir(&path)?;
+        File::create(&path)
+            .and_then(|file| file.lock().map(|()| file))
+            .map_err(|e| anyhow!("Failed to lock {}: {e}", path.display()))
+    }
+
+    /// Load, change and save the ledger, holding a lock so that entries recorded by other
+    /// processes at the same time are not lost
+    fn update(change: impl FnOnce(&mut Self)) -> Result<()> {
+        let lock = Self::lock()?;
+        let mut ledger = Self::load()?;
+        change(&mut ledger);
+        let saved = ledger.save();
+        drop(lock);
+        saved
+    }
+
+    fn load() -> Result<Self> {
+        let path = Self::path()?;
+        match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| anyhow!("Invalid budget state file {}: {e}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(anyhow!("Failed to read {}: {err}", path.display())),
+        }
+    }
+
+    /// Save the ledger, dropping entries older than a day which no budget looks at. It is
+    /// written to a temporary file renamed over the old one, so readers never see it half
+    /// written.
+    fn save(&mut self) -> Result<()> {
+        let now = now();
+        self.requests.retain(|&t| t + DAY > now);
+        self.spending.retain(|(t, _)| t + DAY > now);
+        let path = Self::path()?;
+        paths::ensure_parent_dir(&path)?;
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_string(self)?)
+            .and_then(|()| std::fs::rename(&temp, &path))
+            .map_err(|e| anyhow!("Failed to write {}: {e}", path.display()))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::ResponseUsage;
+
+/// What a model supports, used for trimming, cost estimates and parameter validation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Capability {
+    /// Context window size in tokens, including both prompt and completion
+    pub context_window: usize,
+    /// Whether the model accepts image inputs
+    pub vision: bool,
+    /// Whether the model supports function calling
+    pub tools: bool,
+    /// Whether the model is a reasoning model like o1
+    pub reasoning: bool,
+    /// Whether the model supports streaming API
+    pub streaming: bool,
+    /// Price in USD per 1M prompt tokens
+    pub input_price: Option<f64>,
+    /// Price in USD per 1M completion tokens
+    pub output_price: Option<f64>,
+}
+
+/// Capability overrides in config file, e.g.
+///
+/// ```toml
+/// [models."llama3"]
+/// context_window = 8192
+/// tools = false
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CapabilityOverride {
+    pub context_window: Option<usize>,
+    pub vision: Option<bool>,
+    pub tools: Option<bool>,
+    pub reasoning: Option<bool>,
+    pub streaming: Option<bool>,
+    pub input_price: Option<f64>,
+    pub output_price: Option<f64>,
+}
+
+const fn cap(
+    context_window: usize,
+    vision: bool,
+    tools: bool,
+    reasoning: bool,
+    streaming: bool,
+    input_price: f64,
+    output_price: f64,
+) -> Capability {
+    Capability {
+        context_window,
+        vision,
+        tools,
+        reasoning,
+        streaming,
+        input_price: Some(input_price),
+        output_price: Some(output_price),
+    }
+}
+
+/// Built-in capabilities keyed by model name prefix. The longest matching prefix wins.
+#[rustfmt::skip]
+const BUILTIN: &[(&str, Capability)] = &[
+    ("gpt-3.5-turbo", cap(16_385, false, true, false, true, 0.5, 1.5)),
+    ("gpt-4", cap(8_192, false, true, false, true, 30.0, 60.0)),
+    ("gpt-4-32k", cap(32_768, false, true, false, true, 60.0, 120.0)),
+    ("gpt-4-turbo", cap(128_000, true, true, false, true, 10.0, 30.0)),
+    ("gpt-4o", cap(128_000, true, true, false, true, 2.5, 10.0)),
+    ("gpt-4o-mini", cap(128_000, true, true, false, true, 0.15, 0.6)),
+    ("gpt-4.1", cap(1_047_576, true, true, false, true, 2.0, 8.0)),
+    ("gpt-4.1-mini", cap(1_047_576, true, true, false, true, 0.4, 1.6)),
+    ("gpt-4.1-nano", cap(1_047_576, true, true, false, true, 0.1, 0.4)),
+    ("o1", cap(200_000, true, true, true, true, 15.0, 60.0)),
+    ("o1-mini", cap(128_000, false, false, true, false, 1.1, 4.4)),
+    ("o1-preview", cap(128_000, false, false, true, false, 15.0, 60.0)),
+    ("o3", cap(200_000, true, true, true, true, 2.0, 8.0)),
+    ("o3-mini", cap(200_000, false, true, true, true, 1.1, 4.4)),
+    ("o4-mini", cap(200_000, true, true, true, true, 1.1, 4.4)),
+];
+
+/// Capability assumed for unknown models: permissive, so that nothing is rejected up front
+const UNKNOWN: Capability = Capability {
+    context_window: 128_000,
+    vision: true,
+    tools: true,
+    reasoning: false,
+    streaming: true,
+    input_price: None,
+    output_price: None,
+};
+
+impl Capability {
+    /// Look up the capability of a model, applying overrides from config file.
+    /// Provider prefixes like `openai/` are ignored.
+    pub fn lookup(model: &str, overrides: &BTreeMap<String, CapabilityOverride>) -> Self {
+        Self::lookup_known(model, overrides).unwrap_or(UNKNOWN)
+    }
+
+    /// Same as `lookup`, but returns `None` for models neither built-in nor in overrides
+    pub fn lookup_known(
+        model: &str,
+        overrides: &BTreeMap<String, CapabilityOverride>,
+    ) -> Option<Self> {
+        let name = model.rsplit('/').next().unwrap_or(model);
+
+        let builtin = longest_prefix_match(name, BUILTIN.iter().map(|(p, c)| (*p, c))).cloned();
+        let over = longest_prefix_match(name, overrides.iter().map(|(p, c)| (p.as_str(), c)))
+            .or_else(|| overrides.get(model));
+
+        match (builtin, over) {
+            (None, None) => None,
+            (builtin, over) => {
+                let mut capability = builtin.unwrap_or(UNKNOWN);
+                if let Some(over) = over {
+                    capability.apply(over);
+                }
+                Some(capability)
+            }
+        }
+    }
+
+    /// Names of built-in models and models in config file
+    pub fn known_models(overrides: &BTreeMap<String, CapabilityOverride>) -> Vec<String> {
+        let mut models: Vec<_> = BUILTIN
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .chain(overrides.keys().cloned())
+            .collect();
+        models.sort();
+        models.dedup();
+        models
+    }
+
+    fn apply(&mut self, over: &CapabilityOverride) {
+        self.context_window = over.context_window.unwrap_or(self.context_window);
+        self.vision = over.vision.unwrap_or(self.vision);
+        self.tools = over.tools.unwrap_or(self.tools);
+        self.reasoning = over.reasoning.unwrap_or(self.reasoning);
+        self.streaming = over.streaming.unwrap_or(self.streaming);
+        if over.input_price.is_some() {
+            self.input_price = over.input_price;
+        }
+        if over.output_price.is_some() {
+            self.output_price = over.output_price;
+        }
+    }
+
+    /// Cost in USD of a request, as reported by the API or else estimated from pricing.
+    /// Returns `None` if pricing is unknown.
+    pub fn estimate_cost(&self, usage: &ResponseUsage) -> Option<f64> {
+        if usage.cost.is_some() {
+            return usage.cost;
+        }
+        let input = self.input_price? * usage.prompt_tokens as f64;
+        let output = self.output_price? * usage.completion_tokens as f64;
+        Some((input + output) / 1_000_000.0)
+    }
+}
+
+fn longest_prefix_match<'a, T>(
+    name: &str,
+    candidates: impl Iterator<Item = (&'a str, T)>,
+) -> Option<T> {
+    candidates
+        .filter(|(prefix, _)| name.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, c)| c)
+}
@@ -0,0 +1,292 @@
+//! Recording of raw API traffic into a cassette file, and replaying it without network access.
+//!
+//! Both work by pointing the API base URL at a local server: when recording it forwards the
+//! requests to the real endpoint and saves each exchange, and when replaying it answers with
+//! the saved exchanges in order. A cassette is a JSON Lines file, one exchange per line.
+
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, HOST};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::serve::error_response;
+use crate::{http_client, Options};
+
+/// A request and its response as sent over the wire
+#[derive(Serialize, Deserialize, Debug)]
+struct Exchange {
+    method: String,
+    /// Path relative to the API base URL, with query
+    path: String,
+    request: String,
+    status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    /// Response body in chunks as received, so that streaming responses are replayed with the
+    /// same chunk boundaries
+    chunks: Vec<String>,
+}
+
+/// Proxy saving every exchange with the upstream API into the cassette
+struct Recorder {
+    client: Client,
+    upstream: String,
+    file: Mutex<File>,
+}
+
+/// Server answering requests with the exchanges of a cassette in order
+struct Player {
+    exchanges: Mutex<std::vec::IntoIter<Exchange>>,
+}
+
+/// Start recording the traffic to the API at `options.api_base_url` into the cassette file.
+/// Returns the base URL to send requests to instead.
+pub fn record(options: &Options, path: &str) -> Result<String> {
+    let file =
+        File::create(path).map_err(|e| anyhow!("Failed to create cassette file `{path}`: {e}"))?;
+    let recorder = Arc::new(Recorder {
+        client: http_client(options)?,
+        upstream: options.api_base_url.trim_end_matches('/').to_owned(),
+        file: Mutex::new(file),
+    });
+
+    let make_svc = make_service_fn(move |_conn| {
+        let recorder = recorder.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let recorder = recorder.clone();
+                async move { Ok::<_, Infallible>(recorder.handle(req).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_svc);
+    let base_url = format!("http://{}", server.local_addr());
+    debug!("Recording API traffic via {base_url}");
+    tokio::spawn(server);
+    Ok(base_url)
+}
+
+/// Start serving the exchanges recorded in the cassette file. Returns the base URL to send
+/// requests to instead of the API.
+pub fn replay(path: &str) -> Result<String> {
+    let file =
+        File::open(path).map_err(|e| anyhow!("Failed to open cassette file `{path}`: {e}"))?;
+    let mut exchanges = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let exchange: Exchange = serde_json::from_str(&line)
+            .map_err(|e| anyhow!("Invalid cassette file `{path}` at line {}: {e}", i + 1))?;
+        exchanges.push(exchange);
+    }
+    let player = Arc::new(Player {
+        exchanges: Mutex::new(exchanges.into_iter()),
+    });
+
+    let make_svc = make_service_fn(move |_conn| {
+        let player = player.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let player = player.clone();
+                async move { Ok::<_, Infallible>(player.handle(req)) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_svc);
+    let base_url = format!("http://{}", server.local_addr());
+    debug!("Replaying API traffic via {base_url}");
+    tokio::spawn(server);
+    Ok(base_url)
+}
+
+/// Path of the request with query
+fn path_of(req: &Request<Body>) -> String {
+    req.uri()
+        .path_and_query()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_default()
+}
+
+impl Recorder {
+    async fn handle(self: Arc<Self>, req: Request<Body>) -> Response<Body> {
+        let method = req.method().clone();
+        let path = path_of(&req);
+        info!("record: {} {}", method, path);
+        self.forward(req).await.unwrap_or_else(|err| {
+            warn!("{} {}: {}", method, path, err);
+            error_response(StatusCode::BAD_GATEWAY, &err.to_string())
+        })
+    }
+
+    /// Forward the request upstream and stream the response back, saving the exchange once
+    /// the response is complete
+    async fn forward(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>> {
+        let method = req.method().clone();
+        let path = path_of(&req);
+        let mut headers = req.headers().clone();
+        headers.remove(HOST);
+        headers.remove(CONTENT_LENGTH);
+        let body = hyper::body::to_bytes(req.into_body()).await?;
+
+        let upstream = self
+            .client
+            .request(method.clone(), format!("{}{}", self.upstream, path))
+            .headers(headers)
+            .body(body.clone())
+            .send()
+            .await?;
+        let status = upstream.status();
+        let content_type = upstream.headers().get(CONTENT_TYPE).cloned();
+
+        let mut exchange = Exchange {
+            method: method.to_string(),
+            path,
+            request: String::from_utf8_lossy(&body).into_owned(),
+            status: status.as_u16(),
+            content_type: content_type
+                .as_ref()
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned),
+            chunks: Vec::new(),
+        };
+        let (mut sender, response_body) = Body::channel();
+        let mut stream = upstream.bytes_stream();
+        tokio::spawn(async move {
+            // A character split between chunks is saved with the chunk it ends in
+            let mut pending = Vec::new();
+            while let Some(chunk) = stream.next().await {
+                match chunk {
+                    Ok(bytes) => {
+                        pending.extend_from_slice(&bytes);
+                        let text = take_text(&mut pending);
+                        if !text.is_empty() {
+                            exchange.chunks.push(text);
+                        }
+                        if sender.send_data(bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        warn!("Failed to read response of {}: {}", exchange.path, err);
+                        sender.abort();
+                        break;
+                    }
+                }
+            }
+            if !pending.is_empty() {
+                exchange
+                    .chunks
+                    .push(String::from_utf8_lossy(&pending).into_owned());
+            }
+            if let Err(err) = self.save(&exchange) {
+                warn!("Failed to save exchange of {}: {}", exchange.path, err);
+            }
+        });
+
+        let mut response = Response::new(response_body);
+        *response.status_mut() = status;
+        if let Some(content_type) = content_type {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        Ok(response)
+    }
+
+    fn save(&self, exchange: &Exchange) -> Result<()> {
+        let line = serde_json::to_string(exchange)?;
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{line}")?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Take the text of the bytes received so far, leaving an incomplete UTF-8 character at the end
+/// for the next chunk. Invalid bytes are replaced.
+fn take_text(pending: &mut Vec<u8>) -> String {
+    let end = match std::str::from_utf8(pending) {
+        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+        _ => pending.len(),
+    };
+    let rest = pending.split_off(end);
+    let text = String::from_utf8_lossy(pending).into_owned();
+    *pending = rest;
+    text
+}
+
+impl Player {
+    fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let method = req.method().to_string();
+        let path = path_of(&req);
+        info!("replay: {} {}", method, path);
+
+        let Some(exchange) = self.exchanges.lock().unwrap().next() else {
+            return error_response(
+                StatusCode::NOT_FOUND,
+                &format!("No more recorded exchanges for {method} {path}"),
+            );
+        };
+        if exchange.method != method || exchange.path != path {
+            return error_response(
+                StatusCode::NOT_FOUND,
+                &format!(
+                    "Recorded exchange is {} {} but got {method} {path}",
+                    exchange.method, exchange.path
+                ),
+            );
+        }
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in exchange.chunks {
+                if sender.send_data(chunk.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut response = Response::new(body);
+        *response.status_mut() = StatusCode::from_u16(exchange.status).unwrap_or(StatusCode::OK);
+        let content_type = exchange.content_type.as_deref().map(HeaderValue::from_str);
+        if let Some(Ok(content_type)) = content_type {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn characters_split_between_chunks() {
+        let bytes = "data: 你好\n".as_bytes();
+        let mut pending = bytes[..8].to_vec();
+        assert_eq!(take_text(&mut pending), "data: ");
+        assert_eq!(pending, &bytes[6..8]);
+        pending.extend_from_slice(&bytes[8..]);
+        assert_eq!(take_text(&mut pending), "你好\n");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced() {
+        let mut pending = b"a\xffb".to_vec();
+        assert_eq!(take_text(&mut pending), "a\u{fffd}b");
+        assert!(pending.is_empty());
+    }
+}
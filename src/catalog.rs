@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::Args;
+use reqwest::{Client, Method};
+use tracing::debug;
+
+use crate::capability::Capability;
+use crate::model::{ModelInfo, ModelList};
+use crate::{api_request, check_response, http_client, verbose, warning, Options};
+
+#[derive(Args, Debug)]
+pub struct ModelsArgs {
+    /// Show all models, including those not for chat such as embedding models
+    #[arg(long)]
+    pub all: bool,
+}
+
+/// Keywords of models that are not for chat completions
+const NON_CHAT_KEYWORDS: &[&str] = &[
+    "embedding",
+    "whisper",
+    "tts",
+    "dall-e",
+    "image",
+    "moderation",
+    "transcribe",
+    "realtime",
+    "davinci",
+    "babbage",
+];
+
+/// How long to wait for the list of models when checking `--model`
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub async fn run(options: Options, args: ModelsArgs) -> Result<()> {
+    print_models(&http_client(&options)?, &options, args.all).await
+}
+
+/// Print models offered by the endpoint with their context windows and prices, and warn if
+/// the configured model is not among them
+pub async fn print_models(client: &Client, options: &Options, all: bool) -> Result<()> {
+    let mut models = fetch_models(client, options).await?;
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+
+    for model in &models {
+        if !all && !is_chat_model(&model.id) {
+            continue;
+        }
+        let capability = Capability::lookup_known(&model.id, &options.models);
+        let context_window = model
+            .context_length
+            .or(capability.as_ref().map(|c| c.context_window));
+        // Per million tokens, like the prices in config file
+        let prices = match (&model.pricing, &capability) {
+            (Some(pricing), _) => pricing
+                .prompt
+                .parse::<f64>()
+                .ok()
+                .zip(pricing.completion.parse::<f64>().ok())
+                .map(|(input, output)| (input * 1_000_000.0, output * 1_000_000.0)),
+            (None, Some(capability)) => capability.input_price.zip(capability.output_price),
+            (None, None) => None,
+        };
+        let mut line = format!("{:<40}", model.id);
+        if let Some(context_window) = context_window {
+            line.push_str(&format!(
+                " {:>9}",
+                format!("{}k ctx", context_window / 1000)
+            ));
+        }
+        if let Some((input, output)) = prices {
+            line.push_str(&format!("  ${input:.2} / ${output:.2} per 1M tokens"));
+        }
+        println!("{}", line.trim_end());
+    }
+
+    let ids: Vec<_> = models.into_iter().map(|m| m.id).collect();
+    check_model(&options.model, &ids);
+    Ok(())
+}
+
+/// Warn if the model given by `--model` is not offered by the endpoint, before asking it.
+/// Endpoints that can't list their models in time are not checked.
+pub async fn check_requested_model(options: &Options) -> Result<()> {
+    let client = http_client(options)?;
+    match tokio::time::timeout(CHECK_TIMEOUT, fetch_models(&client, options)).await {
+        Ok(Ok(models)) => {
+            let ids: Vec<_> = models.into_iter().map(|m| m.id).collect();
+            check_model(&options.model, &ids);
+        }
+        Ok(Err(err)) => debug!("failed to list models to check `--model`: {err}"),
+        Err(_) => debug!("timed out listing models to check `--model`"),
+    }
+    Ok(())
+}
+
+/// Models offered by the endpoint
+async fn fetch_models(client: &Client, options: &Options) -> Result<Vec<ModelInfo>> {
+    let req = api_request(client, options, Method::GET, "/models")?;
+    let response = verbose::send(options, req).await?;
+    let list: ModelList = check_response(response).await?.json().await?;
+    Ok(list.data)
+}
+
+fn is_chat_model(id: &str) -> bool {
+    !NON_CHAT_KEYWORDS.iter().any(|k| id.contains(k))
+}
+
+/// Warn if the model is not offered by the endpoint, suggesting the closest one
+pub fn check_model(model: &str, models: &[String]) {
+    if models.is_empty() || models.iter().any(|m| m == model) {
+        return;
+    }
+    match suggest_model(model, models) {
+        Some(suggestion) => warning(format!(
+            "Model `{model}` is not offered by the endpoint. Did you mean `{suggestion}`?"
+        )),
+        None => warning(format!("Model `{model}` is not offered by the endpoint")),
+    }
+}
+
+/// The model with the most similar name, if it's similar enough
+pub fn suggest_model<'a>(model: &str, models: &'a [String]) -> Option<&'a str> {
+    models
+        .iter()
+        .map(|m| (m, strsim::normalized_damerau_levenshtein(model, m)))
+        .filter(|(_, similarity)| *similarity > 0.6)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(m, _)| m.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggest_similar_model() {
+        let models = [
+            "gpt-4o".to_owned(),
+            "gpt-4o-mini".to_owned(),
+            "mock".to_owned(),
+        ];
+        assert_eq!(suggest_model("mok", &models), Some("mock"));
+        assert_eq!(suggest_model("gpt-4o-mni", &models), Some("gpt-4o-mini"));
+        assert_eq!(suggest_model("claude", &models), None);
+    }
+}
@@ -0,0 +1,152 @@
+//! `heygpt commit`: write a commit message for the staged changes and commit them
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+use console::style;
+
+use crate::git::{git, head_parent, truncate_diff};
+use crate::model::Message;
+use crate::{edit_in_editor, http_client, Options, Session};
+
+#[derive(Args, Debug)]
+pub struct CommitArgs {
+    /// Amend the last commit, describing its changes together with the staged ones
+    #[arg(long)]
+    pub amend: bool,
+
+    /// Follow the Conventional Commits format, e.g. `fix(parser): handle empty input`
+    #[arg(long)]
+    pub conventional: bool,
+
+    /// Commit without asking for confirmation
+    #[arg(short, long)]
+    pub yes: bool,
+
+    /// Extra context for the message, e.g. why the change is made
+    pub hint: Vec<String>,
+}
+
+/// System prompt for writing commit messages
+const COMMIT_PROMPT: &str = "You write git commit messages. Given a diff, write a concise subject line in the imperative mood, under 72 characters and without a trailing period. If the change is not trivial, add a blank line and a body wrapped at 72 characters explaining what changed and why. Follow the style of the recent commit messages if given. Reply with the commit message only, without code fences or any other text.";
+
+/// Appended to the system prompt for `--conventional`
+const CONVENTIONAL_PROMPT: &str = " Use the Conventional Commits format for the subject, i.e. `type(scope): description` where type is one of feat, fix, docs, style, refactor, perf, test, build, ci and chore, and the scope is optional.";
+
+pub async fn run(options: Options, args: CommitArgs) -> Result<()> {
+    let mut diff_args = vec!["diff", "--cached"];
+    if args.amend {
+        diff_args.push(head_parent());
+    }
+    let diff = git(&diff_args)?;
+    if diff.trim().is_empty() {
+        bail!("No staged changes to commit. Stage them with `git add` first.");
+    }
+    diff_args.push("--stat");
+    let stat = git(&diff_args)?;
+    let recent = git(&["log", "-n", "10", "--format=%s"]).unwrap_or_default();
+
+    let client = http_client(&options)?;
+    let is_stdin = atty::is(atty::Stream::Stdin);
+    let is_stdout = atty::is(atty::Stream::Stdout);
+    let mut session = Session::new(options, client, false, is_stdout);
+
+    let mut system = COMMIT_PROMPT.to_owned();
+    if args.conventional {
+        system.push_str(CONVENTIONAL_PROMPT);
+    }
+    let mut prompt = String::new();
+    if !recent.trim().is_empty() {
+        prompt.push_str(&format!("Recent commit messages:\n{recent}\n"));
+    }
+    if !args.hint.is_empty() {
+        prompt.push_str(&format!(
+            "Context from the author: {}\n\n",
+            args.hint.join(" ")
+        ));
+    }
+    // Leave half of the context window for the rest
+    let budget = session.capability().context_window / 2;
+    prompt.push_str(&format!(
+        "Changed files:\n{stat}\nDiff:\n{}",
+        truncate_diff(&diff, budget)
+    ));
+    session.messages = vec![
+        Message {
+            role: "system".to_string(),
+            content: system,
+            ..Default::default()
+        },
+        Message {
+            role: "user".to_string(),
+            content: prompt,
+            ..Default::default()
+        },
+    ];
+
+    let response = session.complete_and_print().await?;
+    let mut message = strip_fences(&response.content);
+    if message.is_empty() {
+        bail!("The model returned an empty commit message");
+    }
+
+    if !args.yes {
+        if !is_stdin {
+            eprintln!(
+                "{}",
+                style("Not committing as stdin is not a terminal. Use `--yes` to commit.").dim()
+            );
+            return Ok(());
+        }
+        eprint!("Commit with this message? [y]es / [e]dit / [N]o ");
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => {}
+            "e" | "edit" => {
+                message = edit_in_editor(&message)?;
+                if message.trim().is_empty() {
+                    bail!("Aborting commit due to empty commit message");
+                }
+            }
+            _ => return Ok(()),
+        }
+    }
+
+    commit(&message, args.amend)
+}
+
+/// Run `git commit` with the message
+fn commit(message: &str, amend: bool) -> Result<()> {
+    let mut command = Command::new("git");
+    command.args(["commit", "-F", "-"]);
+    if amend {
+        command.arg("--amend");
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run git: {e}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(message.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("`git commit` exited with {status}");
+    }
+    Ok(())
+}
+
+/// Remove the code fence that models sometimes wrap the message in despite being told not to
+fn strip_fences(text: &str) -> String {
+    let text = text.trim();
+    let Some(inner) = text.strip_prefix("```") else {
+        return text.to_owned();
+    };
+    // Skip the language tag, if any
+    let inner = inner.split_once('\n').map_or("", |(_, rest)| rest);
+    inner.trim_end().trim_end_matches("```").trim().to_owned()
+}
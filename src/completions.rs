@@ -0,0 +1,124 @@
+//! Shell completion scripts, generated from the definition of command-line arguments, with
+//! model names of `--model` listed by `heygpt completions --list-models` when completing
+
+use anyhow::{bail, Result};
+use clap::Args;
+use clap_complete::Shell;
+
+use crate::capability::Capability;
+use crate::Options;
+
+/// Name of the executable that completions are registered for
+const BIN: &str = "heygpt";
+
+#[derive(Args, Debug)]
+pub struct CompletionsArgs {
+    /// Shell to generate the completion script for
+    #[arg(value_enum, required_unless_present = "list_models")]
+    pub shell: Option<Shell>,
+
+    /// List model names for dynamic completion of `--model`
+    #[arg(long, hide = true)]
+    pub list_models: bool,
+}
+
+pub fn run(options: &Options, args: CompletionsArgs, mut command: clap::Command) -> Result<()> {
+    if args.list_models {
+        for model in Capability::known_models(&options.models) {
+            println!("{model}");
+        }
+        return Ok(());
+    }
+    let Some(shell) = args.shell else {
+        bail!("Shell is required");
+    };
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, BIN, &mut script);
+    let script = String::from_utf8(script)?;
+    print!("{}", with_models(shell, script)?);
+    Ok(())
+}
+
+/// Complete `--model` of chat completions with the model names of `--list-models` in the
+/// generated script. The models of subcommands such as `embed` are of other kinds. Elvish gets
+/// no model names.
+fn with_models(shell: Shell, script: String) -> Result<String> {
+    let list = format!("{BIN} completions --list-models 2>/dev/null");
+    match shell {
+        // The options of the top-level command come first
+        Shell::Bash => replace_first(
+            &script,
+            "--model)\n                    COMPREPLY=($(compgen -f \"${cur}\"))",
+            &format!("--model)\n                    COMPREPLY=($(compgen -W \"$({list})\" -- \"${{cur}}\"))"),
+        ),
+        Shell::Zsh => {
+            let script = replace_first(
+                &script,
+                ":MODEL: ' \\",
+                &format!(":MODEL:_{BIN}_models' \\"),
+            )?;
+            replace_first(
+                &script,
+                &format!("if [ \"$funcstack[1]\" = \"_{BIN}\" ]; then"),
+                &format!(
+                    r#"(( $+functions[_{BIN}_models] )) ||
+_{BIN}_models() {{
+    local models; models=(${{(f)"$({list})"}})
+    _describe -t models 'model' models "$@"
+}}
+
+if [ "$funcstack[1]" = "_{BIN}" ]; then"#
+                ),
+            )
+        }
+        Shell::Fish => replace_first(
+            &script,
+            &format!("complete -c {BIN} -n \"__fish_use_subcommand\" -l model "),
+            &format!("complete -c {BIN} -n \"__fish_use_subcommand\" -l model -f -a \"({BIN} completions --list-models)\" "),
+        ),
+        Shell::PowerShell => replace_first(
+            &script,
+            "    $completions = @(switch ($command) {",
+            &format!(
+                r#"    $previous = if ($wordToComplete) {{ $commandElements[-2] }} else {{ $commandElements[-1] }}
+    if ($command -eq '{BIN}' -and "$previous" -eq '--model') {{
+        return & {BIN} completions --list-models | Where-Object {{ $_ -like "$wordToComplete*" }} |
+            ForEach-Object {{ [CompletionResult]::new($_, $_, [CompletionResultType]::ParameterValue, $_) }}
+    }}
+
+    $completions = @(switch ($command) {{"#
+            ),
+        ),
+        _ => Ok(script),
+    }
+}
+
+/// Replace the first occurrence of `from`, which must be in the script generated by
+/// clap_complete
+fn replace_first(script: &str, from: &str, to: &str) -> Result<String> {
+    if !script.contains(from) {
+        bail!("Failed to add model names to the completion script");
+    }
+    Ok(script.replacen(from, to, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn model_names_in_scripts() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish, Shell::PowerShell] {
+            let mut script = Vec::new();
+            clap_complete::generate(shell, &mut crate::Cli::command(), BIN, &mut script);
+            let script = with_models(shell, String::from_utf8(script).unwrap()).unwrap();
+            assert_eq!(
+                script.matches("completions --list-models").count(),
+                1,
+                "{shell}"
+            );
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Subcommand};
+use clap_serde_derive::ClapSerde;
+use tracing::debug;
+
+use crate::{paths, redacted, warning, Options};
+
+#[derive(Args, Debug)]
+pub struct ConfigArgs {
+    /// Operate on `.heygpt.toml` in the current directory instead of the global config file
+    #[arg(long, global = true)]
+    pub project: bool,
+
+    #[command(subcommand)]
+    pub action: ConfigAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigAction {
+    /// Print the effective value of an option
+    Get { key: String },
+    /// Set an option in config file
+    Set { key: String, value: String },
+    /// Remove an option from config file
+    Unset { key: String },
+    /// List effective values of all options
+    List,
+    /// Open config file in `$EDITOR`
+    Edit,
+    /// Print the path of config file
+    Path,
+}
+
+/// Options that can't be set in config file
+const NOT_CONFIGURABLE: &[&str] = &[
+    "prompt",
+    "system",
+    "assistant_prefix",
+    "until",
+    "file",
+    "dir",
+    "include",
+    "url",
+    "messages_stdin",
+    "compare",
+    "best_of",
+    "map_reduce",
+    "interactive",
+    "verbose",
+    "no_config",
+    "show_config",
+    "no_interactive",
+    "stdin_as",
+    "record",
+    "replay",
+    "tee",
+    "watch",
+    "transcript",
+    "resume",
+    "hard",
+];
+
+/// Name of project config files
+pub const CONFIG_FILE: &str = ".heygpt.toml";
+
+/// Options that a project config file may set. The others are ignored, as a project could send
+/// your API key to an endpoint of its choice, run commands of its choice or change your own
+/// settings such as logging, so new options are left out until they are known to be safe.
+const PROJECT_KEYS: &[&str] = &[
+    "stream",
+    "model",
+    "temperature",
+    "top_p",
+    "frequency_penalty",
+    "presence_penalty",
+    "logit_bias",
+    "logit_bias_file",
+    "max_tokens",
+    "reasoning",
+    "system_file",
+    "examples",
+    "max_tokens_context",
+    "format",
+    "moderate",
+    "moderate_response",
+    "voice",
+    "speed",
+    "safe_prompt",
+    "timeout",
+    "connect_timeout",
+    "stall_timeout",
+    "first_token_timeout",
+    "max_requests_per_hour",
+    "max_cost_per_day",
+    "redact",
+    "auto_continue",
+    "stream_retries",
+    "auto_summarize",
+    "title_model",
+    "fallback_models",
+    "auto_model",
+    "router",
+    "rag",
+    "rag_top_k",
+    "show_candidates",
+];
+
+type OptionsOpt = <Options as ClapSerde>::Opt;
+
+/// Load options from the global config file, then from the project config file found in the
+/// current directory or its ancestors, recording where the values came from
+pub fn load(mut options: Options, sources: &mut Sources) -> Result<Options> {
+    if let Some(path) = global_config_file() {
+        if path.exists() {
+            let before = options.clone();
+            options = options.merge(read_config_file(&path)?);
+            sources.record(&before, &options, |_| path.display().to_string())?;
+        }
+    }
+
+    if let Some(path) = project_config_file() {
+        let (mut config, mut ignored) = read_project_config_file(&path)?;
+        // A project may lower your budgets but not raise them
+        if let Some(limit) = config.max_requests_per_hour {
+            if loosens(limit, options.max_requests_per_hour) {
+                config.max_requests_per_hour = None;
+                ignored.push("`max_requests_per_hour`".to_owned());
+            }
+        }
+        if let Some(limit) = config.max_cost_per_day {
+            if loosens(limit, options.max_cost_per_day) {
+                config.max_cost_per_day = None;
+                ignored.push("`max_cost_per_day`".to_owned());
+            }
+        }
+        if !ignored.is_empty() {
+            warning(format!(
+                "Ignoring {} in project config file {}",
+                ignored.join(", "),
+                path.display()
+            ));
+        }
+        let before = options.clone();
+        options = options.merge(config);
+        sources.record(&before, &options, |_| path.display().to_string())?;
+    }
+
+    Ok(options)
+}
+
+/// Whether a budget of the project config is looser than the current one, where `None` is no
+/// limit
+fn loosens<T: PartialOrd>(limit: Option<T>, current: Option<T>) -> bool {
+    match (limit, current) {
+        (Some(limit), Some(current)) => limit > current,
+        (None, current) => current.is_some(),
+        (Some(_), None) => false,
+    }
+}
+
+/// Where the effective value of each option came from, for `--show-config`. Options not in
+/// it have their default values.
+#[derive(Default)]
+pub struct Sources(BTreeMap<String, String>);
+
+impl Sources {
+    /// Attribute the options changed from `before` to `after` to the source named by `source`
+    pub fn record(
+        &mut self,
+        before: &Options,
+        after: &Options,
+        source: impl Fn(&str) -> String,
+    ) -> Result<()> {
+        let before = effective_values(before)?;
+        for (key, value) in effective_values(after)? {
+            if before.get(&key) != Some(&value) {
+                self.0.insert(key.clone(), source(&key));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print the effective options for `--show-config`, each with where its value came from
+pub fn show(options: &Options, sources: &Sources) -> Result<()> {
+    for (key, value) in effective_values(&redacted(options))? {
+        if value.is_null() {
+            continue;
+        }
+        let source = sources.0.get(&key).map_or("default", String::as_str);
+        println!("{key} = {value}  # {source}");
+    }
+    Ok(())
+}
+
+/// The global config file, or the legacy one in home directory if it failed to be migrated
+pub fn global_config_file() -> Option<PathBuf> {
+    let path = paths::config_file().ok();
+    match paths::legacy_config_file() {
+        Some(legacy) if legacy.is_file() && !path.as_ref().is_some_and(|p| p.exists()) => {
+            Some(legacy)
+        }
+        _ => path,
+    }
+}
+
+/// Walk up from the current directory to find a config file, like `.editorconfig`.
+/// The legacy global config file in home directory is skipped.
+fn project_config_file() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    let legacy = paths::legacy_config_file();
+    cwd.ancestors()
+        .map(|dir| dir.join(CONFIG_FILE))
+        .find(|path| path.is_file() && Some(path) != legacy.as_ref())
+}
+
+fn read_config_file(path: &Path) -> Result<OptionsOpt> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read config file {}: {e}", path.display()))?;
+    let config = toml::from_str::<OptionsOpt>(&content)
+        .map_err(|e| anyhow!("Failed to parse config file {}: {e}", path.display()))?;
+    debug!("Loaded config file {}: {}", path.display(), &content);
+    Ok(relative_to(path, config))
+}
+
+/// Read a project config file without the options not in `PROJECT_KEYS`, whose keys are
+/// returned to be warned about
+fn read_project_config_file(path: &Path) -> Result<(OptionsOpt, Vec<String>)> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read config file {}: {e}", path.display()))?;
+    let mut table = toml::from_str::<toml::Table>(&content)
+        .map_err(|e| anyhow!("Failed to parse config file {}: {e}", path.display()))?;
+    debug!("Loaded config file {}: {}", path.display(), &content);
+    let mut ignored = Vec::new();
+    table.retain(|key, _| {
+        let allowed = PROJECT_KEYS.contains(&key);
+        if !allowed {
+            ignored.push(format!("`{key}`"));
+        }
+        allowed
+    });
+    let config = toml::Value::Table(table)
+        .try_into::<OptionsOpt>()
+        .map_err(|e| anyhow!("Failed to parse config file {}: {e}", path.display()))?;
+    Ok((relative_to(path, config), ignored))
+}
+
+/// Resolve relative paths in a config file against its directory rather than the current one
+fn relative_to(path: &Path, mut config: OptionsOpt) -> OptionsOpt {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let paths = [
+        &mut config.system_file,
+        &mut config.rcfile,
+        &mut config.logit_bias_file,
+        &mut config.log_file,
+        &mut config.examples,
+        &mut config.mock_file,
+    ];
+    for file in paths.into_iter().flatten().flatten() {
+        if Path::new(file).is_relative() {
+            *file = dir.join(&*file).display().to_string();
+        }
+    }
+    config
+}
+
+pub fn run(options: Options, args: ConfigArgs) -> Result<()> {
+    let path = if args.project {
+        std::env::current_dir()?.join(CONFIG_FILE)
+    } else {
+        global_config_file().ok_or_else(|| anyhow!("Cannot find config directory"))?
+    };
+
+    match args.action {
+        ConfigAction::Get { key } => {
+            let values = effective_values(&options)?;
+            match values.get(&key) {
+                Some(serde_json::Value::Null) => {}
+                Some(value) => println!("{}", display_value(value)),
+                None => bail!("Unknown option `{key}`"),
+            }
+        }
+        ConfigAction::List => {
+            for (key, value) in effective_values(&redacted(&options))? {
+                if value.is_null() {
+                    continue;
+                }
+                println!("{key} = {value}");
+            }
+        }
+        ConfigAction::Set { key, value } => {
+            if !is_known_key(&key)? {
+                bail!("Unknown option `{key}`");
+            }
+            let mut doc = read_document(&path)?;
+            let mut value = parse_value(&value);
+            // Keep comments around the existing value
+            if let Some(old) = doc.get(&key).and_then(|item| item.as_value()) {
+                *value.decor_mut() = old.decor().clone();
+            }
+            doc[&key] = toml_edit::Item::Value(value);
+            write_document(&path, &doc)?;
+        }
+        ConfigAction::Unset { key } => {
+            let mut doc = read_document(&path)?;
+            if doc.remove(&key).is_none() {
+                bail!("Option `{key}` is not set in {}", path.display());
+            }
+            write_document(&path, &doc)?;
+        }
+        ConfigAction::Edit => {
+            let editor = editor();
+            paths::ensure_parent_dir(&path)?;
+            let status = Command::new(&editor).arg(&path).status()?;
+            if !status.success() {
+                bail!("{editor} exited with {status}");
+            }
+            if path.exists() {
+                read_config_file(&path)?;
+            }
+        }
+        ConfigAction::Path => println!("{}", path.display()),
+    }
+    Ok(())
+}
+
+/// The user's editor from `$VISUAL` or `$EDITOR`
+pub fn editor() -> String {
+    std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| if cfg!(windows) { "notepad" } else { "vi" }.to_owned())
+}
+
+/// Effective options keyed by option name. Options without value are `null`.
+fn effective_values(options: &Options) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let serde_json::Value::Object(mut values) = serde_json::to_value(options)? else {
+        unreachable!("options must be serialized as an object")
+    };
+    for key in NOT_CONFIGURABLE {
+        values.remove(*key);
+    }
+    Ok(values)
+}
+
+/// Whether the key is an option that can be set in config file
+fn is_known_key(key: &str) -> Result<bool> {
+    Ok(effective_values(&Options::default())?.contains_key(key))
+}
+
+/// Parse a value from command line as TOML, falling back to a plain string
+fn parse_value(value: &str) -> toml_edit::Value {
+    value
+        .parse::<toml_edit::Value>()
+        .unwrap_or_else(|_| value.into())
+}
+
+fn display_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn read_document(path: &Path) -> Result<toml_edit::Document> {
+    if !path.exists() {
+        return Ok(toml_edit::Document::new());
+    }
+    let content = std::fs::read_to_string(path)?;
+    content
+        .parse()
+        .map_err(|e| anyhow!("Failed to parse config file {}: {e}", path.display()))
+}
+
+/// Validate the document against options schema before writing it
+fn write_document(path: &Path, doc: &toml_edit::Document) -> Result<()> {
+    let content = doc.to_string();
+    toml::from_str::<OptionsOpt>(&content).map_err(|e| anyhow!("Invalid config: {e}"))?;
+    paths::ensure_parent_dir(path)?;
+    std::fs::write(path, content)
+        .map_err(|e| anyhow!("Failed to write config file {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_keys_are_options() {
+        for key in PROJECT_KEYS {
+            assert!(is_known_key(key).unwrap(), "{key}");
+        }
+    }
+}
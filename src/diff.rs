@@ -0,0 +1,61 @@
+//! Colored diffs of messages shown when history is changed by `\back` or `\edit`, and of files
+//! changed by `\apply`
+
+use std::time::{Duration, Instant};
+
+use console::style;
+use similar::{Algorithm, ChangeTag};
+
+use crate::model::Message;
+
+/// Time given to computing a diff of long texts
+const DIFF_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Print messages being removed from history, in red
+pub fn print_removed(messages: &[Message], first_index: usize) {
+    for (i, message) in messages.iter().enumerate() {
+        let header = format!("- [{}] {} =>", first_index + i, message.role);
+        println!("{}", style(header).red().bold());
+        for line in message.content.lines() {
+            println!("{}", style(format!("- {line}")).red());
+        }
+    }
+}
+
+/// Print a line diff of the changed message, with removed lines in red and added lines in green
+pub fn print_changed(index: usize, old: &Message, new: &str) {
+    let header = format!("~ [{index}] {} =>", old.role);
+    println!("{}", style(header).yellow().bold());
+    for (tag, line) in diff_lines(&old.content, new) {
+        print_line(tag, line);
+    }
+}
+
+/// Print a line of a diff tagged with `-`, `+` or ` `
+pub fn print_line(tag: char, line: &str) {
+    match tag {
+        '-' => println!("{}", style(format!("- {line}")).red()),
+        '+' => println!("{}", style(format!("+ {line}")).green()),
+        _ => println!("{}", style(format!("  {line}")).dim()),
+    }
+}
+
+/// Line diff tagged with `-`, `+` or ` `. A diff taking longer than `DIFF_TIMEOUT` is finished
+/// roughly, with fewer lines in common.
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<(char, &'a str)> {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+    let deadline = Instant::now() + DIFF_TIMEOUT;
+    let ops = similar::capture_diff_slices_deadline(Algorithm::Myers, &old, &new, Some(deadline));
+    ops.iter()
+        .flat_map(|op| op.iter_changes(&old, &new))
+        .map(|change| {
+            let tag = match change.tag() {
+                ChangeTag::Delete => '-',
+                ChangeTag::Insert => '+',
+                ChangeTag::Equal => ' ',
+            };
+            (tag, change.value())
+        })
+        .collect()
+}
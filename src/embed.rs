@@ -0,0 +1,122 @@
+use std::io::Write;
+
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+use reqwest::{Client, Method};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::model::{EmbeddingRequest, EmbeddingResponse};
+use crate::{api_request, check_response, http_client, verbose, Options};
+
+#[derive(Args, Debug)]
+pub struct EmbedArgs {
+    /// The embedding model
+    #[arg(long, default_value = "text-embedding-3-small")]
+    pub model: String,
+
+    /// Parse each input line as JSON: a string or an object with `text` and an optional `id`
+    #[arg(long)]
+    pub jsonl: bool,
+
+    /// Number of inputs sent per request
+    #[arg(long, default_value_t = 100)]
+    pub batch_size: usize,
+
+    /// Number of dimensions of the embeddings, only supported by newer models
+    #[arg(long)]
+    pub dimensions: Option<u32>,
+}
+
+/// A line of JSONL input
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum EmbedInput {
+    Text(String),
+    Object {
+        #[serde(default)]
+        id: Option<serde_json::Value>,
+        text: String,
+    },
+}
+
+/// A line of output
+#[derive(Serialize, Debug)]
+struct EmbedOutput<'a> {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a serde_json::Value>,
+    text: &'a str,
+    embedding: Vec<f32>,
+}
+
+pub async fn run(options: Options, args: EmbedArgs) -> Result<()> {
+    if args.batch_size == 0 {
+        bail!("Batch size must be at least 1");
+    }
+
+    let input = std::io::read_to_string(std::io::stdin())?;
+    let mut inputs = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if args.jsonl {
+            let input: EmbedInput = serde_json::from_str(line)
+                .map_err(|e| anyhow!("Invalid input at line {}: {e}", i + 1))?;
+            inputs.push(match input {
+                EmbedInput::Text(text) => (None, text),
+                EmbedInput::Object { id, text } => (id, text),
+            });
+        } else {
+            inputs.push((None, line.to_owned()));
+        }
+    }
+
+    let client = http_client(&options)?;
+    let mut stdout = std::io::stdout().lock();
+    for (batch_index, batch) in inputs.chunks(args.batch_size).enumerate() {
+        let texts = batch.iter().map(|(_, text)| text.clone()).collect();
+        let embeddings = embed(&client, &options, &args.model, texts, args.dimensions).await?;
+        for (i, ((id, text), embedding)) in batch.iter().zip(embeddings).enumerate() {
+            let output = EmbedOutput {
+                index: batch_index * args.batch_size + i,
+                id: id.as_ref(),
+                text,
+                embedding,
+            };
+            writeln!(stdout, "{}", serde_json::to_string(&output)?)?;
+        }
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// Get embeddings of the inputs in one request, in the same order as inputs
+pub async fn embed(
+    client: &Client,
+    options: &Options,
+    model: &str,
+    input: Vec<String>,
+    dimensions: Option<u32>,
+) -> Result<Vec<Vec<f32>>> {
+    let count = input.len();
+    let data = EmbeddingRequest {
+        model: model.to_owned(),
+        input,
+        dimensions,
+    };
+    let req = api_request(client, options, Method::POST, "/embeddings")?.json(&data);
+    let response = verbose::send(options, req).await?;
+    let mut response: EmbeddingResponse = check_response(response).await?.json().await?;
+    debug!("Embedding usage: {:?}", &response.usage);
+
+    if response.data.len() != count {
+        bail!(
+            "Expected {count} embeddings in response, got {}",
+            response.data.len()
+        );
+    }
+    response.data.sort_by_key(|d| d.index);
+    Ok(response.data.into_iter().map(|d| d.embedding).collect())
+}
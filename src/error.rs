@@ -0,0 +1,361 @@
+//! Classification of failures into actionable messages and process exit codes.
+//!
+//! Exit codes are a contract for scripts: 0 success, 1 other errors, 2 usage error, 3 auth
+//! error, 4 rate limited, 5 content filtered, 6 network error, 7 truncated by length.
+
+use std::fmt;
+
+use reqwest::StatusCode;
+
+use crate::model::{Message, WrappedApiError};
+
+/// Class of a failure, which decides the message and the exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    Usage,
+    Auth,
+    ModelNotFound,
+    RateLimited,
+    QuotaExceeded,
+    /// A budget of `max_requests_per_hour` or `max_cost_per_day` would be exceeded
+    BudgetExceeded,
+    ContextLength,
+    ContentFiltered,
+    /// 5xx errors, e.g. the model is overloaded
+    ServerError,
+    Network,
+    Truncated,
+    Other,
+}
+
+impl FailureKind {
+    /// Exit code of the process failing for this reason
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailureKind::Usage => 2,
+            FailureKind::Auth => 3,
+            FailureKind::RateLimited | FailureKind::QuotaExceeded | FailureKind::BudgetExceeded => {
+                4
+            }
+            FailureKind::ContentFiltered => 5,
+            FailureKind::Network => 6,
+            FailureKind::Truncated => 7,
+            FailureKind::ModelNotFound
+            | FailureKind::ContextLength
+            | FailureKind::ServerError
+            | FailureKind::Other => 1,
+        }
+    }
+}
+
+/// A failure with an actionable message. The raw error, if any, is kept as its source, which
+/// is printed as the cause.
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: FailureKind,
+    message: String,
+    cause: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+/// Error response as returned by the API
+#[derive(Debug)]
+struct RawApiError {
+    status: StatusCode,
+    r#type: Option<String>,
+    message: String,
+}
+
+impl fmt::Display for RawApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        if let Some(r#type) = &self.r#type {
+            write!(f, ": {type}")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for RawApiError {}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.cause.as_ref().map(|cause| cause.as_ref() as _)
+    }
+}
+
+/// Nothing arrived in the response stream in time. Some gateways break streaming, while the
+/// request may still be answered without it.
+#[derive(Debug)]
+pub struct NoFirstToken(pub std::time::Duration);
+
+impl fmt::Display for NoFirstToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "No tokens in the response stream for {} seconds",
+            self.0.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for NoFirstToken {}
+
+/// The response stream broke after part of the response was received, which can be resumed
+#[derive(Debug)]
+pub struct Interrupted {
+    pub partial: Message,
+    cause: anyhow::Error,
+}
+
+impl Interrupted {
+    /// The error breaking the stream, as `Interrupted` if the partial response is worth resuming
+    pub fn wrap(mut partial: Message, cause: anyhow::Error) -> anyhow::Error {
+        if partial.content.is_empty() || !partial.tool_calls.is_empty() {
+            return cause;
+        }
+        if partial.role.is_empty() {
+            partial.role = "assistant".to_owned();
+        }
+        Interrupted { partial, cause }.into()
+    }
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The response stream broke: {}", self.cause)
+    }
+}
+
+impl std::error::Error for Interrupted {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.cause.as_ref())
+    }
+}
+
+/// Exit the process with the code without printing anything, e.g. when the response has been
+/// printed but is incomplete
+#[derive(Debug)]
+pub struct Exit(pub FailureKind);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exit with code {}", self.0.exit_code())
+    }
+}
+
+impl std::error::Error for Exit {}
+
+/// Stdout is closed by the reader, e.g. `heygpt ... | head -5`. The process exits quietly with
+/// 0, as the reader has got what it wants.
+#[derive(Debug)]
+pub struct ClosedOutput;
+
+impl fmt::Display for ClosedOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("stdout is closed")
+    }
+}
+
+impl std::error::Error for ClosedOutput {}
+
+/// Turn an error of writing to stdout into `ClosedOutput` if the reader has gone
+pub fn output(err: std::io::Error) -> anyhow::Error {
+    if err.kind() == std::io::ErrorKind::BrokenPipe {
+        ClosedOutput.into()
+    } else {
+        err.into()
+    }
+}
+
+/// Whether the process should exit without printing the error
+pub fn is_quiet(err: &anyhow::Error) -> bool {
+    err.is::<Exit>() || err.is::<ClosedOutput>()
+}
+
+impl Failure {
+    pub fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            cause: None,
+        }
+    }
+
+    /// Turn an error response of the API into a failure
+    pub async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        Self::from_body(status, &body)
+    }
+
+    /// Turn an error event in the middle of a response stream, e.g. when the model is
+    /// overloaded, into a failure. The status is taken from a numeric `code` as OpenRouter
+    /// sends, or taken as a server error.
+    pub fn from_stream_error(error: &serde_json::Value) -> Self {
+        let status = error["code"]
+            .as_u64()
+            .and_then(|code| u16::try_from(code).ok())
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        Self::from_body(status, &serde_json::json!({ "error": error }).to_string())
+    }
+
+    fn from_body(status: StatusCode, body: &str) -> Self {
+        let (r#type, code, message) = match serde_json::from_str::<WrappedApiError>(body) {
+            Ok(r) => {
+                let code = r
+                    .error
+                    .code
+                    .as_ref()
+                    .and_then(|c| c.as_str())
+                    .map(str::to_owned);
+                (r.error.r#type, code, r.error.message)
+            }
+            Err(_) if body.trim().is_empty() => (None, None, "no error message".to_owned()),
+            Err(_) => (None, None, body.trim().to_owned()),
+        };
+        let raw = RawApiError {
+            status,
+            r#type,
+            message,
+        };
+        let kind = classify(&raw, code.as_deref());
+        match describe(kind, &raw) {
+            Some(message) => Self {
+                kind,
+                message,
+                cause: Some(Box::new(raw)),
+            },
+            None => Self {
+                kind,
+                message: raw.to_string(),
+                cause: None,
+            },
+        }
+    }
+}
+
+/// Invalid arguments or input, as opposed to failures of the API
+pub fn usage(message: impl Into<String>) -> anyhow::Error {
+    Failure::new(FailureKind::Usage, message).into()
+}
+
+/// Turn a transport error into a failure if the network is to blame, so it gets a hint and
+/// the exit code of network errors
+pub fn transport(err: reqwest::Error) -> anyhow::Error {
+    let message = if err.is_connect() {
+        let host = err
+            .url()
+            .and_then(|url| url.host_str())
+            .unwrap_or("the API");
+        format!(
+            "Failed to connect to {host}. Check your network, proxy settings and `--api-base-url`."
+        )
+    } else if err.is_timeout() {
+        "The API request timed out. Try again later or raise `--timeout`.".to_owned()
+    } else {
+        return err.into();
+    };
+    Failure {
+        kind: FailureKind::Network,
+        message,
+        cause: Some(Box::new(err)),
+    }
+    .into()
+}
+
+/// Turn an error of the response stream into a failure
+pub async fn from_event_source(err: reqwest_eventsource::Error) -> anyhow::Error {
+    match err {
+        reqwest_eventsource::Error::InvalidStatusCode(_, response) => {
+            Failure::from_response(response).await.into()
+        }
+        reqwest_eventsource::Error::Transport(err) => transport(err),
+        err => err.into(),
+    }
+}
+
+/// Exit code of the process failing with the error
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    for cause in err.chain() {
+        if let Some(failure) = cause.downcast_ref::<Failure>() {
+            return failure.kind.exit_code();
+        }
+        if let Some(Exit(kind)) = cause.downcast_ref::<Exit>() {
+            return kind.exit_code();
+        }
+        if cause.is::<ClosedOutput>() {
+            return 0;
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            if err.is_connect() || err.is_timeout() {
+                return FailureKind::Network.exit_code();
+            }
+        }
+    }
+    1
+}
+
+/// Whether the error is specific to the model, so that another model may succeed
+pub fn is_model_failure(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<Failure>())
+        .any(|failure| {
+            matches!(
+                failure.kind,
+                FailureKind::ModelNotFound
+                    | FailureKind::ContextLength
+                    | FailureKind::RateLimited
+                    | FailureKind::QuotaExceeded
+                    | FailureKind::ServerError
+            )
+        })
+}
+
+fn classify(raw: &RawApiError, code: Option<&str>) -> FailureKind {
+    let message = raw.message.to_lowercase();
+    match (raw.status.as_u16(), code) {
+        (_, Some("invalid_api_key")) | (401 | 403, _) => FailureKind::Auth,
+        (_, Some("insufficient_quota")) => FailureKind::QuotaExceeded,
+        (429, _) => FailureKind::RateLimited,
+        (_, Some("model_not_found")) => FailureKind::ModelNotFound,
+        (404, _) if message.contains("model") => FailureKind::ModelNotFound,
+        (_, Some("context_length_exceeded")) => FailureKind::ContextLength,
+        (400, _) if message.contains("context length") => FailureKind::ContextLength,
+        (_, Some("content_filter" | "content_policy_violation")) => FailureKind::ContentFiltered,
+        (500..=599, _) => FailureKind::ServerError,
+        _ => FailureKind::Other,
+    }
+}
+
+/// Actionable message of the failure, or `None` if there is nothing to add to the raw error
+fn describe(kind: FailureKind, raw: &RawApiError) -> Option<String> {
+    let message = match kind {
+        FailureKind::Auth => "Authentication failed. Check your API key, set via OPENAI_API_KEY, `heygpt auth` or the config file.".to_owned(),
+        FailureKind::ModelNotFound => {
+            // The message usually quotes the model, e.g. "The model `gpt-5` does not exist"
+            let model = raw.message.split('`').nth(1).filter(|m| !m.contains(' '));
+            match model {
+                Some(model) => format!("Model `{model}` is not available on this endpoint. Run `heygpt models` to list the available models."),
+                None => "The model is not available on this endpoint. Run `heygpt models` to list the available models.".to_owned(),
+            }
+        }
+        FailureKind::RateLimited => "Rate limited by the API. Wait a moment and try again.".to_owned(),
+        FailureKind::QuotaExceeded => "You have exceeded your API quota. Check your plan and billing details.".to_owned(),
+        FailureKind::ContextLength => "The conversation is too long for the model's context window. Shorten the prompt, lower `--max-tokens`, or use a model with a larger context window.".to_owned(),
+        FailureKind::ContentFiltered => "The request was rejected by the content filter of the API.".to_owned(),
+        FailureKind::Usage
+        | FailureKind::BudgetExceeded
+        | FailureKind::ServerError
+        | FailureKind::Network
+        | FailureKind::Truncated
+        | FailureKind::Other => return None,
+    };
+    Some(message)
+}
@@ -0,0 +1,63 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+
+use crate::model::Message;
+
+/// An example in examples file: either a pair of user prompt and assistant answer, or a message
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Example {
+    Pair { user: String, assistant: String },
+    Message(Message),
+}
+
+/// TOML files need a top-level table, i.e. `[[examples]]`
+#[derive(Deserialize, Debug)]
+struct TomlExamples {
+    examples: Vec<Example>,
+}
+
+/// Load few-shot examples from a JSON or TOML file as messages, e.g.
+///
+/// ```toml
+/// [[examples]]
+/// user = "2 + 2"
+/// assistant = "4"
+/// ```
+pub fn load(path: &str) -> Result<Vec<Message>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read examples file `{path}`: {e}"))?;
+    let examples: Vec<Example> = if path.ends_with(".toml") {
+        toml::from_str::<TomlExamples>(&content)
+            .map(|t| t.examples)
+            .map_err(|e| anyhow!("Failed to parse examples file `{path}`: {e}"))?
+    } else {
+        serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Failed to parse examples file `{path}`: {e}"))?
+    };
+
+    let mut messages = Vec::new();
+    for example in examples {
+        match example {
+            Example::Pair { user, assistant } => {
+                messages.push(Message {
+                    role: "user".to_string(),
+                    content: user,
+                    ..Default::default()
+                });
+                messages.push(Message {
+                    role: "assistant".to_string(),
+                    content: assistant,
+                    ..Default::default()
+                });
+            }
+            Example::Message(message) => {
+                if !["system", "user", "assistant"].contains(&message.role.as_str()) {
+                    bail!("Invalid role `{}` in examples file `{path}`", message.role);
+                }
+                messages.push(message);
+            }
+        }
+    }
+    Ok(messages)
+}
@@ -0,0 +1,160 @@
+//! `heygpt explain`: explain a shell command flag by flag
+
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use clap::Args;
+
+use crate::model::Message;
+use crate::{http_client, warning, Options, Session};
+
+#[derive(Args, Debug)]
+pub struct ExplainArgs {
+    /// Also run `PROGRAM --help` and give its output to the model for accuracy
+    #[arg(long)]
+    pub with_help: bool,
+
+    /// The command to explain, e.g. `heygpt explain -- tar -xzvf archive.tar.gz`
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+/// System prompt of explaining commands
+const EXPLAIN_PROMPT: &str = "You explain shell commands. First summarize in one sentence what the command does. Then, for each program, subcommand, flag and argument in order, write a bullet with the part in backticks followed by what it does. For combined short flags like `-xzvf`, explain each letter. Finally warn about any dangerous side effects, such as deleting or overwriting files. Be concise and do not suggest alternatives unless the command is wrong.";
+
+/// Maximum length of `--help` output given to the model
+const MAX_HELP_LEN: usize = 20_000;
+
+/// How long to wait for `--help` to finish
+const HELP_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn run(options: Options, args: ExplainArgs) -> Result<()> {
+    let command = args
+        .command
+        .iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut prompt = format!("Command:\n```sh\n{command}\n```\n");
+    if args.with_help {
+        match help_of(&args.command[0]) {
+            Some(help) => prompt.push_str(&format!(
+                "\nOutput of `{} --help`:\n```\n{help}\n```\n",
+                args.command[0]
+            )),
+            None => warning(format!("No help from `{} --help`", args.command[0])),
+        }
+    }
+
+    let client = http_client(&options)?;
+    let is_stdout = atty::is(atty::Stream::Stdout);
+    let mut session = Session::new(options, client, false, is_stdout);
+    session.messages = vec![
+        Message {
+            role: "system".to_string(),
+            content: EXPLAIN_PROMPT.to_string(),
+            ..Default::default()
+        },
+        Message {
+            role: "user".to_string(),
+            content: prompt,
+            ..Default::default()
+        },
+    ];
+    session.complete_and_print().await?;
+    Ok(())
+}
+
+/// Output of `program --help`, or `None` if it fails or prints nothing in time
+fn help_of(program: &str) -> Option<String> {
+    let mut child = Command::new(program)
+        .arg("--help")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+    // The pipes are drained meanwhile, or a long help would block the program when they fill up
+    let stdout = drain(child.stdout.take()?);
+    let stderr = drain(child.stderr.take()?);
+    // Some programs don't know `--help` and wait for input or run forever
+    let start = Instant::now();
+    while child.try_wait().ok()?.is_none() {
+        if start.elapsed() > HELP_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let stdout = stdout.join().ok()?;
+    let stderr = stderr.join().ok()?;
+    // Some programs print help to stderr
+    let text = if stdout.is_empty() { stderr } else { stdout };
+    let mut help = String::from_utf8_lossy(&text).trim().to_owned();
+    if help.is_empty() {
+        return None;
+    }
+    if help.len() > MAX_HELP_LEN {
+        let mut end = MAX_HELP_LEN;
+        while !help.is_char_boundary(end) {
+            end -= 1;
+        }
+        help.truncate(end);
+        help.push_str("\n[... truncated ...]");
+    }
+    Some(help)
+}
+
+/// Read the pipe to the end on a thread
+fn drain(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// Quote the argument for shell if needed, so that the command reads as typed
+fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%^".contains(c));
+    if safe {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn long_help_is_read() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // More than the pipe buffer of 64KB
+        let dir = std::env::temp_dir().join(format!("heygpt-explain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("verbose");
+        std::fs::write(
+            &program,
+            "#!/bin/sh\nhead -c 200000 /dev/zero | tr '\\0' a\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let start = Instant::now();
+        let help = help_of(program.to_str().unwrap()).unwrap();
+        assert!(start.elapsed() < HELP_TIMEOUT);
+        assert!(help.starts_with("aaaa") && help.ends_with("[... truncated ...]"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,238 @@
+//! Spreading requests over several API keys or endpoints, failing over to the next one when a
+//! request is rate limited or the server fails.
+//!
+//! Like recording, this works by pointing the API base URL at a local server, which forwards
+//! each request to the endpoints in turn with their own API keys until one succeeds. The base
+//! URL has a secret path only known to this process, so that other programs can't use the keys.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use futures::StreamExt;
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, HOST};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::serve::{error_response, missing_secret, proxy_secret, strip_secret};
+use crate::{http_client, warning, Options};
+
+/// An API endpoint with its own key, in `[[endpoints]]` of the config file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Endpoint {
+    /// Defaults to `api_base_url`
+    pub api_base_url: Option<String>,
+    pub api_key: String,
+}
+
+/// Whether more than one API key or endpoint is configured
+pub fn is_enabled(options: &Options) -> bool {
+    !options.api_keys.is_empty() || !options.endpoints.is_empty()
+}
+
+struct Pool {
+    client: Client,
+    /// Path prefix of requests from this process, see `proxy_secret`
+    secret: String,
+    /// Base URLs and API keys, the one from `api_base_url` and `api_key` first
+    endpoints: Vec<(String, String)>,
+    round_robin: bool,
+    /// The endpoint to try first: the last one that succeeded, or the next one in turn with
+    /// `round_robin`
+    next: AtomicUsize,
+}
+
+/// Start the local server forwarding requests to the endpoints. Returns the base URL to send
+/// requests to instead of the API.
+pub fn start(options: &Options) -> Result<String> {
+    let base_url = |url: &Option<String>| {
+        url.as_deref()
+            .unwrap_or(&options.api_base_url)
+            .trim_end_matches('/')
+            .to_owned()
+    };
+    let mut endpoints = vec![(base_url(&None), options.api_key.clone())];
+    endpoints.extend(
+        options
+            .api_keys
+            .iter()
+            .map(|key| (base_url(&None), key.clone())),
+    );
+    endpoints.extend(
+        options
+            .endpoints
+            .iter()
+            .map(|e| (base_url(&e.api_base_url), e.api_key.clone())),
+    );
+    // Processes of one-shot mode send a single request each, so start from a different
+    // endpoint in each of them to spread the requests
+    let first = if options.round_robin {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos() as usize)
+            % endpoints.len()
+    } else {
+        0
+    };
+    let secret = proxy_secret()?;
+    let pool = Arc::new(Pool {
+        client: http_client(options)?,
+        secret: secret.clone(),
+        endpoints,
+        round_robin: options.round_robin,
+        next: AtomicUsize::new(first),
+    });
+
+    let make_svc = make_service_fn(move |_conn| {
+        let pool = pool.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let pool = pool.clone();
+                async move { Ok::<_, Infallible>(pool.handle(req).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_svc);
+    debug!(
+        "Failing over between API endpoints via {}",
+        server.local_addr()
+    );
+    let base_url = format!("http://{}{secret}", server.local_addr());
+    tokio::spawn(server);
+    Ok(base_url)
+}
+
+impl Pool {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let method = req.method().clone();
+        let path = req.uri().path_and_query().map_or("", |p| p.as_str());
+        let Some(path) = strip_secret(path, &self.secret).map(str::to_owned) else {
+            return missing_secret();
+        };
+        let mut headers = req.headers().clone();
+        headers.remove(HOST);
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(AUTHORIZATION);
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => body,
+            Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+        };
+
+        let n = self.endpoints.len();
+        let first = if self.round_robin {
+            self.next.fetch_add(1, Ordering::Relaxed) % n
+        } else {
+            self.next.load(Ordering::Relaxed)
+        };
+        for attempt in 0..n {
+            let index = (first + attempt) % n;
+            let (base_url, api_key) = &self.endpoints[index];
+            info!("{} {} via endpoint #{}", method, path, index + 1);
+            let result = self
+                .client
+                .request(method.clone(), format!("{base_url}{path}"))
+                .headers(headers.clone())
+                .bearer_auth(api_key)
+                .body(body.clone())
+                .send()
+                .await;
+            let is_last = attempt + 1 == n;
+            let failure = match result {
+                Ok(response) if is_last || !should_fail_over(response.status()) => {
+                    if !self.round_robin {
+                        self.next.store(index, Ordering::Relaxed);
+                    }
+                    return forward(response);
+                }
+                Ok(response) => response.status().to_string(),
+                Err(err) if is_last => {
+                    return error_response(StatusCode::BAD_GATEWAY, &err.to_string())
+                }
+                Err(err) => err.to_string(),
+            };
+            warning(format!(
+                "Endpoint #{} ({base_url}) failed with {failure}, failing over to endpoint #{}",
+                index + 1,
+                (index + 1) % n + 1
+            ));
+        }
+        unreachable!("the last attempt always returns")
+    }
+}
+
+/// Whether another endpoint may succeed where one failed with the status
+fn should_fail_over(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Stream the response of the endpoint back to the client
+fn forward(upstream: reqwest::Response) -> Response<Body> {
+    let status = upstream.status();
+    let content_type = upstream.headers().get(CONTENT_TYPE).cloned();
+    let (mut sender, body) = Body::channel();
+    let mut stream = upstream.bytes_stream();
+    tokio::spawn(async move {
+        while let Some(chunk) = stream.next().await {
+            let sent = match chunk {
+                Ok(bytes) => sender.send_data(bytes).await.is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                sender.abort();
+                break;
+            }
+        }
+    });
+
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    if let Some(content_type) = content_type {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_need_the_secret_path() {
+        let options = Options {
+            api_base_url: "http://127.0.0.1:9".to_owned(),
+            api_key: "sk-first".to_owned(),
+            api_keys: vec!["sk-second".to_owned()],
+            ..Default::default()
+        };
+        let base_url = start(&options).unwrap();
+        let url = Url::parse(&base_url).unwrap();
+        let client = Client::new();
+
+        let origin = format!("http://{}", url.authority());
+        for path in [
+            "/chat/completions",
+            "/models",
+            &format!("{}x/models", url.path()),
+        ] {
+            let response = client.get(format!("{origin}{path}")).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{path}");
+        }
+        // Forwarded to the endpoints, which are down
+        let response = client
+            .get(format!("{base_url}/models"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+}
@@ -0,0 +1,57 @@
+//! Running git commands for the subcommands working on repositories
+
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::token::estimate_tokens;
+
+/// Hash of the empty tree, to diff against when there is no parent commit
+const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+/// Run git with the arguments and return its output
+pub fn git(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("Failed to run git: {e}"))?;
+    if !output.status.success() {
+        bail!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The parent of HEAD, or the empty tree for the root commit
+pub fn head_parent() -> &'static str {
+    match git(&["rev-parse", "--verify", "--quiet", "HEAD^"]) {
+        Ok(_) => "HEAD^",
+        Err(_) => EMPTY_TREE,
+    }
+}
+
+/// Truncate the diff by lines to about `max_tokens`, noting how much is left out
+pub fn truncate_diff(diff: &str, max_tokens: usize) -> String {
+    if estimate_tokens(diff) <= max_tokens {
+        return diff.to_owned();
+    }
+    let mut kept = String::new();
+    let mut tokens = 0;
+    let mut lines = diff.lines();
+    for line in lines.by_ref() {
+        tokens += estimate_tokens(line) + 1;
+        if tokens > max_tokens {
+            break;
+        }
+        kept.push_str(line);
+        kept.push('\n');
+    }
+    let omitted = lines.count() + 1;
+    kept.push_str(&format!(
+        "[... {omitted} more lines of the diff omitted ...]\n"
+    ));
+    kept
+}
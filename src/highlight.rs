@@ -0,0 +1,99 @@
+//! Highlight the syntax of fenced code blocks in streamed responses, line by line as they arrive,
+//! with the language from the info string of the fence like ```` ```rust ````
+
+use std::sync::OnceLock;
+
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::as_24_bit_terminal_escaped;
+
+/// Theme of the highlighted code, for terminals with dark background
+const THEME: &str = "base16-ocean.dark";
+
+/// Syntaxes and the theme, loaded on the first code block
+fn syntaxes() -> &'static (SyntaxSet, Theme) {
+    static SYNTAXES: OnceLock<(SyntaxSet, Theme)> = OnceLock::new();
+    SYNTAXES.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults();
+        let theme = themes.themes.remove(THEME).unwrap_or_default();
+        (SyntaxSet::load_defaults_nonewlines(), theme)
+    })
+}
+
+/// A highlighter of the language named by the info string of a fence, by its name or file
+/// extension, e.g. `rust` or `rs`
+fn highlighter(info: &str) -> Option<HighlightLines<'static>> {
+    let token = info.split_whitespace().next()?;
+    let (syntaxes, theme) = syntaxes();
+    let syntax = syntaxes.find_syntax_by_token(token)?;
+    Some(HighlightLines::new(syntax, theme))
+}
+
+/// Highlights the code blocks in the deltas of a response. Text outside code blocks is passed
+/// through as it comes, and a line of code is held back until it ends.
+#[derive(Default)]
+pub struct Highlight {
+    /// The current line so far
+    line: String,
+    /// The highlighter of the code block we are in, if the language is known
+    code: Option<Option<HighlightLines<'static>>>,
+}
+
+impl Highlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a delta, returning the text to print
+    pub fn push(&mut self, delta: &str) -> String {
+        let mut out = String::new();
+        for c in delta.chars() {
+            if c != '\n' {
+                if self.code.is_none() {
+                    out.push(c);
+                }
+                self.line.push(c);
+                continue;
+            }
+            let fence = self.line.trim_start();
+            let is_fence = fence.starts_with("```") || fence.starts_with("~~~");
+            match &mut self.code {
+                None if is_fence => {
+                    let info = fence.trim_start_matches(['`', '~']);
+                    self.code = Some(highlighter(info));
+                }
+                None => {}
+                Some(_) if is_fence => {
+                    out.push_str(&self.line);
+                    self.code = None;
+                }
+                Some(highlighter) => out.push_str(&highlight_line(highlighter, &self.line)),
+            }
+            out.push('\n');
+            self.line.clear();
+        }
+        out
+    }
+
+    /// Return the rest of the response held back
+    pub fn finish(&mut self) -> String {
+        let out = match &mut self.code {
+            Some(highlighter) => highlight_line(highlighter, &self.line),
+            None => String::new(),
+        };
+        self.line.clear();
+        out
+    }
+}
+
+/// The line with escape codes of its colors, or as is in a code block of unknown language
+fn highlight_line(highlighter: &mut Option<HighlightLines<'static>>, line: &str) -> String {
+    let Some(highlighter) = highlighter else {
+        return line.to_owned();
+    };
+    match highlighter.highlight_line(line, &syntaxes().0) {
+        Ok(ranges) => format!("{}\x1b[0m", as_24_bit_terminal_escaped(&ranges, false)),
+        Err(_) => line.to_owned(),
+    }
+}
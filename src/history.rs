@@ -0,0 +1,320 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Subcommand};
+use console::style;
+use serde::{Deserialize, Serialize};
+
+use crate::model::Message;
+use crate::paths;
+
+#[derive(Args, Debug)]
+pub struct HistoryArgs {
+    #[command(subcommand)]
+    pub action: HistoryAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HistoryAction {
+    /// List saved conversations, most recent first
+    List {
+        /// Maximum number of conversations to list
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Search past messages for all of the given words, case-insensitively
+    Search {
+        #[arg(required = true)]
+        terms: Vec<String>,
+    },
+    /// Print a conversation
+    Show {
+        /// Conversation ID, or a unique prefix of it
+        id: String,
+    },
+    /// Delete conversations
+    Delete {
+        /// Conversation IDs, or unique prefixes of them
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+}
+
+/// A conversation saved in data directory as `conversations/<id>.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    /// Creation time like `20230401-120000`, with a suffix if it's taken
+    pub id: String,
+    /// Unix timestamp of creation
+    pub created: u64,
+    /// Unix timestamp of the last update
+    pub updated: u64,
+    pub model: String,
+    /// Short title generated with `title_model`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// ID of the conversation in the ChatGPT export it was imported from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imported_from: Option<String>,
+    pub messages: Vec<Message>,
+}
+
+/// A message matching a search
+pub struct SearchHit {
+    pub conversation: Conversation,
+    pub index: usize,
+}
+
+impl Conversation {
+    pub fn new(model: String) -> Result<Self> {
+        Self::created_at(model, unix_now())
+    }
+
+    /// A new conversation created at the Unix timestamp, e.g. an imported one
+    pub fn created_at(model: String, created: u64) -> Result<Self> {
+        let base = format_time(created)
+            .replace(['-', ':'], "")
+            .replace(' ', "-");
+        let dir = conversations_dir()?;
+        let mut id = base.clone();
+        let mut n = 1;
+        while dir.join(format!("{id}.json")).exists() {
+            n += 1;
+            id = format!("{base}-{n}");
+        }
+        Ok(Self {
+            id,
+            created,
+            updated: created,
+            model,
+            title: None,
+            imported_from: None,
+            messages: Vec::new(),
+        })
+    }
+
+    /// Load a conversation by ID or a unique prefix of it
+    pub fn load(id: &str) -> Result<Self> {
+        let matches: Vec<_> = list()?
+            .into_iter()
+            .filter(|c| c.id.starts_with(id))
+            .collect();
+        match matches.len() {
+            0 => bail!("No conversation `{id}`"),
+            1 => Ok(matches.into_iter().next().unwrap()),
+            _ if matches.iter().any(|c| c.id == id) => {
+                Ok(matches.into_iter().find(|c| c.id == id).unwrap())
+            }
+            n => bail!("`{id}` matches {n} conversations, please be more specific"),
+        }
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        self.updated = unix_now();
+        self.write()
+    }
+
+    /// Save the conversation without touching its update time
+    pub fn write(&self) -> Result<()> {
+        let path = conversations_dir()?.join(format!("{}.json", self.id));
+        paths::ensure_parent_dir(&path)?;
+        std::fs::write(&path, serde_json::to_string(self)?)
+            .map_err(|e| anyhow!("Failed to save conversation to {}: {e}", path.display()))
+    }
+
+    pub fn delete(&self) -> Result<()> {
+        let path = conversations_dir()?.join(format!("{}.json", self.id));
+        std::fs::remove_file(&path).map_err(|e| anyhow!("Failed to delete {}: {e}", path.display()))
+    }
+
+    /// First user message, used as a summary in listings
+    pub fn preview(&self) -> &str {
+        self.messages
+            .iter()
+            .find(|m| m.role == "user")
+            .map(|m| m.content.as_str())
+            .unwrap_or("")
+    }
+
+    /// The generated title, or else the first user message
+    pub fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or_else(|| self.preview())
+    }
+}
+
+fn conversations_dir() -> Result<PathBuf> {
+    Ok(paths::data_dir()?.join("conversations"))
+}
+
+/// All saved conversations, most recently updated first
+pub fn list() -> Result<Vec<Conversation>> {
+    let dir = conversations_dir()?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut conversations = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let content = std::fs::read_to_string(&path)?;
+            match serde_json::from_str::<Conversation>(&content) {
+                Ok(conversation) => conversations.push(conversation),
+                Err(err) => {
+                    tracing::warn!("Skipped invalid conversation {}: {err}", path.display())
+                }
+            }
+        }
+    }
+    conversations.sort_by(|a, b| b.updated.cmp(&a.updated).then(b.id.cmp(&a.id)));
+    Ok(conversations)
+}
+
+/// Messages containing all the terms, case-insensitively, most recent first
+pub fn search(terms: &[String]) -> Result<Vec<SearchHit>> {
+    let terms: Vec<_> = terms.iter().map(|t| t.to_lowercase()).collect();
+    let mut hits = Vec::new();
+    for conversation in list()? {
+        let indices: Vec<_> = conversation
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| {
+                let content = m.content.to_lowercase();
+                terms.iter().all(|t| content.contains(t))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        for index in indices {
+            hits.push(SearchHit {
+                conversation: conversation.clone(),
+                index,
+            });
+        }
+    }
+    Ok(hits)
+}
+
+pub fn run(args: HistoryArgs) -> Result<()> {
+    match args.action {
+        HistoryAction::List { limit } => {
+            for conversation in list()?.iter().take(limit) {
+                println!(
+                    "{}  {}  {}",
+                    style(&conversation.id).bold(),
+                    style(format!(
+                        "{}  {:<16} {:>3} msgs",
+                        format_time(conversation.updated),
+                        one_line(&conversation.model, 16),
+                        conversation.messages.len()
+                    ))
+                    .dim(),
+                    one_line(conversation.title(), 60)
+                );
+            }
+        }
+        HistoryAction::Search { terms } => print_search(&terms)?,
+        HistoryAction::Show { id } => {
+            let conversation = Conversation::load(&id)?;
+            if let Some(title) = &conversation.title {
+                println!("{}", style(title).bold());
+            }
+            println!(
+                "{}",
+                style(format!(
+                    "{} ({}, {})",
+                    conversation.id,
+                    conversation.model,
+                    format_time(conversation.created)
+                ))
+                .bold()
+            );
+            for message in &conversation.messages {
+                println!(
+                    "{} => {}",
+                    style(&message.role).bold().green(),
+                    message.content
+                );
+            }
+        }
+        HistoryAction::Delete { ids } => {
+            for id in ids {
+                let conversation = Conversation::load(&id)?;
+                conversation.delete()?;
+                println!("Deleted {}", conversation.id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print search results with a snippet around the first term
+pub fn print_search(terms: &[String]) -> Result<()> {
+    let hits = search(terms)?;
+    if hits.is_empty() {
+        println!("No matches");
+    }
+    for hit in hits {
+        let message = &hit.conversation.messages[hit.index];
+        println!(
+            "{} [{}] {} => {}",
+            style(&hit.conversation.id).bold(),
+            hit.index,
+            style(&message.role).green(),
+            snippet(&message.content, &terms[0], 80)
+        );
+    }
+    Ok(())
+}
+
+/// Part of the text around the first occurrence of the term, on a single line
+fn snippet(text: &str, term: &str, width: usize) -> String {
+    let lower = text.to_lowercase();
+    // Lowercasing may change byte offsets of non-ASCII text, so only trust it for ASCII
+    let start = match lower.find(&term.to_lowercase()) {
+        Some(pos) if text.is_ascii() && pos + term.len() > width => pos.saturating_sub(width / 3),
+        _ => 0,
+    };
+    let prefix = if start > 0 { "..." } else { "" };
+    format!("{prefix}{}", one_line(&text[start..], width))
+}
+
+/// Collapse whitespace and truncate to `width` characters
+pub fn one_line(text: &str, width: usize) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() > width {
+        let truncated: String = line.chars().take(width).collect();
+        format!("{truncated}...")
+    } else {
+        line
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Format a Unix timestamp as `YYYY-MM-DD HH:MM:SS` in UTC
+pub fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Civil date from days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,26 @@
+//! User-defined commands run around each chat completion request, e.g. for redaction or logging
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::model::{Request, ResponseMessage};
+use crate::postprocess::pipe;
+
+/// Pipe the JSON request through the `pre_request_hook` command, which may rewrite it. The
+/// request is sent unchanged if the command prints nothing, and not sent at all if it fails.
+pub fn pre_request(command: &str, data: &Request) -> Result<Value> {
+    let request = serde_json::to_string(data)? + "\n";
+    let output = pipe("pre-request hook", command, &request)?;
+    if output.trim().is_empty() {
+        return Ok(serde_json::to_value(data)?);
+    }
+    serde_json::from_str(&output)
+        .map_err(|e| anyhow!("Invalid JSON from the pre-request hook `{command}`: {e}"))
+}
+
+/// Pipe the JSON response to the `post_response_hook` command. Its output is discarded.
+pub fn post_response(command: &str, response: &ResponseMessage) -> Result<()> {
+    let response = serde_json::to_string(response)? + "\n";
+    pipe("post-response hook", command, &response)?;
+    Ok(())
+}
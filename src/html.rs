@@ -0,0 +1,82 @@
+//! Conversion of HTML pages to readable text, to be read by a model
+
+use html2text::render::text_renderer::TrivialDecorator;
+
+/// Width that text is wrapped to, wide enough to keep paragraphs on their lines
+const WIDTH: usize = 1000;
+
+/// Text of the page without markup, scripts and styles, keeping line breaks of blocks and the
+/// content of `<pre>` as is. Tables are read cell by cell. The HTML is returned as is if it
+/// can't be laid out, e.g. when nested too deep.
+pub fn to_text(html: &str) -> String {
+    html2text::config::with_decorator(TrivialDecorator::new())
+        .raw_mode(true)
+        .string_from_read(html.as_bytes(), WIDTH)
+        .map_or_else(|_| html.to_owned(), |text| tidy_lines(&text))
+}
+
+/// Trim trailing spaces of lines and keep at most one blank line in a row
+fn tidy_lines(text: &str) -> String {
+    let mut result = String::new();
+    let mut blank = true;
+    for line in text.lines() {
+        let line = line.trim_end();
+        if line.trim().is_empty() {
+            if !blank {
+                result.push('\n');
+            }
+            blank = true;
+        } else {
+            result.push_str(line);
+            result.push('\n');
+            blank = false;
+        }
+    }
+    result.trim_end().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_are_read_as_text() {
+        let html = r#"<!DOCTYPE html>
+<html><head><title>Page</title><style>p { color: red; }</style>
+<script>if (a < b) { document.write("</p>"); }</script></head>
+<body><h1>Hello &amp; welcome</h1>
+<!-- a comment -->
+<p>First   paragraph,
+with <b>bold</b> and <a href="/x">a link</a>.</p>
+<SCRIPT type="module">alert("hi")</SCRIPT>
+<ul><li>one</li><li>two&nbsp;&mdash;&#x2192;</li></ul>
+<pre>fn main() {
+    println!("&lt;hi&gt;");
+}</pre>
+<table><tr><td>cell</td><td>another</td></tr></table>
+</body></html>"#;
+        let text = to_text(html);
+        for expected in [
+            "Hello & welcome",
+            "First paragraph, with bold and a link.",
+            "one\n",
+            "two —→",
+            "fn main() {\n    println!(\"<hi>\");\n}",
+            "cell\n",
+            "another",
+        ] {
+            assert!(text.contains(expected), "{expected:?} in {text}");
+        }
+        for skipped in ["color", "document", "alert", "comment", "href"] {
+            assert!(!text.contains(skipped), "{skipped:?} in {text}");
+        }
+    }
+
+    #[test]
+    fn snippets_are_read_as_text() {
+        assert_eq!(
+            to_text("The <strong>Rust</strong> book &ndash; learn Rust"),
+            "The Rust book – learn Rust"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use clap::Args;
+use reqwest::Method;
+use tracing::debug;
+
+use crate::model::{ImageRequest, ImageResponse};
+use crate::{api_request, check_response, http_client, verbose, Options};
+
+#[derive(Args, Debug)]
+pub struct ImageArgs {
+    /// Description of the image to generate
+    #[arg(required = true)]
+    pub prompt: Vec<String>,
+
+    /// The image generation model
+    #[arg(long, default_value = "dall-e-3")]
+    pub model: String,
+
+    /// Size of the images, e.g. 1024x1024, 1792x1024 or 1024x1792
+    #[arg(long, default_value = "1024x1024")]
+    pub size: String,
+
+    /// Number of images to generate
+    #[arg(long, default_value_t = 1)]
+    pub n: u32,
+
+    /// Quality of the images, e.g. `standard` or `hd`
+    #[arg(long)]
+    pub quality: Option<String>,
+
+    /// Directory to save the images in
+    #[arg(long, default_value = ".")]
+    pub out: PathBuf,
+}
+
+pub async fn run(options: Options, args: ImageArgs) -> Result<()> {
+    let data = ImageRequest {
+        model: args.model,
+        prompt: args.prompt.join(" "),
+        n: args.n,
+        size: args.size,
+        quality: args.quality,
+    };
+    debug!("Image request: {:?}", &data);
+
+    let client = http_client(&options)?;
+
+    let req = api_request(&client, &options, Method::POST, "/images/generations")?.json(&data);
+    let response = verbose::send(&options, req).await?;
+    let response: ImageResponse = check_response(response).await?.json().await?;
+
+    std::fs::create_dir_all(&args.out)
+        .map_err(|e| anyhow!("Failed to create directory {}: {e}", args.out.display()))?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    for (i, image) in response.data.into_iter().enumerate() {
+        if let Some(revised_prompt) = &image.revised_prompt {
+            debug!("Revised prompt of image {}: {}", i, revised_prompt);
+        }
+        let bytes = match (image.b64_json, image.url) {
+            (Some(b64), _) => base64::engine::general_purpose::STANDARD
+                .decode(b64.as_bytes())
+                .map_err(|e| anyhow!("Invalid base64 image data: {e}"))?,
+            (None, Some(url)) => {
+                let response = client.get(&url).send().await?.error_for_status()?;
+                response.bytes().await?.to_vec()
+            }
+            (None, None) => return Err(anyhow!("Neither url nor b64_json in response")),
+        };
+        let path = args.out.join(format!("heygpt-{timestamp}-{i}.png"));
+        std::fs::write(&path, bytes)
+            .map_err(|e| anyhow!("Failed to write {}: {e}", path.display()))?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,199 @@
+//! Import of conversations from the data export of ChatGPT into the saved conversations, to
+//! continue them with `--resume`
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+use serde::Deserialize;
+
+use crate::history::{self, Conversation};
+use crate::model::Message;
+
+#[derive(Args, Debug)]
+pub struct ImportArgs {
+    /// The export zip file of ChatGPT, or `conversations.json` in it
+    pub path: PathBuf,
+}
+
+/// A conversation in `conversations.json`, a tree of messages where edits and regenerated
+/// answers are branches. The last visited branch ends at `current_node`.
+#[derive(Deserialize, Debug)]
+struct ExportedConversation {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    create_time: Option<f64>,
+    #[serde(default)]
+    update_time: Option<f64>,
+    #[serde(default)]
+    default_model_slug: Option<String>,
+    #[serde(default)]
+    current_node: Option<String>,
+    mapping: BTreeMap<String, Node>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Node {
+    #[serde(default)]
+    message: Option<ExportedMessage>,
+    #[serde(default)]
+    parent: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ExportedMessage {
+    author: Author,
+    content: Content,
+    #[serde(default)]
+    metadata: Metadata,
+}
+
+#[derive(Deserialize, Debug)]
+struct Author {
+    role: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Content {
+    content_type: String,
+    /// Text, or objects like images for `multimodal_text`
+    #[serde(default)]
+    parts: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Metadata {
+    #[serde(default)]
+    model_slug: Option<String>,
+    #[serde(default)]
+    is_visually_hidden_from_conversation: bool,
+}
+
+pub fn run(args: ImportArgs) -> Result<()> {
+    let json = read_export(&args.path)?;
+    let exported: Vec<ExportedConversation> = serde_json::from_str(&json)
+        .map_err(|e| anyhow!("Invalid ChatGPT export {}: {e}", args.path.display()))?;
+
+    let imported: HashSet<String> = history::list()?
+        .into_iter()
+        .filter_map(|c| c.imported_from)
+        .collect();
+    let (mut added, mut skipped) = (0, 0);
+    for conversation in exported {
+        let source = conversation
+            .id
+            .clone()
+            .or(conversation.conversation_id.clone());
+        if source.as_ref().is_some_and(|id| imported.contains(id)) {
+            skipped += 1;
+            continue;
+        }
+        let Some(mut converted) = convert(conversation)? else {
+            skipped += 1;
+            continue;
+        };
+        converted.imported_from = source;
+        converted.write()?;
+        added += 1;
+    }
+    println!("Imported {added} conversations, skipped {skipped} empty or already imported ones");
+    Ok(())
+}
+
+/// Read `conversations.json`, from the zip file with `unzip` or `tar` if given one
+fn read_export(path: &Path) -> Result<String> {
+    if path.extension().is_none_or(|ext| ext != "zip") {
+        return std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read {}: {e}", path.display()));
+    }
+    // The `tar` of macOS and Windows reads zip files, while Linux usually has `unzip`
+    let commands: [(&str, &[&str]); 2] = [("unzip", &["-p"]), ("tar", &["-xOf"])];
+    for (program, args) in commands {
+        let output = match Command::new(program)
+            .args(args)
+            .arg(path)
+            .arg("conversations.json")
+            .output()
+        {
+            Ok(output) => output,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => bail!("Failed to run `{program}`: {err}"),
+        };
+        if !output.status.success() {
+            bail!(
+                "Failed to extract conversations.json from {}: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        return Ok(String::from_utf8(output.stdout)?);
+    }
+    bail!(
+        "Neither `unzip` nor `tar` is found to extract {}. Please extract conversations.json from it and import that instead.",
+        path.display()
+    )
+}
+
+/// Convert the current branch of an exported conversation. Returns `None` if it has no text
+/// messages.
+fn convert(exported: ExportedConversation) -> Result<Option<Conversation>> {
+    let mut branch = Vec::new();
+    let mut node_id = exported.current_node.clone();
+    while let Some(node) = node_id.and_then(|id| exported.mapping.get(&id)) {
+        branch.extend(node.message.as_ref());
+        node_id = node.parent.clone();
+    }
+    branch.reverse();
+
+    let mut model = exported.default_model_slug.clone();
+    let mut messages = Vec::new();
+    for message in branch {
+        let role = message.author.role.as_str();
+        if !matches!(role, "system" | "user" | "assistant")
+            || message.metadata.is_visually_hidden_from_conversation
+            || !matches!(
+                message.content.content_type.as_str(),
+                "text" | "multimodal_text"
+            )
+        {
+            continue;
+        }
+        // Attached images are not in the export, only references to them
+        let content = message
+            .content
+            .parts
+            .iter()
+            .filter_map(|part| part.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        if content.trim().is_empty() {
+            continue;
+        }
+        if role == "assistant" && message.metadata.model_slug.is_some() {
+            model.clone_from(&message.metadata.model_slug);
+        }
+        messages.push(Message {
+            role: role.to_owned(),
+            content,
+            ..Default::default()
+        });
+    }
+    if messages.is_empty() {
+        return Ok(None);
+    }
+
+    let created = exported.create_time.unwrap_or_default() as u64;
+    let mut conversation =
+        Conversation::created_at(model.unwrap_or_else(|| "gpt-4o".to_owned()), created)?;
+    conversation.updated = exported.update_time.map_or(created, |t| t as u64);
+    conversation.title = exported.title.filter(|t| !t.trim().is_empty());
+    conversation.messages = messages;
+    Ok(Some(conversation))
+}
@@ -0,0 +1,106 @@
+//! Store API keys in the OS keychain: Keychain on macOS, Credential Manager on Windows and
+//! Secret Service on Linux.
+
+use ::keyring::Entry;
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Subcommand};
+use console::Term;
+
+/// Service name of keychain entries
+const SERVICE: &str = "heygpt";
+
+#[derive(Args, Debug)]
+pub struct AuthArgs {
+    #[command(subcommand)]
+    pub action: AuthAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AuthAction {
+    /// Store an API key in the OS keychain. Reads the key from terminal or stdin.
+    Set {
+        /// Name of the entry, referenced by `api_key_source = "keyring:<name>"`
+        #[arg(default_value = "openai")]
+        name: String,
+    },
+    /// Check whether an API key is stored in the OS keychain
+    Check {
+        #[arg(default_value = "openai")]
+        name: String,
+    },
+    /// Delete an API key from the OS keychain
+    Delete {
+        #[arg(default_value = "openai")]
+        name: String,
+    },
+}
+
+pub fn run(args: AuthArgs) -> Result<()> {
+    match args.action {
+        AuthAction::Set { name } => {
+            let key = if atty::is(atty::Stream::Stdin) {
+                eprint!("API key for {name}: ");
+                Term::stderr().read_secure_line()?
+            } else {
+                std::io::read_to_string(std::io::stdin())?
+            };
+            let key = key.trim();
+            if key.is_empty() {
+                bail!("API key is empty");
+            }
+            set(&name, key)?;
+            eprintln!("Stored API key `{name}` in keychain. Use it with `api_key_source = \"keyring:{name}\"` in config file.");
+        }
+        AuthAction::Check { name } => {
+            let key = get(&name)?;
+            let masked: String = key.chars().take(3).collect();
+            println!("API key `{name}` is stored in keychain: {masked}...");
+        }
+        AuthAction::Delete { name } => {
+            delete(&name)?;
+            eprintln!("Deleted API key `{name}` from keychain");
+        }
+    }
+    Ok(())
+}
+
+/// Resolve the name of keychain entry from `api_key_source`, e.g. `keyring` or `keyring:work`.
+/// Returns `None` if the key is not from keychain.
+pub fn entry_name(source: &str) -> Result<Option<&str>> {
+    match source.split_once(':') {
+        _ if source.is_empty() || source == "config" => Ok(None),
+        None if source == "keyring" => Ok(Some("openai")),
+        Some(("keyring", name)) if !name.is_empty() => Ok(Some(name)),
+        _ => bail!(
+            "Invalid api_key_source `{source}`, expected `config`, `keyring` or `keyring:<name>`"
+        ),
+    }
+}
+
+/// Entry of the API key named `name` in the keychain
+fn entry(name: &str) -> Result<Entry> {
+    Entry::new(SERVICE, name).map_err(|e| anyhow!("Failed to open keychain entry `{name}`: {e}"))
+}
+
+pub fn get(name: &str) -> Result<String> {
+    entry(name)?.get_password().map_err(|e| match e {
+        ::keyring::Error::NoEntry => {
+            anyhow!(
+                "API key `{name}` not found in keychain. Store it with `heygpt auth set {name}`."
+            )
+        }
+        e => anyhow!("Failed to read API key `{name}` from keychain: {e}"),
+    })
+}
+
+pub fn set(name: &str, key: &str) -> Result<()> {
+    entry(name)?
+        .set_password(key)
+        .map_err(|e| anyhow!("Failed to store API key in keychain: {e}"))
+}
+
+pub fn delete(name: &str) -> Result<()> {
+    entry(name)?
+        .delete_credential()
+        .map_err(|e| anyhow!("Failed to delete API key `{name}` from keychain: {e}"))
+}
@@ -0,0 +1,144 @@
+//! Logs of heygpt and the crates inside: to stderr as filtered by `RUST_LOG`, and with
+//! `--log-file` as JSON lines into a file rotated by size. Requests to the API are in `request`
+//! spans, whose IDs are in the lines logged within them to tell concurrent requests apart.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{anyhow, Result};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::paths;
+
+/// The log file is rotated when it grows beyond this size
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated log files kept, e.g. `heygpt.log.1` to `heygpt.log.3`
+const ROTATED_FILES: usize = 3;
+
+/// Filter of the log file without `RUST_LOG`
+const DEFAULT_FILE_FILTER: &str = "info,heygpt=debug";
+
+/// Filter of the log file, which logs nothing until `--log-file` is given
+static FILE_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Start logging to stderr as filtered by `RUST_LOG`, only errors by default like env_logger
+pub fn init() {
+    let (file_filter, handle) = reload::Layer::new(EnvFilter::new("off"));
+    let file = fmt::layer()
+        .json()
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_writer(|| FileWriter)
+        .with_filter(file_filter);
+    let stderr = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(console::colors_enabled_stderr())
+        .with_filter(env_filter("error"));
+    let subscriber = Registry::default().with(file).with(stderr);
+    if tracing::subscriber::set_global_default(subscriber).is_ok() {
+        // Logs of the crates inside such as reqwest
+        let _ = tracing_log::LogTracer::init();
+        let _ = FILE_FILTER.set(handle);
+    }
+}
+
+/// Also write logs into the file, at the debug level of heygpt unless `RUST_LOG` is set
+pub fn log_to_file(path: &str) -> Result<()> {
+    let handle = FILE_FILTER
+        .get()
+        .ok_or_else(|| anyhow!("Logging is not initialized"))?;
+    let file = LogFile::open(paths::expand_home(path))?;
+    *LOG_FILE.lock().unwrap() = Some(file);
+    handle
+        .reload(env_filter(DEFAULT_FILE_FILTER))
+        .map_err(|e| anyhow!("Failed to start logging to {path}: {e}"))
+}
+
+/// A new ID for the `request` span
+pub fn request_id() -> u64 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The filter of `RUST_LOG`, or the default if it is not set or invalid
+fn env_filter(default: &str) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default))
+}
+
+/// Writer of log lines into the log file, if opened
+struct FileWriter;
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(file) = LOG_FILE.lock().unwrap().as_mut() {
+            file.write_line(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A log file rotated when it grows beyond `MAX_FILE_SIZE`
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    fn open(path: PathBuf) -> Result<Self> {
+        paths::ensure_parent_dir(&path)?;
+        let file = append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { path, file, size })
+    }
+
+    fn write_line(&mut self, line: &[u8]) {
+        if self.size > 0 && self.size + line.len() as u64 > MAX_FILE_SIZE {
+            if let Err(err) = self.rotate() {
+                eprintln!("Failed to rotate log file {}: {err}", self.path.display());
+            }
+        }
+        // Logging must not fail the command, e.g. when the disk is full
+        if self.file.write_all(line).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+
+    /// Shift `heygpt.log` to `heygpt.log.1`, `heygpt.log.1` to `heygpt.log.2` and so on
+    fn rotate(&mut self) -> Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        for n in (1..ROTATED_FILES).rev() {
+            if rotated(n).exists() {
+                std::fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated(1))?;
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("Failed to open log file {}: {e}", path.display()))
+}
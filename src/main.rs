@@ -1,28 +1,162 @@
 use anyhow::{anyhow, bail, Result};
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueHint};
 use clap_serde_derive::serde::Serialize;
 use clap_serde_derive::ClapSerde;
 use console::style;
 use futures::stream::StreamExt;
-use log::{debug, trace};
-use repl_helper::ReplHelper;
-use reqwest::header::{HeaderMap, AUTHORIZATION};
-use reqwest::{Client, RequestBuilder, StatusCode};
+use repl_helper::{EditMode, ReplHelper};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Client, Method, RequestBuilder};
 use reqwest_eventsource::{Event, EventSource};
 use rustyline::error::ReadlineError;
-use rustyline::{Cmd, Editor, EventHandler, KeyCode, KeyEvent, Modifiers};
-use std::io::Write;
+use rustyline::{Cmd, Config, Editor, EventHandler, KeyCode, KeyEvent, Modifiers};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use tracing::{debug, info_span, trace, Instrument};
 
+mod agent;
+mod alias;
+mod attach;
+mod audio;
+mod batch;
+mod bedrock;
+mod budget;
+mod capability;
+mod cassette;
+mod catalog;
+mod commit;
+mod completions;
+mod config;
+mod diff;
+mod embed;
+mod error;
+mod examples;
+mod explain;
+mod failover;
+mod git;
+mod highlight;
+mod history;
+mod hooks;
+mod html;
+mod image;
+mod import;
+mod keyring;
+mod logging;
+mod mcp;
+mod mock;
 mod model;
+mod moderation;
+mod output;
+mod pager;
+mod patch;
+mod paths;
+mod postprocess;
+mod provider;
+mod rag;
+mod redact;
 mod repl_helper;
+mod review;
+mod router;
+mod search;
+mod serve;
+mod settings;
 mod spinner;
+mod stats;
+mod token;
+mod tools;
+mod translate;
+mod verbose;
+mod wrap;
 
+use alias::Alias;
+use capability::{Capability, CapabilityOverride};
+use highlight::Highlight;
+use history::Conversation;
 use model::*;
+use output::{JsonDelta, JsonOutput, OutputFormat};
+use provider::Provider;
+use router::RouterConfig;
 use spinner::Spinner;
+use stats::ResponseStats;
+use wrap::Wrap;
 
-/// Command-line options
-#[derive(Parser, ClapSerde, Debug, Serialize)]
-#[command(about, long_about = None, trailing_var_arg=true)]
+/// Command-line arguments
+#[derive(Parser)]
+#[command(
+    about,
+    long_about = None,
+    trailing_var_arg = true,
+    disable_help_subcommand = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    options: <Options as ClapSerde>::Opt,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve an OpenAI-compatible API backed by heygpt's configuration
+    Serve(serve::ServeArgs),
+
+    /// Run prompts from a JSONL file concurrently and write responses as JSONL
+    Batch(batch::BatchArgs),
+
+    /// Transcribe an audio file into text
+    Transcribe(audio::TranscribeArgs),
+
+    /// Convert text to speech and play it or save it to a file
+    Speak(audio::SpeakArgs),
+
+    /// Generate images from a prompt
+    Image(image::ImageArgs),
+
+    /// Read lines from stdin and print their embeddings as JSONL
+    Embed(embed::EmbedArgs),
+
+    /// Build an embeddings index of a directory of notes for `--rag`, e.g. `heygpt index ~/notes`
+    Index(rag::IndexArgs),
+
+    /// Check whether the text is flagged by moderation API
+    Moderate(moderation::ModerateArgs),
+
+    /// List models available on the endpoint
+    Models(catalog::ModelsArgs),
+
+    /// Write a commit message for the staged changes and commit them
+    Commit(commit::CommitArgs),
+
+    /// Review a git diff and print comments per file
+    Review(review::ReviewArgs),
+
+    /// Translate a Markdown or text file, keeping code blocks, e.g. `heygpt translate --to de < README.md`
+    Translate(translate::TranslateArgs),
+
+    /// Explain a shell command flag by flag, e.g. `heygpt explain -- tar -xzvf a.tgz`
+    Explain(explain::ExplainArgs),
+
+    /// Manage API keys stored in the OS keychain
+    Auth(keyring::AuthArgs),
+
+    /// Read and write the config file
+    Config(config::ConfigArgs),
+
+    /// Browse and search saved conversations
+    History(history::HistoryArgs),
+
+    /// Import conversations from the data export of ChatGPT, e.g. `heygpt import chatgpt-export.zip`
+    Import(import::ImportArgs),
+
+    /// Print a shell completion script, e.g. `heygpt completions bash > /etc/bash_completion.d/heygpt`
+    Completions(completions::CompletionsArgs),
+}
+
+/// Options from command-line arguments and config file
+#[derive(ClapSerde, Debug, Clone, Serialize)]
 struct Options {
     /// Whether to use streaming API (default: true)
     #[default(true)]
@@ -30,7 +164,7 @@ struct Options {
     pub stream: bool,
 
     /// The model to query (default: gpt-3.5-turbo)
-    #[default(String::from("gpt-3.5-turbo"))]
+    #[default(String::from(DEFAULT_MODEL))]
     #[arg(long)]
     pub model: String,
 
@@ -43,8 +177,48 @@ struct Options {
     )]
     pub api_key: String,
 
+    /// Where to get the API key: `config` (default) or `keyring[:<name>]` for OS keychain
+    #[arg(long, hide_short_help = true)]
+    pub api_key_source: Option<String>,
+
+    /// Backend of the API, detected from `--api-base-url` by default
+    #[default(Provider::Auto)]
+    #[arg(
+        long,
+        value_enum,
+        hide_short_help = true,
+        hide_possible_values = true,
+        long_help = "Backend of the API: `openai`, `openrouter`, `groq`, `together`, `mistral`, `bedrock`, `llama-cpp`, or `mock` for canned responses without network. By default, it is detected from `--api-base-url` to work around the quirks of the provider, and falls back to `openai` for unknown endpoints."
+    )]
+    pub provider: Provider,
+
+    /// JSON file of canned responses replayed by `--provider mock`. Implies `--provider mock`.
+    #[arg(
+        long,
+        hide_short_help = true,
+        env = "HEYGPT_MOCK",
+        value_hint = ValueHint::FilePath
+    )]
+    pub mock_file: Option<String>,
+
+    /// Save all raw API requests and responses into the cassette file, for debugging
+    #[arg(long, value_name = "FILE", hide_short_help = true, value_hint = ValueHint::FilePath)]
+    #[serde(skip_deserializing)]
+    pub record: Option<String>,
+
+    /// Answer API requests with those saved by `--record`, without network access
+    #[arg(
+        long,
+        value_name = "FILE",
+        hide_short_help = true,
+        conflicts_with = "record",
+        value_hint = ValueHint::FilePath
+    )]
+    #[serde(skip_deserializing)]
+    pub replay: Option<String>,
+
     /// OpenAI API base URL
-    #[default(String::from("https://api.openai.com/v1"))]
+    #[default(String::from(DEFAULT_API_BASE_URL))]
     #[arg(
         long,
         hide_short_help = true,
@@ -53,6 +227,24 @@ struct Options {
     )]
     pub api_base_url: String,
 
+    /// OpenAI organization ID, sent as `OpenAI-Organization` header
+    #[arg(
+        long,
+        hide_short_help = true,
+        env = "OPENAI_ORG_ID",
+        hide_env_values = true
+    )]
+    pub openai_org: Option<String>,
+
+    /// OpenAI project ID, sent as `OpenAI-Project` header
+    #[arg(
+        long,
+        hide_short_help = true,
+        env = "OPENAI_PROJECT_ID",
+        hide_env_values = true
+    )]
+    pub openai_project: Option<String>,
+
     /// Sampling temperature to use, between 0 and 2.
     #[arg(
         long,
@@ -71,6 +263,57 @@ We generally recommend altering this or temperature but not both."#
     )]
     pub top_p: Option<f64>,
 
+    /// Penalty for token frequency, between -2.0 and 2.0
+    #[arg(
+        long,
+        hide_short_help = true,
+        allow_negative_numbers = true,
+        long_help = r#"Positive values penalize new tokens based on their existing frequency in the text so far, decreasing the model's likelihood to repeat the same line verbatim.
+Number between -2.0 and 2.0."#
+    )]
+    pub frequency_penalty: Option<f64>,
+
+    /// Penalty for token presence, between -2.0 and 2.0
+    #[arg(
+        long,
+        hide_short_help = true,
+        allow_negative_numbers = true,
+        long_help = r#"Positive values penalize new tokens based on whether they appear in the text so far, increasing the model's likelihood to talk about new topics.
+Number between -2.0 and 2.0."#
+    )]
+    pub presence_penalty: Option<f64>,
+
+    /// Bias the likelihood of a token, e.g. `--logit-bias 50256=-100`
+    #[arg(
+        long,
+        hide_short_help = true,
+        value_name = "TOKEN_ID=BIAS",
+        allow_hyphen_values = true,
+        long_help = r#"Modify the likelihood of specified tokens appearing in the completion. Maps a token ID in the tokenizer to a bias value from -100 to 100. Values like -100 or 100 should result in a ban or exclusive selection of the relevant token.
+Can be repeated. Overrides the entries loaded from `--logit-bias-file`."#
+    )]
+    pub logit_bias: Vec<String>,
+
+    /// JSON file mapping token IDs to bias values
+    #[arg(long, hide_short_help = true)]
+    pub logit_bias_file: Option<String>,
+
+    /// Maximum number of tokens to generate
+    #[arg(long, hide_short_help = true)]
+    pub max_tokens: Option<usize>,
+
+    /// Whether the model is a reasoning model like o1 (default: detect from model name)
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        long_help = r#"Whether the model is a reasoning model like o1 or o3-mini. Reasoning models take the system prompt as a `developer` message and do not accept sampling parameters such as temperature, which are dropped from the request.
+By default, it is looked up from the model capability table."#
+    )]
+    pub reasoning: Option<bool>,
+
     /// System prompt
     #[arg(
         long,
@@ -82,342 +325,3514 @@ We generally recommend altering this or temperature but not both."#
     #[serde(skip_deserializing)]
     pub system: Option<String>,
 
-    /// The prompt to ask. Leave it empty to activate interactive mode
-    #[serde(skip_deserializing)]
-    pub prompt: Vec<String>,
-}
+    /// File of REPL commands run at the start of interactive mode (default: `init` in config directory)
+    #[arg(
+        long,
+        value_hint = ValueHint::FilePath,
+        value_name = "PATH",
+        hide_short_help = true,
+        long_help = "File of REPL commands like `\\set model gpt-4o`, one per line, run at the start of interactive mode. Defaults to `init` in the config directory if it exists. Empty lines and lines starting with `#` are ignored."
+    )]
+    pub rcfile: Option<String>,
 
-const CONFIG_FILE: &str = ".heygpt.toml";
-const READLINE_HISTORY: &str = ".heygpt_history";
+    /// Read system prompt from a file
+    #[arg(
+        long,
+        value_hint = ValueHint::FilePath,
+        value_name = "PATH",
+        hide_short_help = true,
+        long_help = "Read system prompt from a file. Ignored if `--system` is given."
+    )]
+    pub system_file: Option<String>,
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-    env_logger::init();
-
-    let config_file_path = dirs::home_dir().unwrap().join(CONFIG_FILE);
-    let options = if config_file_path.exists() {
-        let config_file = std::fs::read_to_string(&config_file_path)?;
-        let options = toml::from_str::<<Options as ClapSerde>::Opt>(&config_file)?;
-        debug!("Loaded config file: {}", &config_file);
-        Options::from(options).merge_clap()
-    } else {
-        Options::parse()
-    };
+    /// Continue a saved conversation, by ID or a unique prefix of it (see `heygpt history list`)
+    #[arg(long, value_name = "ID", hide_short_help = true)]
+    #[serde(skip_deserializing)]
+    pub resume: Option<String>,
 
-    debug!("Final options: {:?}", &options);
+    /// JSON or TOML file of few-shot examples, loaded before the prompt
+    #[arg(
+        long,
+        value_hint = ValueHint::FilePath,
+        hide_short_help = true,
+        long_help = r#"JSON or TOML file of few-shot examples, loaded as messages before the prompt.
+A JSON file is an array of `{"user": "...", "assistant": "..."}` pairs or `{"role": "...", "content": "..."}` messages. A TOML file has them in `[[examples]]`."#
+    )]
+    pub examples: Option<String>,
 
-    if options.api_key.is_empty() {
-        bail!("OpenAI API key is required. Please set it via OPENAI_API_KEY environment variable or config file `$HOME/.heygpt.toml`.");
-    }
+    /// Attach a file to the prompt. Can be repeated.
+    #[arg(
+        long,
+        value_hint = ValueHint::FilePath,
+        value_name = "PATH",
+        long_help = "Attach a text file to the prompt, in a code block labeled with its path. Can be repeated.\n`-` attaches stdin, which may also be an image for vision models. In interactive mode, files are attached to the first prompt."
+    )]
+    #[serde(skip_deserializing)]
+    pub file: Vec<String>,
 
-    let is_stdout = atty::is(atty::Stream::Stdout);
-    let is_stdin = atty::is(atty::Stream::Stdin);
+    /// Attach a file and ask again whenever it changes. Can be repeated.
+    #[arg(
+        long,
+        value_hint = ValueHint::FilePath,
+        value_name = "PATH",
+        hide_short_help = true,
+        long_help = "Attach a file to the prompt like `--file`, and send the prompt again whenever the file changes, until interrupted by Ctrl-C. Can be repeated. Only for one-shot mode."
+    )]
+    #[serde(skip_deserializing)]
+    pub watch: Vec<String>,
 
-    let mut session = Session::new(options, is_stdin, is_stdout);
-    if !session.is_interactive() {
-        session.run_one_shot().await?;
-    } else {
-        session.run_interactive().await?;
-    }
+    /// Attach text files in a directory to the prompt, respecting `.gitignore`. Can be repeated.
+    #[arg(
+        long,
+        value_hint = ValueHint::DirPath,
+        value_name = "PATH",
+        long_help = "Attach text files in a directory to the prompt, after a manifest of them. Files ignored by `.gitignore` are skipped. Can be repeated."
+    )]
+    #[serde(skip_deserializing)]
+    pub dir: Vec<String>,
 
-    Ok(())
-}
+    /// Only attach files matching the glob from `--dir`, e.g. `*.rs`. Can be repeated.
+    #[arg(
+        long,
+        value_name = "GLOB",
+        hide_short_help = true,
+        long_help = "Only attach files matching the glob from `--dir`, e.g. `*.rs` or `src/**/*.rs`. Globs with a `/` match the path relative to the directory, others match the file name. Can be repeated."
+    )]
+    #[serde(skip_deserializing)]
+    pub include: Vec<String>,
 
-struct Session {
-    /// Command-line options
-    options: Options,
+    /// Maximum number of tokens of files attached by each `--dir`
+    #[arg(
+        long,
+        hide_short_help = true,
+        long_help = "Maximum number of tokens of files attached by each `--dir`. Larger files are truncated to fit."
+    )]
+    #[default(8000)]
+    pub max_tokens_context: usize,
 
-    /// Messages history
-    messages: Vec<Message>,
+    /// Attach the text of a web page to the prompt. Can be repeated.
+    #[arg(
+        long,
+        long_help = "Download a web page and attach its text to the prompt. HTML is converted to plain text. Can be repeated."
+    )]
+    #[serde(skip_deserializing)]
+    pub url: Vec<String>,
 
-    /// Whether input from stdin
-    is_stdin: bool,
+    /// Start of the answer for the model to continue from, in one-shot mode
+    #[arg(long, hide_short_help = true)]
+    #[serde(skip_deserializing)]
+    pub assistant_prefix: Option<String>,
 
-    /// Whether output to stdout
-    is_stdout: bool,
+    /// Stop the response right after the marker
+    #[arg(
+        long,
+        value_name = "MARKER",
+        hide_short_help = true,
+        long_help = "Stop the response as soon as it contains the marker, which ends the printed response, and close the connection so no more tokens are generated. Unlike `stop` of the API, the marker can be any text, e.g. `$'\\n```\\n'` in bash to stop when the first code block ends."
+    )]
+    #[serde(skip_deserializing)]
+    pub until: Option<String>,
 
-    /// Spinner holder
-    spinner: Option<Spinner>,
-}
+    /// Output format of the response (default: text)
+    #[default(OutputFormat::Text)]
+    #[arg(long, value_enum, hide_short_help = true)]
+    pub format: OutputFormat,
 
-impl Session {
-    pub fn new(options: Options, is_stdin: bool, is_stdout: bool) -> Self {
-        Self {
-            options,
-            is_stdin,
-            is_stdout,
-            messages: Vec::new(),
-            spinner: None,
-        }
-    }
+    /// Refuse to send prompts flagged by moderation API
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub moderate: bool,
 
-    pub fn is_interactive(&self) -> bool {
-        // Enter interactive mode if prompt is empty and no redirection
-        self.options.prompt.is_empty() && self.is_stdout && self.is_stdin
-    }
+    /// Warn about responses flagged by moderation API
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub moderate_response: bool,
 
-    pub async fn run_one_shot(&mut self) -> Result<()> {
-        let prompt = if !self.options.prompt.is_empty() {
-            self.options.prompt.join(" ")
-        } else if !self.is_stdin {
-            std::io::read_to_string(std::io::stdin())?
-        } else {
-            bail!("Prompt is required")
-        };
+    /// Speak the response aloud via text-to-speech API
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub speak: bool,
 
-        if let Some(system_prompt) = &self.options.system {
-            self.messages.push(Message {
-                role: "system".to_string(),
-                content: system_prompt.clone(),
-            });
-        }
+    /// The voice for text-to-speech (default: alloy)
+    #[default(String::from("alloy"))]
+    #[arg(long, hide_short_help = true)]
+    pub voice: String,
 
-        self.messages.push(Message {
-            role: "user".to_string(),
-            content: prompt,
-        });
+    /// Speed of text-to-speech, between 0.25 and 4.0
+    #[arg(long, hide_short_help = true)]
+    pub speed: Option<f64>,
 
-        let _ = self.complete_and_print().await?;
-        Ok(())
-    }
+    /// Extra HTTP header sent with every request, e.g. `--header "X-Tenant: foo"`
+    #[arg(
+        long,
+        hide_short_help = true,
+        value_name = "NAME: VALUE",
+        long_help = r#"Extra HTTP header sent with every API request, e.g. `--header "X-Tenant: foo"`.
+Can be repeated. Overrides the entries of the same name in `[headers]` of config file."#
+    )]
+    pub header: Vec<String>,
 
-    pub async fn run_interactive(&mut self) -> Result<()> {
-        let mut rl = Editor::<repl_helper::ReplHelper, _>::new()?;
-        rl.set_helper(Some(ReplHelper::default()));
+    /// More API keys for `api_base_url`, failed over to when a request is rate limited or the
+    /// server fails. Only available in config file.
+    #[arg(skip)]
+    pub api_keys: Vec<String>,
 
-        // Bind CTRL-J to newline
-        rl.bind_sequence(
-            KeyEvent(KeyCode::Char('j'), Modifiers::CTRL),
-            EventHandler::Simple(Cmd::Newline),
-        );
+    /// More endpoints with their own API keys, failed over to after `api_keys`. Only available
+    /// in config file.
+    #[arg(skip)]
+    pub endpoints: Vec<failover::Endpoint>,
 
-        // Persist input history in `$HOME/.heygpt_history`
-        let history_file = {
-            let mut p = dirs::home_dir().unwrap();
-            p.push(READLINE_HISTORY);
-            p.to_str().unwrap().to_owned()
-        };
-        let _ = rl.load_history(&history_file);
+    /// Spread requests over the API keys and endpoints in turn, instead of failing over only
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub round_robin: bool,
 
-        // If `--system` or `--system="..."` is specified
-        if let Some(s) = &self.options.system {
-            let system_prompt = if !s.is_empty() {
-                // If `--system="..."` is specified, use it as system prompt
-                s.clone()
-            } else {
-                // Otherwise, read system prompt interactively
-                if let Some(p) = self.read_prompt(&mut rl, "system").await? {
-                    p
-                } else {
-                    return Ok(());
-                }
-            };
-            self.messages.push(Message {
-                role: "system".to_string(),
-                content: system_prompt,
-            });
-        };
+    /// Ask Mistral to prepend its guardrail system prompt (`safe_prompt`)
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub safe_prompt: bool,
 
-        loop {
-            let prompt = if let Some(p) = self.read_prompt(&mut rl, "user").await? {
-                p
-            } else {
-                break;
-            };
+    /// Extra HTTP headers sent with every request. Only available in config file.
+    #[arg(skip)]
+    pub headers: BTreeMap<String, String>,
 
-            self.messages.push(Message {
-                role: "user".to_string(),
-                content: prompt,
-            });
+    /// Timeout in seconds of a whole request, including reading the response
+    #[arg(long, hide_short_help = true, value_name = "SECONDS")]
+    pub timeout: Option<f64>,
 
-            match self.complete_and_print().await {
-                Ok(response) => self.messages.push(response),
-                Err(err) => {
-                    let last_msg = self.messages.pop(); // remove the last message
-                    assert!(last_msg.is_some());
-                    println!("{}: {err}", style("ERROR").bold().red());
-                }
-            }
-        }
+    /// Timeout in seconds of connecting to the API endpoint
+    #[arg(long, hide_short_help = true, value_name = "SECONDS")]
+    pub connect_timeout: Option<f64>,
 
-        rl.append_history(&history_file)?;
-        Ok(())
-    }
+    /// Abort a streaming response if no event arrives for this many seconds
+    #[arg(long, hide_short_help = true, value_name = "SECONDS")]
+    pub stall_timeout: Option<f64>,
 
-    async fn read_prompt<H, I>(
-        &mut self,
-        rl: &mut Editor<H, I>,
-        role: &str,
-    ) -> Result<Option<String>>
-    where
-        H: rustyline::Helper,
-        I: rustyline::history::History,
-    {
+    /// Retry without streaming if the stream sends nothing for this many seconds
+    #[arg(
+        long,
+        hide_short_help = true,
+        value_name = "SECONDS",
+        long_help = "If a streaming response sends no tokens for this many seconds (default: 60, none for reasoning models), cancel it and send the request again without streaming, as some gateways break streaming but answer fine otherwise. Later requests of the session are not streamed either. 0 disables it."
+    )]
+    pub first_token_timeout: Option<f64>,
+
+    /// Shell command to pipe responses through before printing and saving them. Can be repeated.
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        hide_short_help = true,
+        long_help = "Shell command to pipe each response through before it is printed and saved, e.g. `--post-process \"sed '/^```/d'\"`. Can be repeated to form a pipeline. Responses are not streamed when post-processing."
+    )]
+    pub post_process: Vec<String>,
+
+    /// Shell command to rewrite each chat request, given as JSON on stdin
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        hide_short_help = true,
+        long_help = "Shell command run before each chat completion request, with the request as JSON on stdin. If it prints a JSON object, that is sent instead, e.g. to strip secrets. The request is sent unchanged if it prints nothing, and not sent at all if it fails."
+    )]
+    pub pre_request_hook: Option<String>,
+
+    /// Maximum number of requests per hour, counted across heygpt processes
+    #[arg(long, hide_short_help = true, value_name = "N")]
+    pub max_requests_per_hour: Option<u32>,
+
+    /// Maximum estimated cost in USD per 24 hours, counted across heygpt processes
+    #[arg(long, hide_short_help = true, value_name = "USD")]
+    pub max_cost_per_day: Option<f64>,
+
+    /// Replace secrets like API keys and private keys in prompts with placeholders
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        long_help = "Replace secrets in outgoing messages with placeholders before sending, e.g. AWS keys, private key blocks, bearer tokens and API keys of OpenAI, GitHub, Slack and Google. A warning tells what was redacted."
+    )]
+    pub redact: bool,
+
+    /// Shell command to receive each chat response as JSON on stdin
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        hide_short_help = true,
+        long_help = "Shell command run after each chat completion response, with the response as JSON on stdin, e.g. for custom logging. Its output is discarded, and a failure is only warned about."
+    )]
+    pub post_response_hook: Option<String>,
+
+    /// Write logs as JSON lines into the file, rotated by size
+    #[arg(
+        long,
+        value_name = "PATH",
+        hide_short_help = true,
+        value_hint = ValueHint::FilePath,
+        long_help = "Append logs as JSON lines into the file, at the debug level of heygpt or as set by `RUST_LOG`. Lines logged during a request to the API carry the ID of its `request` span. The file is rotated when it reaches 10 MB, keeping 3 previous files as `PATH.1` to `PATH.3`."
+    )]
+    pub log_file: Option<String>,
+
+    /// Also append responses to the file, while printing them as usual
+    #[arg(long, value_name = "PATH", hide_short_help = true, value_hint = ValueHint::FilePath)]
+    #[serde(skip_deserializing)]
+    pub tee: Option<String>,
+
+    /// Automatically continue a truncated response up to N times
+    #[default(0)]
+    #[arg(
+        long,
+        hide_short_help = true,
+        value_name = "N",
+        long_help = "When a response is truncated because it reached the token limit, ask the model to continue where it left off, up to N times, and stitch the parts together."
+    )]
+    pub auto_continue: usize,
+
+    /// Resume a response up to N times when its stream breaks
+    #[default(2)]
+    #[arg(
+        long,
+        hide_short_help = true,
+        value_name = "N",
+        long_help = "When the connection breaks in the middle of a streaming response, e.g. on flaky Wi-Fi, send the request again with the partial response, asking the model to continue where it left off, up to N times (default: 2). 0 disables it."
+    )]
+    pub stream_retries: usize,
+
+    /// Summarize earlier messages instead of dropping them when exceeding the context window
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub auto_summarize: bool,
+
+    /// Model to generate titles of saved conversations with, e.g. gpt-4o-mini
+    #[arg(
+        long,
+        hide_short_help = true,
+        long_help = "Model to generate a short title of each saved conversation with after its first answer, e.g. `gpt-4o-mini`. Titles are shown by `heygpt history list`, which shows the first message otherwise."
+    )]
+    pub title_model: Option<String>,
+
+    /// Print token usage and estimated cost after each response
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub show_usage: bool,
+
+    /// Print latency and tokens after each response
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub stats: bool,
+
+    /// Print nothing but the response, without spinner or role prefixes even on a terminal
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub quiet: bool,
+
+    /// Print the response byte-exact as returned by the model, without any trimming or rendering
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub raw: bool,
+
+    /// Wrap streamed responses at word boundaries to the terminal width (default: true)
+    #[default(true)]
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub wrap: bool,
+
+    /// Highlight the syntax of code blocks in streamed responses on a terminal (default: true)
+    #[default(true)]
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub highlight: bool,
+
+    /// Key bindings of line editor in interactive mode (default: emacs)
+    #[default(EditMode::Emacs)]
+    #[arg(long, value_enum, hide_short_help = true)]
+    pub edit_mode: EditMode,
+
+    /// Key bindings of interactive mode, e.g. `"alt-enter" = "newline"`. Only available in config file.
+    #[arg(skip)]
+    pub keybindings: BTreeMap<String, String>,
+
+    /// Whether to save conversations to history (default: true)
+    #[default(true)]
+    #[arg(long, hide_short_help = true, hide_possible_values = true)]
+    pub save_history: bool,
+
+    /// Whether to show long non-streaming responses in `$PAGER` in interactive mode (default: true)
+    #[default(true)]
+    #[arg(long, hide_short_help = true, hide_possible_values = true)]
+    pub pager: bool,
+
+    /// Print the request instead of sending it
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        long_help = "Print the request that would be sent, including the endpoint and headers (with API key redacted), without sending it."
+    )]
+    pub dry_run: bool,
+
+    /// Ignore config files, using only defaults, environment variables and arguments
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    #[serde(skip_deserializing)]
+    pub no_config: bool,
+
+    /// Print the effective options, each with where its value came from, and exit
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        long_help = "Print the effective options after merging defaults, config files, aliases, environment variables and arguments, each annotated with where its value came from, and exit. API keys are redacted."
+    )]
+    #[serde(skip_deserializing)]
+    pub show_config: bool,
+
+    /// Print HTTP requests and responses to stderr, with bodies and stream events if repeated
+    #[arg(
+        short,
+        long,
+        action = clap::ArgAction::Count,
+        long_help = "Print the method, URL and headers of HTTP requests to the API and the status and headers of responses to stderr, with credentials redacted. Repeat it as `-vv` to also print the bodies and the raw events of response streams."
+    )]
+    #[serde(skip_deserializing)]
+    pub verbose: u8,
+
+    /// Start interactive mode, loading the input from stdin if any, e.g. `cat design.md | heygpt -i`
+    #[arg(
+        short,
+        long,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        conflicts_with_all = ["prompt", "messages_stdin", "no_interactive"],
+        long_help = "Start interactive mode even if stdin or stdout is not detected as a terminal. Input redirected to stdin is loaded as the first message of the conversation, as set by `--stdin-as`, and the terminal is reopened for the prompts."
+    )]
+    #[serde(skip_deserializing)]
+    pub interactive: bool,
+
+    /// Answer once and exit, reading the prompt from stdin if not given
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        long_help = "Answer once and exit even if stdin and stdout are terminals. Without a prompt in arguments, it is read from stdin until EOF."
+    )]
+    #[serde(skip_deserializing)]
+    pub no_interactive: bool,
+
+    /// Role of the message loaded from stdin by `--interactive`
+    #[arg(
+        long,
+        value_name = "ROLE",
+        value_parser = ["user", "system"],
+        default_value = "user",
+        hide_short_help = true
+    )]
+    #[serde(skip_deserializing)]
+    pub stdin_as: String,
+
+    /// Read the conversation as a JSON array of messages from stdin
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        conflicts_with_all = ["prompt", "system", "assistant_prefix", "file", "dir", "url"],
+        long_help = r#"Read the conversation as a JSON array of `{"role": "...", "content": "..."}` messages from stdin, and send it as is to get the next message."#
+    )]
+    #[serde(skip_deserializing)]
+    pub messages_stdin: bool,
+
+    /// Models to fall back to in turn if the model fails, e.g. `gpt-4o-mini,gpt-3.5-turbo`
+    #[arg(
+        long,
+        value_name = "MODELS",
+        value_delimiter = ',',
+        hide_short_help = true,
+        long_help = "Models to retry with in turn when the model fails because it is unavailable, rate limited, out of quota, overloaded or its context window is exceeded, e.g. `--fallback-models gpt-4o-mini,gpt-3.5-turbo`. A note tells which model answered."
+    )]
+    pub fallback_models: Vec<String>,
+
+    /// Use a cheap model for short and simple prompts, and `--model` for the others
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        long_help = "Send short and simple prompts to a cheap model (`cheap_model` in `[router]` of the config file, default gpt-4o-mini), and prompts containing code, long conversations or prompts marked `--hard` to `--model`. A note tells which model was chosen."
+    )]
+    pub auto_model: bool,
+
+    /// With `--auto-model`, always use `--model`
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    #[serde(skip_deserializing)]
+    pub hard: bool,
+
+    /// Rules of `--auto-model`. Only available in config file.
+    #[arg(skip)]
+    pub router: RouterConfig,
+
+    /// Shortcuts for prompts with preset options and for command sequences. Only available in config file.
+    #[arg(skip)]
+    pub aliases: BTreeMap<String, Alias>,
+
+    /// Let the model run shell commands, read and write files and fetch web pages
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        long_help = "Offer the tools `run_shell`, `read_file`, `write_file` and `http_get`, and `search` with a backend in `[web_search]` of the config file, to the model, and send their results back until it answers. Each call is shown and needs confirmation on terminal unless allowed by `--allow-tools`, and is recorded in `tools.log` of the data directory. Only for text output."
+    )]
+    pub tools: bool,
+
+    /// Tools of `--tools` run without confirmation, e.g. `read_file,http_get`, or `all`
+    #[arg(
+        long,
+        value_name = "TOOLS",
+        value_delimiter = ',',
+        hide_short_help = true
+    )]
+    pub allow_tools: Vec<String>,
+
+    /// Work on the task in steps with the tools of `--tools`, until a final answer
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        long_help = "Let the model work on the task in steps, calling the tools of `--tools` and observing their results, until it gives a final answer or `--max-steps` is reached. Steps are marked as they are streamed, and recorded in a Markdown transcript in `transcripts` of the data directory, or at `--transcript`."
+    )]
+    pub agent: bool,
+
+    /// Most requests answered with tool calls before giving up
+    #[arg(long, value_name = "N", hide_short_help = true)]
+    #[default(20)]
+    pub max_steps: usize,
+
+    /// Write the transcript of `--agent` to the file
+    #[arg(long, value_name = "PATH", hide_short_help = true, value_hint = ValueHint::FilePath)]
+    #[serde(skip_deserializing)]
+    pub transcript: Option<String>,
+
+    /// MCP servers whose tools are offered along with those of `--tools`. Only available in config file.
+    #[arg(skip)]
+    pub mcp_servers: BTreeMap<String, mcp::ServerConfig>,
+
+    /// Backend of the `search` tool and `--search`. Only available in config file.
+    #[arg(skip)]
+    pub web_search: search::SearchConfig,
+
+    /// Search the web for the prompt and attach the top results
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        long_help = "Search the web for the prompt with the backend in `[web_search]` of the config file, and attach the title, URL and snippet of the top results to it. In interactive mode, every prompt is searched for."
+    )]
+    pub search: bool,
+
+    /// Attach notes relevant to each prompt from the index built by `heygpt index`
+    #[arg(
+        long,
+        value_name = "NAME",
+        hide_short_help = true,
+        long_help = "Attach the chunks of notes most similar to each prompt from the index of the name, built by `heygpt index DIR`. The prompt is embedded with the model of the index. Toggled by `\\rag` in interactive mode."
+    )]
+    pub rag: Option<String>,
+
+    /// Number of chunks attached by `--rag`
+    #[arg(long, value_name = "N", hide_short_help = true)]
+    #[default(4)]
+    pub rag_top_k: usize,
+
+    /// Send the prompt to several models concurrently and print their answers, e.g. `gpt-4o,o3-mini`
+    #[arg(
+        long,
+        value_name = "MODELS",
+        value_delimiter = ',',
+        hide_short_help = true,
+        long_help = "Send the prompt to several models concurrently, e.g. `--compare gpt-4o,o3-mini`, and print their answers one after another with latency and cost. Only for one-shot mode.\nA model prefixed with a provider, e.g. `groq:llama-3.1-8b-instant`, is sent to that provider with the API key in its environment variable, e.g. `GROQ_API_KEY`."
+    )]
+    #[serde(skip_deserializing)]
+    pub compare: Vec<String>,
+
+    /// Generate N answers and ask the model to pick the best one
+    #[arg(
+        long,
+        value_name = "N",
+        hide_short_help = true,
+        value_parser = clap::value_parser!(u8).range(2..),
+        long_help = "Generate N answers concurrently, then ask the model to judge them and print only the best one. Only for one-shot mode."
+    )]
+    #[serde(skip_deserializing)]
+    pub best_of: Option<u8>,
+
+    /// Print all answers of `--best-of` to stderr
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true
+    )]
+    pub show_candidates: bool,
+
+    /// Run the prompt over chunks of input from stdin too long for the context window
+    #[arg(
+        long,
+        hide_short_help = true,
+        default_missing_value = "true",
+        num_args(0..=1),
+        require_equals = true,
+        conflicts_with = "messages_stdin",
+        long_help = "When the input from stdin doesn't fit in the context window with the prompt, split it into chunks that do, run the prompt over each chunk concurrently, then ask the model to combine the partial results into one answer. Only for one-shot mode."
+    )]
+    #[serde(skip_deserializing)]
+    pub map_reduce: bool,
+
+    /// Model capability overrides, keyed by model name prefix. Only available in config file.
+    #[arg(skip)]
+    pub models: BTreeMap<String, CapabilityOverride>,
+
+    /// The prompt to ask. Leave it empty to activate interactive mode
+    #[arg(
+        long_help = "The prompt to ask. Leave it empty to activate interactive mode.\n\nA prompt starting with the name of a subcommand runs the subcommand, unless options only meaningful for a prompt like `--dry-run` or `--system` are given. Put `--` before the prompt to always ask it, e.g. `heygpt -- explain how tides work`."
+    )]
+    #[serde(skip_deserializing)]
+    pub prompt: Vec<String>,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    if let Err(err) = run().await {
+        if !error::is_quiet(&err) {
+            eprintln!("Error: {err:?}");
+        }
+        std::process::exit(error::exit_code(&err));
+    }
+}
+
+/// Options set by environment variables, a layer between the config files and the command line.
+/// Options without a variable of their own can be set by `HEYGPT_` and their names, e.g.
+/// `HEYGPT_MODEL` for `--model`, except those conflicting with an option on the command line,
+/// which takes precedence, e.g. `--no-interactive` over `HEYGPT_INTERACTIVE=true`.
+fn env_options(cli: &clap::ArgMatches) -> <Options as ClapSerde>::Opt {
+    let command = Cli::command();
+    let conflicts = |a: &clap::Arg, b: &clap::Arg| {
+        let conflicts_with = |a: &clap::Arg, b: &clap::Arg| {
+            command
+                .get_arg_conflicts_with(a)
+                .iter()
+                .any(|arg| arg.get_id() == b.get_id())
+        };
+        conflicts_with(a, b) || conflicts_with(b, a)
+    };
+    let given: Vec<_> = command
+        .get_arguments()
+        .filter(|arg| cli.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine))
+        .collect();
+    let ids: Vec<_> = command
+        .get_arguments()
+        .filter(|arg| !arg.is_positional() && arg.get_env().is_none())
+        .filter(|arg| !given.iter().any(|given| conflicts(arg, given)))
+        .map(|arg| arg.get_id().clone())
+        .collect();
+    let matches = command
+        .clone()
+        .mut_args(|arg| {
+            if !ids.contains(arg.get_id()) {
+                return arg;
+            }
+            let env = format!("HEYGPT_{}", arg.get_id().as_str().to_uppercase());
+            arg.env(env)
+        })
+        .try_get_matches_from(["heygpt"])
+        .unwrap_or_else(|e| e.exit());
+    Cli::from_arg_matches(&matches)
+        .unwrap_or_else(|e| e.exit())
+        .options
+}
+
+/// Options only meaningful for a prompt
+const PROMPT_OPTIONS: &[&str] = &[
+    "system",
+    "assistant_prefix",
+    "until",
+    "file",
+    "dir",
+    "include",
+    "url",
+    "messages_stdin",
+    "compare",
+    "best_of",
+    "map_reduce",
+    "interactive",
+    "stdin_as",
+    "watch",
+    "transcript",
+    "resume",
+    "search",
+    "dry_run",
+];
+
+/// With options only meaningful for a prompt, a prompt starting with the name of a subcommand is
+/// asked instead of running the subcommand, e.g. `heygpt --dry-run explain how tides work`
+fn prompt_over_subcommand(matches: clap::ArgMatches) -> clap::ArgMatches {
+    let Some(name) = matches.subcommand_name() else {
+        return matches;
+    };
+    if !PROMPT_OPTIONS
+        .iter()
+        .any(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+    {
+        return matches;
+    }
+    // Parse again with `--` before the name. Going backwards, it's before the subcommand rather
+    // than a value of an option when nothing parses as a subcommand anymore.
+    let args: Vec<_> = std::env::args_os().collect();
+    for i in (1..args.len()).rev().filter(|&i| args[i] == name) {
+        let mut escaped = args.clone();
+        escaped.insert(i, "--".into());
+        if let Ok(prompt) = Cli::command().try_get_matches_from(escaped) {
+            if prompt.subcommand_name().is_none() {
+                return prompt;
+            }
+        }
+    }
+    matches
+}
+
+async fn run() -> Result<()> {
+    logging::init();
+    enable_ansi();
+
+    let matches = prompt_over_subcommand(Cli::command().get_matches());
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    paths::migrate();
+
+    let mut sources = config::Sources::default();
+    let options = if cli.options.no_config == Some(true) {
+        Options::default()
+    } else {
+        config::load(Options::default(), &mut sources)?
+    };
+    let before = options.clone();
+    let mut options = options.merge(env_options(&matches));
+    sources.record(&before, &options, |_| "environment".to_owned())?;
+    // `heygpt NAME ...` with an alias named NAME
+    let alias = cli
+        .command
+        .is_none()
+        .then(|| alias::take(&options, &mut cli.options.prompt))
+        .flatten();
+    if let Some(alias) = &alias {
+        let before = options.clone();
+        alias.apply(&mut options);
+        sources.record(&before, &options, |_| "alias".to_owned())?;
+    }
+    let before = options.clone();
+    let mut options = options.merge(cli.options);
+    sources.record(&before, &options, |key| {
+        match matches.value_source(key) {
+            Some(ValueSource::EnvVariable) => "environment",
+            _ => "command line",
+        }
+        .to_owned()
+    })?;
+    // Without words left on the command line, the prefix goes before the prompt from stdin
+    let alias_prompt = alias.and_then(|a| a.prompt);
+    if let Some(prefix) = &alias_prompt {
+        if !options.prompt.is_empty() {
+            options.prompt.insert(0, prefix.clone());
+        }
+    }
+    if options.show_config {
+        return config::show(&options, &sources);
+    }
+
+    custom_headers(&options)?;
+    if let Some(path) = &options.log_file {
+        logging::log_to_file(path)?;
+    }
+    options.tools |= options.agent;
+
+    if let Some(name) = tools::unknown_tool(&options.allow_tools, &options.mcp_servers) {
+        return Err(error::usage(format!(
+            "Unknown tool `{name}` in --allow-tools, expected `all`, an MCP server or its tool, or some of {}",
+            tools::TOOLS.join(", ")
+        )));
+    }
+    if options.search && !options.web_search.is_configured() {
+        return Err(error::usage(
+            "--search needs a backend in `[web_search]` of the config file",
+        ));
+    }
+
+    if options.until.as_deref() == Some("") {
+        return Err(error::usage("The marker of --until must not be empty"));
+    }
+
+    let timeouts = [
+        options.timeout,
+        options.connect_timeout,
+        options.stall_timeout,
+    ];
+    if timeouts
+        .into_iter()
+        .flatten()
+        .any(|t| !(t.is_finite() && t > 0.0))
+    {
+        return Err(error::usage("Timeouts must be positive numbers of seconds"));
+    }
+    if options
+        .first_token_timeout
+        .is_some_and(|t| !(t.is_finite() && t >= 0.0))
+    {
+        return Err(error::usage(
+            "--first-token-timeout must be a number of seconds, or 0 to disable it",
+        ));
+    }
+
+    // Commands that don't need an API key
+    let command = match cli.command {
+        Some(Command::Auth(args)) => return keyring::run(args),
+        Some(Command::Config(args)) => return config::run(options, args),
+        Some(Command::History(args)) => return history::run(args),
+        Some(Command::Import(args)) => return import::run(args),
+        Some(Command::Completions(args)) => {
+            return completions::run(&options, args, Cli::command())
+        }
+        command => command,
+    };
+
+    if let (None, Some(path)) = (&options.system, &options.system_file) {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read system prompt file `{path}`: {e}"))?;
+        if content.trim().is_empty() {
+            return Err(error::usage(format!(
+                "System prompt file `{path}` is empty"
+            )));
+        }
+        options.system = Some(content.trim_end().to_owned());
+    }
+
+    if options.mock_file.is_some() {
+        options.provider = Provider::Mock;
+    }
+    if options.provider == Provider::Auto {
+        options.provider = Provider::detect(&options.api_base_url);
+    }
+    if let Some(url) = options.provider.default_base_url() {
+        if options.api_base_url == DEFAULT_API_BASE_URL {
+            options.api_base_url = url.to_owned();
+        }
+    }
+    options.api_base_url = options.provider.complete_base_url(&options.api_base_url);
+    if let Some(model) = options.provider.default_model() {
+        if options.model == DEFAULT_MODEL {
+            options.model = model.to_owned();
+        }
+    }
+    if options.provider == Provider::Mock {
+        options.api_base_url = mock::start(options.mock_file.as_deref())?;
+        if options.api_key.is_empty() {
+            options.api_key = "mock".to_owned();
+        }
+    }
+    if let Some(path) = &options.replay {
+        options.api_base_url = cassette::replay(path)?;
+        if options.api_key.is_empty() {
+            options.api_key = "replay".to_owned();
+        }
+    }
+
+    if options.api_key.is_empty() {
+        if let Some(source) = &options.api_key_source {
+            if let Some(name) = keyring::entry_name(source)? {
+                options.api_key = keyring::get(name)?;
+            }
+        }
+    }
+
+    debug!("Final options: {:?}", redacted(&options));
+
+    if options.api_key.is_empty() && options.provider.requires_api_key() {
+        return Err(error::Failure::new(error::FailureKind::Auth, "OpenAI API key is required. Please set it via OPENAI_API_KEY environment variable or config file. Run `heygpt config path` to find the config file.").into());
+    }
+
+    if options.replay.is_none() {
+        if options.provider == Provider::Bedrock {
+            options.api_base_url = bedrock::start(&options)?;
+        } else if failover::is_enabled(&options) {
+            options.api_base_url = failover::start(&options)?;
+        }
+        if let Some(path) = &options.record {
+            options.api_base_url = cassette::record(&options, path)?;
+        }
+    }
+
+    let model_from_cli = matches.value_source("model") == Some(ValueSource::CommandLine);
+    if let Some(command) = command {
+        return match command {
+            Command::Serve(args) => serve::run(options, args).await,
+            Command::Batch(args) => batch::run(options, args).await,
+            Command::Transcribe(args) => audio::transcribe(options, args).await,
+            Command::Speak(args) => audio::speak(options, args).await,
+            Command::Image(args) => image::run(options, args).await,
+            Command::Embed(args) => embed::run(options, args).await,
+            Command::Index(args) => rag::index(options, args).await,
+            Command::Moderate(args) => moderation::run(options, args).await,
+            Command::Models(args) => catalog::run(options, args).await,
+            Command::Commit(args) => commit::run(options, args).await,
+            Command::Review(args) => review::run(options, args).await,
+            Command::Explain(args) => explain::run(options, args).await,
+            Command::Translate(args) => translate::run(options, args).await,
+            Command::Auth(_)
+            | Command::Config(_)
+            | Command::History(_)
+            | Command::Import(_)
+            | Command::Completions(_) => unreachable!("run before loading the API key"),
+        };
+    }
+
+    // `--interactive` and `--no-interactive` override the detection of terminals, which may
+    // misfire in editors and terminal multiplexers
+    let is_stdout = atty::is(atty::Stream::Stdout) || options.interactive;
+    let is_stdin =
+        atty::is(atty::Stream::Stdin) && !(options.no_interactive && options.prompt.is_empty());
+
+    let client = http_client(&options)?;
+    let mut session = Session::new(options, client, is_stdin, is_stdout);
+    if session.options.prompt.is_empty() {
+        session.stdin_prompt_prefix = alias_prompt;
+    }
+    session.check_options();
+    // Only in interactive mode, where it's worth a round trip, and not with a cassette, which
+    // would record or expect the listing too
+    if model_from_cli
+        && session.is_interactive()
+        && session.options.record.is_none()
+        && session.options.replay.is_none()
+    {
+        catalog::check_requested_model(&session.options).await?;
+    }
+    session.resume()?;
+    session.connect_mcp_servers().await;
+    if !session.options.watch.is_empty() {
+        session.run_watch().await?;
+    } else if !session.is_interactive() {
+        session.run_one_shot().await?;
+    } else {
+        session.run_interactive().await?;
+    }
+
+    Ok(())
+}
+
+struct Session {
+    /// Command-line options
+    options: Options,
+
+    /// Options as given on startup, restored by `\unset`
+    initial_options: Options,
+
+    /// Messages history
+    messages: Vec<Message>,
+
+    /// Whether input from stdin
+    is_stdin: bool,
+
+    /// Whether output to stdout
+    is_stdout: bool,
+
+    /// Spinner holder
+    spinner: Option<Spinner>,
+
+    /// HTTP client shared by requests in the session, so that connections are reused
+    client: Client,
+
+    /// Usage of the last response including its continuations, if reported by the API
+    last_usage: Option<ResponseUsage>,
+
+    /// Why the model stopped generating the last response, e.g. `length` if truncated
+    last_finish_reason: Option<String>,
+
+    /// Total usage of responses in the session
+    total_usage: ResponseUsage,
+
+    /// Saved conversation of the session, created on the first response
+    conversation: Option<Conversation>,
+
+    /// Prompt of the last failed request, to be sent again by `\retry`
+    failed_prompt: Option<String>,
+
+    /// Name of the current branch of conversation
+    branch: String,
+
+    /// Other branches created by `\fork`, keyed by name
+    branches: BTreeMap<String, Branch>,
+
+    /// Time to the first token of the current response
+    first_token: Option<Duration>,
+
+    /// Timing of responses in the session, for `\stats`
+    stats: Vec<ResponseStats>,
+
+    /// Files attached by `--file` or `\file`, to be sent with the next prompt
+    attachments: Vec<String>,
+
+    /// Connected MCP servers, whose tools are offered with `--tools`
+    mcp_servers: Vec<mcp::Server>,
+
+    /// Index of `--rag`, loaded on the first prompt
+    rag_index: Option<rag::Index>,
+
+    /// Index turned off by `\rag off`, to be turned on again by `\rag on`
+    rag_off: Option<String>,
+
+    /// `prompt` of the alias, put before the prompt read from stdin
+    stdin_prompt_prefix: Option<String>,
+}
+
+/// An inactive branch of conversation
+struct Branch {
+    messages: Vec<Message>,
+    conversation: Option<Conversation>,
+}
+
+impl Session {
+    pub fn new(options: Options, client: Client, is_stdin: bool, is_stdout: bool) -> Self {
+        Self {
+            client,
+            initial_options: options.clone(),
+            options,
+            is_stdin,
+            is_stdout,
+            messages: Vec::new(),
+            spinner: None,
+            last_usage: None,
+            last_finish_reason: None,
+            total_usage: ResponseUsage::default(),
+            conversation: None,
+            failed_prompt: None,
+            branch: DEFAULT_BRANCH.to_string(),
+            branches: BTreeMap::new(),
+            attachments: Vec::new(),
+            first_token: None,
+            stats: Vec::new(),
+            mcp_servers: Vec::new(),
+            rag_index: None,
+            rag_off: None,
+            stdin_prompt_prefix: None,
+        }
+    }
+
+    pub fn is_interactive(&self) -> bool {
+        // Enter interactive mode if prompt is empty and no redirection
+        self.options.prompt.is_empty()
+            && !self.options.messages_stdin
+            && self.is_stdout
+            && (self.is_stdin || self.options.interactive)
+            && !self.options.no_interactive
+    }
+
+    /// Read the input from stdin for `--interactive`, and make the terminal stdin again
+    fn read_seed(&mut self) -> Result<String> {
+        let seed = std::io::read_to_string(std::io::stdin())
+            .map_err(|e| anyhow!("Failed to read input from stdin: {e}"))?;
+        reopen_tty()?;
+        self.is_stdin = true;
+        Ok(seed)
+    }
+
+    /// Load the input from stdin as the first message, appended to the system prompt if it's
+    /// loaded as a system message
+    fn push_seed(&mut self, seed: String) {
+        if seed.trim().is_empty() {
+            warning("No input from stdin");
+            return;
+        }
+        let role = self.options.stdin_as.as_str();
+        eprintln!(
+            "{}",
+            style(format!(
+                "(Loaded {} lines from stdin, ~{} tokens, as the {role} message)",
+                seed.lines().count(),
+                token::estimate_tokens(&seed)
+            ))
+            .dim()
+        );
+        match self.messages.first_mut() {
+            Some(system) if role == "system" && system.role == "system" => {
+                system.content.push_str("\n\n");
+                system.content.push_str(&seed);
+            }
+            _ if role == "system" => self.messages.insert(
+                0,
+                Message {
+                    role: "system".to_string(),
+                    content: seed,
+                    ..Default::default()
+                },
+            ),
+            _ => self.messages.push(Message {
+                role: "user".to_string(),
+                content: seed,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Load the conversation of `--resume` to continue it
+    pub fn resume(&mut self) -> Result<()> {
+        let Some(id) = &self.options.resume else {
+            return Ok(());
+        };
+        let conversation = Conversation::load(id)?;
+        if self.is_interactive() {
+            eprintln!(
+                "{}",
+                style(format!(
+                    "(resumed {} \"{}\", {} messages)",
+                    conversation.id,
+                    history::one_line(conversation.title(), 60),
+                    conversation.messages.len()
+                ))
+                .dim()
+            );
+        }
+        self.messages = conversation.messages.clone();
+        self.conversation = Some(conversation);
+        Ok(())
+    }
+
+    /// Save messages of the session to history, unless `--save-history=false`
+    fn save_conversation(&mut self) {
+        if !self.options.save_history {
+            return;
+        }
+        let conversation = match self.conversation.take() {
+            Some(conversation) => Ok(conversation),
+            None => Conversation::new(self.options.model.clone()),
+        };
+        let result = conversation.and_then(|mut conversation| {
+            conversation.model = self.options.model.clone();
+            conversation.messages = self.messages.clone();
+            conversation.save()?;
+            self.conversation = Some(conversation);
+            Ok(())
+        });
+        if let Err(err) = result {
+            warning(format!("Failed to save conversation: {err}"));
+        }
+    }
+
+    /// Give the saved conversation a title with `title_model`, once it has an answer. Failures
+    /// are only logged, as the first message serves as the title too.
+    async fn generate_title(&mut self) {
+        let (Some(model), Some(conversation)) = (&self.options.title_model, &self.conversation)
+        else {
+            return;
+        };
+        if conversation.title.is_some() {
+            return;
+        }
+        let transcript: String = self
+            .messages
+            .iter()
+            .filter(|m| m.role != "system")
+            .take(2)
+            .map(|m| format!("{}: {}\n\n", m.role, history::one_line(&m.content, 1000)))
+            .collect();
+        let messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: TITLE_PROMPT.to_string(),
+                ..Default::default()
+            },
+            Message {
+                role: "user".to_string(),
+                content: transcript,
+                ..Default::default()
+            },
+        ];
+        let result = async {
+            let mut data = self.build_request_with(messages)?;
+            data.model = model.clone();
+            data.set_stream(false);
+            data.max_tokens = None;
+            data.max_completion_tokens = None;
+            let response = self.fetch(&data).await?;
+            let choice = response.choices.into_iter().next();
+            choice.ok_or_else(|| anyhow!("No title in response"))
+        }
+        .await;
+        match result {
+            Ok(choice) => {
+                let title = choice.message.content.trim().trim_matches('"').to_owned();
+                if let Some(conversation) = &mut self.conversation {
+                    conversation.title = Some(history::one_line(&title, 80));
+                }
+                self.save_conversation();
+            }
+            Err(err) => debug!("Failed to generate a title: {err}"),
+        }
+    }
+
+    /// Speak the response aloud if `--speak` is enabled
+    async fn speak(&self, message: &Message) {
+        if !self.options.speak || message.content.is_empty() {
+            return;
+        }
+        if let Err(err) = audio::speak_text(&self.client, &self.options, &message.content).await {
+            warning(format!("Failed to speak the response: {err}"));
+        }
+    }
+
+    /// The printer of streamed responses, word-wrapping and highlighting them on a terminal
+    fn printer(&self) -> Printer {
+        let text = !self.options.raw && self.output_format() == OutputFormat::Text;
+        Printer {
+            wrap: (text && self.options.wrap && wrap::width().is_some()).then(Wrap::new),
+            highlight: (text && self.options.highlight && console::colors_enabled())
+                .then(Highlight::new),
+        }
+    }
+
+    /// Output format in effect. Interactive mode always outputs text.
+    fn output_format(&self) -> OutputFormat {
+        if self.is_interactive() {
+            OutputFormat::Text
+        } else {
+            self.options.format
+        }
+    }
+
+    pub async fn run_one_shot(&mut self) -> Result<()> {
+        if self.options.messages_stdin {
+            self.messages = serde_json::from_reader(std::io::stdin())
+                .map_err(|e| anyhow!("Failed to parse messages from stdin: {e}"))?;
+            if self.messages.is_empty() {
+                return Err(error::usage("No messages from stdin"));
+            }
+        } else if self.options.map_reduce {
+            self.push_map_reduce().await?;
+        } else {
+            self.push_prompt().await?;
+        }
+
+        if self.options.dry_run {
+            return self.print_dry_run();
+        }
+
+        if !self.options.compare.is_empty() {
+            return self.compare().await;
+        }
+
+        if let Some(n) = self.options.best_of {
+            return self.best_of(n.into()).await;
+        }
+
+        if self.options.format == OutputFormat::Json {
+            self.complete_and_print_json().await?;
+            return self.check_complete();
+        }
+
+        if self.options.assistant_prefix.is_some() && self.output_format() == OutputFormat::Text {
+            let prefix = &self.messages.last().unwrap().content;
+            write!(std::io::stdout(), "{prefix}").map_err(error::output)?;
+        }
+        let mut response = self.complete_and_print().await?;
+        if self.options.assistant_prefix.is_some() {
+            let prefix = self.messages.pop().unwrap();
+            response.content = prefix.content + &response.content;
+        }
+        self.messages.push(response.clone());
+        self.save_conversation();
+        self.generate_title().await;
+        self.speak(&response).await;
+        self.check_complete()
+    }
+
+    /// Run one-shot mode again whenever a file of `--watch` changes
+    pub async fn run_watch(&mut self) -> Result<()> {
+        if self.options.prompt.is_empty() {
+            return Err(error::usage("Prompt is required for --watch"));
+        }
+        let paths = self.options.watch.clone();
+        for path in &paths {
+            if !self.options.file.contains(path) {
+                self.options.file.push(path.clone());
+            }
+        }
+        let modified = || -> Vec<_> {
+            paths
+                .iter()
+                .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+                .collect()
+        };
+
+        let mut last = modified();
+        loop {
+            if let Err(err) = self.run_one_shot().await {
+                println!("{}: {err}", style("ERROR").bold().red());
+            }
+            self.messages.clear();
+            self.conversation = None;
+
+            // Wait for the files to change, then for a moment more, as editors may save a file
+            // in several writes
+            let changed = loop {
+                tokio::time::sleep(WATCH_INTERVAL).await;
+                let current = modified();
+                if current != last {
+                    let changed: Vec<_> = paths
+                        .iter()
+                        .zip(current.iter().zip(&last))
+                        .filter(|(_, (a, b))| a != b)
+                        .map(|(path, _)| path.as_str())
+                        .collect();
+                    break changed.join(", ");
+                }
+            };
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            last = modified();
+            println!();
+            println!("{}", style(format!("──── {changed} changed ────")).dim());
+        }
+    }
+
+    /// Fail with a distinct exit code if the response of one-shot mode is incomplete. The
+    /// reason has already been warned about.
+    fn check_complete(&self) -> Result<()> {
+        match self.last_finish_reason.as_deref() {
+            Some("length") => Err(error::Exit(error::FailureKind::Truncated).into()),
+            Some("content_filter") => Err(error::Exit(error::FailureKind::ContentFiltered).into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Assemble messages of one-shot mode from system prompt, examples, attachments and the prompt
+    async fn push_prompt(&mut self) -> Result<()> {
+        // Stdin is only read without a prompt in arguments, or with `--file -`, so that a
+        // prompt doesn't wait for the end of a pipe left open by the caller
+        let prompt = if !self.options.prompt.is_empty() {
+            self.options.prompt.join(" ")
+        } else if !self.is_stdin {
+            let mut input = Vec::new();
+            std::io::stdin().read_to_end(&mut input)?;
+            if attach::image_data_url(&input).is_some() {
+                return Err(error::usage("Prompt is required for the image from stdin"));
+            }
+            let input = String::from_utf8(input)
+                .map_err(|_| anyhow!("Input from stdin is neither text nor image"))?;
+            match &self.stdin_prompt_prefix {
+                Some(prefix) => format!("{prefix}\n{input}"),
+                None => input,
+            }
+        } else {
+            return Err(error::usage("Prompt is required"));
+        };
+
+        // An image from stdin is attached to the prompt, e.g.
+        // `maim | heygpt --file - "what's on my screen?"`
+        let mut images = Vec::new();
+        if self.options.file.iter().any(|path| path == "-") {
+            self.options.file.retain(|path| path != "-");
+            let mut input = Vec::new();
+            std::io::stdin().read_to_end(&mut input)?;
+            match attach::image_data_url(&input) {
+                Some(image) => {
+                    if !self.capability().vision {
+                        return Err(error::usage(format!(
+                            "Model {} does not accept images",
+                            self.options.model
+                        )));
+                    }
+                    images.push(image);
+                }
+                None => {
+                    let text = String::from_utf8(input)
+                        .map_err(|_| anyhow!("Input from stdin is neither text nor image"))?;
+                    self.attach_block(attach::fence("stdin", &text))?;
+                }
+            }
+        }
+
+        // A resumed conversation has its system prompt and examples already
+        if self.messages.is_empty() {
+            if let Some(system_prompt) = &self.options.system {
+                self.messages.push(Message {
+                    role: "system".to_string(),
+                    content: system_prompt.clone(),
+                    ..Default::default()
+                });
+            }
+
+            if let Some(path) = &self.options.examples {
+                self.messages.extend(examples::load(path)?);
+            }
+        }
+
+        self.attach_from_options().await?;
+        self.attach_search(&prompt).await?;
+        self.attach_rag(&prompt).await?;
+        let prompt = self.with_attachments(prompt);
+        self.messages.push(Message {
+            role: "user".to_string(),
+            content: prompt,
+            images,
+            ..Default::default()
+        });
+
+        if let Some(prefix) = &self.options.assistant_prefix {
+            self.messages.push(Message {
+                role: "assistant".to_string(),
+                content: prefix.clone(),
+                ..Default::default()
+            });
+        }
+        Ok(())
+    }
+
+    pub async fn run_interactive(&mut self) -> Result<()> {
+        if !self.options.compare.is_empty()
+            || self.options.best_of.is_some()
+            || self.options.map_reduce
+        {
+            return Err(error::usage(
+                "--compare, --best-of and --map-reduce are only available in one-shot mode",
+            ));
+        }
+
+        // Input piped to stdin is read before the line editor takes the terminal
+        let seed = if self.is_stdin {
+            None
+        } else {
+            Some(self.read_seed()?)
+        };
+
+        // With bracketed paste, a pasted multi-line text is inserted as a whole instead of being
+        // submitted line by line
+        let config = Config::builder()
+            .bracketed_paste(true)
+            .edit_mode(self.options.edit_mode.into())
+            .build();
+        let mut rl = Editor::<repl_helper::ReplHelper, _>::with_config(config)?;
+        rl.set_helper(Some(ReplHelper::default()));
+
+        // Bind CTRL-J to newline
+        rl.bind_sequence(
+            KeyEvent(KeyCode::Char('j'), Modifiers::CTRL),
+            EventHandler::Simple(Cmd::Newline),
+        );
+        for (key, action) in &self.options.keybindings {
+            let key = repl_helper::parse_key(key)?;
+            let action = repl_helper::parse_action(action)?;
+            rl.bind_sequence(key, EventHandler::Simple(action));
+        }
+
+        // Persist input history in data directory
+        let history_file = paths::history_file()?;
+        paths::ensure_parent_dir(&history_file)?;
+        let _ = rl.load_history(&history_file);
+
+        // If `--system` or `--system="..."` is specified, unless resuming a conversation
+        if let Some(s) = self
+            .options
+            .system
+            .as_ref()
+            .filter(|_| self.messages.is_empty())
+        {
+            let system_prompt = if !s.is_empty() {
+                // If `--system="..."` is specified, use it as system prompt
+                s.clone()
+            } else {
+                // Otherwise, read system prompt interactively
+                if let Some(p) = self.read_prompt(&mut rl, "system").await? {
+                    p
+                } else {
+                    return Ok(());
+                }
+            };
+            self.messages.push(Message {
+                role: "system".to_string(),
+                content: system_prompt,
+                ..Default::default()
+            });
+        };
+
+        if let Some(path) = self
+            .options
+            .examples
+            .as_ref()
+            .filter(|_| self.messages.is_empty())
+        {
+            self.messages.extend(examples::load(path)?);
+        }
+
+        if let Some(seed) = seed {
+            self.push_seed(seed);
+        }
+        self.attach_from_options().await?;
+        self.run_rcfile().await?;
+
+        loop {
+            let prompt = if let Some(p) = self.read_prompt(&mut rl, "user").await? {
+                p
+            } else {
+                break;
+            };
+
+            let attached = match self.attach_search(&prompt).await {
+                Ok(()) => self.attach_rag(&prompt).await,
+                err => err,
+            };
+            if let Err(err) = attached {
+                println!("{}: {err}", style("ERROR").bold().red());
+                continue;
+            }
+            let prompt = self.with_attachments(prompt);
+            self.messages.push(Message {
+                role: "user".to_string(),
+                content: prompt,
+                ..Default::default()
+            });
+
+            if self.options.dry_run {
+                if let Err(err) = self.print_dry_run() {
+                    println!("{}: {err}", style("ERROR").bold().red());
+                }
+                self.messages.pop();
+                continue;
+            }
+
+            match self.respond().await {
+                Ok(()) => self.failed_prompt = None,
+                Err(err) => {
+                    let last_msg = self.messages.pop(); // remove the last message
+                    self.failed_prompt = last_msg.map(|m| m.content);
+                    println!("{}: {err}", style("ERROR").bold().red());
+                }
+            }
+        }
+
+        rl.append_history(&history_file)?;
+        Ok(())
+    }
+
+    async fn read_prompt<I>(
+        &mut self,
+        rl: &mut Editor<ReplHelper, I>,
+        role: &str,
+    ) -> Result<Option<String>>
+    where
+        I: rustyline::history::History,
+    {
         loop {
-            let readline = rl.readline(&format!("{} => ", role));
+            if let Some(helper) = rl.helper_mut() {
+                helper.branches = self.branches.keys().cloned().collect();
+                helper.failed = self.failed_prompt.is_some();
+            }
+            let readline = if self.branch == DEFAULT_BRANCH {
+                rl.readline(&format!("{} => ", role))
+            } else {
+                rl.readline(&format!("{}@{} => ", role, self.branch))
+            };
             match readline {
                 Ok(line) => {
                     if line.is_empty() {
                         continue; // ignore empty input
                     }
                     rl.add_history_entry(line.as_str())?;
+                    // Text pasted in Windows consoles may have CRLF line endings
+                    let line = repl_helper::join_continued_lines(&line.replace("\r\n", "\n"));
+
+                    if let Some(cmd) = line.strip_prefix('\\') {
+                        let (cmd, arg) = cmd.split_once(' ').unwrap_or((cmd, ""));
+                        if cmd == "retry" {
+                            match self.failed_prompt.take() {
+                                Some(prompt) => return Ok(Some(prompt)),
+                                None => {
+                                    println!("No failed request to retry");
+                                    continue;
+                                }
+                            }
+                        }
+                        if cmd == "paste" {
+                            match read_paste()? {
+                                Some(text) => return Ok(Some(text)),
+                                None => continue,
+                            }
+                        }
+                        self.run_command_or_alias(cmd, arg.trim()).await;
+                        continue;
+                    } else {
+                        return Ok(Some(line));
+                    }
+                }
+                Err(ReadlineError::Interrupted) => {
+                    println!("CTRL-C");
+                    return Ok(None);
+                }
+                Err(ReadlineError::Eof) => {
+                    println!("CTRL-D");
+                    return Ok(None);
+                }
+                Err(err) => {
+                    bail!("Readline error: {:?}", err);
+                }
+            };
+        }
+    }
+
+    /// Complete the message sequence in interactive mode and append the response to it
+    async fn respond(&mut self) -> Result<()> {
+        let response = self.complete_and_print().await?;
+        self.messages.push(response.clone());
+        self.save_conversation();
+        self.generate_title().await;
+        self.speak(&response).await;
+        Ok(())
+    }
+
+    /// Complete the message sequence and returns the next message.
+    /// Meanwhile, output the response to stdout.
+    async fn complete_and_print(&mut self) -> Result<Message> {
+        if self.options.moderate {
+            if let Some(message) = self.messages.last() {
+                let result = moderation::moderate(
+                    &self.client,
+                    &self.options,
+                    None,
+                    message.content.clone(),
+                )
+                .await?;
+                if result.flagged {
+                    bail!(
+                        "Prompt is flagged by moderation: {}",
+                        moderation::flagged_categories(&result)
+                    );
+                }
+            }
+        }
+
+        // `--auto-model` and `--fallback-models` may switch the model for this response only
+        let primary = self.options.model.clone();
+        self.route_model();
+        let routed = self.options.model.clone();
+        let result = self.print_completion_with_tools().await;
+        if result.is_ok() && self.options.model != routed && self.show_spinner() {
+            eprintln!(
+                "{}",
+                style(format!("(answered by `{}`)", self.options.model)).dim()
+            );
+        }
+        self.options.model = primary;
+        result
+    }
+
+    /// Like `print_completion`, but run the tools called by the model and send their results
+    /// back until it answers, for at most `--max-steps` requests. With `--agent`, steps are
+    /// marked and recorded in a transcript. On failure, messages of the tool calls are dropped.
+    async fn print_completion_with_tools(&mut self) -> Result<Message> {
+        let len = self.messages.len();
+        let mut transcript = self.start_transcript();
+        let max_steps = self.options.max_steps;
+        for step in 1..=max_steps {
+            if self.options.agent && !self.options.quiet {
+                eprintln!("{}", style(format!("── Step {step} ──")).dim());
+            }
+            let response = match self.print_completion().await {
+                Ok(response) if !response.tool_calls.is_empty() => response,
+                Ok(response) => {
+                    if let Some(transcript) = &mut transcript {
+                        transcript.final_answer(step, &response);
+                        if !self.options.quiet {
+                            let note = format!(
+                                "── Final answer in step {step}, transcript at {} ──",
+                                transcript.path().display()
+                            );
+                            eprintln!("{}", style(note).dim());
+                        }
+                    }
+                    return Ok(response);
+                }
+                Err(err) => {
+                    if let Some(transcript) = &mut transcript {
+                        transcript.failed(&err);
+                    }
+                    self.messages.truncate(len);
+                    return Err(err);
+                }
+            };
+            if let Some(transcript) = &mut transcript {
+                transcript.step(step, &response);
+            }
+            self.messages.push(response.clone());
+            for call in &response.tool_calls {
+                let content = tools::run(
+                    &self.client,
+                    call,
+                    &self.options.allow_tools,
+                    &self.mcp_servers,
+                    &self.options.web_search,
+                )
+                .await;
+                if let Some(transcript) = &mut transcript {
+                    transcript.tool_result(call, &content);
+                }
+                self.messages.push(Message {
+                    role: "tool".to_owned(),
+                    content,
+                    tool_call_id: Some(call.id.clone()),
+                    ..Default::default()
+                });
+            }
+            self.save_conversation();
+        }
+        let err = anyhow!("No final answer after {max_steps} steps, see `--max-steps`");
+        if let Some(transcript) = &mut transcript {
+            transcript.failed(&err);
+        }
+        self.messages.truncate(len);
+        Err(err)
+    }
+
+    /// Start the transcript of `--agent` with the last prompt as the task
+    fn start_transcript(&self) -> Option<agent::Transcript> {
+        if !self.options.agent {
+            return None;
+        }
+        let task = self
+            .messages
+            .iter()
+            .rev()
+            .find(|m| m.role == "user")
+            .map_or("", |m| m.content.as_str());
+        let transcript = agent::Transcript::create(
+            self.options.transcript.as_deref(),
+            &self.options.model,
+            task,
+        );
+        transcript
+            .map_err(|err| warning(format!("Failed to start the transcript: {err}")))
+            .ok()
+    }
+
+    /// The tools of `--tools` and MCP servers in requests
+    fn offered_tools(&self) -> Option<Vec<Tool>> {
+        if !self.options.tools {
+            return None;
+        }
+        let mcp_tools = self.mcp_servers.iter().flat_map(|s| s.tools()).cloned();
+        Some(
+            tools::definitions(&self.options.web_search)
+                .into_iter()
+                .chain(mcp_tools)
+                .collect(),
+        )
+    }
+
+    /// Connect to the MCP servers in the config file if `--tools` is given. Servers failing to
+    /// connect are skipped with a warning.
+    pub async fn connect_mcp_servers(&mut self) {
+        if !self.options.tools {
+            return;
+        }
+        for (name, config) in &self.options.mcp_servers {
+            match mcp::connect(name, config, &self.client).await {
+                Ok(server) => self.mcp_servers.push(server),
+                Err(err) => warning(format!("Failed to connect to MCP server `{name}`: {err}")),
+            }
+        }
+    }
+
+    /// Switch to the model chosen by `--auto-model` for the conversation. The caller restores
+    /// the model afterwards.
+    fn route_model(&mut self) {
+        if !self.options.auto_model {
+            return;
+        }
+        let (model, reason) = router::route(
+            &self.options.router,
+            &self.options.model,
+            &self.messages,
+            self.options.hard,
+        );
+        debug!("Routed to `{model}`: {reason}");
+        if self.show_spinner() {
+            eprintln!("{}", style(format!("(using `{model}`: {reason})")).dim());
+        }
+        self.options.model = model.to_owned();
+    }
+
+    /// Request the response, continuing it if truncated, and print it with metadata
+    async fn print_completion(&mut self) -> Result<Message> {
+        self.last_usage = None;
+        self.summarize_if_needed().await?;
+        let start = Instant::now();
+        // A trailing assistant message is a partial answer to be continued
+        let prefilled = self.messages.last().is_some_and(|m| m.role == "assistant");
+        let mut response = self.request_with_fallback(prefilled).await?;
+
+        let mut continues = 0;
+        while self.last_finish_reason.as_deref() == Some("length") {
+            if self.output_format() != OutputFormat::Text || continues >= self.options.auto_continue
+            {
+                break;
+            }
+            continues += 1;
+            debug!("response is truncated, continuing ({continues})");
+            let more = self.continue_response(&response).await?;
+            response.content.push_str(&more.content);
+        }
+        self.tee(&response.content);
+        let stats = ResponseStats {
+            first_token: self.first_token.take(),
+            total: start.elapsed(),
+            completion_tokens: self.last_usage.as_ref().map(|u| u.completion_count()),
+        };
+
+        // Raw output of one-shot mode is not followed by a newline, nor is nothing but tool calls
+        let tools_only = response.content.is_empty() && !response.tool_calls.is_empty();
+        if self.output_format() == OutputFormat::Text
+            && (self.is_interactive() || !self.options.raw && !tools_only)
+        {
+            writeln!(std::io::stdout()).map_err(error::output)?;
+        }
+        self.check_finish_reason();
+        if self.options.show_usage {
+            self.print_usage();
+        } else {
+            self.print_reasoning_tokens();
+        }
+        if self.options.stats && self.output_format() == OutputFormat::Text {
+            let footer = stats.footer(&self.options.model, self.last_usage.as_ref());
+            eprintln!("{}", style(footer).dim());
+        }
+        self.stats.push(stats);
+
+        if self.options.moderate_response {
+            let result =
+                moderation::moderate(&self.client, &self.options, None, response.content.clone());
+            match result.await {
+                Ok(result) if result.flagged => warning(format!(
+                    "Response is flagged by moderation: {}",
+                    moderation::flagged_categories(&result)
+                )),
+                Ok(_) => {}
+                Err(err) => warning(format!("Failed to moderate the response: {err}")),
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// Like `request_and_print`, but switch to the models of `--fallback-models` in turn while
+    /// the request fails in a way another model may not
+    async fn request_with_fallback(&mut self, continuation: bool) -> Result<Message> {
+        let mut fallbacks = self.options.fallback_models.clone().into_iter();
+        loop {
+            match self.request_resuming(continuation).await {
+                Err(err) => self.fall_back(err, &mut fallbacks)?,
+                result => return result,
+            }
+        }
+    }
+
+    /// Send the request and print the response. If the response stream breaks, ask the model to
+    /// continue from the partial response, up to `--stream-retries` times.
+    async fn request_resuming(&mut self, continuation: bool) -> Result<Message> {
+        let mut result = self.request_and_print(continuation).await;
+        let mut response: Option<Message> = None;
+        let mut retries = 0;
+        loop {
+            let err = match result {
+                Ok(more) => {
+                    return Ok(match response {
+                        Some(mut response) => {
+                            response.content.push_str(&more.content);
+                            response
+                        }
+                        None => more,
+                    });
+                }
+                Err(err) => err,
+            };
+            let interrupted = match err.downcast::<error::Interrupted>() {
+                Ok(interrupted) if retries < self.options.stream_retries => interrupted,
+                Ok(interrupted) => return Err(interrupted.into()),
+                Err(err) => return Err(err),
+            };
+            retries += 1;
+            let partial = match response.take() {
+                Some(mut response) => {
+                    response.content.push_str(&interrupted.partial.content);
+                    response
+                }
+                None => interrupted.partial.clone(),
+            };
+            // The partial response is left on the line
+            if self.is_stdout {
+                eprintln!();
+            }
+            warning(format!(
+                "{interrupted}, resuming ({retries}/{})",
+                self.options.stream_retries
+            ));
+            result = self.continue_response(&partial).await;
+            response = Some(partial);
+        }
+    }
+
+    /// Switch to the next fallback model if the error is worth retrying with another model,
+    /// otherwise return the error
+    fn fall_back(
+        &mut self,
+        err: anyhow::Error,
+        fallbacks: &mut impl Iterator<Item = String>,
+    ) -> Result<()> {
+        if !error::is_model_failure(&err) {
+            return Err(err);
+        }
+        let Some(model) = fallbacks.next() else {
+            return Err(err);
+        };
+        self.spinner = None;
+        warning(format!(
+            "Falling back to `{model}` as `{}` failed: {err}",
+            self.options.model
+        ));
+        self.options.model = model;
+        Ok(())
+    }
 
-                    if let Some(cmd) = line.strip_prefix('\\') {
-                        self.run_command(cmd);
-                        continue;
-                    } else {
-                        return Ok(Some(line));
+    /// Send the request built from messages history and print the response.
+    /// A continuation is printed right after the truncated response, without role or spinner.
+    async fn request_and_print(&mut self, continuation: bool) -> Result<Message> {
+        let span = info_span!("request", id = logging::request_id(), model = %self.options.model);
+        async move {
+            let mut data = self.build_chat_request()?;
+            self.check_budget(&data)?;
+            let req = self.http_request(&data)?;
+
+            // Show spinner if stdout is not redirected
+            if !continuation && self.show_spinner() {
+                self.spinner = Some(Spinner::new(&self.options.model));
+            }
+
+            if !data.stream {
+                return self.do_non_stream_request(req, continuation).await;
+            }
+            match self.do_stream_request(req, continuation).await {
+                Err(err) if err.is::<error::NoFirstToken>() => {
+                    self.spinner = None;
+                    warning(format!("{err}, retrying without streaming"));
+                    // The endpoint is likely to break streaming again
+                    self.options.stream = false;
+                    data.set_stream(false);
+                    let req = self.http_request(&data)?;
+                    if !continuation && self.show_spinner() {
+                        self.spinner = Some(Spinner::new(&self.options.model));
                     }
+                    self.do_non_stream_request(req, continuation).await
                 }
-                Err(ReadlineError::Interrupted) => {
-                    println!("CTRL-C");
-                    return Ok(None);
+                result => result,
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Ask the model to continue the truncated response, which is not in messages history
+    async fn continue_response(&mut self, truncated: &Message) -> Result<Message> {
+        let len = self.messages.len();
+        self.messages.push(truncated.clone());
+        self.messages.push(Message {
+            role: "user".to_string(),
+            content: CONTINUE_PROMPT.to_string(),
+            ..Default::default()
+        });
+        let result = self.request_and_print(true).await;
+        self.messages.truncate(len);
+        result
+    }
+
+    /// Whether to show a spinner while waiting for the response
+    fn show_spinner(&self) -> bool {
+        self.is_stdout && !self.options.quiet && self.output_format() == OutputFormat::Text
+    }
+
+    /// Pipe the response through the commands of `--post-process`. On failure, the response
+    /// is kept as is.
+    fn post_process(&self, content: String) -> String {
+        if self.options.post_process.is_empty() {
+            return content;
+        }
+        match postprocess::run(&self.options.post_process, &content) {
+            Ok(processed) => processed,
+            Err(err) => {
+                warning(err);
+                content
+            }
+        }
+    }
+
+    /// Append the response to the file of `--tee`
+    fn tee(&self, content: &str) {
+        let Some(path) = &self.options.tee else {
+            return;
+        };
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{content}"));
+        if let Err(err) = result {
+            warning(format!("Failed to append the response to `{path}`: {err}"));
+        }
+    }
+
+    /// Warn if the last response didn't finish normally
+    fn check_finish_reason(&self) {
+        match self.last_finish_reason.as_deref() {
+            Some("length") if self.is_interactive() => warning(
+                "Response is truncated as it reached the token limit. Enter `\\continue` to continue.",
+            ),
+            Some("length") => warning(
+                "Response is truncated as it reached the token limit. Use `--auto-continue` to continue automatically.",
+            ),
+            Some("content_filter") => warning("Response is cut off by content filter"),
+            _ => {}
+        }
+    }
+
+    fn record_usage(&mut self, usage: &ResponseUsage) {
+        self.total_usage.add(usage);
+        self.last_usage
+            .get_or_insert_with(ResponseUsage::default)
+            .add(usage);
+    }
+
+    /// Print reasoning tokens of the last response to stderr, which are not visible otherwise
+    fn print_reasoning_tokens(&self) {
+        let reasoning_tokens = self
+            .last_usage
+            .as_ref()
+            .and_then(|u| u.completion_tokens_details.as_ref())
+            .and_then(|d| d.reasoning_tokens)
+            .filter(|&tokens| tokens > 0);
+        if let Some(tokens) = reasoning_tokens {
+            if atty::is(atty::Stream::Stderr) {
+                eprintln!("{}", style(format!("({tokens} reasoning tokens)")).dim());
+            }
+        }
+    }
+
+    /// Print usage of the last response to stderr
+    fn print_usage(&self) {
+        let Some(usage) = &self.last_usage else {
+            eprintln!("{}", style("(usage is not reported by the API)").dim());
+            return;
+        };
+        let mut line = format!(
+            "({} prompt + {} completion = {} tokens",
+            usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+        );
+        if let Some(cost) = self.capability().estimate_cost(usage) {
+            line.push_str(&format!(", ${cost:.6}"));
+        }
+        if self.is_interactive() {
+            line.push_str(&format!(
+                "; {} tokens in total",
+                self.total_usage.total_tokens
+            ));
+        }
+        line.push(')');
+        eprintln!("{}", style(line).dim());
+    }
+
+    /// Build the HTTP request to chat completions API, rewritten by `pre_request_hook` if set
+    fn http_request(&self, data: &Request) -> Result<RequestBuilder> {
+        self.http_request_to(&self.options, data)
+    }
+
+    /// Like `http_request`, to the endpoint of other options, e.g. of a model in `--compare`
+    fn http_request_to(&self, options: &Options, data: &Request) -> Result<RequestBuilder> {
+        debug!("Request body: {:?}", data);
+        let req = api_request(&self.client, options, Method::POST, "/chat/completions")?;
+        match &self.options.pre_request_hook {
+            Some(command) => {
+                let data = hooks::pre_request(command, data)?;
+                debug!("Request body rewritten by hook: {data}");
+                Ok(req.json(&data))
+            }
+            None => Ok(req.json(data)),
+        }
+    }
+
+    /// Send the non-streaming request and get the response
+    async fn fetch(&self, data: &Request) -> Result<ResponseMessage> {
+        self.fetch_with(data, self.http_request(data)?).await
+    }
+
+    /// Like `fetch`, with the HTTP request already built
+    async fn fetch_with(&self, data: &Request, req: RequestBuilder) -> Result<ResponseMessage> {
+        self.check_budget(data)?;
+        let span = info_span!("request", id = logging::request_id(), model = %data.model);
+        let response = fetch_completion(&self.options, req)
+            .instrument(span)
+            .await?;
+        self.record_spending(&data.model, &response.usage);
+        self.post_response_hook(&response);
+        Ok(response)
+    }
+
+    /// Check the budgets before sending the request. In interactive mode, ask whether to send
+    /// it anyway if a budget would be exceeded.
+    fn check_budget(&self, data: &Request) -> Result<()> {
+        if !budget::is_enabled(&self.options) {
+            return Ok(());
+        }
+        let prompt = ResponseUsage {
+            prompt_tokens: token::estimate_message_tokens(&data.messages) as isize,
+            ..Default::default()
+        };
+        let estimated_cost =
+            Capability::lookup(&data.model, &self.options.models).estimate_cost(&prompt);
+        let (guard, failure) = budget::check(&self.options, &data.model, estimated_cost)?;
+        let Some(failure) = failure else {
+            return guard.record_request();
+        };
+        // Other processes are not kept waiting for the answer
+        drop(guard);
+        if !self.is_interactive() || !confirm(&format!("{failure}. Send anyway?"), false)? {
+            return Err(failure.into());
+        }
+        budget::record_request()
+    }
+
+    /// Add the estimated cost of the response to the spending tracked for budgets
+    fn record_spending(&self, model: &str, usage: &ResponseUsage) {
+        if !budget::is_enabled(&self.options) {
+            return;
+        }
+        let cost = Capability::lookup(model, &self.options.models).estimate_cost(usage);
+        if let Err(err) = budget::record_cost(cost.unwrap_or(0.0)) {
+            warning(err);
+        }
+    }
+
+    /// Give the response to `post_response_hook` if set
+    fn post_response_hook(&self, response: &ResponseMessage) {
+        if let Some(command) = &self.options.post_response_hook {
+            if let Err(err) = hooks::post_response(command, response) {
+                warning(err);
+            }
+        }
+    }
+
+    /// Send the messages to the models of `--compare` concurrently, and print their answers
+    /// with latency and cost. With `--format json`, print an array of JSON outputs.
+    async fn compare(&mut self) -> Result<()> {
+        let models = self.options.compare.clone();
+        let original = (
+            std::mem::take(&mut self.options.model),
+            self.options.provider,
+        );
+        let requests: Result<Vec<_>> = models
+            .iter()
+            .map(|model| {
+                let target = self.compare_target(model)?;
+                self.options.model = target.model.clone();
+                self.options.provider = target.provider;
+                let mut data = self.build_request()?;
+                data.set_stream(false);
+                let req = self.http_request_to(&target, &data);
+                Ok((self.capability(), data, req))
+            })
+            .collect();
+        (self.options.model, self.options.provider) = original;
+        let this = &*self;
+
+        let spinner = self
+            .show_spinner()
+            .then(|| Spinner::new(&models.join(", ")));
+        let results = futures::future::join_all(requests?.into_iter().map(
+            |(capability, data, req)| async move {
+                let start = Instant::now();
+                let response = match req {
+                    Ok(req) => this.fetch_with(&data, req).await,
+                    Err(err) => Err(err),
+                };
+                (capability, response, start.elapsed())
+            },
+        ))
+        .await;
+        drop(spinner);
+
+        let mut outputs = Vec::new();
+        let mut failures = 0;
+        for (model, (capability, response, duration)) in models.iter().zip(results) {
+            let output = response.and_then(|response| {
+                self.record_usage(&response.usage);
+                let choice = response
+                    .choices
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("No choice in response"))?;
+                Ok(JsonOutput {
+                    content: choice.message.content.trim_start().to_owned(),
+                    model: response.model,
+                    finish_reason: choice.finish_reason,
+                    usage: Some(response.usage),
+                    duration_ms: duration.as_millis(),
+                    first_token_ms: None,
+                    tokens_per_second: None,
+                })
+            });
+            failures += usize::from(output.is_err());
+            if self.options.format != OutputFormat::Text {
+                match output {
+                    Ok(output) => outputs.push(serde_json::to_value(output)?),
+                    Err(err) => outputs.push(serde_json::json!({
+                        "model": model,
+                        "error": err.to_string(),
+                    })),
                 }
-                Err(ReadlineError::Eof) => {
-                    println!("CTRL-D");
-                    return Ok(None);
+                continue;
+            }
+            let mut header = format!("{model} ({:.2}s", duration.as_secs_f64());
+            if let Some(cost) = output
+                .as_ref()
+                .ok()
+                .and_then(|o| capability.estimate_cost(o.usage.as_ref()?))
+            {
+                header.push_str(&format!(", ${cost:.6}"));
+            }
+            header.push(')');
+            let mut stdout = std::io::stdout();
+            writeln!(
+                stdout,
+                "{}",
+                style(format!("=== {header} ===")).bold().green()
+            )
+            .map_err(error::output)?;
+            match output {
+                Ok(output) => writeln!(stdout, "{}\n", output.content),
+                Err(err) => writeln!(stdout, "{}: {err}\n", style("ERROR").bold().red()),
+            }
+            .map_err(error::output)?;
+        }
+        if self.options.format != OutputFormat::Text {
+            writeln!(std::io::stdout(), "{}", serde_json::to_string(&outputs)?)
+                .map_err(error::output)?;
+        }
+        if failures > 0 {
+            bail!("{failures} of {} models failed", models.len());
+        }
+        Ok(())
+    }
+
+    /// Where to send a model of `--compare`. A model prefixed with another provider than the
+    /// configured one, e.g. `groq:llama-3.1-8b-instant`, is sent to the default base URL of
+    /// that provider with the API key in its environment variable, e.g. `GROQ_API_KEY`.
+    fn compare_target(&self, model: &str) -> Result<Options> {
+        let (provider, name) = Provider::split_model(model);
+        let mut target = self.options.clone();
+        target.model = name.to_owned();
+        let Some(provider) = provider.filter(|p| *p != self.options.provider) else {
+            return Ok(target);
+        };
+        // Replayed and mocked responses never leave the machine
+        if self.options.replay.is_some() || self.options.provider == Provider::Mock {
+            return Ok(target);
+        }
+        let prefix = &model[..model.len() - name.len() - 1];
+        if matches!(provider, Provider::Bedrock | Provider::Mock) {
+            return Err(error::usage(format!(
+                "`{model}` in --compare is only available with `--provider {prefix}`"
+            )));
+        }
+        target.provider = provider;
+        target.api_base_url = provider
+            .default_base_url()
+            .unwrap_or(DEFAULT_API_BASE_URL)
+            .to_owned();
+        target.api_key = match provider.api_key_env() {
+            Some(var) => std::env::var(var).unwrap_or_default(),
+            None => String::new(),
+        };
+        if target.api_key.is_empty() && provider.requires_api_key() {
+            return Err(error::usage(format!(
+                "API key of `{prefix}` is required for `{model}` in --compare. Please set it via {} environment variable.",
+                provider.api_key_env().unwrap_or_default()
+            )));
+        }
+        // Headers and organization of the configured endpoint are not for the other provider
+        target.headers.clear();
+        target.header.clear();
+        target.openai_org = None;
+        target.openai_project = None;
+        Ok(target)
+    }
+
+    /// Set up the request for the prompt over the input from stdin. If the input doesn't fit in
+    /// the context window, the prompt is run over each chunk of it concurrently, and the request
+    /// combines their results.
+    async fn push_map_reduce(&mut self) -> Result<()> {
+        if self.is_stdin {
+            return Err(error::usage("--map-reduce needs the input from stdin"));
+        }
+        if self.options.prompt.is_empty() {
+            return Err(error::usage("Prompt is required for --map-reduce"));
+        }
+        let task = self.options.prompt.join(" ");
+        let input = std::io::read_to_string(std::io::stdin())
+            .map_err(|e| anyhow!("Failed to read input from stdin: {e}"))?;
+
+        // Room is left for the system prompt, the task, the instructions and the answer
+        let context_window = self.capability().context_window;
+        let reserved = self.options.max_tokens.unwrap_or(context_window / 4)
+            + self
+                .options
+                .system
+                .as_deref()
+                .map_or(0, token::estimate_tokens)
+            + token::estimate_tokens(&task)
+            + token::estimate_tokens(MAP_PROMPT)
+            + 50;
+        let chunk_tokens = context_window.saturating_sub(reserved);
+        if chunk_tokens < context_window / 8 {
+            return Err(error::usage(format!(
+                "The prompt leaves too little room for input in the context window of {} ({context_window} tokens)",
+                self.options.model
+            )));
+        }
+        let chunks = token::split(&input, chunk_tokens);
+        let system = self.options.system.clone();
+        let messages = |content: String| {
+            let mut messages = Vec::new();
+            if let Some(system) = &system {
+                messages.push(Message {
+                    role: "system".to_string(),
+                    content: system.clone(),
+                    ..Default::default()
+                });
+            }
+            messages.push(Message {
+                role: "user".to_string(),
+                content,
+                ..Default::default()
+            });
+            messages
+        };
+        if chunks.len() <= 1 {
+            self.messages = messages(format!("{task}\n\n{input}"));
+            return Ok(());
+        }
+
+        let n = chunks.len();
+        let part = |i: usize, chunk: &str| {
+            format!(
+                "{MAP_PROMPT}\n\nTask: {task}\n\nPart {} of {n}:\n\n{chunk}",
+                i + 1
+            )
+        };
+        if self.options.dry_run {
+            eprintln!(
+                "{}",
+                style(format!("(The input is split into {n} parts, run concurrently and then combined. The request of part 1 is shown.)")).dim()
+            );
+            self.messages = messages(part(0, &chunks[0].1));
+            return Ok(());
+        }
+        eprintln!(
+            "{}",
+            style(format!("(The input is split into {n} parts)")).dim()
+        );
+
+        let spinner = self
+            .show_spinner()
+            .then(|| Spinner::new(&self.options.model));
+        let mut requests = Vec::new();
+        for (i, (_, chunk)) in chunks.iter().enumerate() {
+            let mut data = self.build_request_with(messages(part(i, chunk)))?;
+            data.set_stream(false);
+            requests.push(data);
+        }
+        let responses: Vec<_> = futures::stream::iter(&requests)
+            .map(|data| self.fetch(data))
+            .buffered(MAP_CONCURRENCY)
+            .collect()
+            .await;
+        drop(spinner);
+
+        let mut combined = format!("{REDUCE_PROMPT}\n\nTask: {task}");
+        for (i, response) in responses.into_iter().enumerate() {
+            let response = response.map_err(|e| anyhow!("Failed on part {} of {n}: {e}", i + 1))?;
+            self.record_usage(&response.usage);
+            let choice = response
+                .choices
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("No choice in the response of part {}", i + 1))?;
+            if choice.finish_reason.as_deref() == Some("length") {
+                warning(format!("The result of part {} of {n} is truncated", i + 1));
+            }
+            combined.push_str(&format!(
+                "\n\nResult of part {}:\n\n{}",
+                i + 1,
+                choice.message.content.trim()
+            ));
+        }
+        self.messages = messages(combined);
+        Ok(())
+    }
+
+    /// Generate `n` answers concurrently, ask the model to pick the best one and print it
+    async fn best_of(&mut self, n: usize) -> Result<()> {
+        let start = Instant::now();
+        let spinner = self
+            .show_spinner()
+            .then(|| Spinner::new(&self.options.model));
+
+        let mut data = self.build_request()?;
+        data.set_stream(false);
+        let requests = (0..n).map(|_| self.fetch(&data));
+        let mut candidates = Vec::new();
+        let mut last_err = None;
+        for response in futures::future::join_all(requests).await {
+            match response {
+                Ok(response) => {
+                    self.record_usage(&response.usage);
+                    if let Some(choice) = response.choices.into_iter().next() {
+                        candidates.push((choice, response.model, response.usage));
+                    }
                 }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        if candidates.is_empty() {
+            return Err(last_err.unwrap_or_else(|| anyhow!("No choice in responses")));
+        }
+        let contents: Vec<_> = candidates
+            .iter()
+            .map(|(choice, _, _)| choice.message.content.trim().to_owned())
+            .collect();
+        let best = if contents.len() > 1 {
+            self.judge(&contents).await?
+        } else {
+            0
+        };
+        drop(spinner);
+
+        if self.options.show_candidates {
+            for (i, content) in contents.iter().enumerate() {
+                let chosen = if i == best { " (chosen)" } else { "" };
+                eprintln!(
+                    "{}",
+                    style(format!("=== Candidate {}{chosen} ===", i + 1)).bold()
+                );
+                eprintln!("{content}\n");
+            }
+        }
+
+        let (choice, model, usage) = candidates.swap_remove(best);
+        let content = self.post_process(contents[best].clone());
+        self.tee(&content);
+        if self.options.format == OutputFormat::Text {
+            writeln!(std::io::stdout(), "{content}").map_err(error::output)?;
+        } else {
+            let output = JsonOutput {
+                content: content.clone(),
+                model,
+                finish_reason: choice.finish_reason,
+                usage: Some(usage),
+                duration_ms: start.elapsed().as_millis(),
+                first_token_ms: None,
+                tokens_per_second: None,
+            };
+            writeln!(std::io::stdout(), "{}", serde_json::to_string(&output)?)
+                .map_err(error::output)?;
+        }
+        self.messages.push(Message {
+            role: "assistant".to_string(),
+            content,
+            ..Default::default()
+        });
+        self.save_conversation();
+        Ok(())
+    }
+
+    /// Ask the model which of the answers to the conversation is the best. Returns its index.
+    async fn judge(&mut self, answers: &[String]) -> Result<usize> {
+        let mut transcript = String::new();
+        for message in &self.messages {
+            transcript.push_str(&format!("{}: {}\n\n", message.role, message.content));
+        }
+        for (i, answer) in answers.iter().enumerate() {
+            transcript.push_str(&format!("Answer {}:\n{answer}\n\n", i + 1));
+        }
+        let messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: JUDGE_PROMPT.to_string(),
+                ..Default::default()
+            },
+            Message {
+                role: "user".to_string(),
+                content: transcript,
+                ..Default::default()
+            },
+        ];
+        let mut data = self.build_request_with(messages)?;
+        data.set_stream(false);
+        let response = self.fetch(&data).await?;
+        self.record_usage(&response.usage);
+        let verdict = response
+            .choices
+            .into_iter()
+            .next()
+            .map(|c| c.message.content)
+            .unwrap_or_default();
+        debug!("verdict of best-of: {verdict}");
+        let number = verdict
+            .split(|c: char| !c.is_ascii_digit())
+            .find_map(|s| s.parse::<usize>().ok())
+            .filter(|n| (1..=answers.len()).contains(n));
+        match number {
+            Some(n) => Ok(n - 1),
+            None => {
+                warning(format!(
+                    "Failed to find the best answer in verdict `{verdict}`, using the first one"
+                ));
+                Ok(0)
+            }
+        }
+    }
+
+    /// Complete the message sequence and print the response with metadata as JSON
+    async fn complete_and_print_json(&mut self) -> Result<()> {
+        let primary = self.options.model.clone();
+        self.route_model();
+        let mut fallbacks = self.options.fallback_models.clone().into_iter();
+        let start = Instant::now();
+        let result = loop {
+            let mut data = self.build_request()?;
+            data.set_stream(false);
+            match self.fetch(&data).await {
                 Err(err) => {
-                    bail!("Readline error: {:?}", err);
+                    if let Err(err) = self.fall_back(err, &mut fallbacks) {
+                        break Err(err);
+                    }
                 }
+                result => break result,
+            }
+        };
+        self.options.model = primary;
+        let response = result?;
+        let duration = start.elapsed();
+        debug!("response message: {:?}", &response);
+
+        let choice = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No choice in response"))?;
+
+        // Trick: Sometimes the response starts with a newline. Strip it here.
+        let mut content = choice.message.content;
+        if content.starts_with('\n') && !self.options.raw {
+            content = content.trim_start().to_owned();
+        }
+        self.last_finish_reason = choice.finish_reason.clone();
+        let content = self.post_process(content);
+        self.tee(&content);
+
+        let stats = ResponseStats {
+            first_token: Some(duration),
+            total: duration,
+            completion_tokens: Some(response.usage.completion_count()),
+        };
+        let output = JsonOutput {
+            content,
+            model: response.model,
+            finish_reason: choice.finish_reason,
+            usage: Some(response.usage),
+            duration_ms: duration.as_millis(),
+            first_token_ms: Some(duration.as_millis()),
+            tokens_per_second: stats.tokens_per_second(),
+        };
+        writeln!(std::io::stdout(), "{}", serde_json::to_string(&output)?)
+            .map_err(error::output)?;
+        Ok(())
+    }
+
+    /// Print the HTTP request that would be sent for `--dry-run`
+    fn print_dry_run(&self) -> Result<()> {
+        let data = self.build_chat_request()?;
+        let req = self.http_request(&data)?.build()?;
+
+        let mut text = format!("{} {}\n", req.method(), req.url());
+        for (name, value) in req.headers() {
+            let value = if is_secret_header(name.as_str()) {
+                "<redacted>"
+            } else {
+                value.to_str().unwrap_or("<binary>")
             };
+            text.push_str(&format!("{}: {}\n", name, value));
         }
+        text.push('\n');
+        if self.options.pre_request_hook.is_some() {
+            // Print the body as rewritten by the hook
+            let body = req.body().and_then(|b| b.as_bytes()).unwrap_or_default();
+            let body: serde_json::Value = serde_json::from_slice(body)?;
+            text.push_str(&serde_json::to_string_pretty(&body)?);
+        } else {
+            text.push_str(&serde_json::to_string_pretty(&data)?);
+        }
+        writeln!(std::io::stdout(), "{text}").map_err(error::output)
     }
 
-    /// Complete the message sequence and returns the next message.
-    /// Meanwhile, output the response to stdout.
-    async fn complete_and_print(&mut self) -> Result<Message> {
-        // Build the request
-        let data = Request {
-            model: self.options.model.clone(),
-            stream: self.options.stream,
-            messages: self.messages.to_vec(),
-            temperature: self.options.temperature,
-            top_p: self.options.top_p,
-        };
-
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            AUTHORIZATION,
-            format!("Bearer {}", self.options.api_key).parse().unwrap(),
-        );
+    /// Time to wait for the first message of a response stream before retrying without
+    /// streaming. Reasoning models may think for minutes, so there is no default for them.
+    fn first_token_timeout(&self) -> Option<Duration> {
+        match self.options.first_token_timeout {
+            Some(0.0) => None,
+            Some(t) => Some(Duration::from_secs_f64(t)),
+            None if self.capability().reasoning => None,
+            None => Some(FIRST_TOKEN_TIMEOUT),
+        }
+    }
 
-        let client = Client::new();
-        let req = client
-            .post(format!("{}/chat/completions", &self.options.api_base_url))
-            .headers(headers)
-            .json(&data);
+    /// Capability of the model in use, with `--reasoning` applied
+    fn capability(&self) -> Capability {
+        let mut capability = Capability::lookup(&self.options.model, &self.options.models);
+        if let Some(reasoning) = self.options.reasoning {
+            capability.reasoning = reasoning;
+        }
+        capability
+    }
+
+    /// Warn about options that the model does not support, instead of letting the API reject them
+    pub fn check_options(&self) {
+        let capability = self.capability();
+        let model = &self.options.model;
+        if capability.reasoning {
+            let unsupported = [
+                ("temperature", self.options.temperature.is_some()),
+                ("top_p", self.options.top_p.is_some()),
+                (
+                    "frequency_penalty",
+                    self.options.frequency_penalty.is_some(),
+                ),
+                ("presence_penalty", self.options.presence_penalty.is_some()),
+                (
+                    "logit_bias",
+                    !self.options.logit_bias.is_empty() || self.options.logit_bias_file.is_some(),
+                ),
+            ];
+            for (param, _) in unsupported.iter().filter(|(_, set)| *set) {
+                warning(format!(
+                    "Reasoning model `{model}` does not support `{param}`, ignored"
+                ));
+            }
+        }
+        if self.options.stream && !capability.streaming {
+            warning(format!(
+                "Model `{model}` does not support streaming, falling back to non-streaming API"
+            ));
+        }
+        if let Some(max_tokens) = self.options.max_tokens {
+            if max_tokens > capability.context_window {
+                warning(format!(
+                    "`max_tokens` exceeds the context window of model `{model}` ({} tokens)",
+                    capability.context_window
+                ));
+            }
+        }
+        let provider = self.options.provider;
+        if (!self.options.logit_bias.is_empty() || self.options.logit_bias_file.is_some())
+            && !provider.supports_logit_bias()
+        {
+            warning("Mistral does not support `logit_bias`, ignored");
+        }
+        if self.options.safe_prompt && provider != Provider::Mistral {
+            warning("`safe_prompt` is only supported by Mistral, ignored");
+        }
+        if !self.options.allow_tools.is_empty() && !self.options.tools {
+            warning("`allow_tools` has no effect without `--tools`");
+        }
+    }
 
-        debug!("Request body: {:?}", &data);
+    /// Build the request body from options and messages history
+    fn build_request(&self) -> Result<Request> {
+        let capability = self.capability();
+        self.build_request_with(self.trimmed_messages(&capability))
+    }
 
-        // Show spinner if stdout is not redirected
-        if self.is_stdout {
-            self.spinner = Some(Spinner::new());
+    /// Build the request of the conversation with the tools of `--tools`, and the instruction
+    /// of `--agent` if given
+    fn build_chat_request(&self) -> Result<Request> {
+        let capability = self.capability();
+        let mut messages = self.trimmed_messages(&capability);
+        if self.options.agent {
+            messages.insert(
+                0,
+                Message {
+                    role: "system".to_owned(),
+                    content: AGENT_PROMPT.to_owned(),
+                    ..Default::default()
+                },
+            );
         }
+        let mut data = self.build_request_with(messages)?;
+        data.tools = self.offered_tools();
+        Ok(data)
+    }
 
-        if self.options.stream {
-            self.do_stream_request(req).await
+    /// Build the request body from options and the given messages
+    fn build_request_with(&self, messages: Vec<Message>) -> Result<Request> {
+        let messages = if self.options.redact {
+            self.redact(messages)
         } else {
-            self.do_non_stream_request(req).await
+            messages
+        };
+        let model = self.options.model.clone();
+        let capability = self.capability();
+        // Post-processing needs the whole response
+        let stream =
+            self.options.stream && capability.streaming && self.options.post_process.is_empty();
+        let stream_options = StreamOptions::include_usage(stream)
+            .filter(|_| self.options.provider.supports_stream_options());
+        if !capability.reasoning {
+            return Ok(Request {
+                stream,
+                stream_options,
+                messages,
+                temperature: self.options.temperature,
+                top_p: self.options.top_p,
+                frequency_penalty: self.options.frequency_penalty,
+                presence_penalty: self.options.presence_penalty,
+                logit_bias: self
+                    .logit_bias()?
+                    .filter(|_| self.options.provider.supports_logit_bias()),
+                max_tokens: self.options.max_tokens,
+                max_completion_tokens: None,
+                response_format: None,
+                safe_prompt: self.safe_prompt(),
+                usage: UsageOptions::include_cost(self.options.provider),
+                tools: None,
+                model,
+            });
         }
+
+        // Reasoning models take instructions from `developer` messages instead of `system`,
+        // and reject sampling parameters.
+        let messages = messages
+            .into_iter()
+            .map(|m| match m.role.as_str() {
+                "system" => Message {
+                    role: "developer".to_string(),
+                    ..m
+                },
+                _ => m,
+            })
+            .collect();
+        Ok(Request {
+            stream,
+            stream_options,
+            messages,
+            temperature: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            logit_bias: None,
+            max_tokens: None,
+            max_completion_tokens: self.options.max_tokens,
+            response_format: None,
+            safe_prompt: self.safe_prompt(),
+            usage: UsageOptions::include_cost(self.options.provider),
+            tools: None,
+            model,
+        })
+    }
+
+    /// Replace secrets in the messages with placeholders, warning about those in the messages
+    /// after the last response. Earlier ones were warned about when they were sent.
+    fn redact(&self, mut messages: Vec<Message>) -> Vec<Message> {
+        let new_from = messages
+            .iter()
+            .rposition(|m| m.role == "assistant")
+            .map_or(0, |i| i + 1);
+        let mut found = BTreeMap::new();
+        for (i, message) in messages.iter_mut().enumerate() {
+            let mut found_in_message = BTreeMap::new();
+            message.content = redact::redact(&message.content, &mut found_in_message);
+            if i >= new_from {
+                for (kind, count) in found_in_message {
+                    *found.entry(kind).or_default() += count;
+                }
+            }
+        }
+        if !found.is_empty() {
+            warning(format!(
+                "Redacted {} from the prompt",
+                redact::describe(&found)
+            ));
+        }
+        messages
+    }
+
+    /// Drop the earliest messages that do not fit in the context window.
+    /// System messages and the last message are always kept.
+    fn trimmed_messages(&self, capability: &Capability) -> Vec<Message> {
+        let (messages, dropped) = self.trim_messages(capability);
+        if dropped > 0 {
+            warning(format!(
+                "Dropped {dropped} earliest message(s) to fit the context window of model `{}` ({} tokens)",
+                self.options.model, capability.context_window
+            ));
+        }
+        messages
+    }
+
+    /// Messages that fit in the context window, and the number of messages dropped
+    fn trim_messages(&self, capability: &Capability) -> (Vec<Message>, usize) {
+        let budget = capability
+            .context_window
+            .saturating_sub(self.options.max_tokens.unwrap_or(0));
+        let mut messages = self.messages.to_vec();
+        let mut dropped = 0usize;
+        while token::estimate_message_tokens(&messages) > budget {
+            let last = messages.len().saturating_sub(1);
+            match messages[..last].iter().position(|m| m.role != "system") {
+                Some(i) => {
+                    messages.remove(i);
+                    dropped += 1;
+                    // Results of tool calls are invalid without the calls
+                    while i < messages.len() - 1 && messages[i].role == "tool" {
+                        messages.remove(i);
+                        dropped += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+        (messages, dropped)
+    }
+
+    /// If earlier messages would be dropped to fit the context window, replace them with a
+    /// summary instead. Done automatically with `--auto-summarize`, or asked in interactive mode.
+    async fn summarize_if_needed(&mut self) -> Result<()> {
+        let capability = self.capability();
+        let (_, dropped) = self.trim_messages(&capability);
+        if dropped == 0 {
+            return Ok(());
+        }
+        let summarize = self.options.auto_summarize
+            || (self.is_interactive()
+                && confirm(
+                    &format!("Conversation exceeds the context window. Summarize earlier messages instead of dropping {dropped} of them?"),
+                    true,
+                )?);
+        if !summarize {
+            return Ok(());
+        }
+
+        // Summarize the dropped messages, and the older half of the rest for some headroom.
+        // Previous summaries are folded into the new one.
+        let turns: Vec<_> = (0..self.messages.len())
+            .filter(|&i| {
+                let message = &self.messages[i];
+                message.role != "system" || message.content.starts_with(SUMMARY_PREFIX)
+            })
+            .collect();
+        let recent = turns.len().saturating_sub(dropped) / 2;
+        let count = (turns.len() - recent).min(turns.len() - 1);
+        if count == 0 {
+            return Ok(());
+        }
+        let summarized = &turns[..count];
+
+        let mut transcript = String::new();
+        for &i in summarized {
+            let message = &self.messages[i];
+            transcript.push_str(&format!("{}: {}\n\n", message.role, message.content));
+        }
+        // Older text beyond the context window is lost anyway
+        let budget = capability.context_window / 2;
+        while token::estimate_tokens(&transcript) > budget {
+            let cut = transcript.len() / 10;
+            let cut = (cut..transcript.len())
+                .find(|&i| transcript.is_char_boundary(i))
+                .unwrap_or(transcript.len());
+            transcript.drain(..cut);
+        }
+
+        let messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: SUMMARIZE_PROMPT.to_string(),
+                ..Default::default()
+            },
+            Message {
+                role: "user".to_string(),
+                content: transcript,
+                ..Default::default()
+            },
+        ];
+        let mut data = self.build_request_with(messages)?;
+        data.set_stream(false);
+        data.max_tokens = None;
+        data.max_completion_tokens = None;
+
+        let spinner = self
+            .show_spinner()
+            .then(|| Spinner::new(&self.options.model));
+        let response = self.fetch(&data).await;
+        drop(spinner);
+        let response = response?;
+        self.record_usage(&response.usage);
+        let summary = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No summary in response"))?
+            .message
+            .content;
+
+        let mut summary = Some(Message {
+            role: "system".to_string(),
+            content: format!("{SUMMARY_PREFIX}{}", summary.trim()),
+            ..Default::default()
+        });
+        let messages = std::mem::take(&mut self.messages).into_iter().enumerate();
+        for (i, message) in messages {
+            if summarized.contains(&i) {
+                self.messages.extend(summary.take());
+            } else {
+                self.messages.push(message);
+            }
+        }
+        eprintln!(
+            "{}",
+            style(format!("(Summarized {count} earlier message(s))")).dim()
+        );
+        Ok(())
+    }
+
+    /// `safe_prompt` of Mistral, which other providers reject
+    fn safe_prompt(&self) -> Option<bool> {
+        (self.options.safe_prompt && self.options.provider == Provider::Mistral).then_some(true)
+    }
+
+    /// Collect logit bias from `--logit-bias-file` and `--logit-bias`. Returns `None` if neither
+    /// is given.
+    fn logit_bias(&self) -> Result<Option<BTreeMap<String, i32>>> {
+        let mut logit_bias = BTreeMap::new();
+        if let Some(path) = &self.options.logit_bias_file {
+            let file = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read logit bias file `{path}`: {e}"))?;
+            let biases: BTreeMap<String, i32> = serde_json::from_str(&file)
+                .map_err(|e| anyhow!("Failed to parse logit bias file `{path}`: {e}"))?;
+            logit_bias.extend(biases);
+        }
+        for pair in &self.options.logit_bias {
+            let (token, bias) = pair.split_once('=').ok_or_else(|| {
+                error::usage(format!(
+                    "Invalid logit bias `{pair}`, expected TOKEN_ID=BIAS"
+                ))
+            })?;
+            let bias: i32 = bias.trim().parse().map_err(|_| {
+                error::usage(format!(
+                    "Invalid logit bias `{pair}`, bias must be an integer"
+                ))
+            })?;
+            logit_bias.insert(token.trim().to_owned(), bias);
+        }
+        for (token, bias) in &logit_bias {
+            if token.parse::<u32>().is_err() {
+                return Err(error::usage(format!(
+                    "Invalid logit bias token `{token}`, expected a token ID"
+                )));
+            }
+            if !(-100..=100).contains(bias) {
+                return Err(error::usage(format!(
+                    "Logit bias of token {token} must be between -100 and 100"
+                )));
+            }
+        }
+        Ok((!logit_bias.is_empty()).then_some(logit_bias))
     }
 
-    async fn do_stream_request(&mut self, req: RequestBuilder) -> Result<Message> {
+    async fn do_stream_request(
+        &mut self,
+        req: RequestBuilder,
+        continuation: bool,
+    ) -> Result<Message> {
         let mut full_message = Message::default();
+        let format = self.output_format();
+        let start = Instant::now();
+        let mut model = String::new();
+        let mut finish_reason = None;
+        let mut usage = None;
+        let mut first_token = None;
+        let mut malformed = 0;
 
+        let stall_timeout = self.options.stall_timeout.map(Duration::from_secs_f64);
+        let first_token_timeout = self.first_token_timeout();
+        if let Some(req) = req.try_clone().and_then(|r| r.build().ok()) {
+            verbose::request(&self.options, &req);
+        }
         let mut es = EventSource::new(req)?;
-        while let Some(event) = es.next().await {
+        let mut printer = self.printer();
+        'stream: loop {
+            // Until the first message, wait at most for the rest of the first-token timeout
+            let first_token_left = first_token_timeout
+                .filter(|_| full_message.role.is_empty())
+                .map(|t| t.saturating_sub(start.elapsed()));
+            let wait = match (stall_timeout, first_token_left) {
+                (Some(stall), Some(left)) => Some(stall.min(left)),
+                (stall, left) => stall.or(left),
+            };
+            let event = match wait {
+                Some(t) => match tokio::time::timeout(t, es.next()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        es.close();
+                        if let (Some(limit), Some(left)) = (first_token_timeout, first_token_left) {
+                            if left == t {
+                                return Err(error::NoFirstToken(limit).into());
+                            }
+                        }
+                        let err = anyhow!("No response from API for {} seconds", t.as_secs_f64());
+                        printer.finish()?;
+                        return Err(error::Interrupted::wrap(full_message, err));
+                    }
+                },
+                None => es.next().await,
+            };
+            let Some(event) = event else {
+                break;
+            };
             self.spinner = None;
+            if let Ok(Event::Message(message)) = &event {
+                verbose::event(&self.options, &message.event, &message.data);
+            }
             match event {
                 Ok(Event::Open) => {
                     debug!("response stream opened");
+                    verbose::stream_opened(&self.options);
                 }
-                Ok(Event::Message(message)) if message.data == "[DONE]" => {
+                Ok(Event::Message(message)) if message.data.trim() == "[DONE]" => {
                     debug!("response stream ended with [DONE]");
-                    println!();
                     break;
                 }
                 Ok(Event::Message(message)) => {
                     trace!("response stream message: {:?}", &message);
-                    let message: ResponseStreamMessage = serde_json::from_str(&message.data)?;
-                    let delta = message.choices.into_iter().next().unwrap().delta;
-                    if let Some(role) = delta.role {
-                        full_message.role.push_str(&role);
-
-                        if self.is_interactive() {
-                            print!("{} => ", style(role).bold().green());
-                            std::io::stdout().flush().unwrap();
+                    // Some servers put several JSON objects in one event, or send empty events
+                    // to keep the connection alive
+                    let chunks = serde_json::Deserializer::from_str(&message.data)
+                        .into_iter::<serde_json::Value>();
+                    for chunk in chunks {
+                        // Some servers fail in the middle of the stream, e.g. when overloaded
+                        if let Some(error) = chunk.as_ref().ok().and_then(|c| c.get("error")) {
+                            es.close();
+                            printer.finish()?;
+                            if !full_message.content.is_empty() {
+                                writeln!(std::io::stdout()).map_err(error::output)?;
+                            }
+                            return Err(error::Failure::from_stream_error(error).into());
                         }
-                    }
-                    if let Some(mut content) = delta.content {
-                        // Trick: Sometimes the response starts with a newline. Strip it here.
-                        if content.starts_with('\n') && full_message.content.is_empty() {
-                            content = content.trim_start().to_owned();
+                        let chunk = chunk.and_then(serde_json::from_value::<ResponseStreamMessage>);
+                        let mut chunk = match chunk {
+                            Ok(chunk) => chunk,
+                            Err(err) => {
+                                debug!("malformed response stream message: {err}");
+                                malformed += 1;
+                                break;
+                            }
+                        };
+                        if let Some(chunk_usage) = chunk.take_usage() {
+                            usage = Some(chunk_usage);
+                        }
+                        if !chunk.model.is_empty() {
+                            model = chunk.model;
+                        }
+                        // The usage chunk has no choices
+                        let Some(choice) = chunk.choices.into_iter().next() else {
+                            continue;
+                        };
+                        if choice.finish_reason.is_some() {
+                            finish_reason = choice.finish_reason;
+                        }
+                        let delta = choice.delta;
+                        // Some servers send the role in every chunk, others never
+                        if full_message.role.is_empty()
+                            && (delta.role.is_some() || delta.content.is_some())
+                        {
+                            let role = delta.role.unwrap_or_else(|| "assistant".to_owned());
+                            if self.is_interactive() && !continuation && !self.options.quiet {
+                                write!(std::io::stdout(), "{} => ", style(&role).bold().green())
+                                    .map_err(error::output)?;
+                                printer.advance(&format!("{role} => "));
+                            }
+                            full_message.role = role;
+                        }
+                        for call in delta.tool_calls.into_iter().flatten() {
+                            if full_message.tool_calls.len() <= call.index {
+                                full_message
+                                    .tool_calls
+                                    .resize_with(call.index + 1, || ToolCall {
+                                        id: String::new(),
+                                        r#type: "function".to_owned(),
+                                        function: FunctionCall::default(),
+                                    });
+                            }
+                            let tool_call = &mut full_message.tool_calls[call.index];
+                            if let Some(id) = call.id {
+                                tool_call.id = id;
+                            }
+                            if let Some(function) = call.function {
+                                tool_call.function.name += &function.name.unwrap_or_default();
+                                tool_call.function.arguments +=
+                                    &function.arguments.unwrap_or_default();
+                            }
+                        }
+                        if let Some(mut content) = delta.content {
+                            // Trick: Sometimes the response starts with a newline. Strip it here.
+                            if content.starts_with('\n')
+                                && full_message.content.is_empty()
+                                && !continuation
+                                && !self.options.raw
+                            {
+                                content = content.trim_start().to_owned();
+                            }
+                            if !content.is_empty() {
+                                first_token.get_or_insert_with(|| start.elapsed());
+                            }
+                            let until = self.options.until.as_deref().and_then(|marker| {
+                                until_end(&full_message.content, &content, marker)
+                            });
+                            if let Some(end) = until {
+                                content.truncate(end);
+                            }
+                            // Returning drops the stream, cancelling the request
+                            match format {
+                                OutputFormat::JsonlStream => {
+                                    print_json_delta(&content, choice.index)?
+                                }
+                                _ => printer.print(&content)?,
+                            }
+                            full_message.content.push_str(&content);
+                            if until.is_some() {
+                                debug!("response stream stopped at the marker of --until");
+                                es.close();
+                                finish_reason = Some("stop".to_owned());
+                                printer.finish()?;
+                                std::io::stdout().flush().map_err(error::output)?;
+                                break 'stream;
+                            }
                         }
-                        print!("{}", content);
-                        full_message.content.push_str(&content);
                     }
-                    std::io::stdout().flush().unwrap();
+                    std::io::stdout().flush().map_err(error::output)?;
+                }
+                Err(reqwest_eventsource::Error::StreamEnded) => {
+                    debug!("response stream ended without [DONE]");
+                    break;
                 }
                 Err(err) => {
                     es.close();
                     debug!("EventSource stream error: {}", err);
-                    return Err(err.into());
+                    if let reqwest_eventsource::Error::InvalidStatusCode(_, response)
+                    | reqwest_eventsource::Error::InvalidContentType(_, response) = &err
+                    {
+                        verbose::response_head(&self.options, response);
+                    }
+                    let err = error::from_event_source(err).await;
+                    printer.finish()?;
+                    return Err(error::Interrupted::wrap(full_message, err));
                 }
             }
         }
+        printer.finish()?;
 
+        if malformed > 0 {
+            warning(format!(
+                "Skipped {malformed} malformed message(s) in the response stream"
+            ));
+        }
+        if full_message.role.is_empty() {
+            full_message.role = "assistant".to_owned();
+        }
         debug!("response stream full message: {:?}", &full_message);
+        self.last_finish_reason = finish_reason.clone();
+        if let Some(usage) = &usage {
+            debug!("response stream usage: {:?}", usage);
+            self.record_usage(usage);
+            self.record_spending(&self.options.model, usage);
+        }
+
+        if !continuation {
+            self.first_token = first_token;
+        }
+
+        if self.options.post_response_hook.is_some() {
+            self.post_response_hook(&ResponseMessage {
+                choices: vec![ResponseChoice {
+                    message: full_message.clone(),
+                    index: 0,
+                    finish_reason: finish_reason.clone(),
+                }],
+                created: 0,
+                id: String::new(),
+                model: model.clone(),
+                object: "chat.completion".to_owned(),
+                usage: usage.clone().unwrap_or_default(),
+            });
+        }
+
+        if format == OutputFormat::JsonlStream {
+            let stats = ResponseStats {
+                first_token,
+                total: start.elapsed(),
+                completion_tokens: usage.as_ref().map(|u| u.completion_count()),
+            };
+            let summary = JsonOutput {
+                content: full_message.content.clone(),
+                model,
+                finish_reason,
+                usage,
+                duration_ms: stats.total.as_millis(),
+                first_token_ms: first_token.map(|t| t.as_millis()),
+                tokens_per_second: stats.tokens_per_second(),
+            };
+            writeln!(std::io::stdout(), "{}", serde_json::to_string(&summary)?)
+                .map_err(error::output)?;
+        }
 
         Ok(full_message)
     }
 
-    async fn do_non_stream_request(&mut self, req: RequestBuilder) -> Result<Message> {
-        let response = req.send().await?;
+    async fn do_non_stream_request(
+        &mut self,
+        req: RequestBuilder,
+        continuation: bool,
+    ) -> Result<Message> {
+        let start = Instant::now();
+        let response = fetch_completion(&self.options, req).await;
 
         self.spinner = None;
 
-        if response.status() != StatusCode::OK {
-            let r: WrappedApiError = response.json().await?;
-            return Err(anyhow!("{}: {}", r.error.r#type, r.error.message));
-        }
-
-        let response: ResponseMessage = response.json().await?;
+        let response = response?;
         debug!("response message: {:?}", &response);
+        self.post_response_hook(&response);
+        self.record_usage(&response.usage);
+        self.record_spending(&self.options.model, &response.usage);
+        if !continuation {
+            self.first_token = Some(start.elapsed());
+        }
+        if let Some(cost) = self.capability().estimate_cost(&response.usage) {
+            debug!("estimated cost: ${:.6}", cost);
+        }
 
-        let mut message = response.choices[0].message.clone();
+        let choice = response
+            .choices
+            .first()
+            .ok_or_else(|| anyhow!("No choice in response"))?;
+        let mut message = choice.message.clone();
+        let finish_reason = choice.finish_reason.clone();
+        self.last_finish_reason = finish_reason.clone();
 
         // Trick: Sometimes the response starts with a newline. Strip it here.
-        if message.content.starts_with('\n') {
+        if message.content.starts_with('\n') && !continuation && !self.options.raw {
             message.content = message.content.trim_start().to_owned();
         }
+        if let Some(marker) = &self.options.until {
+            if let Some(i) = message.content.find(marker.as_str()) {
+                message.content.truncate(i + marker.len());
+            }
+        }
+        message.content = self.post_process(message.content);
 
-        if self.is_interactive() {
-            print!("{} => ", style(&message.role).bold().green());
+        if self.output_format() == OutputFormat::JsonlStream {
+            print_json_delta(&message.content, 0)?;
+            let stats = ResponseStats {
+                first_token: Some(start.elapsed()),
+                total: start.elapsed(),
+                completion_tokens: Some(response.usage.completion_count()),
+            };
+            let summary = JsonOutput {
+                content: message.content.clone(),
+                model: response.model,
+                finish_reason,
+                usage: Some(response.usage),
+                duration_ms: stats.total.as_millis(),
+                first_token_ms: stats.first_token.map(|t| t.as_millis()),
+                tokens_per_second: stats.tokens_per_second(),
+            };
+            writeln!(std::io::stdout(), "{}", serde_json::to_string(&summary)?)
+                .map_err(error::output)?;
+            return Ok(message);
         }
-        println!("{}", &message.content);
-        std::io::stdout().flush()?;
+
+        if self.is_interactive() && !continuation {
+            let formatted = if self.options.quiet {
+                message.content.clone()
+            } else {
+                format_message(&message)
+            };
+            if self.options.pager && !pager::fits_screen(&formatted) {
+                match pager::page(&formatted) {
+                    Ok(()) => return Ok(message),
+                    Err(err) => warning(err.to_string()),
+                }
+            }
+            write!(std::io::stdout(), "{formatted}").map_err(error::output)?;
+        } else {
+            write!(std::io::stdout(), "{}", &message.content).map_err(error::output)?;
+        }
+        std::io::stdout().flush().map_err(error::output)?;
 
         Ok(message)
     }
 
-    fn run_command(&mut self, cmd: &str) {
+    async fn run_command(&mut self, cmd: &str, arg: &str) {
         match cmd {
             "?" | "help" => {
                 println!("{}", style("Available commands:").bold());
-                println!("  \\?, \\help     Show this help");
-                println!("  \\b, \\back     Retract and back to the last user message");
-                println!("  \\h, \\history  View current conversation history");
-                println!("Hint: Press Ctrl-J to input newline");
+                for (command, description) in COMMANDS_HELP {
+                    println!("  {command:<17}{description}");
+                }
+                println!("Hint: Press Ctrl-J, or end a line with `\\`, to input newline. Input continues in an unclosed ``` code block.");
             }
             "b" | "back" => match self.retract() {
-                Ok(()) => println!("Retracted last message"),
+                Ok(removed) => {
+                    diff::print_removed(&removed, self.messages.len());
+                    println!("Retracted last message");
+                    self.save_conversation();
+                }
                 Err(err) => println!("{}: {err}", style("ERROR").bold().red()),
             },
+            "last" => match self.messages.iter().rev().find(|m| m.role == "assistant") {
+                Some(message) => {
+                    if let Err(err) = pager::page(&format_message(message)) {
+                        println!("{}: {err}", style("ERROR").bold().red());
+                    }
+                }
+                None => println!("No response yet"),
+            },
+            "apply" => match self.messages.iter().rev().find(|m| m.role == "assistant") {
+                Some(message) => {
+                    if let Err(err) = patch::apply(&message.content) {
+                        println!("{}: {err}", style("ERROR").bold().red());
+                    }
+                }
+                None => println!("No response yet"),
+            },
+            "stats" => stats::print_summary(&self.stats, &self.total_usage),
             "h" | "history" => {
-                println!("{}", style("History:").bold());
-                for (i, message) in self.messages.iter().enumerate() {
-                    println!("[{}] {} => {}", i, message.role, message.content);
+                if let Err(err) = self.print_history(arg) {
+                    println!("{}: {err}", style("ERROR").bold().red());
+                }
+            }
+            "c" | "continue" => match self.messages.last() {
+                Some(message) if message.role == "assistant" => {
+                    let truncated = self.messages.pop().unwrap();
+                    print!("{} => ", style(&truncated.role).bold().green());
+                    let _ = std::io::stdout().flush();
+                    self.last_usage = None;
+                    match self.continue_response(&truncated).await {
+                        Ok(more) => {
+                            println!();
+                            self.check_finish_reason();
+                            let mut message = truncated;
+                            message.content.push_str(&more.content);
+                            self.messages.push(message);
+                            self.save_conversation();
+                        }
+                        Err(err) => {
+                            println!("{}: {err}", style("ERROR").bold().red());
+                            self.messages.push(truncated);
+                        }
+                    }
+                }
+                _ => println!("No response to continue"),
+            },
+            "clear" => {
+                if let Err(err) = console::Term::stdout().clear_screen() {
+                    println!("{}: {err}", style("ERROR").bold().red());
+                }
+            }
+            "reset" => {
+                let keep_system = arg != "all";
+                self.messages.retain(|m| keep_system && m.role == "system");
+                // Following messages go to a new conversation in history
+                self.conversation = None;
+                self.failed_prompt = None;
+                println!("Started a new conversation");
+            }
+            "assistant" if arg.is_empty() => println!("Usage: \\assistant TEXT"),
+            "assistant" => {
+                self.messages.push(Message {
+                    role: "assistant".to_string(),
+                    content: arg.to_owned(),
+                    ..Default::default()
+                });
+                self.save_conversation();
+                println!("Appended an assistant message");
+            }
+            "file" if arg.is_empty() => println!("Usage: \\file PATH"),
+            "file" => match self.attach(arg) {
+                Ok(tokens) => println!("Attached `{arg}` (~{tokens} tokens) to the next prompt"),
+                Err(err) => println!("{}: {err}", style("ERROR").bold().red()),
+            },
+            "url" if arg.is_empty() => println!("Usage: \\url URL"),
+            "url" => match self.attach_url(arg).await {
+                Ok(tokens) => println!("Attached `{arg}` (~{tokens} tokens) to the next prompt"),
+                Err(err) => println!("{}: {err}", style("ERROR").bold().red()),
+            },
+            "edit" => {
+                if let Err(err) = self.edit_message(arg).await {
+                    println!("{}: {err}", style("ERROR").bold().red());
+                }
+            }
+            "fork" => match self.fork(arg) {
+                Ok(()) => println!("Forked into branch `{arg}`"),
+                Err(err) => println!("{}: {err}", style("ERROR").bold().red()),
+            },
+            "switch" if arg.is_empty() => {
+                let mut names: Vec<_> = self.branches.keys().chain([&self.branch]).collect();
+                names.sort();
+                for name in names {
+                    let marker = if *name == self.branch { "*" } else { " " };
+                    println!("{marker} {name}");
+                }
+            }
+            "switch" => match self.switch(arg) {
+                Ok(()) => println!(
+                    "Switched to branch `{arg}` ({} messages)",
+                    self.messages.len()
+                ),
+                Err(err) => println!("{}: {err}", style("ERROR").bold().red()),
+            },
+            "rag" => {
+                if let Err(err) = self.set_rag(arg) {
+                    println!("{}: {err}", style("ERROR").bold().red());
+                }
+            }
+            "search" if arg.is_empty() => println!("Usage: \\search TERMS"),
+            "search" => {
+                let terms: Vec<_> = arg.split_whitespace().map(str::to_owned).collect();
+                if let Err(err) = history::print_search(&terms) {
+                    println!("{}: {err}", style("ERROR").bold().red());
+                }
+            }
+            "set" => match arg.split_once(char::is_whitespace) {
+                Some((name, value)) => match settings::set(&mut self.options, name, value.trim()) {
+                    Ok(()) => println!("Set {name} to {}", value.trim()),
+                    Err(err) => println!("{}: {err}", style("ERROR").bold().red()),
+                },
+                None => println!("Usage: \\set PARAMETER VALUE"),
+            },
+            "unset" if arg.is_empty() => println!("Usage: \\unset PARAMETER"),
+            "unset" => match settings::unset(&mut self.options, &self.initial_options, arg) {
+                Ok(()) => println!("Unset {arg}"),
+                Err(err) => println!("{}: {err}", style("ERROR").bold().red()),
+            },
+            "show" if arg == "settings" => settings::print(&self.options, &self.initial_options),
+            "show" => println!("Usage: \\show settings"),
+            "models" => {
+                if let Err(err) = catalog::print_models(&self.client, &self.options, false).await {
+                    println!("{}: {err}", style("ERROR").bold().red());
                 }
             }
             _ => {
@@ -426,8 +3841,261 @@ impl Session {
         }
     }
 
-    /// Retract the last message sent by user, as well as the subsequent messages
-    fn retract(&mut self) -> Result<()> {
+    /// Run the REPL commands of `--rcfile`, or of the init file if it exists
+    async fn run_rcfile(&mut self) -> Result<()> {
+        let path = match &self.options.rcfile {
+            Some(path) => paths::expand_home(path),
+            None => match paths::init_file()? {
+                path if path.exists() => path,
+                _ => return Ok(()),
+            },
+        };
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some(cmd) = line.strip_prefix('\\') else {
+                warning(format!(
+                    "{}:{}: `{line}` is not a command, ignored",
+                    path.display(),
+                    i + 1
+                ));
+                continue;
+            };
+            let (cmd, arg) = cmd.split_once(' ').unwrap_or((cmd, ""));
+            if !is_command(cmd) && !self.options.aliases.contains_key(cmd) {
+                warning(format!(
+                    "{}:{}: `\\{cmd}` is not a command, see `\\help`, ignored",
+                    path.display(),
+                    i + 1
+                ));
+                continue;
+            }
+            self.run_command_or_alias(cmd, arg.trim()).await;
+        }
+        Ok(())
+    }
+
+    /// Run a command, or the commands of an alias in `[aliases]` with `commands`. Aliases
+    /// don't expand in the commands of aliases.
+    async fn run_command_or_alias(&mut self, cmd: &str, arg: &str) {
+        let Some(alias) = self
+            .options
+            .aliases
+            .get(cmd)
+            .filter(|a| !a.commands.is_empty())
+        else {
+            return self.run_command(cmd, arg).await;
+        };
+        for line in alias.commands.clone() {
+            let Some(command) = line.strip_prefix('\\') else {
+                println!(
+                    "{}: `{line}` in alias `{cmd}` is not a command",
+                    style("ERROR").bold().red()
+                );
+                return;
+            };
+            let (command, arg) = command.split_once(' ').unwrap_or((command, ""));
+            self.run_command(command, arg.trim()).await;
+        }
+    }
+
+    /// Attach a file to the next prompt. Returns the estimated number of tokens of the file.
+    fn attach(&mut self, path: &str) -> Result<usize> {
+        let block = attach::read_file(path)?;
+        self.attach_block(block)
+    }
+
+    /// Attach a web page to the next prompt. Returns the estimated number of tokens of the page.
+    async fn attach_url(&mut self, url: &str) -> Result<usize> {
+        let block = attach::fetch_url(&self.client, url).await?;
+        self.attach_block(block)
+    }
+
+    /// Attach files and pages given by `--file`, `--dir` and `--url`
+    async fn attach_from_options(&mut self) -> Result<()> {
+        for path in self.options.file.clone() {
+            if path == "-" {
+                return Err(error::usage(
+                    "`--file -` for stdin is only available in one-shot mode",
+                ));
+            }
+            self.attach(&path)?;
+        }
+        for dir in self.options.dir.clone() {
+            let block =
+                attach::read_dir(&dir, &self.options.include, self.options.max_tokens_context)?;
+            self.attach_block(block)?;
+        }
+        for url in self.options.url.clone() {
+            self.attach_url(&url).await?;
+        }
+        Ok(())
+    }
+
+    /// Attach a code block to the next prompt, refusing it if the attachments wouldn't fit in
+    /// the context window
+    fn attach_block(&mut self, block: String) -> Result<usize> {
+        let tokens = token::estimate_tokens(&block);
+        let total = tokens
+            + self
+                .attachments
+                .iter()
+                .map(|a| token::estimate_tokens(a))
+                .sum::<usize>();
+        let context_window = self.capability().context_window;
+        if total > context_window {
+            bail!(
+                "Attached files take about {total} tokens, exceeding the context window of {} ({context_window} tokens)",
+                self.options.model
+            );
+        }
+        if total > context_window / 2 {
+            warning(format!(
+                "Attached files take about {total} tokens, more than half of the context window"
+            ));
+        }
+        self.attachments.push(block);
+        Ok(tokens)
+    }
+
+    /// Attach the top results of web search for the prompt if `--search` is given
+    async fn attach_search(&mut self, prompt: &str) -> Result<()> {
+        if !self.options.search {
+            return Ok(());
+        }
+        let results = search::context(&self.client, &self.options.web_search, prompt).await?;
+        let tokens = self.attach_block(results)?;
+        eprintln!(
+            "{}",
+            style(format!("(attached web search results, ~{tokens} tokens)")).dim()
+        );
+        Ok(())
+    }
+
+    /// Attach the chunks of the `--rag` index most similar to the prompt
+    async fn attach_rag(&mut self, prompt: &str) -> Result<()> {
+        let Some(name) = self.options.rag.clone() else {
+            return Ok(());
+        };
+        if self.rag_index.as_ref().map(|i| &i.name) != Some(&name) {
+            self.rag_index = Some(rag::load(&name)?);
+        }
+        let index = self.rag_index.as_ref().unwrap();
+        let context = index
+            .context(&self.client, &self.options, prompt, self.options.rag_top_k)
+            .await?;
+        let tokens = self.attach_block(context)?;
+        eprintln!(
+            "{}",
+            style(format!("(attached notes from `{name}`, ~{tokens} tokens)")).dim()
+        );
+        Ok(())
+    }
+
+    /// Turn `--rag` on or off, or switch to another index
+    fn set_rag(&mut self, arg: &str) -> Result<()> {
+        match arg {
+            "" => match &self.options.rag {
+                Some(name) => println!("Attaching notes from index `{name}` to each prompt"),
+                None => {
+                    let names = rag::list()?;
+                    if names.is_empty() {
+                        println!("RAG is off. Build an index with `heygpt index DIR`");
+                    } else {
+                        println!("RAG is off. Indexes: {}", names.join(", "));
+                    }
+                }
+            },
+            "off" => {
+                self.rag_off = self.options.rag.take().or(self.rag_off.take());
+                println!("RAG is off");
+            }
+            "on" => {
+                let name = self
+                    .options
+                    .rag
+                    .clone()
+                    .or(self.rag_off.take())
+                    .ok_or_else(|| anyhow!("No index to turn on, use `\\rag NAME`"))?;
+                return self.set_rag(&name);
+            }
+            name => {
+                let index = rag::load(name)?;
+                println!(
+                    "Attaching notes from index `{name}` ({} chunks) to each prompt",
+                    index.chunks.len()
+                );
+                self.rag_index = Some(index);
+                self.options.rag = Some(name.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// Put pending attachments before the prompt
+    fn with_attachments(&mut self, prompt: String) -> String {
+        if self.attachments.is_empty() {
+            return prompt;
+        }
+        let mut content = std::mem::take(&mut self.attachments).join("\n\n");
+        content.push_str("\n\n");
+        content.push_str(&prompt);
+        content
+    }
+
+    /// Print a one-line preview of each message with its token count, paging if it's long,
+    /// or a message completely with `full INDEX`
+    fn print_history(&self, arg: &str) -> Result<()> {
+        if let Some(index) = arg.strip_prefix("full") {
+            let index: usize = index
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Usage: \\history full INDEX"))?;
+            let message = self
+                .messages
+                .get(index)
+                .ok_or_else(|| anyhow!("No message at index {index}"))?;
+            println!("[{index}] {}", format_message(message));
+            return Ok(());
+        }
+        if !arg.is_empty() {
+            bail!("Usage: \\history [full INDEX]");
+        }
+
+        let (_, cols) = console::Term::stdout().size();
+        let mut text = format!("{}\n", style("History:").bold());
+        for (i, message) in self.messages.iter().enumerate() {
+            let tokens = token::estimate_message_tokens(std::slice::from_ref(message));
+            let count = format!("(~{tokens} tokens)");
+            let header_width = format!("[{i}] {} {count} => ", message.role).len();
+            let mut preview = history::one_line(
+                &message.content,
+                usize::from(cols).saturating_sub(header_width + 3).max(20),
+            );
+            if !message.images.is_empty() {
+                preview.push_str(&format!(" [{} image(s)]", message.images.len()));
+            }
+            text.push_str(&format!(
+                "[{i}] {} {} => {preview}\n",
+                message.role,
+                style(count).dim()
+            ));
+        }
+        if self.options.pager && !pager::fits_screen(&text) {
+            pager::page(&text)
+        } else {
+            print!("{text}");
+            Ok(())
+        }
+    }
+
+    /// Retract the last message sent by user, as well as the subsequent messages.
+    /// Returns the retracted messages.
+    fn retract(&mut self) -> Result<Vec<Message>> {
         let mut count = 0usize;
         for message in self.messages.iter().rev() {
             count += 1;
@@ -438,8 +4106,514 @@ impl Session {
         if count == 0 {
             bail!("No message to retract");
         } else {
-            self.messages.truncate(self.messages.len() - count);
-            Ok(())
+            Ok(self.messages.split_off(self.messages.len() - count))
+        }
+    }
+
+    /// Edit the message at the index of `\history` in editor, drop the messages after it,
+    /// and get a new response if it's a user message
+    async fn edit_message(&mut self, index: &str) -> Result<()> {
+        let index: usize = index
+            .parse()
+            .map_err(|_| anyhow!("Usage: \\edit INDEX, see `\\history` for indexes"))?;
+        let Some(message) = self.messages.get(index) else {
+            bail!("No message at index {index}");
+        };
+
+        let content = edit_in_editor(&message.content)?;
+        if content == message.content.trim_end_matches('\n') {
+            println!("Message is not changed");
+            return Ok(());
+        }
+        diff::print_changed(index, message, &content);
+        let dropped = self.messages.len() - index - 1;
+        diff::print_removed(&self.messages[index + 1..], index + 1);
+        if dropped > 0 && !confirm(&format!("Drop {dropped} message(s) after it?"), false)? {
+            return Ok(());
+        }
+        self.messages.truncate(index + 1);
+        self.messages[index].content = content;
+        self.save_conversation();
+
+        if self.messages[index].role == "user" && confirm("Get a new response?", true)? {
+            if let Err(err) = self.respond().await {
+                println!("{}: {err}", style("ERROR").bold().red());
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the current messages into a new branch and switch to it.
+    /// The new branch is saved as a separate conversation.
+    fn fork(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            bail!("Branch name must be a non-empty word");
+        }
+        if name == self.branch || self.branches.contains_key(name) {
+            bail!("Branch `{name}` already exists");
+        }
+        let branch = Branch {
+            messages: self.messages.clone(),
+            conversation: None,
+        };
+        self.branches.insert(name.to_owned(), branch);
+        self.switch(name)
+    }
+
+    /// Switch to another branch, keeping the current one
+    fn switch(&mut self, name: &str) -> Result<()> {
+        let target = self
+            .branches
+            .remove(name)
+            .ok_or_else(|| anyhow!("No branch `{name}`"))?;
+        let current = Branch {
+            messages: std::mem::replace(&mut self.messages, target.messages),
+            conversation: std::mem::replace(&mut self.conversation, target.conversation),
+        };
+        let current_name = std::mem::replace(&mut self.branch, name.to_owned());
+        self.branches.insert(current_name, current);
+        Ok(())
+    }
+}
+
+/// API base URL unless `--api-base-url` is given or the provider has its own default
+const DEFAULT_API_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// How often files of `--watch` are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Model used unless `--model` is given or the provider has its own default
+const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+
+/// Commands of interactive mode and their descriptions, shown by `\help`
+const COMMANDS_HELP: &[(&str, &str)] = &[
+    ("\\?, \\help", "Show this help"),
+    ("\\b, \\back", "Retract and back to the last user message"),
+    (
+        "\\h, \\history",
+        "View current conversation history, or a message with `full INDEX`",
+    ),
+    ("\\last", "Show the last response in `$PAGER`"),
+    (
+        "\\apply",
+        "Apply diffs and files in the last response to the working tree",
+    ),
+    (
+        "\\stats",
+        "Show time to first token, latency and throughput of responses",
+    ),
+    (
+        "\\c, \\continue",
+        "Continue the last response if it was truncated",
+    ),
+    (
+        "\\retry",
+        "Send the prompt of the last failed request again",
+    ),
+    ("\\clear", "Clear the screen"),
+    (
+        "\\reset [all]",
+        "Start a new conversation, keeping system prompt unless `all`",
+    ),
+    (
+        "\\assistant TEXT",
+        "Append an assistant message, e.g. as an example answer",
+    ),
+    (
+        "\\edit INDEX",
+        "Edit a message in `$EDITOR` and drop the messages after it",
+    ),
+    ("\\paste", "Read a multi-line prompt until a line of `EOF`"),
+    ("\\file PATH", "Attach a file to the next prompt"),
+    (
+        "\\url URL",
+        "Attach the text of a web page to the next prompt",
+    ),
+    ("\\models", "List models available on the endpoint"),
+    (
+        "\\set NAME VALUE",
+        "Set a parameter like `temperature` for subsequent requests",
+    ),
+    (
+        "\\unset NAME",
+        "Go back to the parameter from command line or config",
+    ),
+    ("\\show settings", "Show the effective request parameters"),
+    ("\\search TERMS", "Search past conversations"),
+    (
+        "\\rag on|off|NAME",
+        "Attach notes relevant to each prompt from an index",
+    ),
+    (
+        "\\fork NAME",
+        "Copy the conversation into a new branch and switch to it",
+    ),
+    (
+        "\\switch NAME",
+        "Switch to another branch, or list branches without NAME",
+    ),
+];
+
+/// Whether the name is of a command in `COMMANDS_HELP`, e.g. `set` or its short form like `h`
+fn is_command(name: &str) -> bool {
+    COMMANDS_HELP
+        .iter()
+        .flat_map(|(usage, _)| usage.split(", "))
+        .any(|usage| usage.split(' ').next() == Some(&format!("\\{name}")))
+}
+
+/// Edit the text in a temporary file with the user's editor
+fn edit_in_editor(text: &str) -> Result<String> {
+    // Created exclusively with a random name, and removed on drop
+    let mut file = tempfile::Builder::new()
+        .prefix("heygpt-edit-")
+        .suffix(".md")
+        .tempfile()?;
+    file.write_all(text.as_bytes())?;
+    // Closed for editors that replace the file rather than write into it
+    let path = file.into_temp_path();
+    let editor = config::editor();
+    let status = std::process::Command::new(&editor).arg(&path).status();
+    let content = std::fs::read_to_string(&path);
+    let status = status.map_err(|e| anyhow!("Failed to run {editor}: {e}"))?;
+    if !status.success() {
+        bail!("{editor} exited with {status}");
+    }
+    // Editors usually append a newline at the end of file
+    Ok(content?.trim_end_matches('\n').to_owned())
+}
+
+/// Read lines from terminal until a line of `EOF`, for terminals without bracketed paste
+fn read_paste() -> Result<Option<String>> {
+    println!(
+        "{}",
+        style("Paste text, then enter EOF on its own line:").dim()
+    );
+    let mut text = String::new();
+    for line in std::io::stdin().lines() {
+        let line = line?;
+        if line.trim_end() == "EOF" {
+            break;
+        }
+        text.push_str(&line);
+        text.push('\n');
+    }
+    let text = text.trim_end().to_owned();
+    Ok((!text.is_empty()).then_some(text))
+}
+
+/// Turn on processing of ANSI escape codes in Windows consoles, where it's off by default, so
+/// that colors and the spinner aren't garbled. Checking color support turns it on where it can
+/// be, and colors are off elsewhere.
+fn enable_ansi() {
+    console::colors_enabled();
+    console::colors_enabled_stderr();
+}
+
+/// Make the terminal stdin again after the piped input is read, for interactive mode
+#[cfg(unix)]
+fn reopen_tty() -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let tty = std::fs::File::open("/dev/tty")
+        .map_err(|e| anyhow!("No terminal for interactive mode: {e}"))?;
+    // SAFETY: both are valid file descriptors, and stdin is replaced as a whole
+    if unsafe { libc::dup2(tty.as_raw_fd(), libc::STDIN_FILENO) } < 0 {
+        bail!(
+            "Failed to reopen the terminal: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reopen_tty() -> Result<()> {
+    bail!("Interactive mode with input from stdin is only supported on Unix")
+}
+
+/// Ask the user a yes/no question on terminal
+fn confirm(question: &str, default: bool) -> Result<bool> {
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    print!("{question} {hint} ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(match answer.trim().to_lowercase().as_str() {
+        "" => default,
+        answer => answer == "y" || answer == "yes",
+    })
+}
+
+/// System prompt of the summarization request for `--auto-summarize`
+const SUMMARIZE_PROMPT: &str = "Summarize the following conversation between a user and an assistant concisely. Keep the facts, decisions, code and open questions needed to continue the conversation.";
+
+/// Instruction for generating titles of conversations
+const TITLE_PROMPT: &str = "Write a short title of at most six words for the following conversation between a user and an assistant. Reply with only the title, without quotes.";
+
+/// Prefix of the system message replacing summarized messages
+const SUMMARY_PREFIX: &str = "Summary of earlier conversation: ";
+
+/// Name of the branch a session starts with
+const DEFAULT_BRANCH: &str = "main";
+
+/// System prompt of the judging request of `--best-of`
+const JUDGE_PROMPT: &str = "You are given a conversation between a user and an assistant, followed by several candidate answers to the last message. Judge them on correctness, helpfulness and clarity. Reply with only the number of the best answer.";
+
+/// Instruction for each chunk of `--map-reduce`
+const MAP_PROMPT: &str = "The input is too long to process at once, so it is split into parts. Do the task on the part below only, as its result will be combined with those of the other parts.";
+
+/// Instruction of the request combining the results of `--map-reduce`
+const REDUCE_PROMPT: &str = "The input was too long to process at once, so the task was done on each of its parts separately. Combine the results of the parts below into one answer to the task, as if the whole input was processed at once.";
+
+/// Instruction of `--agent`, before the system prompt
+const AGENT_PROMPT: &str = "You are an agent working on the task of the user with tools in their environment. Work in steps: call tools to gather information or make changes, observe the results, and continue until the task is done. Verify your work when possible. When finished, reply with the final answer without calling tools.";
+
+/// Default time to wait for the first message of a response stream, see `--first-token-timeout`
+const FIRST_TOKEN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Chunks of `--map-reduce` processed at the same time
+const MAP_CONCURRENCY: usize = 4;
+
+/// Follow-up prompt asking the model to continue a truncated response
+const CONTINUE_PROMPT: &str =
+    "Continue exactly where you left off, without repeating anything or adding any preamble.";
+
+/// Build an authenticated request to an API endpoint, e.g. `/chat/completions`
+fn api_request(
+    client: &Client,
+    options: &Options,
+    method: Method,
+    path: &str,
+) -> Result<RequestBuilder> {
+    let mut headers = HeaderMap::new();
+    if !options.api_key.is_empty() {
+        // Keys pasted with a stray newline or from a keyring may not be valid in a header
+        let value =
+            HeaderValue::from_str(&format!("Bearer {}", options.api_key)).map_err(|_| {
+                anyhow!("Invalid API key: it contains characters not allowed in HTTP headers")
+            })?;
+        headers.insert(AUTHORIZATION, value);
+    }
+
+    headers.extend(custom_headers(options)?);
+
+    Ok(client
+        .request(method, format!("{}{}", &options.api_base_url, path))
+        .headers(headers))
+}
+
+/// Headers from `--openai-org`, `--openai-project`, `[headers]` in config file and `--header`
+/// options, the latter taking precedence
+fn custom_headers(options: &Options) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in options.provider.headers() {
+        headers.insert(*name, HeaderValue::from_static(value));
+    }
+    let openai = [
+        ("OpenAI-Organization", &options.openai_org),
+        ("OpenAI-Project", &options.openai_project),
+    ];
+    for (name, value) in openai {
+        if let Some(value) = value {
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| anyhow!("Invalid value of header `{name}`"))?;
+            headers.insert(name, value);
+        }
+    }
+    let from_cli = options.header.iter().map(|header| {
+        header.split_once(':').ok_or_else(|| {
+            error::usage(format!("Invalid header `{header}`, expected `NAME: VALUE`"))
+        })
+    });
+    let from_config = options
+        .headers
+        .iter()
+        .map(|(k, v)| Ok((k.as_str(), v.as_str())));
+    for header in from_config.chain(from_cli) {
+        let (name, value) = header?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| error::usage(format!("Invalid header name `{}`", name.trim())))?;
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|_| error::usage(format!("Invalid value of header `{name}`")))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// HTTP client with timeouts from options. Build it once and share it to reuse connections.
+fn http_client(options: &Options) -> Result<Client> {
+    let mut builder = Client::builder();
+    if let Some(timeout) = options.timeout {
+        builder = builder.timeout(Duration::from_secs_f64(timeout));
+    }
+    if let Some(timeout) = options.connect_timeout {
+        builder = builder.connect_timeout(Duration::from_secs_f64(timeout));
+    }
+    builder
+        .build()
+        .map_err(|e| anyhow!("Failed to build HTTP client: {e}"))
+}
+
+/// Check the status of an API response, turning error responses into errors
+async fn check_response(response: reqwest::Response) -> Result<reqwest::Response> {
+    if !response.status().is_success() {
+        return Err(error::Failure::from_response(response).await.into());
+    }
+    Ok(response)
+}
+
+/// A message with colored role, as printed in interactive mode
+fn format_message(message: &Message) -> String {
+    format!(
+        "{} => {}",
+        style(&message.role).bold().green(),
+        message.content
+    )
+}
+
+/// Send a non-streaming request to chat completions API and parse the response
+async fn fetch_completion(options: &Options, req: RequestBuilder) -> Result<ResponseMessage> {
+    let response = verbose::send(options, req)
+        .await
+        .map_err(error::transport)?;
+    let response = check_response(response).await?;
+    let body = response.text().await.map_err(error::transport)?;
+    verbose::body(options, &body);
+    serde_json::from_str(&body).map_err(|e| {
+        let body: String = body.chars().take(200).collect();
+        anyhow!("Malformed response from the API: {e}, in `{body}`")
+    })
+}
+
+/// Prints the deltas of a streamed response, word-wrapped and highlighted if enabled
+struct Printer {
+    wrap: Option<Wrap>,
+    highlight: Option<Highlight>,
+}
+
+impl Printer {
+    /// Account for text printed on the row before the response
+    fn advance(&mut self, printed: &str) {
+        if let Some(wrap) = &mut self.wrap {
+            wrap.advance(printed);
+        }
+    }
+
+    fn print(&mut self, delta: &str) -> Result<()> {
+        let mut text = match &mut self.wrap {
+            Some(wrap) => wrap.push(delta),
+            None => delta.to_owned(),
+        };
+        if let Some(highlight) = &mut self.highlight {
+            text = highlight.push(&text);
+        }
+        write!(std::io::stdout(), "{text}").map_err(error::output)
+    }
+
+    /// Print the rest of the response held back
+    fn finish(&mut self) -> Result<()> {
+        let mut text = match &mut self.wrap {
+            Some(wrap) => wrap.finish(),
+            None => String::new(),
+        };
+        if let Some(highlight) = &mut self.highlight {
+            text = highlight.push(&text) + &highlight.finish();
+        }
+        write!(std::io::stdout(), "{text}").map_err(error::output)
+    }
+}
+
+/// Print a content delta as a JSON line for `--format jsonl-stream`
+fn print_json_delta(content: &str, index: usize) -> Result<()> {
+    let delta = JsonDelta {
+        delta: content.to_owned(),
+        index,
+    };
+    writeln!(std::io::stdout(), "{}", serde_json::to_string(&delta)?).map_err(error::output)
+}
+
+/// Options with API keys, values of custom headers and environment variables of MCP servers
+/// redacted, to be logged or printed
+fn redacted(options: &Options) -> Options {
+    fn redact(secret: &mut String) {
+        if !secret.is_empty() {
+            *secret = "<redacted>".to_owned();
+        }
+    }
+    let mut options = options.clone();
+    redact(&mut options.api_key);
+    options.api_keys.iter_mut().for_each(redact);
+    for endpoint in &mut options.endpoints {
+        redact(&mut endpoint.api_key);
+    }
+    if let Some(api_key) = &mut options.web_search.api_key {
+        redact(api_key);
+    }
+    options.headers.values_mut().for_each(redact);
+    for server in options.mcp_servers.values_mut() {
+        server.env.values_mut().for_each(redact);
+    }
+    for header in &mut options.header {
+        if let Some((name, _)) = header.split_once(':') {
+            *header = format!("{name}: <redacted>");
+        }
+    }
+    options
+}
+
+/// Length of the delta to keep for the response to end right after the marker of `--until`, if
+/// the delta completes the marker, which may start in the output so far
+fn until_end(output: &str, delta: &str, marker: &str) -> Option<usize> {
+    let mut from = output.len().saturating_sub(marker.len().saturating_sub(1));
+    while !output.is_char_boundary(from) {
+        from -= 1;
+    }
+    let tail = format!("{}{delta}", &output[from..]);
+    let end = tail.find(marker)? + marker.len();
+    Some(end - (output.len() - from))
+}
+
+/// Whether the header carries credentials and should be redacted when printed
+fn is_secret_header(name: &str) -> bool {
+    ["authorization", "api-key", "x-api-key"]
+        .iter()
+        .any(|h| name.eq_ignore_ascii_case(h))
+}
+
+/// Print a warning message to stderr
+fn warning(message: impl std::fmt::Display) {
+    eprintln!("{}: {message}", style("WARNING").bold().yellow());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_of_help() {
+        assert!(is_command("set") && is_command("h") && is_command("history"));
+        assert!(!is_command("persona") && !is_command("model") && !is_command("load"));
+    }
+
+    #[test]
+    fn readme_init_file_has_commands() {
+        let readme = include_str!("../README.md");
+        let example = readme
+            .split("run at the start of interactive mode")
+            .nth(1)
+            .and_then(|rest| rest.split("```").nth(1))
+            .unwrap();
+        let commands: Vec<_> = example
+            .lines()
+            .filter_map(|line| line.strip_prefix('\\'))
+            .map(|line| line.split(' ').next().unwrap())
+            .collect();
+        assert!(!commands.is_empty());
+        for command in commands {
+            assert!(
+                is_command(command),
+                "\\{command} in README is not a command"
+            );
         }
     }
 }
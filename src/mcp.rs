@@ -0,0 +1,670 @@
+//! Client of MCP (Model Context Protocol) servers in `[mcp_servers]` of the config file. Their
+//! tools, and a tool reading their resources, are offered to the model along with those of
+//! `--tools`, and calls of them are sent to the servers.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use futures::StreamExt;
+use reqwest::Client;
+use reqwest_eventsource::{Event, EventSource};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::debug;
+
+use crate::model::{FunctionDefinition, Tool};
+
+/// Version of the protocol asked for in initialization
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// How long to wait for the server to start and list its tools
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for a tool call
+const CALL_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Name of the tool reading resources of a server, after the server name
+const READ_RESOURCE: &str = "read_resource";
+
+/// Separator between server and tool names in the names of tools offered to the model
+const SEPARATOR: &str = "__";
+
+/// An MCP server in the config file, run as a command talking over stdio, or reached by SSE at
+/// a URL, e.g.
+///
+/// ```toml
+/// [mcp_servers.files]
+/// command = "npx"
+/// args = ["-y", "@modelcontextprotocol/server-filesystem", "."]
+///
+/// [mcp_servers.remote]
+/// url = "http://localhost:8000/sse"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub command: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Environment variables of the command
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    pub url: Option<String>,
+}
+
+/// A connected server
+pub struct Server {
+    name: String,
+    transport: Transport,
+    /// Messages from the server
+    incoming: tokio::sync::Mutex<UnboundedReceiver<Value>>,
+    next_id: AtomicU64,
+    /// Tools offered to the model, and their names on the server
+    tools: Vec<(Tool, String)>,
+}
+
+enum Transport {
+    Stdio {
+        child: Mutex<Child>,
+        stdin: Mutex<ChildStdin>,
+    },
+    Sse {
+        client: Client,
+        /// Where messages are posted to, told by the server in the `endpoint` event
+        endpoint: String,
+    },
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Transport::Stdio { child, .. } = &self.transport {
+            if let Ok(mut child) = child.lock() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoteTool {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    input_schema: Option<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Resource {
+    uri: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+/// Connect to the server, and list its tools and resources
+pub async fn connect(name: &str, config: &ServerConfig, client: &Client) -> Result<Server> {
+    let (tx, rx) = unbounded_channel();
+    let transport = match (&config.command, &config.url) {
+        (Some(command), None) => spawn(command, config, tx)?,
+        (None, Some(url)) => tokio::time::timeout(CONNECT_TIMEOUT, open_sse(client, url, tx))
+            .await
+            .map_err(|_| anyhow!("No endpoint from {url}"))??,
+        _ => bail!("Either `command` or `url` is expected"),
+    };
+    let mut server = Server {
+        name: sanitize(name),
+        transport,
+        incoming: tokio::sync::Mutex::new(rx),
+        next_id: AtomicU64::new(1),
+        tools: Vec::new(),
+    };
+
+    let params = json!({
+        "protocolVersion": PROTOCOL_VERSION,
+        "capabilities": {},
+        "clientInfo": { "name": "heygpt", "version": env!("CARGO_PKG_VERSION") },
+    });
+    let init = server
+        .request("initialize", params, CONNECT_TIMEOUT)
+        .await?;
+    server.notify("notifications/initialized").await?;
+    let capabilities = &init["capabilities"];
+
+    if capabilities.get("tools").is_some() {
+        for tool in server.list::<RemoteTool>("tools/list", "tools").await? {
+            let parameters = tool
+                .input_schema
+                .unwrap_or_else(|| json!({ "type": "object", "properties": {} }));
+            server.tools.push((
+                definition(&server.name, &tool.name, &tool.description, parameters),
+                tool.name,
+            ));
+        }
+    }
+    if capabilities.get("resources").is_some() {
+        let resources = server
+            .list::<Resource>("resources/list", "resources")
+            .await?;
+        if !resources.is_empty() {
+            let list: Vec<_> = resources
+                .iter()
+                .map(|r| match &r.description {
+                    Some(description) => format!("{} ({}): {description}", r.uri, r.name),
+                    None => format!("{} ({})", r.uri, r.name),
+                })
+                .collect();
+            let description = format!(
+                "Read a resource by URI. Available resources:\n{}",
+                list.join("\n")
+            );
+            let parameters = json!({
+                "type": "object",
+                "properties": { "uri": { "type": "string" } },
+                "required": ["uri"],
+            });
+            server.tools.push((
+                definition(&server.name, READ_RESOURCE, &description, parameters),
+                READ_RESOURCE.to_owned(),
+            ));
+        }
+    }
+    debug!(
+        "MCP server `{}` has tools: {:?}",
+        server.name,
+        server
+            .tools
+            .iter()
+            .map(|(_, name)| name)
+            .collect::<Vec<_>>()
+    );
+    Ok(server)
+}
+
+/// Run the server command, forwarding messages on its stdout from a thread
+fn spawn(command: &str, config: &ServerConfig, tx: UnboundedSender<Value>) -> Result<Transport> {
+    // Servers log to stderr, which is only shown when debugging
+    let stderr = if tracing::enabled!(tracing::Level::DEBUG) {
+        Stdio::inherit()
+    } else {
+        Stdio::null()
+    };
+    let mut child = Command::new(command)
+        .args(&config.args)
+        .envs(&config.env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(stderr)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run `{command}`: {e}"))?;
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else { break };
+            match serde_json::from_str(&line) {
+                Ok(message) => {
+                    if tx.send(message).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => debug!("MCP server printed a non-JSON line: {line}"),
+            }
+        }
+    });
+    Ok(Transport::Stdio {
+        child: Mutex::new(child),
+        stdin: Mutex::new(stdin),
+    })
+}
+
+/// Open the event stream, forwarding messages from a task once the server tells the endpoint
+async fn open_sse(client: &Client, url: &str, tx: UnboundedSender<Value>) -> Result<Transport> {
+    let base = reqwest::Url::parse(url).map_err(|e| anyhow!("Invalid URL {url}: {e}"))?;
+    let mut es = EventSource::new(client.get(base.clone()))?;
+    let (endpoint_tx, endpoint_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let mut endpoint_tx = Some(endpoint_tx);
+        while let Some(event) = es.next().await {
+            match event {
+                Ok(Event::Open) => {}
+                Ok(Event::Message(message)) if message.event == "endpoint" => {
+                    if let Some(endpoint_tx) = endpoint_tx.take() {
+                        let _ = endpoint_tx.send(base.join(message.data.trim()));
+                    }
+                }
+                Ok(Event::Message(message)) => match serde_json::from_str(&message.data) {
+                    Ok(message) => {
+                        if tx.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => debug!("MCP server sent a non-JSON message: {}", message.data),
+                },
+                Err(err) => {
+                    debug!("MCP event stream error: {err}");
+                    break;
+                }
+            }
+        }
+        es.close();
+    });
+    let endpoint = endpoint_rx
+        .await
+        .map_err(|_| anyhow!("Event stream of {url} closed without telling the endpoint"))?
+        .map_err(|e| anyhow!("Invalid endpoint from {url}: {e}"))?;
+    Ok(Transport::Sse {
+        client: client.clone(),
+        endpoint: endpoint.to_string(),
+    })
+}
+
+/// Definition of a tool of a server, named after both
+fn definition(server: &str, tool: &str, description: &str, parameters: Value) -> Tool {
+    Tool {
+        r#type: "function".to_owned(),
+        function: FunctionDefinition {
+            // Function names are limited to 64 characters
+            name: format!("{server}{SEPARATOR}{}", sanitize(tool))
+                .chars()
+                .take(64)
+                .collect(),
+            description: description.to_owned(),
+            parameters,
+        },
+    }
+}
+
+/// Keep only characters allowed in function names
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Whether the name in `--allow-tools` is a server in the config, or one of its tools
+pub fn is_known(servers: &BTreeMap<String, ServerConfig>, name: &str) -> bool {
+    servers.keys().any(|server| {
+        let server = sanitize(server);
+        name == server
+            || name
+                .strip_prefix(&server)
+                .is_some_and(|rest| rest.starts_with(SEPARATOR))
+    })
+}
+
+/// Whether the tool offered to the model is allowed by a name in `--allow-tools`, which may
+/// be its server
+pub fn is_allowed_by(tool: &str, allowed: &str) -> bool {
+    tool.strip_prefix(allowed)
+        .is_some_and(|rest| rest.starts_with(SEPARATOR))
+}
+
+/// The server of a tool offered to the model, and the name of the tool on it
+pub fn find<'a>(servers: &'a [Server], name: &str) -> Option<(&'a Server, &'a str)> {
+    servers.iter().find_map(|server| {
+        server
+            .tools
+            .iter()
+            .find(|(tool, _)| tool.function.name == name)
+            .map(|(_, original)| (server, original.as_str()))
+    })
+}
+
+impl Server {
+    /// Tools offered to the model
+    pub fn tools(&self) -> impl Iterator<Item = &Tool> {
+        self.tools.iter().map(|(tool, _)| tool)
+    }
+
+    /// Call the tool, or read a resource, and return the result as text
+    pub async fn call(&self, tool: &str, arguments: Value) -> Result<String> {
+        if tool == READ_RESOURCE {
+            let uri = arguments["uri"].clone();
+            let result = self
+                .request("resources/read", json!({ "uri": uri }), CALL_TIMEOUT)
+                .await?;
+            let contents = result["contents"].as_array().cloned().unwrap_or_default();
+            let texts: Vec<_> = contents
+                .iter()
+                .map(|c| match c["text"].as_str() {
+                    Some(text) => text.to_owned(),
+                    None => format!("[binary content of {}]", c["uri"]),
+                })
+                .collect();
+            return Ok(texts.join("\n"));
+        }
+
+        let params = json!({ "name": tool, "arguments": arguments });
+        let result = self.request("tools/call", params, CALL_TIMEOUT).await?;
+        let content = result["content"].as_array().cloned().unwrap_or_default();
+        let texts: Vec<_> = content
+            .iter()
+            .map(|c| match c["type"].as_str() {
+                Some("text") => c["text"].as_str().unwrap_or_default().to_owned(),
+                Some("resource") => c["resource"]["text"].as_str().map_or_else(
+                    || format!("[resource {}]", c["resource"]["uri"]),
+                    str::to_owned,
+                ),
+                Some(other) => format!("[{other} content]"),
+                None => String::new(),
+            })
+            .collect();
+        let text = texts.join("\n");
+        if result["isError"].as_bool() == Some(true) {
+            bail!("{text}");
+        }
+        Ok(text)
+    }
+
+    /// Items of a list method, following the pages
+    async fn list<T: for<'de> Deserialize<'de>>(&self, method: &str, key: &str) -> Result<Vec<T>> {
+        let mut items = Vec::new();
+        let mut cursor = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let mut result = self.request(method, params, CONNECT_TIMEOUT).await?;
+            let page: Vec<T> = serde_json::from_value(result[key].take())
+                .map_err(|e| anyhow!("Invalid result of {method}: {e}"))?;
+            items.extend(page);
+            cursor = result["nextCursor"].as_str().map(str::to_owned);
+            if cursor.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+
+    /// Send a request and wait for its response
+    async fn request(&self, method: &str, params: Value, timeout: Duration) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        self.send(&message).await?;
+
+        let mut incoming = self.incoming.lock().await;
+        let wait = async {
+            while let Some(message) = incoming.recv().await {
+                if message.get("method").is_some() {
+                    self.answer(&message).await?;
+                } else if message["id"].as_u64() == Some(id) {
+                    return Ok(message);
+                }
+            }
+            Err(anyhow!(
+                "Connection to MCP server `{}` is closed",
+                self.name
+            ))
+        };
+        let mut response = tokio::time::timeout(timeout, wait)
+            .await
+            .map_err(|_| anyhow!("No response to {method} from MCP server `{}`", self.name))??;
+        if let Some(error) = response.get("error") {
+            bail!(
+                "MCP server `{}` failed {method}: {}",
+                self.name,
+                error["message"].as_str().unwrap_or("unknown error")
+            );
+        }
+        Ok(response["result"].take())
+    }
+
+    /// Answer a request from the server. Only `ping` is supported.
+    async fn answer(&self, request: &Value) -> Result<()> {
+        let Some(id) = request.get("id") else {
+            // A notification
+            return Ok(());
+        };
+        let response = if request["method"] == "ping" {
+            json!({ "jsonrpc": "2.0", "id": id, "result": {} })
+        } else {
+            let error = json!({ "code": -32601, "message": "Method not found" });
+            json!({ "jsonrpc": "2.0", "id": id, "error": error })
+        };
+        self.send(&response).await
+    }
+
+    async fn notify(&self, method: &str) -> Result<()> {
+        self.send(&json!({ "jsonrpc": "2.0", "method": method }))
+            .await
+    }
+
+    async fn send(&self, message: &Value) -> Result<()> {
+        match &self.transport {
+            Transport::Stdio { stdin, .. } => {
+                let mut stdin = stdin.lock().unwrap();
+                writeln!(stdin, "{message}")
+                    .and_then(|_| stdin.flush())
+                    .map_err(|e| anyhow!("Failed to write to MCP server `{}`: {e}", self.name))
+            }
+            Transport::Sse { client, endpoint } => {
+                client
+                    .post(endpoint)
+                    .json(message)
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| anyhow!("Failed to send to MCP server `{}`: {e}", self.name))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response};
+
+    use super::*;
+
+    /// An MCP server over SSE, recording the messages it gets
+    #[derive(Default)]
+    struct FakeServer {
+        events: tokio::sync::Mutex<Option<hyper::body::Sender>>,
+        received: Mutex<Vec<Value>>,
+    }
+
+    impl FakeServer {
+        async fn handle(&self, req: Request<Body>) -> Response<Body> {
+            if req.method() == Method::GET {
+                let (mut sender, body) = Body::channel();
+                let endpoint = "event: endpoint\ndata: /messages?session=1\n\n";
+                sender.send_data(endpoint.into()).await.unwrap();
+                *self.events.lock().await = Some(sender);
+                return Response::builder()
+                    .header("Content-Type", "text/event-stream")
+                    .body(body)
+                    .unwrap();
+            }
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let message: Value = serde_json::from_slice(&body).unwrap();
+            self.received.lock().unwrap().push(message.clone());
+            for reply in Self::reply(&message) {
+                let event = format!("event: message\ndata: {reply}\n\n");
+                let mut events = self.events.lock().await;
+                events
+                    .as_mut()
+                    .unwrap()
+                    .send_data(event.into())
+                    .await
+                    .unwrap();
+            }
+            Response::new(Body::empty())
+        }
+
+        /// Messages sent back for a message from the client
+        fn reply(message: &Value) -> Vec<Value> {
+            let id = &message["id"];
+            let result = match message["method"].as_str() {
+                Some("initialize") => json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": { "tools": {}, "resources": {} },
+                }),
+                // The first page is sent after a ping, which has to be answered
+                Some("tools/list") if message["params"]["cursor"].is_null() => {
+                    let ping = json!({ "jsonrpc": "2.0", "id": "ping-1", "method": "ping" });
+                    let page = json!({
+                        "tools": [{
+                            "name": "echo",
+                            "description": "Echo the text",
+                            "inputSchema": { "type": "object", "properties": { "text": { "type": "string" } } },
+                        }],
+                        "nextCursor": "2",
+                    });
+                    return vec![ping, json!({ "jsonrpc": "2.0", "id": id, "result": page })];
+                }
+                Some("tools/list") => json!({ "tools": [{ "name": "read file" }] }),
+                Some("resources/list") => json!({
+                    "resources": [{ "uri": "file:///notes.md", "name": "notes" }],
+                }),
+                Some("tools/call") if message["params"]["name"] == "echo" => json!({
+                    "content": [{ "type": "text", "text": message["params"]["arguments"]["text"] }],
+                }),
+                Some("tools/call") => json!({
+                    "content": [{ "type": "text", "text": "no such file" }],
+                    "isError": true,
+                }),
+                Some("resources/read") => json!({
+                    "contents": [{ "uri": message["params"]["uri"], "text": "# Notes" }],
+                }),
+                _ => return Vec::new(),
+            };
+            vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })]
+        }
+    }
+
+    async fn start() -> (Arc<FakeServer>, String) {
+        let fake = Arc::new(FakeServer::default());
+        let server = fake.clone();
+        let make_svc = make_service_fn(move |_conn| {
+            let server = server.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let server = server.clone();
+                    async move { Ok::<_, Infallible>(server.handle(req).await) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let url = format!("http://{}/sse", server.local_addr());
+        tokio::spawn(server);
+        (fake, url)
+    }
+
+    #[tokio::test]
+    async fn tools_of_server() {
+        let (fake, url) = start().await;
+        let config = ServerConfig {
+            url: Some(url),
+            ..Default::default()
+        };
+        let server = connect("my files", &config, &Client::new()).await.unwrap();
+
+        let names: Vec<_> = server.tools().map(|t| t.function.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "my_files__echo",
+                "my_files__read_file",
+                "my_files__read_resource"
+            ]
+        );
+        let tools: Vec<_> = server.tools().collect();
+        assert_eq!(tools[0].function.description, "Echo the text");
+        assert_eq!(
+            tools[0].function.parameters["properties"]["text"]["type"],
+            "string"
+        );
+        assert_eq!(
+            tools[1].function.parameters,
+            json!({ "type": "object", "properties": {} })
+        );
+        assert!(tools[2]
+            .function
+            .description
+            .contains("file:///notes.md (notes)"));
+
+        let (found, original) = find(std::slice::from_ref(&server), "my_files__read_file").unwrap();
+        assert_eq!((found.name.as_str(), original), ("my_files", "read file"));
+
+        let received = fake.received.lock().unwrap().clone();
+        let methods: Vec<_> = received.iter().map(|m| m["method"].clone()).collect();
+        assert_eq!(
+            methods,
+            [
+                json!("initialize"),
+                json!("notifications/initialized"),
+                json!("tools/list"),
+                Value::Null,
+                json!("tools/list"),
+                json!("resources/list"),
+            ]
+        );
+        assert!(received.iter().all(|m| m["jsonrpc"] == "2.0"));
+        // Requests have increasing ids, the notification none, and the ping is answered
+        assert_eq!(received[0]["id"], 1);
+        assert!(received[1].get("id").is_none());
+        assert_eq!(received[2]["id"], 2);
+        assert_eq!(
+            received[3],
+            json!({ "jsonrpc": "2.0", "id": "ping-1", "result": {} })
+        );
+        assert_eq!(received[4]["params"], json!({ "cursor": "2" }));
+        assert_eq!(received[5]["id"], 4);
+    }
+
+    #[tokio::test]
+    async fn call_tools() {
+        let (_fake, url) = start().await;
+        let config = ServerConfig {
+            url: Some(url),
+            ..Default::default()
+        };
+        let server = connect("files", &config, &Client::new()).await.unwrap();
+
+        let text = server
+            .call("echo", json!({ "text": "hello" }))
+            .await
+            .unwrap();
+        assert_eq!(text, "hello");
+        let err = server.call("read file", json!({})).await.unwrap_err();
+        assert_eq!(err.to_string(), "no such file");
+        let text = server
+            .call(READ_RESOURCE, json!({ "uri": "file:///notes.md" }))
+            .await
+            .unwrap();
+        assert_eq!(text, "# Notes");
+    }
+
+    #[test]
+    fn allowed_tools() {
+        let servers = BTreeMap::from([("my files".to_owned(), ServerConfig::default())]);
+        assert!(is_known(&servers, "my_files"));
+        assert!(is_known(&servers, "my_files__echo"));
+        assert!(!is_known(&servers, "my_filesystem"));
+        assert!(is_allowed_by("my_files__echo", "my_files"));
+        assert!(!is_allowed_by("my_files2__echo", "my_files"));
+    }
+}
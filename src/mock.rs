@@ -0,0 +1,322 @@
+use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{debug, info};
+
+use crate::model::{EmbeddingRequest, FunctionCall, Message, ResponseUsage};
+use crate::serve::error_response;
+use crate::token::{estimate_message_tokens, estimate_tokens};
+
+/// Delay between chunks of a streaming response
+const CHUNK_DELAY: Duration = Duration::from_millis(20);
+
+/// Dimensions of mock embeddings
+const EMBEDDING_DIMENSIONS: usize = 64;
+
+/// A canned response in the replay file, either a plain string or an object
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Canned {
+    Text(String),
+    Full {
+        #[serde(default)]
+        content: String,
+        #[serde(default)]
+        finish_reason: Option<String>,
+        /// Respond with an API error of this status code, with `content` as the message
+        #[serde(default)]
+        status: Option<u16>,
+        /// Delay in milliseconds before responding
+        #[serde(default)]
+        delay_ms: u64,
+        /// Call tools, e.g. `[{"name": "read_file", "arguments": "{\"path\": \"a.txt\"}"}]`
+        #[serde(default)]
+        tool_calls: Vec<FunctionCall>,
+        /// Break off a streaming response after `content` with an error event of this message,
+        /// as servers failing in the middle of the stream do
+        #[serde(default)]
+        stream_error: Option<String>,
+    },
+}
+
+/// State of the mock server
+struct Mock {
+    /// Responses replayed in order. If empty, the last message is echoed.
+    responses: Vec<Canned>,
+    next: AtomicUsize,
+}
+
+/// Start a fake OpenAI-compatible server on a random local port and return its base URL.
+///
+/// With a replay file, which is a JSON array of responses, they are returned in order and
+/// then from the beginning again. Otherwise every response echoes the last message.
+pub fn start(replay: Option<&str>) -> Result<String> {
+    let responses = match replay {
+        Some(path) => {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read replay file `{path}`: {e}"))?;
+            let responses: Vec<Canned> = serde_json::from_str(&content)
+                .map_err(|e| anyhow!("Invalid replay file `{path}`: {e}"))?;
+            if responses.is_empty() {
+                bail!("Replay file `{path}` has no responses");
+            }
+            responses
+        }
+        None => Vec::new(),
+    };
+    let mock = Arc::new(Mock {
+        responses,
+        next: AtomicUsize::new(0),
+    });
+
+    let make_svc = make_service_fn(move |_conn| {
+        let mock = mock.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let mock = mock.clone();
+                async move { Ok::<_, Infallible>(mock.handle(req).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_svc);
+    let base_url = format!("http://{}/v1", server.local_addr());
+    debug!("Mock server listening on {base_url}");
+    tokio::spawn(server);
+    Ok(base_url)
+}
+
+/// The parts of a chat completion request the mock cares about
+#[derive(Deserialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<Message>,
+    #[serde(default)]
+    stream: bool,
+}
+
+impl Mock {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        info!("mock: {} {}", method, path);
+
+        match (&method, path.strip_prefix("/v1").unwrap_or(&path)) {
+            (&Method::POST, "/chat/completions") => {
+                let data = hyper::body::to_bytes(req.into_body())
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|body| Ok(serde_json::from_slice(&body)?));
+                match data {
+                    Ok(data) => self.chat_completions(data).await,
+                    Err(err) => error_response(
+                        StatusCode::BAD_REQUEST,
+                        &format!("Invalid request body: {err}"),
+                    ),
+                }
+            }
+            (&Method::POST, "/embeddings") => {
+                let data = hyper::body::to_bytes(req.into_body())
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|body| Ok(serde_json::from_slice(&body)?));
+                match data {
+                    Ok(data) => embeddings(data),
+                    Err(err) => error_response(
+                        StatusCode::BAD_REQUEST,
+                        &format!("Invalid request body: {err}"),
+                    ),
+                }
+            }
+            (&Method::GET, "/models") => json_response(json!({
+                "object": "list",
+                "data": [{"id": "mock", "object": "model", "created": 0, "owned_by": "heygpt"}],
+            })),
+            _ => error_response(
+                StatusCode::NOT_FOUND,
+                &format!("Unknown endpoint of mock server: {method} {path}"),
+            ),
+        }
+    }
+
+    /// The next canned response, or an echo of the last message
+    fn next_response(&self, messages: &[Message]) -> Canned {
+        if self.responses.is_empty() {
+            let last = messages.last().map(|m| m.content.as_str()).unwrap_or("");
+            return Canned::Text(format!("This is a mock response to: {last}"));
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        self.responses[i % self.responses.len()].clone()
+    }
+
+    async fn chat_completions(&self, data: ChatRequest) -> Response<Body> {
+        let (content, finish_reason, status, delay_ms, tool_calls, stream_error) =
+            match self.next_response(&data.messages) {
+                Canned::Text(content) => (content, None, None, 0, Vec::new(), None),
+                Canned::Full {
+                    content,
+                    finish_reason,
+                    status,
+                    delay_ms,
+                    tool_calls,
+                    stream_error,
+                } => (
+                    content,
+                    finish_reason,
+                    status,
+                    delay_ms,
+                    tool_calls,
+                    stream_error,
+                ),
+            };
+        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+
+        if let Some(status) = status {
+            let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_REQUEST);
+            return error_response(status, &content);
+        }
+
+        let default_reason = if tool_calls.is_empty() {
+            "stop"
+        } else {
+            "tool_calls"
+        };
+        let finish_reason = finish_reason.unwrap_or_else(|| default_reason.to_owned());
+        let tool_calls: Vec<_> = tool_calls
+            .into_iter()
+            .enumerate()
+            .map(|(i, call)| {
+                json!({
+                    "index": i,
+                    "id": format!("call_{i}"),
+                    "type": "function",
+                    "function": call,
+                })
+            })
+            .collect();
+        let prompt_tokens = estimate_message_tokens(&data.messages) as isize;
+        let completion_tokens = estimate_tokens(&content) as isize;
+        let usage = ResponseUsage {
+            completion_tokens,
+            prompt_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            completion_tokens_details: None,
+            cost: None,
+        };
+
+        if !data.stream {
+            return json_response(json!({
+                "id": "mock",
+                "object": "chat.completion",
+                "created": 0,
+                "model": data.model,
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": content, "tool_calls": tool_calls},
+                    "finish_reason": finish_reason,
+                }],
+                "usage": usage,
+            }));
+        }
+
+        let chunk = |choices: serde_json::Value| {
+            json!({
+                "id": "mock",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": data.model,
+                "choices": choices,
+            })
+        };
+        let delta = |delta: serde_json::Value, finish_reason: Option<&str>| {
+            chunk(json!([{"index": 0, "delta": delta, "finish_reason": finish_reason}]))
+        };
+        let mut events = vec![delta(json!({"role": "assistant"}), None)];
+        events.extend(
+            content
+                .split_inclusive(' ')
+                .map(|word| delta(json!({ "content": word }), None)),
+        );
+        events.extend(
+            tool_calls
+                .iter()
+                .map(|call| delta(json!({ "tool_calls": [call] }), None)),
+        );
+        if let Some(message) = &stream_error {
+            events.push(json!({ "error": { "message": message, "type": "server_error" } }));
+        } else {
+            events.push(delta(json!({}), Some(&finish_reason)));
+            let mut last = chunk(json!([]));
+            last["usage"] = json!(usage);
+            events.push(last);
+        }
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for event in events {
+                let data = Bytes::from(format!("data: {event}\n\n"));
+                if sender.send_data(data).await.is_err() {
+                    return;
+                }
+                tokio::time::sleep(CHUNK_DELAY).await;
+            }
+            if stream_error.is_none() {
+                let _ = sender.send_data(Bytes::from("data: [DONE]\n\n")).await;
+            }
+        });
+
+        let mut response = Response::new(body);
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+        response
+    }
+}
+
+/// Embeddings hashing the words of each input, so texts sharing words are similar
+fn embeddings(data: EmbeddingRequest) -> Response<Body> {
+    let tokens: usize = data.input.iter().map(|text| estimate_tokens(text)).sum();
+    let data: Vec<_> = data
+        .input
+        .iter()
+        .enumerate()
+        .map(|(index, text)| {
+            let mut embedding = vec![0.0f32; EMBEDDING_DIMENSIONS];
+            for word in text
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|w| !w.is_empty())
+            {
+                let mut hasher = DefaultHasher::new();
+                word.to_lowercase().hash(&mut hasher);
+                embedding[hasher.finish() as usize % EMBEDDING_DIMENSIONS] += 1.0;
+            }
+            json!({"object": "embedding", "index": index, "embedding": embedding})
+        })
+        .collect();
+    json_response(json!({
+        "object": "list",
+        "data": data,
+        "model": "mock",
+        "usage": {"prompt_tokens": tokens, "total_tokens": tokens},
+    }))
+}
+
+fn json_response(body: serde_json::Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
@@ -1,15 +1,161 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::provider::Provider;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(from = "RawMessage", into = "RawMessage")]
 pub struct Message {
     pub role: String,
     pub content: String,
+    /// Attached images as URLs, including `data:` URLs. Sent as content parts along with the text.
+    pub images: Vec<String>,
+    /// Tools the assistant asks to call
+    pub tool_calls: Vec<ToolCall>,
+    /// The call answered by a `tool` message
+    pub tool_call_id: Option<String>,
+}
+
+/// Message as in API, whose content is either a string or an array of content parts. It is
+/// null in assistant messages with only tool calls.
+#[derive(Serialize, Deserialize)]
+struct RawMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<RawContent>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize)]
+struct ImageUrl {
+    url: String,
+}
+
+impl From<RawMessage> for Message {
+    fn from(raw: RawMessage) -> Self {
+        let (content, images) = match raw.content {
+            None => (String::new(), Vec::new()),
+            Some(RawContent::Text(text)) => (text, Vec::new()),
+            Some(RawContent::Parts(parts)) => {
+                let mut texts = Vec::new();
+                let mut images = Vec::new();
+                for part in parts {
+                    match part {
+                        ContentPart::Text { text } => texts.push(text),
+                        ContentPart::ImageUrl { image_url } => images.push(image_url.url),
+                    }
+                }
+                (texts.join("\n"), images)
+            }
+        };
+        Self {
+            role: raw.role,
+            content,
+            images,
+            tool_calls: raw.tool_calls,
+            tool_call_id: raw.tool_call_id,
+        }
+    }
+}
+
+impl From<Message> for RawMessage {
+    fn from(message: Message) -> Self {
+        let content = if message.content.is_empty() && !message.tool_calls.is_empty() {
+            None
+        } else if message.images.is_empty() {
+            Some(RawContent::Text(message.content))
+        } else {
+            let text = ContentPart::Text {
+                text: message.content,
+            };
+            let images = message.images.into_iter().map(|url| ContentPart::ImageUrl {
+                image_url: ImageUrl { url },
+            });
+            Some(RawContent::Parts(
+                std::iter::once(text).chain(images).collect(),
+            ))
+        };
+        Self {
+            role: message.role,
+            content,
+            tool_calls: message.tool_calls,
+            tool_call_id: message.tool_call_id,
+        }
+    }
+}
+
+/// A function call requested by the model, whose arguments are a JSON object in a string
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    pub r#type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+/// A function offered to the model in `tools` of the request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tool {
+    pub r#type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments
+    pub parameters: serde_json::Value,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeltaMessage {
     pub role: Option<String>,
     pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// A piece of a tool call in a response stream. The name and arguments come in pieces, and
+/// the other fields only in the first piece of each call.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub function: Option<FunctionCallDelta>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FunctionCallDelta {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub arguments: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -18,20 +164,109 @@ pub struct Request {
     pub messages: Vec<Message>,
     pub stream: bool,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f64>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<BTreeMap<String, i32>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+
+    /// Replaces `max_tokens` for reasoning models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<usize>,
+
+    /// Constrains the format of the response, e.g. to a JSON object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+
+    /// Asks Mistral to prepend its guardrail system prompt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safe_prompt: Option<bool>,
+
+    /// Asks OpenRouter to report the cost in `usage`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<UsageOptions>,
+
+    /// Functions the model may call
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+}
+
+impl Request {
+    /// Enable or disable streaming. Streaming requests ask for usage in the last chunk.
+    pub fn set_stream(&mut self, stream: bool) {
+        self.stream = stream;
+        self.stream_options = StreamOptions::include_usage(stream);
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResponseFormat {
+    pub r#type: String,
+}
+
+impl ResponseFormat {
+    /// JSON mode, in which the model always responds with a valid JSON object
+    pub fn json_object() -> Self {
+        Self {
+            r#type: "json_object".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StreamOptions {
+    pub include_usage: bool,
+}
+
+impl StreamOptions {
+    /// Ask for usage in the last chunk. Only valid for streaming requests.
+    pub fn include_usage(stream: bool) -> Option<Self> {
+        stream.then_some(Self {
+            include_usage: true,
+        })
+    }
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UsageOptions {
+    pub include: bool,
+}
+
+impl UsageOptions {
+    /// Ask for the cost in usage if the provider reports it
+    pub fn include_cost(provider: Provider) -> Option<Self> {
+        provider.reports_cost().then_some(Self { include: true })
+    }
+}
+
+/// Some OpenAI-compatible servers omit fields other than `choices`, so they default to empty
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ResponseMessage {
     pub choices: Vec<ResponseChoice>,
+    #[serde(default)]
     pub created: u64,
+    #[serde(default)]
     pub id: String,
+    #[serde(default)]
     pub model: String,
+    #[serde(default)]
     pub object: String,
+    #[serde(default)]
     pub usage: ResponseUsage,
 }
 
@@ -42,20 +277,89 @@ pub struct ResponseChoice {
     pub finish_reason: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct ResponseUsage {
+    #[serde(default)]
     pub completion_tokens: isize,
+    #[serde(default)]
     pub prompt_tokens: isize,
+    #[serde(default)]
     pub total_tokens: isize,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+
+    /// Cost in USD as reported by OpenRouter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+impl ResponseUsage {
+    /// Completion tokens as a count, for computing throughput
+    pub fn completion_count(&self) -> usize {
+        self.completion_tokens.max(0) as usize
+    }
+
+    /// Accumulate usage of another response
+    pub fn add(&mut self, other: &ResponseUsage) {
+        self.completion_tokens += other.completion_tokens;
+        self.prompt_tokens += other.prompt_tokens;
+        self.total_tokens += other.total_tokens;
+        if let Some(cost) = other.cost {
+            *self.cost.get_or_insert(0.0) += cost;
+        }
+        if let Some(other) = &other.completion_tokens_details {
+            let details = self
+                .completion_tokens_details
+                .get_or_insert(CompletionTokensDetails {
+                    reasoning_tokens: None,
+                });
+            if let Some(tokens) = other.reasoning_tokens {
+                *details.reasoning_tokens.get_or_insert(0) += tokens;
+            }
+        }
+    }
+}
+
+/// Breakdown of completion tokens, e.g. tokens spent on reasoning by o1 models
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CompletionTokensDetails {
+    pub reasoning_tokens: Option<isize>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ResponseStreamMessage {
+    #[serde(default)]
     pub id: String,
+    #[serde(default)]
     pub object: String,
+    #[serde(default)]
     pub created: u64,
+    #[serde(default)]
     pub model: String,
     pub choices: Vec<ResponseDeltaChoice>,
+
+    /// Only in the last chunk with empty `choices`, if `stream_options.include_usage` is set
+    #[serde(default)]
+    pub usage: Option<ResponseUsage>,
+
+    /// Groq reports usage here in the last chunk instead
+    #[serde(default, skip_serializing)]
+    pub x_groq: Option<GroqExtension>,
+}
+
+impl ResponseStreamMessage {
+    /// Usage reported in the chunk, in either the standard or a provider-specific field
+    pub fn take_usage(&mut self) -> Option<ResponseUsage> {
+        self.usage
+            .take()
+            .or_else(|| self.x_groq.take().and_then(|x| x.usage))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroqExtension {
+    pub usage: Option<ResponseUsage>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -65,11 +369,133 @@ pub struct ResponseDeltaChoice {
     pub finish_reason: Option<String>,
 }
 
+/// Request of audio transcription API, sent as multipart form fields along with the file
+#[derive(Debug, Serialize)]
+pub struct TranscriptionRequest {
+    pub model: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+
+    pub response_format: String,
+}
+
+/// Request of text-to-speech API
+#[derive(Debug, Serialize)]
+pub struct SpeechRequest {
+    pub model: String,
+    pub input: String,
+    pub voice: String,
+    pub response_format: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f64>,
+}
+
+/// Request of image generation API
+#[derive(Debug, Serialize)]
+pub struct ImageRequest {
+    pub model: String,
+    pub prompt: String,
+    pub n: u32,
+    pub size: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImageResponse {
+    pub data: Vec<ImageData>,
+}
+
+/// A generated image, either as URL or base64-encoded data depending on the model
+#[derive(Debug, Deserialize)]
+pub struct ImageData {
+    pub url: Option<String>,
+    pub b64_json: Option<String>,
+    pub revised_prompt: Option<String>,
+}
+
+/// Request of embeddings API
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    pub input: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingResponse {
+    pub data: Vec<EmbeddingData>,
+    pub usage: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingData {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+/// Request of moderation API
+#[derive(Debug, Serialize)]
+pub struct ModerationRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub input: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModerationResponse {
+    pub results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ModerationResult {
+    pub flagged: bool,
+    pub categories: BTreeMap<String, bool>,
+    pub category_scores: BTreeMap<String, f64>,
+}
+
+/// Response of list models API
+#[derive(Debug, Deserialize)]
+pub struct ModelList {
+    pub data: Vec<ModelInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModelInfo {
+    pub id: String,
+
+    /// Context window, reported by OpenRouter
+    #[serde(default)]
+    pub context_length: Option<usize>,
+
+    /// Prices per token, reported by OpenRouter
+    #[serde(default)]
+    pub pricing: Option<ModelPricing>,
+}
+
+/// Prices in USD per token, as decimal strings
+#[derive(Debug, Deserialize)]
+pub struct ModelPricing {
+    pub prompt: String,
+    pub completion: String,
+}
+
 /// OpenAI API returns error object on failure
 #[derive(Debug, Deserialize)]
 pub struct ApiError {
     pub message: String,
-    pub r#type: String,
+    /// Missing in errors of some providers, e.g. OpenRouter
+    #[serde(default)]
+    pub r#type: Option<String>,
+    #[allow(dead_code)]
     pub param: Option<serde_json::Value>,
     pub code: Option<serde_json::Value>,
 }
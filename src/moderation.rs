@@ -0,0 +1,75 @@
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+use reqwest::{Client, Method};
+
+use crate::model::{ModerationRequest, ModerationResponse, ModerationResult};
+use crate::{api_request, check_response, http_client, verbose, Options};
+
+#[derive(Args, Debug)]
+pub struct ModerateArgs {
+    /// The text to check. Reads stdin if omitted.
+    pub text: Vec<String>,
+
+    /// The moderation model (default: decided by the API)
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Print the full moderation result as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Check the text with moderation API. Exits with error if it's flagged.
+pub async fn run(options: Options, args: ModerateArgs) -> Result<()> {
+    let text = if args.text.is_empty() {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        args.text.join(" ")
+    };
+    let result = moderate(&http_client(&options)?, &options, args.model, text).await?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else if result.flagged {
+        println!("Flagged: {}", flagged_categories(&result));
+    } else {
+        println!("Not flagged");
+    }
+
+    if result.flagged {
+        bail!("Content is flagged by moderation");
+    }
+    Ok(())
+}
+
+/// Classify the text with moderation API
+pub async fn moderate(
+    client: &Client,
+    options: &Options,
+    model: Option<String>,
+    input: String,
+) -> Result<ModerationResult> {
+    let data = ModerationRequest { model, input };
+    let req = api_request(client, options, Method::POST, "/moderations")?.json(&data);
+    let response = verbose::send(options, req).await?;
+    let response: ModerationResponse = check_response(response).await?.json().await?;
+    response
+        .results
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No result in moderation response"))
+}
+
+/// Flagged categories with scores, e.g. `violence (0.91), harassment (0.52)`
+pub fn flagged_categories(result: &ModerationResult) -> String {
+    result
+        .categories
+        .iter()
+        .filter(|(_, flagged)| **flagged)
+        .map(|(category, _)| match result.category_scores.get(category) {
+            Some(score) => format!("{category} ({score:.2})"),
+            None => category.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,40 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::model::ResponseUsage;
+
+/// Output format of responses
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// Plain text
+    #[default]
+    Text,
+    /// A JSON object with content and metadata. Only for one-shot mode
+    Json,
+    /// A JSON line per delta, followed by a summary line. Only for one-shot mode
+    JsonlStream,
+}
+
+/// A delta printed by `--format jsonl-stream`
+#[derive(Serialize, Debug)]
+pub struct JsonDelta {
+    pub delta: String,
+    pub index: usize,
+}
+
+/// The JSON envelope printed by `--format json`, or as the summary of `--format jsonl-stream`
+#[derive(Serialize, Debug)]
+pub struct JsonOutput {
+    pub content: String,
+    pub model: String,
+    pub finish_reason: Option<String>,
+    pub usage: Option<ResponseUsage>,
+    pub duration_ms: u128,
+    /// Time to the first token of content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_token_ms: Option<u128>,
+    /// Completion tokens per second after the first token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_per_second: Option<f64>,
+}
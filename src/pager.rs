@@ -0,0 +1,44 @@
+//! Show long responses in a pager like `less`, so that they can be read from the top
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail, Result};
+use console::Term;
+
+/// Whether the text fits in the terminal without scrolling, taking wrapped lines into account
+pub fn fits_screen(text: &str) -> bool {
+    let (rows, cols) = Term::stdout().size();
+    let cols = usize::from(cols.max(1));
+    let lines: usize = text
+        .lines()
+        .map(|line| console::measure_text_width(line).max(1).div_ceil(cols))
+        .sum();
+    // Leave a line for the next prompt
+    lines < usize::from(rows)
+}
+
+/// Pipe the text through `$PAGER`, or `less -R` by default
+pub fn page(text: &str) -> Result<()> {
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less -R".to_owned());
+    let mut args = pager.split_whitespace();
+    let program = args.next().ok_or_else(|| anyhow!("$PAGER is empty"))?;
+    let mut command = Command::new(program);
+    command.args(args).stdin(Stdio::piped());
+    // Like git, keep colors and leave the text on screen after quitting `less`
+    if std::env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run pager `{pager}`: {e}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager may be quit before reading all of the text
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("Pager `{pager}` exited with {status}");
+    }
+    Ok(())
+}
@@ -0,0 +1,570 @@
+//! Applying changes in a response to the working tree by `\apply`: unified diffs, and fenced
+//! code blocks labeled with a path holding the whole new content of the file
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+
+use crate::{confirm, diff};
+
+/// Lines of unchanged context around the changes of file blocks
+const CONTEXT_LINES: usize = 3;
+
+/// Changes to a file found in a response
+#[derive(Debug)]
+struct FileChange {
+    path: String,
+    change: Change,
+}
+
+#[derive(Debug)]
+enum Change {
+    /// Hunks of a unified diff
+    Patch(Vec<Hunk>),
+    /// The whole new content of the file
+    Content(String),
+    /// The file is deleted by the diff, which is not supported
+    Delete,
+}
+
+/// A hunk of a unified diff
+#[derive(Debug, Clone)]
+struct Hunk {
+    /// The `@@ -1,3 +1,4 @@` line
+    header: String,
+    /// Line number in the old file the hunk starts at, which is only a hint to locate it, as
+    /// models often get it wrong
+    start: usize,
+    /// Lines tagged with `-`, `+` or ` `
+    lines: Vec<(char, String)>,
+}
+
+impl Hunk {
+    /// Lines of the hunk in the old file, i.e. without those tagged with `+`
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter(|(tag, _)| *tag != '+')
+            .map(|(_, line)| line.as_str())
+            .collect()
+    }
+
+    fn print(&self) {
+        println!("{}", style(&self.header).cyan());
+        for (tag, line) in &self.lines {
+            diff::print_line(*tag, line);
+        }
+    }
+}
+
+/// Find the changes in the response, show them hunk by hunk and write the confirmed ones
+pub fn apply(response: &str) -> Result<()> {
+    let changes = parse(response);
+    if changes.is_empty() {
+        bail!("No diff or code block labeled with a file path in the last response");
+    }
+    let cwd = std::env::current_dir()?;
+    for FileChange { path, change } in changes {
+        if let Err(err) = inside(&cwd, &path) {
+            println!("{}: {err}", style("ERROR").bold().red());
+            continue;
+        }
+        let result = match change {
+            Change::Patch(hunks) => apply_hunks(&path, hunks),
+            Change::Content(content) => apply_content(&path, &content),
+            Change::Delete => {
+                println!("Skipped deleting `{path}`, please delete it yourself if wanted");
+                continue;
+            }
+        };
+        if let Err(err) = result {
+            println!("{}: {err}", style("ERROR").bold().red());
+        }
+    }
+    Ok(())
+}
+
+/// Replace the file with the content of a file block, by the hunks of their difference
+fn apply_content(path: &str, content: &str) -> Result<()> {
+    let old = read(path)?;
+    let diff = diff::diff_lines(&old, content);
+    if diff.iter().all(|(tag, _)| *tag == ' ') {
+        println!("`{path}` is unchanged");
+        return Ok(());
+    }
+    apply_hunks(path, hunks_of(&diff))
+}
+
+/// Show the hunks one by one, asking whether to apply each, then write the file
+fn apply_hunks(path: &str, hunks: Vec<Hunk>) -> Result<()> {
+    let old = read(path)?;
+    println!("{}", style(format!("--- {path}")).bold());
+    let (content, applied) = patch(&old, &hunks, |hunk, matches| {
+        hunk.print();
+        if !matches {
+            println!(
+                "{}",
+                style("This hunk does not match the file, skipped").yellow()
+            );
+            return Ok(false);
+        }
+        confirm(&format!("Apply this hunk to `{path}`?"), false)
+    })?;
+    if applied == 0 {
+        println!("Left `{path}` unchanged");
+        return Ok(());
+    }
+
+    if let Some(parent) = Path::new(path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| anyhow!("Failed to create directory {}: {e}", parent.display()))?;
+    }
+    std::fs::write(path, content).map_err(|e| anyhow!("Failed to write `{path}`: {e}"))?;
+    println!("Applied {applied} of {} hunks to `{path}`", hunks.len());
+    Ok(())
+}
+
+/// Apply the hunks to the content of a file, each if `accept` says so given whether it matches
+/// the file. Returns the new content and the number of hunks applied.
+///
+/// Unchanged lines are kept as they are in the file, as responses often mangle whitespace, and
+/// so are line endings: added lines get those of the file, e.g. CRLF, and whether the file
+/// ends with a line break stays as it was.
+fn patch(
+    old: &str,
+    hunks: &[Hunk],
+    mut accept: impl FnMut(&Hunk, bool) -> Result<bool>,
+) -> Result<(String, usize)> {
+    let mut lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let eol = if old.contains("\r\n") { "\r\n" } else { "\n" };
+    let added: Vec<Vec<String>> = hunks
+        .iter()
+        .map(|hunk| {
+            hunk.lines
+                .iter()
+                .filter(|(tag, _)| *tag == '+')
+                .map(|(_, line)| format!("{line}{eol}"))
+                .collect()
+        })
+        .collect();
+    let mut applied = 0;
+    // Shift of line numbers by the hunks applied so far, and where the next hunk may start
+    let (mut shift, mut next) = (0isize, 0);
+
+    for (hunk, added) in hunks.iter().zip(&added) {
+        let expected = (hunk.start.saturating_sub(1) as isize + shift).max(0) as usize;
+        let old_lines = hunk.old_lines();
+        let at = locate(&lines, &old_lines, expected, next);
+        if !accept(hunk, at.is_some())? {
+            continue;
+        }
+        let Some(at) = at else { continue };
+        let mut original = lines[at..at + old_lines.len()].iter();
+        let mut added = added.iter();
+        let replacement: Vec<&str> = hunk
+            .lines
+            .iter()
+            .filter_map(|(tag, _)| match tag {
+                '+' => added.next().map(String::as_str),
+                '-' => original.next().and(None),
+                _ => original.next().copied(),
+            })
+            .collect();
+        shift += replacement.len() as isize - old_lines.len() as isize;
+        next = at + replacement.len();
+        lines.splice(at..at + old_lines.len(), replacement);
+        applied += 1;
+    }
+
+    // A line moved from the end of the file gets a line break
+    let ends_with_break = old.is_empty() || old.ends_with('\n');
+    let mut content = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        let text = line
+            .strip_suffix('\n')
+            .map_or(*line, |l| l.trim_end_matches('\r'));
+        content.push_str(text);
+        if i + 1 < lines.len() || ends_with_break {
+            content.push_str(match &line[text.len()..] {
+                "" => eol,
+                ending => ending,
+            });
+        }
+    }
+    Ok((content, applied))
+}
+
+/// Check that the path is in the directory, after following symlinks, so that a response can't
+/// change files elsewhere like `../.bashrc` or `/etc/hosts`
+fn inside(dir: &Path, path: &str) -> Result<()> {
+    let dir = dir.canonicalize()?;
+    let full = dir.join(path);
+    // Only the part of the path that exists can be resolved
+    let existing = full
+        .ancestors()
+        .find(|p| p.exists())
+        .ok_or_else(|| anyhow!("Invalid path `{path}`"))?;
+    let rest = full.strip_prefix(existing)?;
+    let resolved = existing.canonicalize()?.join(rest);
+    let escapes = rest
+        .components()
+        .any(|c| !matches!(c, std::path::Component::Normal(_)));
+    if escapes || !resolved.starts_with(&dir) {
+        bail!("Refused to change `{path}`, which is outside the current directory");
+    }
+    Ok(())
+}
+
+/// Read the file to be changed, or nothing if it does not exist yet
+fn read(path: &str) -> Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => Err(anyhow!("Failed to read `{path}`: {err}")),
+    }
+}
+
+/// Find where the old lines of a hunk are in the file, at or after `from`, nearest to the
+/// expected line. Trailing whitespace is ignored, as it is often lost in responses.
+fn locate(lines: &[&str], old: &[&str], expected: usize, from: usize) -> Option<usize> {
+    if old.is_empty() {
+        return Some(expected.clamp(from, lines.len()));
+    }
+    if old.len() > lines.len() {
+        return None;
+    }
+    (from..=lines.len() - old.len())
+        .filter(|&at| {
+            lines[at..at + old.len()]
+                .iter()
+                .zip(old)
+                .all(|(a, b)| a.trim_end() == b.trim_end())
+        })
+        .min_by_key(|&at| at.abs_diff(expected))
+}
+
+/// Group a line diff into hunks with a few lines of context
+fn hunks_of(diff: &[(char, &str)]) -> Vec<Hunk> {
+    let changed: Vec<_> = (0..diff.len()).filter(|&i| diff[i].0 != ' ').collect();
+    let mut hunks = Vec::new();
+    let mut i = 0;
+    while i < changed.len() {
+        let begin = changed[i].saturating_sub(CONTEXT_LINES);
+        let mut end = changed[i];
+        // Merge changes whose contexts overlap
+        while i < changed.len() && changed[i] <= end + 2 * CONTEXT_LINES + 1 {
+            end = changed[i];
+            i += 1;
+        }
+        let end = (end + CONTEXT_LINES + 1).min(diff.len());
+
+        let old_start = diff[..begin].iter().filter(|(t, _)| *t != '+').count() + 1;
+        let new_start = diff[..begin].iter().filter(|(t, _)| *t != '-').count() + 1;
+        let lines: Vec<_> = diff[begin..end]
+            .iter()
+            .map(|(tag, line)| (*tag, line.to_string()))
+            .collect();
+        let old_count = lines.iter().filter(|(t, _)| *t != '+').count();
+        let new_count = lines.iter().filter(|(t, _)| *t != '-').count();
+        // Like `diff -u`, an empty range starts at the line before it
+        let (old_line, new_line) = (
+            old_start - usize::from(old_count == 0),
+            new_start - usize::from(new_count == 0),
+        );
+        hunks.push(Hunk {
+            header: format!("@@ -{old_line},{old_count} +{new_line},{new_count} @@"),
+            start: old_start,
+            lines,
+        });
+    }
+    hunks
+}
+
+/// Find unified diffs and labeled file blocks in fenced code blocks of the response, or a diff
+/// in the response itself if it has no code blocks
+fn parse(response: &str) -> Vec<FileChange> {
+    let blocks = code_blocks(response);
+    if blocks.is_empty() {
+        return parse_diff(response, None);
+    }
+    let mut changes = Vec::new();
+    for (info, body) in blocks {
+        let language = info.split_whitespace().next().unwrap_or_default();
+        let path = label_path(info);
+        if matches!(language, "diff" | "patch") || is_diff(&body) {
+            changes.extend(parse_diff(&body, path));
+        } else if let Some(path) = path {
+            changes.push(FileChange {
+                path: path.to_owned(),
+                change: Change::Content(body),
+            });
+        }
+    }
+    changes
+}
+
+/// Fenced code blocks of the text, with their info strings
+fn code_blocks(text: &str) -> Vec<(&str, String)> {
+    let mut blocks = Vec::new();
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim_start();
+        let ticks = trimmed.len() - trimmed.trim_start_matches('`').len();
+        if ticks < 3 {
+            continue;
+        }
+        let info = trimmed[ticks..].trim();
+        let mut body = String::new();
+        for line in lines.by_ref() {
+            let trimmed = line.trim();
+            if trimmed.len() >= ticks && trimmed.chars().all(|c| c == '`') {
+                break;
+            }
+            body.push_str(line);
+            body.push('\n');
+        }
+        blocks.push((info, body));
+    }
+    blocks
+}
+
+/// The file path in the info string of a code block, e.g. `src/main.rs`, `rust src/main.rs` or
+/// `rust:src/main.rs`
+fn label_path(info: &str) -> Option<&str> {
+    info.split(|c: char| c.is_whitespace() || c == ':')
+        .filter(|word| !word.is_empty())
+        .rfind(|word| word.contains('/') || word.contains('.'))
+}
+
+fn is_diff(text: &str) -> bool {
+    let lines: Vec<_> = text.lines().collect();
+    lines
+        .windows(2)
+        .any(|pair| pair[0].starts_with("--- ") && pair[1].starts_with("+++ "))
+}
+
+/// Parse a unified diff. Hunks without file headers apply to `default_path` if given.
+fn parse_diff(text: &str, default_path: Option<&str>) -> Vec<FileChange> {
+    let mut changes: Vec<FileChange> = Vec::new();
+    let mut in_hunk = false;
+    let mut lines = text.lines().peekable();
+    while let Some(line) = lines.next() {
+        if line.starts_with("--- ") && lines.peek().is_some_and(|l| l.starts_with("+++ ")) {
+            let old = diff_path(&line[4..]);
+            let new = diff_path(&lines.next().unwrap()[4..]);
+            let change = if new == "/dev/null" {
+                Change::Delete
+            } else {
+                Change::Patch(Vec::new())
+            };
+            let path = if new == "/dev/null" { old } else { new };
+            changes.push(FileChange {
+                path: path.to_owned(),
+                change,
+            });
+            in_hunk = false;
+        } else if line.starts_with("@@") {
+            if changes.is_empty() {
+                let Some(path) = default_path else { continue };
+                changes.push(FileChange {
+                    path: path.to_owned(),
+                    change: Change::Patch(Vec::new()),
+                });
+            }
+            in_hunk = true;
+            if let Some(FileChange {
+                change: Change::Patch(hunks),
+                ..
+            }) = changes.last_mut()
+            {
+                hunks.push(Hunk {
+                    header: line.to_owned(),
+                    start: hunk_start(line),
+                    lines: Vec::new(),
+                });
+            }
+        } else if in_hunk {
+            let Some(FileChange {
+                change: Change::Patch(hunks),
+                ..
+            }) = changes.last_mut()
+            else {
+                continue;
+            };
+            let hunk = hunks.last_mut().unwrap();
+            match line.chars().next() {
+                Some(tag @ (' ' | '-' | '+')) => hunk.lines.push((tag, line[1..].to_owned())),
+                // `\ No newline at end of file`
+                Some('\\') => {}
+                // Responses often lose the leading space of empty context lines
+                None => hunk.lines.push((' ', String::new())),
+                Some(_) => in_hunk = false,
+            }
+        }
+    }
+    for change in &mut changes {
+        if let Change::Patch(hunks) = &mut change.change {
+            for hunk in hunks {
+                while hunk
+                    .lines
+                    .last()
+                    .is_some_and(|(t, l)| *t == ' ' && l.is_empty())
+                {
+                    hunk.lines.pop();
+                }
+            }
+        }
+    }
+    changes.retain(|c| !matches!(&c.change, Change::Patch(hunks) if hunks.is_empty()));
+    changes
+}
+
+/// The path in a `---` or `+++` line, without timestamp and the `a/` or `b/` prefix
+fn diff_path(text: &str) -> &str {
+    let path = text.split('\t').next().unwrap_or_default().trim();
+    path.strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path)
+}
+
+/// The old start line in a hunk header like `@@ -12,5 +12,7 @@`
+fn hunk_start(header: &str) -> usize {
+    header
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix('-'))
+        .and_then(|range| range.split(',').next())
+        .and_then(|start| start.parse().ok())
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hunks(diff: &str) -> Vec<Hunk> {
+        match parse_diff(diff, Some("file")).pop().unwrap().change {
+            Change::Patch(hunks) => hunks,
+            change => panic!("{change:?}"),
+        }
+    }
+
+    fn patch_all(old: &str, diff: &str) -> (String, usize) {
+        patch(old, &hunks(diff), |_, matches| Ok(matches)).unwrap()
+    }
+
+    #[test]
+    fn diffs_are_parsed() {
+        let diff = "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -3,3 +3,3 @@ fn\n one\n-two\n+2\n\n \
+                    three\n\\ No newline at end of file\n\n--- a/old.rs\n+++ /dev/null\n";
+        let changes = parse_diff(diff, None);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].path, "src/lib.rs");
+        let Change::Patch(hunks) = &changes[0].change else {
+            panic!("{:?}", changes[0].change);
+        };
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(hunks[0].start, 3);
+        let lines: Vec<_> = hunks[0]
+            .lines
+            .iter()
+            .map(|(t, l)| (*t, l.as_str()))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (' ', "one"),
+                ('-', "two"),
+                ('+', "2"),
+                (' ', ""),
+                (' ', "three")
+            ]
+        );
+        assert_eq!(changes[1].path, "old.rs");
+        assert!(matches!(changes[1].change, Change::Delete));
+
+        assert_eq!(
+            parse_diff("@@ -1 +1 @@\n-a\n+b\n", Some("x.rs"))[0].path,
+            "x.rs"
+        );
+        assert!(parse_diff("@@ -1 +1 @@\n-a\n+b\n", None).is_empty());
+    }
+
+    #[test]
+    fn line_endings_are_kept() {
+        let diff = "@@ -1,2 +1,3 @@\n a\n+b\n c\n";
+        assert_eq!(patch_all("a\r\nc\r\n", diff), ("a\r\nb\r\nc\r\n".into(), 1));
+        assert_eq!(patch_all("a\nc", diff), ("a\nb\nc".into(), 1));
+        // The last line moved up gets a line break
+        let diff = "@@ -1,2 +1,3 @@\n a\n c\n+d\n";
+        assert_eq!(patch_all("a\nc", diff).0, "a\nc\nd");
+        assert_eq!(patch_all("", "@@ -0,0 +1 @@\n+new\n").0, "new\n");
+    }
+
+    #[test]
+    fn context_is_taken_from_the_file() {
+        let diff = "@@ -1,3 +1,3 @@\n     keep\n-old\n+new\n  last\n";
+        let old = "    keep  \nold\n last\t\n";
+        assert_eq!(
+            patch_all(old, diff),
+            ("    keep  \nnew\n last\t\n".into(), 1)
+        );
+    }
+
+    #[test]
+    fn hunks_not_matching_are_skipped() {
+        let diff = "@@ -1,2 +1,2 @@\n a\n-b\n+B\n@@ -5,2 +5,2 @@\n x\n-y\n+Y\n@@ -9,2 +9,2 @@\n p\n-q\n+Q\n";
+        let old = "a\nb\n1\n2\n3\n4\np\nq\n";
+        let mut seen = Vec::new();
+        let (content, applied) = patch(old, &hunks(diff), |hunk, matches| {
+            seen.push((hunk.start, matches));
+            Ok(true)
+        })
+        .unwrap();
+        assert_eq!((content.as_str(), applied), ("a\nB\n1\n2\n3\n4\np\nQ\n", 2));
+        assert_eq!(seen, [(1, true), (5, false), (9, true)]);
+        // Declined hunks are left out
+        let (content, applied) = patch(old, &hunks(diff), |hunk, _| Ok(hunk.start == 9)).unwrap();
+        assert_eq!((content.as_str(), applied), ("a\nb\n1\n2\n3\n4\np\nQ\n", 1));
+    }
+
+    #[test]
+    fn file_blocks_are_applied_as_hunks() {
+        let old: String = (1..=3000).map(|i| format!("line {i}\n")).collect();
+        let new = old
+            .replace("line 10\n", "line ten\n")
+            .replace("line 2000\n", "")
+            + "line 3001\n";
+        let diff = diff::diff_lines(&old, &new);
+        let hunks = hunks_of(&diff);
+        assert_eq!(hunks.len(), 3);
+        assert_eq!(hunks[0].header, "@@ -7,7 +7,7 @@");
+        let (content, applied) = patch(&old, &hunks, |_, matches| Ok(matches)).unwrap();
+        assert_eq!((content, applied), (new, 3));
+    }
+
+    #[test]
+    fn paths_outside_are_refused() {
+        let root = std::env::temp_dir().join(format!("heygpt-patch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let dir = root.join("work");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        assert!(inside(&dir, "src/main.rs").is_ok());
+        assert!(inside(&dir, "new/nested/file.rs").is_ok());
+        assert!(inside(&dir, "./src/../src/main.rs").is_ok());
+        assert!(inside(&dir, "../outside.rs").is_err());
+        assert!(inside(&dir, "new/../../outside.rs").is_err());
+        assert!(inside(&dir, root.join("outside.rs").to_str().unwrap()).is_err());
+        assert!(inside(&dir, dir.join("src/lib.rs").to_str().unwrap()).is_ok());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&root, dir.join("link")).unwrap();
+            assert!(inside(&dir, "link/outside.rs").is_err());
+            assert!(inside(&dir, "link/work/src/main.rs").is_ok());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+//! Locations of heygpt's files. Config files live in `$XDG_CONFIG_HOME/heygpt/` and data files
+//! in `$XDG_DATA_HOME/heygpt/` on Linux, or the platform-appropriate directories elsewhere.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::warning;
+
+const APP_DIR: &str = "heygpt";
+
+/// Legacy config file in home directory
+const LEGACY_CONFIG_FILE: &str = ".heygpt.toml";
+
+/// Legacy readline history file in home directory
+const LEGACY_HISTORY_FILE: &str = ".heygpt_history";
+
+/// Directory of config files, e.g. `~/.config/heygpt`
+pub fn config_dir() -> Result<PathBuf> {
+    dirs::config_dir()
+        .map(|dir| dir.join(APP_DIR))
+        .ok_or_else(|| anyhow!("Cannot find config directory"))
+}
+
+/// Directory of data files such as history, e.g. `~/.local/share/heygpt`
+pub fn data_dir() -> Result<PathBuf> {
+    dirs::data_dir()
+        .map(|dir| dir.join(APP_DIR))
+        .ok_or_else(|| anyhow!("Cannot find data directory"))
+}
+
+/// The global config file
+pub fn config_file() -> Result<PathBuf> {
+    Ok(config_dir()?.join("config.toml"))
+}
+
+/// Commands run at the start of interactive mode
+pub fn init_file() -> Result<PathBuf> {
+    Ok(config_dir()?.join("init"))
+}
+
+/// Readline history of interactive mode
+pub fn history_file() -> Result<PathBuf> {
+    Ok(data_dir()?.join("history"))
+}
+
+/// Log of tools called by the model, e.g. `~/.local/share/heygpt/tools.log`
+pub fn tools_log() -> Result<PathBuf> {
+    Ok(data_dir()?.join("tools.log"))
+}
+
+/// Transcripts of `--agent` runs
+pub fn transcripts_dir() -> Result<PathBuf> {
+    Ok(data_dir()?.join("transcripts"))
+}
+
+/// Embedding indexes built by `heygpt index`
+pub fn indexes_dir() -> Result<PathBuf> {
+    Ok(data_dir()?.join("indexes"))
+}
+
+/// `~/.heygpt.toml`, used before config directory was adopted
+pub fn legacy_config_file() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(LEGACY_CONFIG_FILE))
+}
+
+/// Expand `~` at the start of a path typed by the user to the home directory, as shells on
+/// Windows and commands in interactive mode don't
+pub fn expand_home(path: &str) -> PathBuf {
+    let rest = match path.strip_prefix('~') {
+        Some("") => "",
+        Some(rest) if rest.starts_with(['/', '\\']) => &rest[1..],
+        _ => return PathBuf::from(path),
+    };
+    match dirs::home_dir() {
+        Some(home) => home.join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Create parent directory of the file if it doesn't exist
+pub fn ensure_parent_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| anyhow!("Failed to create directory {}: {e}", parent.display()))?;
+    }
+    Ok(())
+}
+
+/// Move files from legacy locations in home directory to the new locations.
+/// Failures are reported as warnings; the legacy config file is still read in that case.
+pub fn migrate() {
+    let Some(home) = dirs::home_dir() else {
+        return;
+    };
+    let moves = [
+        (home.join(LEGACY_CONFIG_FILE), config_file()),
+        (home.join(LEGACY_HISTORY_FILE), history_file()),
+    ];
+    for (legacy, new) in moves {
+        let Ok(new) = new else {
+            continue;
+        };
+        if !legacy.is_file() || new.exists() {
+            continue;
+        }
+        match move_file(&legacy, &new) {
+            Ok(()) => eprintln!("Moved {} to {}", legacy.display(), new.display()),
+            Err(err) => warning(format!(
+                "Failed to move {} to {}: {err}",
+                legacy.display(),
+                new.display()
+            )),
+        }
+    }
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    ensure_parent_dir(to)?;
+    if std::fs::rename(from, to).is_err() {
+        // `rename` doesn't work across file systems
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
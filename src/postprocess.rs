@@ -0,0 +1,55 @@
+//! Piping responses through filter commands, e.g. to strip code fences or run a formatter
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail, Result};
+
+/// Pipe the text through the shell commands in turn and return the output of the last one.
+/// The newline that commands like `sed` add at the end is removed if the text had none.
+pub fn run(commands: &[String], text: &str) -> Result<String> {
+    let mut output = text.to_owned();
+    for command in commands {
+        output = pipe("post-process command", command, &output)?;
+    }
+    if !text.ends_with('\n') && output.ends_with('\n') {
+        output.pop();
+    }
+    Ok(output)
+}
+
+/// Run the shell command with the input on stdin and return its output. `what` names the
+/// command in error messages.
+pub fn pipe(what: &str, command: &str, input: &str) -> Result<String> {
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run {what} `{command}`: {e}"))?;
+    // Write in another thread so that a command producing output early doesn't block us
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_owned();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    // The command may exit without reading all of its input
+    let _ = writer.join();
+    if !output.status.success() {
+        bail!("The {what} `{command}` exited with {}", output.status);
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|_| anyhow!("Output of the {what} `{command}` is not UTF-8"))
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
@@ -0,0 +1,142 @@
+use clap::ValueEnum;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+/// Backend serving the API requests
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Provider {
+    /// Detect the provider from `--api-base-url`, falling back to `openai`
+    #[default]
+    Auto,
+    /// OpenAI or any OpenAI-compatible endpoint at `--api-base-url`
+    Openai,
+    /// OpenRouter, https://openrouter.ai
+    Openrouter,
+    /// Groq, https://groq.com
+    Groq,
+    /// Together AI, https://together.ai
+    Together,
+    /// Mistral La Plateforme, https://mistral.ai
+    Mistral,
+    /// Amazon Bedrock, with AWS credentials from the environment or `~/.aws/credentials`
+    Bedrock,
+    /// The server of llama.cpp, which listens on port 8080 by default
+    LlamaCpp,
+    /// Built-in fake server replaying canned responses, for tests and offline demos
+    Mock,
+}
+
+impl Provider {
+    /// Guess the provider from the API base URL
+    pub fn detect(api_base_url: &str) -> Self {
+        let Ok(url) = Url::parse(api_base_url) else {
+            return Provider::Openai;
+        };
+        match (url.host_str().unwrap_or_default(), url.port()) {
+            ("openrouter.ai", _) => Provider::Openrouter,
+            ("api.groq.com", _) => Provider::Groq,
+            ("api.together.xyz" | "api.together.ai", _) => Provider::Together,
+            ("api.mistral.ai", _) => Provider::Mistral,
+            _ if crate::bedrock::region_of_url(api_base_url).is_some() => Provider::Bedrock,
+            ("localhost" | "127.0.0.1" | "[::1]", Some(8080)) => Provider::LlamaCpp,
+            _ => Provider::Openai,
+        }
+    }
+
+    /// Base URL of the API, used with `--provider` unless `--api-base-url` is given
+    pub fn default_base_url(self) -> Option<&'static str> {
+        match self {
+            Provider::Openrouter => Some("https://openrouter.ai/api/v1"),
+            Provider::Groq => Some("https://api.groq.com/openai/v1"),
+            Provider::Together => Some("https://api.together.xyz/v1"),
+            Provider::Mistral => Some("https://api.mistral.ai/v1"),
+            Provider::LlamaCpp => Some("http://localhost:8080/v1"),
+            _ => None,
+        }
+    }
+
+    /// Headers identifying the app. OpenRouter shows them in its rankings and activity logs.
+    pub fn headers(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Provider::Openrouter => &[
+                ("HTTP-Referer", "https://github.com/fuyufjh/heygpt"),
+                ("X-Title", "heygpt"),
+            ],
+            _ => &[],
+        }
+    }
+
+    /// Whether the API reports the cost of a request in `usage` when asked to. OpenRouter
+    /// does, which is more accurate than estimating it from pricing.
+    pub fn reports_cost(self) -> bool {
+        self == Provider::Openrouter
+    }
+
+    /// Append the path of the API to a base URL given without one, e.g. `https://api.groq.com`
+    pub fn complete_base_url(self, api_base_url: &str) -> String {
+        let path = match self {
+            Provider::Openrouter => "/api/v1",
+            Provider::Groq => "/openai/v1",
+            Provider::Together | Provider::Mistral | Provider::LlamaCpp => "/v1",
+            Provider::Auto | Provider::Openai | Provider::Bedrock | Provider::Mock => {
+                return api_base_url.to_owned()
+            }
+        };
+        match Url::parse(api_base_url) {
+            Ok(url) if url.path() == "/" => format!("{}{path}", api_base_url.trim_end_matches('/')),
+            _ => api_base_url.to_owned(),
+        }
+    }
+
+    /// Whether requests need an API key. A local llama.cpp server doesn't by default, and
+    /// Bedrock uses AWS credentials instead.
+    pub fn requires_api_key(self) -> bool {
+        !matches!(self, Provider::LlamaCpp | Provider::Bedrock)
+    }
+
+    /// Environment variable holding the API key of the provider, for models of other providers
+    /// than the configured one in `--compare`
+    pub fn api_key_env(self) -> Option<&'static str> {
+        match self {
+            Provider::Openai => Some("OPENAI_API_KEY"),
+            Provider::Openrouter => Some("OPENROUTER_API_KEY"),
+            Provider::Groq => Some("GROQ_API_KEY"),
+            Provider::Together => Some("TOGETHER_API_KEY"),
+            Provider::Mistral => Some("MISTRAL_API_KEY"),
+            Provider::Auto | Provider::Bedrock | Provider::LlamaCpp | Provider::Mock => None,
+        }
+    }
+
+    /// Split a model prefixed with a provider, e.g. `groq:llama-3.1-8b-instant`. Model names may
+    /// have colons too, e.g. `llama3:8b`, so only the name of a provider is split off.
+    pub fn split_model(model: &str) -> (Option<Self>, &str) {
+        match model.split_once(':') {
+            Some((name, rest)) => match Provider::from_str(name, true) {
+                Ok(provider) if provider != Provider::Auto => (Some(provider), rest),
+                _ => (None, model),
+            },
+            None => (None, model),
+        }
+    }
+
+    /// Model used unless `--model` is given, for providers without the default `gpt-3.5-turbo`
+    pub fn default_model(self) -> Option<&'static str> {
+        match self {
+            Provider::Openrouter => Some("openai/gpt-3.5-turbo"),
+            Provider::Mistral => Some("mistral-small-latest"),
+            _ => None,
+        }
+    }
+
+    /// Whether the API accepts `logit_bias`. Mistral rejects it as an unknown parameter.
+    pub fn supports_logit_bias(self) -> bool {
+        self != Provider::Mistral
+    }
+
+    /// Whether the API accepts `stream_options` to report usage of streaming responses.
+    /// Mistral rejects unknown parameters, and reports usage in the last chunk anyway.
+    pub fn supports_stream_options(self) -> bool {
+        self != Provider::Mistral
+    }
+}
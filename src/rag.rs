@@ -0,0 +1,229 @@
+//! Retrieval over local notes: `heygpt index DIR` splits the text files of a directory into
+//! chunks and stores their embeddings in `indexes` of the data directory, and `--rag NAME`
+//! attaches the chunks most similar to each prompt
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, ValueHint};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::{attach, embed, http_client, paths, token, Options};
+
+/// Prompts longer than this are cut before being embedded as queries
+const MAX_QUERY_CHARS: usize = 8000;
+
+#[derive(Args, Debug)]
+pub struct IndexArgs {
+    /// The directory of notes
+    #[arg(value_hint = ValueHint::DirPath)]
+    pub dir: String,
+
+    /// Name of the index, used by `--rag`. Defaults to the name of the directory.
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Only index files whose paths match any of the globs, e.g. `*.md`
+    #[arg(long, value_delimiter = ',')]
+    pub include: Vec<String>,
+
+    /// The embedding model
+    #[arg(long, default_value = "text-embedding-3-small")]
+    pub model: String,
+
+    /// Most tokens in a chunk
+    #[arg(long, default_value_t = 300)]
+    pub chunk_tokens: usize,
+
+    /// Number of chunks embedded per request
+    #[arg(long, default_value_t = 100)]
+    pub batch_size: usize,
+}
+
+/// An index stored as JSON
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Index {
+    #[serde(skip)]
+    pub name: String,
+    pub dir: String,
+    pub model: String,
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Chunk {
+    pub path: String,
+    /// Line where the chunk starts, from 1
+    pub line: usize,
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+/// Build or update the index of the directory. Embeddings of unchanged chunks are kept from the
+/// previous index, so only new and changed chunks are sent.
+pub async fn index(options: Options, args: IndexArgs) -> Result<()> {
+    if args.batch_size == 0 || args.chunk_tokens == 0 {
+        bail!("Batch size and chunk tokens must be at least 1");
+    }
+    let name = match &args.name {
+        Some(name) => name.clone(),
+        None => Path::new(&args.dir)
+            .canonicalize()?
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .ok_or_else(|| anyhow!("Name of the index is required for `{}`", args.dir))?,
+    };
+    let path = index_file(&name)?;
+
+    let files = attach::text_files(&args.dir, &args.include)?;
+    let mut chunks = Vec::new();
+    for (path, content) in &files {
+        for (line, text) in token::split(content, args.chunk_tokens) {
+            chunks.push(Chunk {
+                path: path.clone(),
+                line,
+                text,
+                embedding: Vec::new(),
+            });
+        }
+    }
+    if chunks.is_empty() {
+        bail!("No text to index in `{}`", args.dir);
+    }
+
+    let mut previous: HashMap<String, Vec<f32>> = match load(&name) {
+        Ok(index) if index.model == args.model => index
+            .chunks
+            .into_iter()
+            .map(|c| (c.text, c.embedding))
+            .collect(),
+        _ => HashMap::new(),
+    };
+    for chunk in &mut chunks {
+        if let Some(embedding) = previous.remove(&chunk.text) {
+            chunk.embedding = embedding;
+        }
+    }
+
+    let client = http_client(&options)?;
+    let pending: Vec<_> = (0..chunks.len())
+        .filter(|&i| chunks[i].embedding.is_empty())
+        .collect();
+    for (n, batch) in pending.chunks(args.batch_size).enumerate() {
+        eprintln!(
+            "Embedding chunks {}-{} of {}",
+            n * args.batch_size + 1,
+            n * args.batch_size + batch.len(),
+            pending.len()
+        );
+        let texts = batch.iter().map(|&i| chunks[i].text.clone()).collect();
+        let embeddings = embed::embed(&client, &options, &args.model, texts, None).await?;
+        for (&i, embedding) in batch.iter().zip(embeddings) {
+            chunks[i].embedding = embedding;
+        }
+    }
+
+    let index = Index {
+        name: name.clone(),
+        dir: args.dir.clone(),
+        model: args.model.clone(),
+        chunks,
+    };
+    paths::ensure_parent_dir(&path)?;
+    // Written aside and renamed, so an interrupted run keeps the previous index
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_string(&index)?)
+        .map_err(|e| anyhow!("Failed to write index {}: {e}", temp.display()))?;
+    std::fs::rename(&temp, &path)?;
+    println!(
+        "Indexed {} chunks of {} files into `{name}` ({} embedded, {} unchanged)",
+        index.chunks.len(),
+        files.len(),
+        pending.len(),
+        index.chunks.len() - pending.len()
+    );
+    Ok(())
+}
+
+/// Load the index of the name
+pub fn load(name: &str) -> Result<Index> {
+    let path = index_file(name)?;
+    let content = std::fs::read_to_string(&path).map_err(|e| {
+        anyhow!(
+            "Failed to read index `{name}`: {e}. Build it with `heygpt index DIR --name {name}`"
+        )
+    })?;
+    let mut index: Index = serde_json::from_str(&content)
+        .map_err(|e| anyhow!("Invalid index {}: {e}", path.display()))?;
+    index.name = name.to_owned();
+    Ok(index)
+}
+
+/// Names of the indexes built
+pub fn list() -> Result<Vec<String>> {
+    let mut names: Vec<_> = match std::fs::read_dir(paths::indexes_dir()?) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|e| {
+                let path = e.path();
+                let name = path.file_stem()?.to_string_lossy().into_owned();
+                (path.extension()? == "json").then_some(name)
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    Ok(names)
+}
+
+fn index_file(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        bail!("Invalid name of index `{name}`");
+    }
+    Ok(paths::indexes_dir()?.join(format!("{name}.json")))
+}
+
+impl Index {
+    /// The chunks most similar to the query, in code blocks labeled with their locations
+    pub async fn context(
+        &self,
+        client: &Client,
+        options: &Options,
+        query: &str,
+        top_k: usize,
+    ) -> Result<String> {
+        let query: String = query.chars().take(MAX_QUERY_CHARS).collect();
+        let embedding = embed::embed(client, options, &self.model, vec![query], None)
+            .await?
+            .remove(0);
+        let mut scored: Vec<_> = self
+            .chunks
+            .iter()
+            .map(|c| (cosine(&embedding, &c.embedding), c))
+            .filter(|(score, _)| *score > 0.0)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let mut context = format!("Notes relevant to the prompt, from index `{}`:", self.name);
+        for (_, chunk) in scored.iter().take(top_k) {
+            context.push_str("\n\n");
+            context.push_str(&attach::fence(
+                &format!("{}:{}", chunk.path, chunk.line),
+                &chunk.text,
+            ));
+        }
+        Ok(context)
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
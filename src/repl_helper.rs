@@ -1,34 +1,251 @@
 use std::borrow::Cow;
 
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
 use console::style;
-use rustyline::completion::Completer;
+use rustyline::completion::{Completer, FilenameCompleter};
 use rustyline::highlight::Highlighter;
-use rustyline::hint::Hinter;
+use rustyline::hint::{Hinter, HistoryHinter};
 use rustyline::validate::{ValidationContext, ValidationResult, Validator};
 use rustyline::Helper;
+use rustyline::{Cmd, Context, KeyCode, KeyEvent, Modifiers, Movement};
+use serde::{Deserialize, Serialize};
+
+use crate::settings;
+
+/// Key bindings of line editor in interactive mode
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum EditMode {
+    #[default]
+    Emacs,
+    Vi,
+}
+
+impl From<EditMode> for rustyline::EditMode {
+    fn from(mode: EditMode) -> Self {
+        match mode {
+            EditMode::Emacs => rustyline::EditMode::Emacs,
+            EditMode::Vi => rustyline::EditMode::Vi,
+        }
+    }
+}
+
+/// Parse a key like `ctrl-j`, `alt-enter` or `f1`
+pub fn parse_key(spec: &str) -> Result<KeyEvent> {
+    let lower = spec.to_lowercase();
+    let mut parts: Vec<_> = lower.split('-').collect();
+    // `-` itself as the key, e.g. `alt--`
+    if lower.ends_with("--") {
+        parts.pop();
+        *parts.last_mut().unwrap() = "-";
+    }
+    let key = parts.pop().unwrap_or_default();
+    let mut mods = Modifiers::NONE;
+    for modifier in parts {
+        mods |= match modifier {
+            "ctrl" | "c" => Modifiers::CTRL,
+            "alt" | "meta" | "m" => Modifiers::ALT,
+            "shift" | "s" => Modifiers::SHIFT,
+            _ => bail!("Unknown modifier `{modifier}` in key `{spec}`"),
+        };
+    }
+    let code = match key {
+        "enter" | "return" => KeyCode::Enter,
+        "tab" => KeyCode::Tab,
+        "esc" | "escape" => KeyCode::Esc,
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        f if f.len() > 1 && f.starts_with('f') && f[1..].parse::<u8>().is_ok() => {
+            KeyCode::F(f[1..].parse().unwrap())
+        }
+        c if c.chars().count() == 1 => return Ok(KeyEvent::new(c.chars().next().unwrap(), mods)),
+        _ => bail!("Unknown key `{spec}`"),
+    };
+    Ok(KeyEvent(code, mods))
+}
+
+/// Parse an editor action like `newline` or `accept-line`
+pub fn parse_action(name: &str) -> Result<Cmd> {
+    Ok(match name {
+        "newline" => Cmd::Newline,
+        "accept-line" => Cmd::AcceptLine,
+        "complete" => Cmd::Complete,
+        "complete-hint" => Cmd::CompleteHint,
+        "clear-screen" => Cmd::ClearScreen,
+        "undo" => Cmd::Undo(1),
+        "interrupt" => Cmd::Interrupt,
+        "end-of-file" => Cmd::EndOfFile,
+        "kill-line" => Cmd::Kill(Movement::EndOfLine),
+        "kill-whole-line" => Cmd::Kill(Movement::WholeLine),
+        "previous-history" => Cmd::PreviousHistory,
+        "next-history" => Cmd::NextHistory,
+        "history-search-backward" => Cmd::HistorySearchBackward,
+        "history-search-forward" => Cmd::HistorySearchForward,
+        "reverse-search-history" => Cmd::ReverseSearchHistory,
+        "noop" => Cmd::Noop,
+        _ => return Err(anyhow!("Unknown key binding action `{name}`")),
+    })
+}
+
+/// Commands of interactive mode, for completion
+const COMMANDS: &[&str] = &[
+    "help",
+    "back",
+    "history",
+    "last",
+    "apply",
+    "stats",
+    "continue",
+    "retry",
+    "clear",
+    "reset",
+    "assistant",
+    "edit",
+    "fork",
+    "switch",
+    "search",
+    "rag",
+    "paste",
+    "file",
+    "url",
+    "models",
+    "set",
+    "unset",
+    "show",
+];
 
 /// The rustyline helper for interactive mode.
-/// Currently it's mostly for highlighting the prompt correctly.
+/// It highlights the prompt, completes commands and hints from history.
 #[derive(Clone, Debug, Default)]
-pub struct ReplHelper;
+pub struct ReplHelper {
+    /// Names of conversation branches, for completing `\switch`
+    pub branches: Vec<String>,
+
+    /// Whether the last request failed, to hint `\retry`
+    pub failed: bool,
+}
 
 impl Helper for ReplHelper {}
 
 impl Validator for ReplHelper {
-    fn validate(&self, _ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
-        Ok(ValidationResult::Valid(None))
+    /// Keep editing if a code fence is not closed, or the input ends with a backslash
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+        if in_code_fence(input) || (input.ends_with('\\') && input != "\\") {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
     }
 }
 
+/// Whether the text ends inside a ``` code fence
+fn in_code_fence(text: &str) -> bool {
+    text.lines()
+        .filter(|line| line.trim_start().starts_with("```"))
+        .count()
+        % 2
+        == 1
+}
+
+/// Remove backslashes used for line continuation, except those in code fences
+pub fn join_continued_lines(text: &str) -> String {
+    let mut result = String::new();
+    let mut fenced = false;
+    for line in text.split_inclusive('\n') {
+        if line.trim_start().starts_with("```") {
+            fenced = !fenced;
+        }
+        match line.strip_suffix("\\\n") {
+            Some(line) if !fenced => {
+                result.push_str(line);
+                result.push('\n');
+            }
+            _ => result.push_str(line),
+        }
+    }
+    result
+}
+
 impl Completer for ReplHelper {
     type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let Some(input) = line[..pos].strip_prefix('\\') else {
+            return Ok((pos, Vec::new()));
+        };
+        let (start, word, candidates): (_, _, Vec<&str>) = match input.split_once(' ') {
+            None => (1, input, COMMANDS.to_vec()),
+            Some(("switch", arg)) => (
+                pos - arg.trim_start().len(),
+                arg.trim_start(),
+                self.branches.iter().map(String::as_str).collect(),
+            ),
+            Some(("set" | "unset", arg)) if !arg.trim_start().contains(' ') => (
+                pos - arg.trim_start().len(),
+                arg.trim_start(),
+                settings::PARAMETERS.to_vec(),
+            ),
+            Some(("show", arg)) => (
+                pos - arg.trim_start().len(),
+                arg.trim_start(),
+                vec!["settings"],
+            ),
+            Some(("file", _)) => {
+                let (start, pairs) = FilenameCompleter::new().complete_path(line, pos)?;
+                return Ok((start, pairs.into_iter().map(|p| p.replacement).collect()));
+            }
+            Some(_) => return Ok((pos, Vec::new())),
+        };
+        let matches = candidates
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .map(str::to_owned)
+            .collect();
+        Ok((start, matches))
+    }
 }
 
 impl Hinter for ReplHelper {
     type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        if line.is_empty() {
+            return self.failed.then(|| "\\retry".to_owned());
+        }
+        if let Some(input) = line.strip_prefix('\\') {
+            return COMMANDS
+                .iter()
+                .find(|c| c.starts_with(input) && c.len() > input.len())
+                .map(|c| c[input.len()..].to_owned());
+        }
+        // Like fish shell, hint the rest of the latest history entry starting with the input
+        HistoryHinter {}.hint(line, pos, ctx)
+    }
 }
 
 impl Highlighter for ReplHelper {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(style(hint).dim().to_string())
+    }
+
     fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
         &'s self,
         prompt: &'p str,
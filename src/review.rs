@@ -0,0 +1,213 @@
+//! `heygpt review`: review a git diff and print comments per file
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+use console::style;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::git::{git, truncate_diff};
+use crate::model::{Message, ResponseFormat};
+use crate::spinner::Spinner;
+use crate::token::estimate_tokens;
+use crate::{error, http_client, Options, Session};
+
+#[derive(Args, Debug)]
+pub struct ReviewArgs {
+    /// Revision range to review, e.g. `HEAD~3..` or `main...feature`.
+    /// Reviews uncommitted changes by default.
+    #[arg(long)]
+    pub range: Option<String>,
+
+    /// Print comments as a JSON array, for tools
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// System prompt of review requests
+const REVIEW_PROMPT: &str = r#"You are an experienced code reviewer. Review the given git diff for bugs, security issues, performance problems and unclear code. Lines of the new version are prefixed with their line numbers. Only comment on changed code, and skip praise and trivial style nits. Respond with a JSON object of the form {"comments": [{"file": "path/to/file", "line": 42, "severity": "error", "comment": "..."}]}, where severity is one of "error", "warning" and "suggestion". Respond with {"comments": []} if there is nothing to improve."#;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+    #[serde(other)]
+    Suggestion,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ReviewComment {
+    file: String,
+    #[serde(default)]
+    line: Option<u64>,
+    severity: Severity,
+    comment: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReviewResponse {
+    comments: Vec<ReviewComment>,
+}
+
+pub async fn run(options: Options, args: ReviewArgs) -> Result<()> {
+    let diff = match &args.range {
+        Some(range) => git(&["diff", range])?,
+        None => git(&["diff", "HEAD"])?,
+    };
+    if diff.trim().is_empty() {
+        bail!("No changes to review");
+    }
+
+    let client = http_client(&options)?;
+    let mut session = Session::new(options, client, false, false);
+    // Leave room for the system prompt and the comments
+    let budget = session.capability().context_window / 2;
+    let chunks = chunk_diff(&annotate(&diff), budget);
+
+    let mut comments = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        session.messages = vec![
+            Message {
+                role: "system".to_string(),
+                content: REVIEW_PROMPT.to_string(),
+                ..Default::default()
+            },
+            Message {
+                role: "user".to_string(),
+                content: chunk.clone(),
+                ..Default::default()
+            },
+        ];
+        let mut data = session.build_request()?;
+        data.set_stream(false);
+        data.response_format = Some(ResponseFormat::json_object());
+
+        let label = match chunks.len() {
+            1 => session.options.model.clone(),
+            n => format!("{} ({}/{n})", session.options.model, i + 1),
+        };
+        let spinner = atty::is(atty::Stream::Stderr).then(|| Spinner::new(&label));
+        let response = session.fetch(&data).await;
+        drop(spinner);
+
+        let content = response?
+            .choices
+            .into_iter()
+            .next()
+            .map(|c| c.message.content)
+            .ok_or_else(|| anyhow!("No choice in response"))?;
+        debug!("review response: {content}");
+        let response: ReviewResponse = serde_json::from_str(json_object(&content))
+            .map_err(|e| anyhow!("Invalid review comments from the model: {e}"))?;
+        comments.extend(response.comments);
+    }
+
+    let text = if args.json {
+        format!("{}\n", serde_json::to_string_pretty(&comments)?)
+    } else {
+        format_comments(comments)
+    };
+    write!(std::io::stdout(), "{text}").map_err(error::output)
+}
+
+/// Format comments grouped per file, ordered by line
+fn format_comments(comments: Vec<ReviewComment>) -> String {
+    if comments.is_empty() {
+        return "No comments.\n".to_owned();
+    }
+    let mut files: BTreeMap<String, Vec<ReviewComment>> = BTreeMap::new();
+    for comment in comments {
+        files.entry(comment.file.clone()).or_default().push(comment);
+    }
+    let mut text = String::new();
+    for (file, mut comments) in files {
+        comments.sort_by_key(|c| (c.line, c.severity));
+        text.push_str(&format!("{}\n", style(file).bold()));
+        for comment in comments {
+            let line = comment.line.map(|l| l.to_string()).unwrap_or_default();
+            let severity = match comment.severity {
+                Severity::Error => style("error").red().bold(),
+                Severity::Warning => style("warning").yellow().bold(),
+                Severity::Suggestion => style("suggestion").cyan(),
+            };
+            text.push_str(&format!(
+                "  {:>5} {severity}: {}\n",
+                style(line).dim(),
+                comment.comment
+            ));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Prefix lines of the new version in the diff with their line numbers, so that the model
+/// doesn't need to count them from hunk headers
+fn annotate(diff: &str) -> String {
+    let mut annotated = String::new();
+    let mut line_number: Option<u64> = None;
+    for line in diff.lines() {
+        if line.starts_with("diff --git ") {
+            line_number = None;
+        } else if let Some(header) = line.strip_prefix("@@ ") {
+            // e.g. `@@ -12,7 +12,8 @@ fn main() {`
+            line_number = header
+                .split_whitespace()
+                .find_map(|range| range.strip_prefix('+'))
+                .and_then(|range| range.split(',').next())
+                .and_then(|start| start.parse().ok());
+        } else if let Some(n) = line_number.as_mut() {
+            if line.starts_with('+') || line.starts_with(' ') {
+                annotated.push_str(&format!("{n:>5} "));
+                *n += 1;
+            } else if line.starts_with('-') {
+                annotated.push_str("      ");
+            }
+        }
+        annotated.push_str(line);
+        annotated.push('\n');
+    }
+    annotated
+}
+
+/// Split the diff into chunks of whole files up to `max_tokens` each. Files larger than that
+/// are truncated.
+fn chunk_diff(diff: &str, max_tokens: usize) -> Vec<String> {
+    let mut files = Vec::new();
+    for line in diff.split_inclusive('\n') {
+        if line.starts_with("diff --git ") || files.is_empty() {
+            files.push(String::new());
+        }
+        files.last_mut().unwrap().push_str(line);
+    }
+
+    let mut chunks: Vec<String> = Vec::new();
+    let mut tokens = 0;
+    for file in files {
+        let file = truncate_diff(&file, max_tokens);
+        let file_tokens = estimate_tokens(&file);
+        match chunks.last_mut() {
+            Some(chunk) if tokens + file_tokens <= max_tokens => {
+                chunk.push_str(&file);
+                tokens += file_tokens;
+            }
+            _ => {
+                chunks.push(file);
+                tokens = file_tokens;
+            }
+        }
+    }
+    chunks
+}
+
+/// The JSON object in the content, which some models wrap in a code fence
+fn json_object(content: &str) -> &str {
+    match (content.find('{'), content.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &content[start..=end],
+        _ => content,
+    }
+}
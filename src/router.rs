@@ -0,0 +1,81 @@
+//! Routing of prompts between a cheap model and the stronger `--model` by simple heuristics,
+//! enabled by `--auto-model`
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::Message;
+use crate::token::estimate_message_tokens;
+
+const DEFAULT_CHEAP_MODEL: &str = "gpt-4o-mini";
+const DEFAULT_MAX_CHEAP_TOKENS: usize = 1000;
+
+/// Rules of `--auto-model` in `[router]` of the config file, e.g.
+///
+/// ```toml
+/// [router]
+/// cheap_model = "gpt-4o-mini"
+/// max_cheap_tokens = 500
+/// strong_keywords = ["prove", "refactor"]
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RouterConfig {
+    /// Model for short and simple prompts (default: gpt-4o-mini)
+    pub cheap_model: Option<String>,
+    /// Conversations estimated to take more tokens go to the strong model (default: 1000)
+    pub max_cheap_tokens: Option<usize>,
+    /// Prompts containing any of these words, ignoring case, go to the strong model
+    #[serde(default)]
+    pub strong_keywords: Vec<String>,
+}
+
+/// Choose the model for the conversation: the cheap one, unless the last prompt contains code
+/// or a strong keyword, the conversation is long, or the prompt is marked `hard`. Returns the
+/// model and the reason.
+pub fn route<'a>(
+    config: &'a RouterConfig,
+    strong_model: &'a str,
+    messages: &[Message],
+    hard: bool,
+) -> (&'a str, String) {
+    let prompt = messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .map_or("", |m| m.content.as_str());
+    let lowercase = prompt.to_lowercase();
+    let tokens = estimate_message_tokens(messages);
+    let max_tokens = config.max_cheap_tokens.unwrap_or(DEFAULT_MAX_CHEAP_TOKENS);
+
+    let reason = if hard {
+        Some("marked hard".to_owned())
+    } else if looks_like_code(prompt) {
+        Some("contains code".to_owned())
+    } else if tokens > max_tokens {
+        Some(format!("about {tokens} tokens"))
+    } else {
+        config
+            .strong_keywords
+            .iter()
+            .find(|k| lowercase.contains(&k.to_lowercase()))
+            .map(|k| format!("mentions `{k}`"))
+    };
+    match reason {
+        Some(reason) => (strong_model, reason),
+        None => (
+            config.cheap_model.as_deref().unwrap_or(DEFAULT_CHEAP_MODEL),
+            "simple prompt".to_owned(),
+        ),
+    }
+}
+
+/// Whether the text has a fenced code block, or several lines ending like statements or blocks
+fn looks_like_code(text: &str) -> bool {
+    text.contains("```")
+        || text
+            .lines()
+            .map(str::trim_end)
+            .filter(|l| l.ends_with(';') || l.ends_with('{') || l.ends_with('}'))
+            .count()
+            >= 3
+}
@@ -0,0 +1,142 @@
+//! Web search with the backend in `[web_search]` of the config file, for the `search` tool and
+//! for `--search`, which attaches results for the prompt
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::html;
+
+const DEFAULT_COUNT: usize = 5;
+
+const BRAVE_URL: &str = "https://api.search.brave.com/res/v1/web/search";
+const BING_URL: &str = "https://api.bing.microsoft.com/v7.0/search";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Searxng,
+    Brave,
+    Bing,
+}
+
+/// The search backend, e.g.
+///
+/// ```toml
+/// [web_search]
+/// backend = "searxng"
+/// url = "http://localhost:8888"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SearchConfig {
+    pub backend: Option<Backend>,
+    /// Base URL of the SearxNG instance, or another endpoint of the API
+    pub url: Option<String>,
+    /// API key of Brave or Bing, or in `BRAVE_API_KEY` or `BING_API_KEY`
+    pub api_key: Option<String>,
+    /// Number of results (default: 5)
+    pub count: Option<usize>,
+}
+
+impl SearchConfig {
+    pub fn is_configured(&self) -> bool {
+        self.backend.is_some()
+    }
+}
+
+pub struct SearchResult {
+    pub title: String,
+    pub url: String,
+    pub snippet: String,
+}
+
+/// Search the web for the query
+pub async fn search(
+    client: &Client,
+    config: &SearchConfig,
+    query: &str,
+) -> Result<Vec<SearchResult>> {
+    let Some(backend) = config.backend else {
+        bail!("No search backend is configured in `[web_search]` of the config file");
+    };
+    let count = config.count.unwrap_or(DEFAULT_COUNT);
+    let api_key = |env: &str| {
+        config
+            .api_key
+            .clone()
+            .or_else(|| std::env::var(env).ok())
+            .ok_or_else(|| {
+                anyhow!("API key of {backend:?} search is required in `[web_search]` or `{env}`")
+            })
+    };
+    let request = match backend {
+        Backend::Searxng => {
+            let url = config.url.as_deref().ok_or_else(|| {
+                anyhow!("`url` of the SearxNG instance is required in `[web_search]`")
+            })?;
+            client
+                .get(format!("{}/search", url.trim_end_matches('/')))
+                .query(&[("q", query), ("format", "json")])
+        }
+        Backend::Brave => client
+            .get(config.url.as_deref().unwrap_or(BRAVE_URL))
+            .query(&[("q", query), ("count", &count.to_string())])
+            .header("X-Subscription-Token", api_key("BRAVE_API_KEY")?),
+        Backend::Bing => client
+            .get(config.url.as_deref().unwrap_or(BING_URL))
+            .query(&[("q", query), ("count", &count.to_string())])
+            .header("Ocp-Apim-Subscription-Key", api_key("BING_API_KEY")?),
+    };
+    let response: Value = request
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| anyhow!("Web search failed: {e}"))?
+        .json()
+        .await
+        .map_err(|e| anyhow!("Invalid response of web search: {e}"))?;
+
+    // Results are in different places with different names of fields
+    let (results, title, snippet) = match backend {
+        Backend::Searxng => (&response["results"], "title", "content"),
+        Backend::Brave => (&response["web"]["results"], "title", "description"),
+        Backend::Bing => (&response["webPages"]["value"], "name", "snippet"),
+    };
+    let text = |value: &Value| {
+        html::to_text(value.as_str().unwrap_or_default())
+            .trim()
+            .to_owned()
+    };
+    Ok(results
+        .as_array()
+        .into_iter()
+        .flatten()
+        .take(count)
+        .map(|r| SearchResult {
+            title: text(&r[title]),
+            url: r["url"].as_str().unwrap_or_default().to_owned(),
+            snippet: text(&r[snippet]),
+        })
+        .collect())
+}
+
+/// Search the web and format the results as context for the model
+pub async fn context(client: &Client, config: &SearchConfig, query: &str) -> Result<String> {
+    let results = search(client, config, query).await?;
+    if results.is_empty() {
+        return Ok(format!("No web search results for \"{query}\"."));
+    }
+    let mut context = format!("Web search results for \"{query}\":");
+    for (i, result) in results.iter().enumerate() {
+        context.push_str(&format!(
+            "\n\n{}. {}\n   {}\n   {}",
+            i + 1,
+            result.title,
+            result.url,
+            result.snippet
+        ));
+    }
+    Ok(context)
+}
@@ -0,0 +1,176 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use clap::Args;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use reqwest::Client;
+use tracing::{debug, info, warn};
+
+use crate::{api_request, http_client, verbose, Options};
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: SocketAddr,
+}
+
+/// Shared state of the proxy server
+struct Proxy {
+    options: Options,
+    client: Client,
+}
+
+/// Run an OpenAI-compatible server which forwards requests to the configured API endpoint.
+///
+/// The API key and base URL are taken from heygpt's configuration, so clients don't need to
+/// know them. Requests without `model` use the configured model.
+pub async fn run(options: Options, args: ServeArgs) -> Result<()> {
+    let proxy = Arc::new(Proxy {
+        client: http_client(&options)?,
+        options,
+    });
+
+    let make_svc = make_service_fn(move |_conn| {
+        let proxy = proxy.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let proxy = proxy.clone();
+                async move { Ok::<_, Infallible>(proxy.handle(req).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&args.listen)?.serve(make_svc);
+    eprintln!("Listening on http://{}/v1", args.listen);
+    server.await?;
+    Ok(())
+}
+
+impl Proxy {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        info!("{} {}", method, path);
+
+        let path = path.strip_prefix("/v1").unwrap_or(&path);
+        let result = match (&method, path) {
+            (&Method::POST, "/chat/completions") => self.chat_completions(req).await,
+            (&Method::GET, "/models") => self.forward(Method::GET, "/models", None).await,
+            _ => Ok(error_response(
+                StatusCode::NOT_FOUND,
+                &format!("Unknown endpoint: {method} {path}"),
+            )),
+        };
+        result.unwrap_or_else(|err| {
+            warn!("{} {}: {}", method, path, err);
+            error_response(StatusCode::BAD_GATEWAY, &err.to_string())
+        })
+    }
+
+    async fn chat_completions(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let body = hyper::body::to_bytes(req.into_body()).await?;
+        let mut data: serde_json::Value = match serde_json::from_slice(&body) {
+            Ok(data) => data,
+            Err(err) => {
+                return Ok(error_response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Invalid JSON body: {err}"),
+                ))
+            }
+        };
+        if let Some(obj) = data.as_object_mut() {
+            obj.entry("model")
+                .or_insert_with(|| self.options.model.clone().into());
+        }
+        debug!("Request body: {}", &data);
+        self.forward(Method::POST, "/chat/completions", Some(data))
+            .await
+    }
+
+    /// Forward the request to upstream, streaming the response body back as it arrives
+    async fn forward(
+        &self,
+        method: Method,
+        path: &str,
+        data: Option<serde_json::Value>,
+    ) -> Result<Response<Body>> {
+        let mut req = api_request(&self.client, &self.options, method, path)?;
+        if let Some(data) = data {
+            req = req.json(&data);
+        }
+        let mut upstream = verbose::send(&self.options, req).await?;
+
+        let mut response = Response::builder().status(upstream.status());
+        if let Some(content_type) = upstream.headers().get(CONTENT_TYPE) {
+            response = response.header(CONTENT_TYPE, content_type);
+        }
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            loop {
+                match upstream.chunk().await {
+                    Ok(Some(chunk)) => {
+                        if sender.send_data(chunk).await.is_err() {
+                            debug!("client disconnected");
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(err) => {
+                        warn!("upstream error: {}", err);
+                        sender.abort();
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(response.body(body)?)
+    }
+}
+
+/// Build an error response in the format of OpenAI API
+pub(crate) fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": "invalid_request_error",
+            "param": null,
+            "code": null,
+        }
+    });
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+/// A random path prefix for the base URL of a local server adding credentials to requests,
+/// e.g. failover, so that other programs on the machine can't send requests with them
+pub(crate) fn proxy_secret() -> Result<String> {
+    let mut bytes = [0; 16];
+    openssl::rand::rand_bytes(&mut bytes)?;
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    Ok(format!("/{hex}"))
+}
+
+/// The path of a request to a local server without its secret prefix, if it has it
+pub(crate) fn strip_secret<'a>(path: &'a str, secret: &str) -> Option<&'a str> {
+    path.strip_prefix(secret)
+        .filter(|rest| rest.is_empty() || rest.starts_with(['/', '?']))
+}
+
+/// Refuse a request to a local server without its secret prefix
+pub(crate) fn missing_secret() -> Response<Body> {
+    error_response(
+        StatusCode::FORBIDDEN,
+        "Requests to this server need its secret path",
+    )
+}
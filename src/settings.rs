@@ -0,0 +1,106 @@
+//! Request parameters adjustable in interactive mode by `\set` and `\unset`
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+
+use crate::Options;
+
+/// Names of the parameters, in the order shown by `\show settings`
+pub const PARAMETERS: &[&str] = &[
+    "model",
+    "stream",
+    "temperature",
+    "top_p",
+    "frequency_penalty",
+    "presence_penalty",
+    "max_tokens",
+];
+
+/// Override the parameter for subsequent requests
+pub fn set(options: &mut Options, name: &str, value: &str) -> Result<()> {
+    match name {
+        "model" if value.contains(char::is_whitespace) => bail!("Model must be a single word"),
+        "model" => options.model = value.to_owned(),
+        "stream" => {
+            options.stream = match value {
+                "true" | "on" | "yes" => true,
+                "false" | "off" | "no" => false,
+                _ => bail!("Value of stream must be `true` or `false`"),
+            }
+        }
+        "temperature" => options.temperature = Some(parse_in(name, value, 0.0, 2.0)?),
+        "top_p" => options.top_p = Some(parse_in(name, value, 0.0, 1.0)?),
+        "frequency_penalty" => options.frequency_penalty = Some(parse_in(name, value, -2.0, 2.0)?),
+        "presence_penalty" => options.presence_penalty = Some(parse_in(name, value, -2.0, 2.0)?),
+        "max_tokens" => {
+            let max_tokens = value
+                .parse()
+                .ok()
+                .filter(|&n: &usize| n > 0)
+                .ok_or_else(|| anyhow!("Value of max_tokens must be a positive integer"))?;
+            options.max_tokens = Some(max_tokens);
+        }
+        _ => bail!(unknown(name)),
+    }
+    Ok(())
+}
+
+/// Drop the override of the parameter, going back to the value from command line or config
+pub fn unset(options: &mut Options, initial: &Options, name: &str) -> Result<()> {
+    match name {
+        "model" => options.model = initial.model.clone(),
+        "stream" => options.stream = initial.stream,
+        "temperature" => options.temperature = initial.temperature,
+        "top_p" => options.top_p = initial.top_p,
+        "frequency_penalty" => options.frequency_penalty = initial.frequency_penalty,
+        "presence_penalty" => options.presence_penalty = initial.presence_penalty,
+        "max_tokens" => options.max_tokens = initial.max_tokens,
+        _ => bail!(unknown(name)),
+    }
+    Ok(())
+}
+
+/// Print the effective parameters, marking the ones overridden by `\set`
+pub fn print(options: &Options, initial: &Options) {
+    println!("{}", style("Settings:").bold());
+    for name in PARAMETERS {
+        let value = value_of(options, name);
+        let marker = if value != value_of(initial, name) {
+            style(" (set)").dim().to_string()
+        } else {
+            String::new()
+        };
+        println!("  {name:<19}{value}{marker}");
+    }
+}
+
+fn value_of(options: &Options, name: &str) -> String {
+    fn or_default<T: ToString>(value: Option<T>) -> String {
+        value.map_or_else(|| "default".to_owned(), |v| v.to_string())
+    }
+    match name {
+        "model" => options.model.clone(),
+        "stream" => options.stream.to_string(),
+        "temperature" => or_default(options.temperature),
+        "top_p" => or_default(options.top_p),
+        "frequency_penalty" => or_default(options.frequency_penalty),
+        "presence_penalty" => or_default(options.presence_penalty),
+        "max_tokens" => or_default(options.max_tokens),
+        _ => unreachable!("unknown parameter {name}"),
+    }
+}
+
+fn parse_in(name: &str, value: &str, min: f64, max: f64) -> Result<f64> {
+    value
+        .parse()
+        .ok()
+        .filter(|v| (min..=max).contains(v))
+        .ok_or_else(|| anyhow!("Value of {name} must be a number between {min} and {max}"))
+}
+
+fn unknown(name: &str) -> String {
+    format!(
+        "Unknown parameter `{name}`, expected one of {}",
+        PARAMETERS.join(", ")
+    )
+}
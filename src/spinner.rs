@@ -1,21 +1,59 @@
-use console::Term;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-/// An auxiliary struct to handle the spinner
+use console::{style, Term};
+
+const FRAMES: &[&str] = &[".  ", ".. ", "...", " ..", "  .", "   "];
+
+/// An auxiliary struct to handle the spinner, showing the model and elapsed seconds like
+/// `gpt-4o · 3.2s`
 ///
 /// A spinner will be shown when this struct is created, and will be removed when it is dropped.
-pub struct Spinner(spinners::Spinner);
+pub struct Spinner {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
 
 impl Spinner {
-    pub fn new() -> Self {
-        use spinners::{Spinner, Spinners};
-        let sp = Spinner::new(Spinners::SimpleDotsScrolling, "".into());
-        Self(sp)
+    pub fn new(label: &str) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let label = label.to_owned();
+        let thread = std::thread::spawn({
+            let stopped = stopped.clone();
+            move || {
+                let start = Instant::now();
+                let mut stdout = std::io::stdout();
+                for frame in FRAMES.iter().cycle() {
+                    if stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let elapsed = start.elapsed().as_secs_f64();
+                    let status = style(format!("{label} · {elapsed:.1}s")).dim();
+                    let _ = write!(stdout, "\r{frame} {status}");
+                    let _ = stdout.flush();
+                    // Woken up early when the spinner is dropped
+                    std::thread::park_timeout(Duration::from_millis(100));
+                }
+            }
+        });
+        Self {
+            stopped,
+            thread: Some(thread),
+        }
     }
 }
 
 impl Drop for Spinner {
     fn drop(&mut self) {
-        self.0.stop();
-        Term::stdout().clear_line().unwrap();
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+        // Fails in consoles without ANSI support when stdout is not a console
+        let _ = Term::stdout().clear_line();
     }
 }
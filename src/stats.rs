@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use console::style;
+
+use crate::model::ResponseUsage;
+
+/// Timing of a response, for `--stats`, `\stats` and JSON output
+#[derive(Debug, Clone)]
+pub struct ResponseStats {
+    /// Time to the first token of content. Same as `total` for non-streaming requests.
+    pub first_token: Option<Duration>,
+    /// Time to the whole response, including continuations
+    pub total: Duration,
+    pub completion_tokens: Option<usize>,
+}
+
+impl ResponseStats {
+    /// Completion tokens per second while generating, i.e. after the first token
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let generating = match self.first_token {
+            Some(first) if self.total > first => self.total - first,
+            _ => self.total,
+        };
+        let secs = generating.as_secs_f64();
+        let tokens = self.completion_tokens?;
+        (secs > 0.0).then(|| tokens as f64 / secs)
+    }
+
+    /// One-line summary printed by `--stats`
+    pub fn footer(&self, model: &str, usage: Option<&ResponseUsage>) -> String {
+        let mut footer = model.to_owned();
+        if let Some(first) = self.first_token {
+            footer.push_str(&format!(" · first token {:.2}s", first.as_secs_f64()));
+        }
+        footer.push_str(&format!(" · {:.2}s", self.total.as_secs_f64()));
+        if let Some(usage) = usage {
+            footer.push_str(&format!(
+                " · {} prompt + {} completion tokens",
+                usage.prompt_tokens, usage.completion_tokens
+            ));
+        }
+        if let Some(speed) = self.tokens_per_second() {
+            footer.push_str(&format!(" · {speed:.0} tokens/s"));
+        }
+        footer
+    }
+}
+
+/// Print aggregate stats of responses in the session, for `\stats`
+pub fn print_summary(stats: &[ResponseStats], total_usage: &ResponseUsage) {
+    if stats.is_empty() {
+        println!("No response yet");
+        return;
+    }
+    println!("{} {}", style("Responses:").bold(), stats.len());
+    let first_tokens: Vec<_> = stats.iter().filter_map(|s| s.first_token).collect();
+    if let Some(line) = summarize_durations(&first_tokens) {
+        println!("{} {line}", style("Time to first token:").bold());
+    }
+    let totals: Vec<_> = stats.iter().map(|s| s.total).collect();
+    if let Some(line) = summarize_durations(&totals) {
+        println!("{} {line}", style("Latency:").bold());
+    }
+    let speeds: Vec<_> = stats.iter().filter_map(|s| s.tokens_per_second()).collect();
+    if !speeds.is_empty() {
+        let avg = speeds.iter().sum::<f64>() / speeds.len() as f64;
+        println!("{} avg {avg:.0} tokens/s", style("Throughput:").bold());
+    }
+    println!(
+        "{} {} prompt + {} completion",
+        style("Tokens:").bold(),
+        total_usage.prompt_tokens,
+        total_usage.completion_tokens
+    );
+}
+
+fn summarize_durations(durations: &[Duration]) -> Option<String> {
+    let min = durations.iter().min()?;
+    let max = durations.iter().max()?;
+    let avg = durations.iter().sum::<Duration>() / durations.len() as u32;
+    Some(format!(
+        "avg {:.2}s, min {:.2}s, max {:.2}s",
+        avg.as_secs_f64(),
+        min.as_secs_f64(),
+        max.as_secs_f64()
+    ))
+}
@@ -0,0 +1,89 @@
+use crate::model::Message;
+
+/// Roughly estimate the number of tokens of a text without a tokenizer.
+///
+/// English text averages about 4 characters per token, while CJK characters usually take
+/// at least one token each.
+pub fn estimate_tokens(text: &str) -> usize {
+    let mut wide = 0usize;
+    let mut narrow = 0usize;
+    for c in text.chars() {
+        if c.is_ascii() {
+            narrow += 1;
+        } else {
+            wide += 1;
+        }
+    }
+    wide + narrow.div_ceil(4)
+}
+
+/// Split the text into chunks of at most about `max_tokens`, with the lines where they start.
+/// Chunks end at blank lines once half full, to keep paragraphs whole, and lines too long for a
+/// chunk are cut.
+pub fn split(text: &str, max_tokens: usize) -> Vec<(usize, String)> {
+    let mut chunks = Vec::new();
+    let mut start = 1;
+    let mut current = String::new();
+    let mut tokens = 0;
+    for (i, line) in text.lines().enumerate() {
+        let blank = line.trim().is_empty();
+        for piece in cut_line(line, max_tokens) {
+            let piece_tokens = estimate_tokens(piece) + 1;
+            if !current.is_empty()
+                && (tokens + piece_tokens > max_tokens || (blank && tokens >= max_tokens / 2))
+            {
+                chunks.push((start, current.trim_end().to_owned()));
+                current.clear();
+                tokens = 0;
+            }
+            if current.is_empty() {
+                if blank {
+                    continue;
+                }
+                start = i + 1;
+            }
+            current.push_str(piece);
+            current.push('\n');
+            tokens += piece_tokens;
+        }
+    }
+    if !current.trim().is_empty() {
+        chunks.push((start, current.trim_end().to_owned()));
+    }
+    chunks
+}
+
+/// Cut the line into pieces of at most `max_tokens`
+fn cut_line(line: &str, max_tokens: usize) -> Vec<&str> {
+    if estimate_tokens(line) < max_tokens {
+        return vec![line];
+    }
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let (mut wide, mut narrow) = (0, 0);
+    for (i, c) in line.char_indices() {
+        if i > start && wide + narrow / 4 + 1 >= max_tokens {
+            pieces.push(&line[start..i]);
+            start = i;
+            (wide, narrow) = (0, 0);
+        }
+        if c.is_ascii() {
+            narrow += 1;
+        } else {
+            wide += 1;
+        }
+    }
+    pieces.push(&line[start..]);
+    pieces
+}
+
+/// Tokens taken by an image of detail `high` with the common size of 1024x1024
+const IMAGE_TOKENS: usize = 765;
+
+/// Roughly estimate the number of tokens of messages, including the per-message overhead
+pub fn estimate_message_tokens(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|m| estimate_tokens(&m.content) + m.images.len() * IMAGE_TOKENS + 4)
+        .sum()
+}
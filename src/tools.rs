@@ -0,0 +1,258 @@
+//! Built-in tools offered to the model by `--tools`: running shell commands, reading and writing
+//! files, fetching web pages, and searching the web if a backend is configured. Each call,
+//! including those of MCP servers, is confirmed on terminal unless allowed by `--allow-tools`, and
+//! recorded in `tools.log` of the data directory.
+
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::process::Command;
+
+use anyhow::{anyhow, Result};
+use console::style;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::mcp::{self, ServerConfig};
+use crate::model::{FunctionDefinition, Tool, ToolCall};
+use crate::search::{self, SearchConfig};
+use crate::{attach, history, paths, warning};
+
+/// Names of the built-in tools
+pub const TOOLS: &[&str] = &["run_shell", "read_file", "write_file", "http_get", "search"];
+
+/// Result of a call denied by the user
+const DENIED: &str = "The user denied running this tool.";
+
+/// Output of tools beyond this many bytes is cut, to save the context window
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// Definitions of the built-in tools in the request, with `search` only if a backend is
+/// configured
+pub fn definitions(search: &SearchConfig) -> Vec<Tool> {
+    let function = |name: &str, description: &str, parameters: serde_json::Value| Tool {
+        r#type: "function".to_owned(),
+        function: FunctionDefinition {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            parameters,
+        },
+    };
+    let object = |properties: serde_json::Value, required: &[&str]| json!({ "type": "object", "properties": properties, "required": required });
+    let mut tools = vec![
+        function(
+            "run_shell",
+            "Run a shell command in the current directory of the user and get its exit status, stdout and stderr",
+            object(json!({ "command": { "type": "string" } }), &["command"]),
+        ),
+        function(
+            "read_file",
+            "Read a text file of the user",
+            object(json!({ "path": { "type": "string" } }), &["path"]),
+        ),
+        function(
+            "write_file",
+            "Create or overwrite a text file of the user",
+            object(
+                json!({ "path": { "type": "string" }, "content": { "type": "string" } }),
+                &["path", "content"],
+            ),
+        ),
+        function(
+            "http_get",
+            "Fetch a web page as text",
+            object(json!({ "url": { "type": "string" } }), &["url"]),
+        ),
+    ];
+    if search.is_configured() {
+        tools.push(function(
+            "search",
+            "Search the web and get the title, URL and snippet of the top results",
+            object(json!({ "query": { "type": "string" } }), &["query"]),
+        ));
+    }
+    tools
+}
+
+/// A name in `--allow-tools` which is neither a tool, an MCP server nor `all`
+pub fn unknown_tool<'a>(
+    allowed: &'a [String],
+    servers: &BTreeMap<String, ServerConfig>,
+) -> Option<&'a str> {
+    allowed
+        .iter()
+        .map(String::as_str)
+        .find(|name| *name != "all" && !TOOLS.contains(name) && !mcp::is_known(servers, name))
+}
+
+#[derive(Deserialize)]
+struct Arguments {
+    #[serde(default)]
+    command: String,
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    query: String,
+}
+
+/// Run a tool called by the model if allowed, and return the result for the model. Failures
+/// are returned as results too, so the model may try something else.
+pub async fn run(
+    client: &Client,
+    call: &ToolCall,
+    allowed: &[String],
+    servers: &[mcp::Server],
+    search: &SearchConfig,
+) -> String {
+    let name = call.function.name.as_str();
+    let result = if let Some((server, tool)) = mcp::find(servers, name) {
+        let arguments: serde_json::Value = match serde_json::from_str(&call.function.arguments) {
+            Ok(arguments) => arguments,
+            Err(err) => return format!("Error: invalid arguments: {err}"),
+        };
+        if !permit(call, &arguments.to_string(), allowed) {
+            return DENIED.to_owned();
+        }
+        server.call(tool, arguments).await
+    } else {
+        let args: Arguments = match serde_json::from_str(&call.function.arguments) {
+            Ok(args) if TOOLS.contains(&name) => args,
+            Ok(_) => return format!("Error: unknown tool `{name}`"),
+            Err(err) => return format!("Error: invalid arguments: {err}"),
+        };
+        let summary = match name {
+            "run_shell" => args.command.clone(),
+            "write_file" => format!("{} ({} lines)", args.path, args.content.lines().count()),
+            "read_file" => args.path.clone(),
+            "search" => args.query.clone(),
+            _ => args.url.clone(),
+        };
+        if !permit(call, &summary, allowed) {
+            return DENIED.to_owned();
+        }
+        match name {
+            "run_shell" => run_shell(&args.command),
+            "read_file" => attach::read_file(&args.path),
+            "write_file" => write_file(&args.path, &args.content),
+            "search" => search::context(client, search, &args.query).await,
+            _ => attach::fetch_url(client, &args.url).await,
+        }
+    };
+    let output = result.unwrap_or_else(|err| format!("Error: {err}"));
+    eprintln!(
+        "{}",
+        style(format!("({} bytes returned to the model)", output.len())).dim()
+    );
+    truncate(output)
+}
+
+/// Show the call, and tell whether it is allowed by `--allow-tools` or confirmed by the user
+fn permit(call: &ToolCall, summary: &str, allowed: &[String]) -> bool {
+    let name = call.function.name.as_str();
+    eprintln!("{} {name}: {summary}", style("tool =>").bold().yellow());
+    let decision = if allowed
+        .iter()
+        .any(|a| a == "all" || a == name || mcp::is_allowed_by(name, a))
+    {
+        "allowed"
+    } else if !std::io::stdin().is_terminal() {
+        eprintln!(
+            "{}",
+            style("Denied, as there is no terminal to confirm it. Allow it with --allow-tools.")
+                .dim()
+        );
+        "denied"
+    } else {
+        match ask(&format!("Allow {name}?")) {
+            Ok(true) => "confirmed",
+            Ok(false) => "denied",
+            Err(err) => {
+                warning(format!("Failed to ask for confirmation: {err}"));
+                "denied"
+            }
+        }
+    };
+    audit(decision, call);
+    decision != "denied"
+}
+
+/// Ask the user on terminal, on stderr to leave stdout to the response
+fn ask(question: &str) -> Result<bool> {
+    eprint!("{question} [y/N] ");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn run_shell(command: &str) -> Result<String> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").args(["/C", command]).output()
+    } else {
+        Command::new("sh").args(["-c", command]).output()
+    }
+    .map_err(|e| anyhow!("Failed to run the command: {e}"))?;
+    Ok(format!(
+        "{}\nstdout:\n{}\nstderr:\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+fn write_file(path: &str, content: &str) -> Result<String> {
+    paths::ensure_parent_dir(std::path::Path::new(path))?;
+    std::fs::write(path, content).map_err(|e| anyhow!("Failed to write `{path}`: {e}"))?;
+    Ok(format!("Wrote {} bytes to `{path}`", content.len()))
+}
+
+/// Cut the output at a character boundary, noting how much is left out
+fn truncate(mut output: String) -> String {
+    if output.len() <= MAX_OUTPUT {
+        return output;
+    }
+    let mut end = MAX_OUTPUT;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    let omitted = output.len() - end;
+    output.truncate(end);
+    output.push_str(&format!("\n[{omitted} more bytes omitted]"));
+    output
+}
+
+/// Append the call and the decision to the audit log
+fn audit(decision: &str, call: &ToolCall) {
+    if let Err(err) = write_audit(decision, call) {
+        warning(format!("Failed to write the tool audit log: {err}"));
+    }
+}
+
+fn write_audit(decision: &str, call: &ToolCall) -> Result<()> {
+    let path = paths::tools_log()?;
+    paths::ensure_parent_dir(&path)?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    // One line per call, with the arguments as compact JSON
+    let arguments = serde_json::from_str::<serde_json::Value>(&call.function.arguments)
+        .map_or_else(
+            |_| call.function.arguments.replace('\n', " "),
+            |v| v.to_string(),
+        );
+    writeln!(
+        file,
+        "{} {decision} {} {arguments}",
+        history::format_time(now),
+        call.function.name
+    )?;
+    Ok(())
+}
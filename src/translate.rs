@@ -0,0 +1,205 @@
+//! `heygpt translate`: translate a Markdown or text file segment by segment, keeping code blocks
+//! and front matter as they are
+
+use std::io::Write;
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, ValueHint};
+use futures::stream::{self, StreamExt};
+
+use crate::model::Message;
+use crate::{http_client, token, warning, Options, Session};
+
+#[derive(Args, Debug)]
+pub struct TranslateArgs {
+    /// The target language, e.g. `de` or `Japanese`
+    #[arg(long)]
+    pub to: String,
+
+    /// The source language, detected if omitted
+    #[arg(long)]
+    pub from: Option<String>,
+
+    /// The file to translate. Reads stdin if omitted.
+    #[arg(value_hint = ValueHint::FilePath)]
+    pub file: Option<String>,
+
+    /// Write the translation to the file instead of stdout
+    #[arg(long, short, value_hint = ValueHint::FilePath)]
+    pub output: Option<String>,
+
+    /// Tokens of text translated in one request, rounded up to whole paragraphs
+    #[arg(long, default_value_t = 1500)]
+    pub segment_tokens: usize,
+
+    /// Maximum number of requests in flight
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+}
+
+/// System prompt of translating a segment
+const TRANSLATE_PROMPT: &str = "You are a translator. Translate the Markdown text given by the user{from} into {to}. Keep the Markdown structure exactly: headings, lists, tables, emphasis, links, images, HTML tags and line breaks. Do not translate inline code, URLs or link targets. Reply with only the translation, without any explanation or code fence around it.";
+
+/// A part of the document
+enum Segment {
+    /// Text to translate
+    Text(String),
+    /// Code blocks, front matter and blank lines, kept as they are
+    Verbatim(String),
+}
+
+pub async fn run(options: Options, args: TranslateArgs) -> Result<()> {
+    if args.concurrency == 0 || args.segment_tokens == 0 {
+        bail!("Concurrency and segment tokens must be at least 1");
+    }
+    let input = match &args.file {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read file `{path}`: {e}"))?,
+        None => std::io::read_to_string(std::io::stdin())?,
+    };
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+
+    let from = match &args.from {
+        Some(from) => format!(" from {from}"),
+        None => String::new(),
+    };
+    let system = TRANSLATE_PROMPT
+        .replace("{from}", &from)
+        .replace("{to}", &args.to);
+    let client = http_client(&options)?;
+    let session = Session::new(options, client, false, false);
+    let segments = split(&input, args.segment_tokens);
+    let mut results = stream::iter(&segments)
+        .map(|segment| translate(&session, &system, segment))
+        .buffered(args.concurrency);
+    while let Some(result) = results.next().await {
+        output.write_all(result?.as_bytes())?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+async fn translate(session: &Session, system: &str, segment: &Segment) -> Result<String> {
+    let text = match segment {
+        Segment::Verbatim(text) => return Ok(text.clone()),
+        Segment::Text(text) => text,
+    };
+    // Blank lines around the text are kept as they are
+    let start = text.len() - text.trim_start().len();
+    let end = text.trim_end().len();
+    let messages = vec![
+        Message {
+            role: "system".to_string(),
+            content: system.to_owned(),
+            ..Default::default()
+        },
+        Message {
+            role: "user".to_string(),
+            content: text[start..end].to_owned(),
+            ..Default::default()
+        },
+    ];
+    let mut data = session.build_request_with(messages)?;
+    data.set_stream(false);
+    let response = session.fetch(&data).await?;
+    let choice = response
+        .choices
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No choice in response"))?;
+    if choice.finish_reason.as_deref() == Some("length") {
+        warning("A segment of the translation is truncated, try a smaller --segment-tokens");
+    }
+    let translation = unfence(choice.message.content.trim());
+    Ok(format!("{}{translation}{}", &text[..start], &text[end..]))
+}
+
+/// The content of a code block around the whole reply, which some models add despite being told
+/// not to
+fn unfence(reply: &str) -> &str {
+    let inner = reply
+        .strip_prefix("```")
+        .and_then(|r| r.strip_suffix("```"));
+    match inner.and_then(|inner| inner.split_once('\n')) {
+        Some((info, body)) if !info.contains('`') => body.trim_end(),
+        _ => reply,
+    }
+}
+
+/// Split the document into fenced code blocks and front matter, and text to translate in runs
+/// of whole paragraphs, each ending once it reaches `max_tokens`
+fn split(input: &str, max_tokens: usize) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut tokens = 0;
+    let mut lines = input.split_inclusive('\n').peekable();
+
+    // YAML front matter
+    if lines.peek().is_some_and(|l| l.trim_end() == "---") {
+        let mut front = lines.next().unwrap().to_owned();
+        for line in lines.by_ref() {
+            front.push_str(line);
+            if line.trim_end() == "---" {
+                break;
+            }
+        }
+        segments.push(Segment::Verbatim(front));
+    }
+
+    let flush = |text: &mut String, tokens: &mut usize, segments: &mut Vec<Segment>| {
+        if !text.is_empty() {
+            segments.push(if text.trim().is_empty() {
+                Segment::Verbatim(std::mem::take(text))
+            } else {
+                Segment::Text(std::mem::take(text))
+            });
+        }
+        *tokens = 0;
+    };
+    let mut after_blank = false;
+    while let Some(line) = lines.next() {
+        if let Some(fence) = fence_of(line) {
+            flush(&mut text, &mut tokens, &mut segments);
+            let mut block = line.to_owned();
+            for line in lines.by_ref() {
+                block.push_str(line);
+                if closes(line, fence) {
+                    break;
+                }
+            }
+            segments.push(Segment::Verbatim(block));
+            after_blank = false;
+            continue;
+        }
+        let blank = line.trim().is_empty();
+        // A full segment ends before the next paragraph
+        if !blank && after_blank && tokens >= max_tokens {
+            flush(&mut text, &mut tokens, &mut segments);
+        }
+        after_blank = blank;
+        text.push_str(line);
+        tokens += token::estimate_tokens(line);
+    }
+    flush(&mut text, &mut tokens, &mut segments);
+    segments
+}
+
+/// The fence opening a code block on the line, e.g. "```" or "~~~~"
+fn fence_of(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start();
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let c = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = trimmed.len() - trimmed.trim_start_matches(c).len();
+    (len >= 3).then(|| &trimmed[..len])
+}
+
+/// Whether the line closes the code block opened by the fence
+fn closes(line: &str, fence: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.starts_with(fence) && trimmed.trim_start_matches(&fence[..1]).is_empty()
+}
@@ -0,0 +1,84 @@
+//! `--verbose`: trace the HTTP traffic with the API on stderr, with credentials redacted. `-v`
+//! prints the method, URL and headers of requests and the status and headers of responses, and
+//! `-vv` adds the bodies and the raw events of response streams.
+
+use console::style;
+use reqwest::header::HeaderMap;
+use reqwest::{Request, RequestBuilder, Response};
+
+use crate::{is_secret_header, Options};
+
+/// Send the request, printing it and the head of the response
+pub async fn send(options: &Options, req: RequestBuilder) -> reqwest::Result<Response> {
+    if options.verbose == 0 {
+        return req.send().await;
+    }
+    let (client, req) = req.build_split();
+    let req = req?;
+    request(options, &req);
+    let response = client.execute(req).await?;
+    response_head(options, &response);
+    Ok(response)
+}
+
+/// Print the request line and headers, and the body with `-vv`
+pub fn request(options: &Options, req: &Request) {
+    if options.verbose == 0 {
+        return;
+    }
+    print(format!("> {} {}", req.method(), req.url()));
+    headers(">", req.headers());
+    if options.verbose >= 2 {
+        if let Some(body) = req.body().and_then(|b| b.as_bytes()) {
+            match std::str::from_utf8(body) {
+                Ok(body) => print(format!("> {body}")),
+                Err(_) => print(format!("> <{} bytes of binary data>", body.len())),
+            }
+        }
+    }
+}
+
+/// Print the status line and headers of the response
+pub fn response_head(options: &Options, response: &Response) {
+    if options.verbose == 0 {
+        return;
+    }
+    print(format!("< {:?} {}", response.version(), response.status()));
+    headers("<", response.headers());
+}
+
+/// Note the opening of a response stream, whose head is not exposed by the event source
+pub fn stream_opened(options: &Options) {
+    if options.verbose > 0 {
+        print("< (response stream opened)".to_owned());
+    }
+}
+
+/// Print the body of a response with `-vv`
+pub fn body(options: &Options, body: &str) {
+    if options.verbose >= 2 {
+        print(format!("< {body}"));
+    }
+}
+
+/// Print a raw event of a response stream with `-vv`
+pub fn event(options: &Options, event: &str, data: &str) {
+    if options.verbose >= 2 {
+        print(format!("< event: {event}, data: {data}"));
+    }
+}
+
+fn headers(prefix: &str, headers: &HeaderMap) {
+    for (name, value) in headers {
+        let value = if is_secret_header(name.as_str()) {
+            "<redacted>"
+        } else {
+            value.to_str().unwrap_or("<binary>")
+        };
+        print(format!("{prefix} {name}: {value}"));
+    }
+}
+
+fn print(line: String) {
+    eprintln!("{}", style(line).dim());
+}
@@ -0,0 +1,163 @@
+//! Wrap streamed responses at word boundaries to the width of the terminal, instead of letting the
+//! terminal break lines in the middle of words
+
+use console::{measure_text_width, Term};
+
+/// The width to wrap at, only when stdout is a terminal, so that piped output is left as it is
+pub fn width() -> Option<usize> {
+    let term = Term::stdout();
+    if !term.is_term() {
+        return None;
+    }
+    let (_, cols) = term.size_checked()?;
+    Some(usize::from(cols))
+}
+
+/// Word-wraps the deltas of a response as they arrive. A word is held back until its end is seen,
+/// so it can be moved to the next line as a whole, and so are the spaces before it, which are
+/// dropped at a line break. Lines in fenced code blocks are never wrapped,
+/// and continuation lines of list items are indented under their text.
+///
+/// The width is checked on every delta, so lines after a resize of the terminal fit the new width.
+#[derive(Default)]
+pub struct Wrap {
+    /// Width of what was printed on the current row
+    column: usize,
+    /// The current line of the response so far, without the word held back
+    line: String,
+    /// The spaces and the word held back
+    spaces: String,
+    word: String,
+    in_code: bool,
+}
+
+impl Wrap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for text printed on the row before the response, e.g. the role in interactive mode
+    pub fn advance(&mut self, printed: &str) {
+        self.column += measure_text_width(printed);
+    }
+
+    /// Take a delta, returning the text to print
+    pub fn push(&mut self, delta: &str) -> String {
+        self.push_at(delta, width().unwrap_or(usize::MAX))
+    }
+
+    fn push_at(&mut self, delta: &str, width: usize) -> String {
+        let mut out = String::new();
+        for c in delta.chars() {
+            match c {
+                '\n' => {
+                    self.flush_word(&mut out, width);
+                    out.push_str(&self.spaces);
+                    out.push('\n');
+                    self.spaces.clear();
+                    let line = self.line.trim_start();
+                    if line.starts_with("```") || line.starts_with("~~~") {
+                        self.in_code = !self.in_code;
+                    }
+                    self.line.clear();
+                    self.column = 0;
+                }
+                _ if self.in_code => {
+                    out.push(c);
+                    self.line.push(c);
+                }
+                ' ' | '\t' => {
+                    self.flush_word(&mut out, width);
+                    self.spaces.push(c);
+                    self.line.push(c);
+                }
+                _ => self.word.push(c),
+            }
+        }
+        out
+    }
+
+    /// Return the rest of the response held back
+    pub fn finish(&mut self) -> String {
+        self.finish_at(width().unwrap_or(usize::MAX))
+    }
+
+    fn finish_at(&mut self, width: usize) -> String {
+        let mut out = String::new();
+        self.flush_word(&mut out, width);
+        out.push_str(&self.spaces);
+        self.spaces.clear();
+        out
+    }
+
+    fn flush_word(&mut self, out: &mut String, width: usize) {
+        if self.word.is_empty() {
+            return;
+        }
+        let len = measure_text_width(&self.word);
+        // A word longer than a row is left to the terminal
+        if self.column + self.spaces.len() + len > width && self.column > hanging_indent(&self.line)
+        {
+            self.break_line(out);
+        } else {
+            out.push_str(&self.spaces);
+            self.column += self.spaces.len();
+        }
+        self.spaces.clear();
+        out.push_str(&self.word);
+        self.column += len;
+        self.line.push_str(&self.word);
+        self.word.clear();
+    }
+
+    fn break_line(&mut self, out: &mut String) {
+        let indent = hanging_indent(&self.line);
+        out.push('\n');
+        out.extend(std::iter::repeat_n(' ', indent));
+        self.column = indent;
+    }
+}
+
+/// Width of the indentation and list marker of a line, e.g. 5 for `  1. item`
+fn hanging_indent(line: &str) -> usize {
+    let text = line.trim_start();
+    let indent = line.len() - text.len();
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let marker = if text.starts_with("- ") || text.starts_with("* ") || text.starts_with("+ ") {
+        2
+    } else if digits > 0 && (text[digits..].starts_with(". ") || text[digits..].starts_with(") ")) {
+        digits + 2
+    } else {
+        0
+    };
+    indent + marker
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap(deltas: &[&str], width: usize) -> String {
+        let mut wrap = Wrap::new();
+        let mut out: String = deltas.iter().map(|d| wrap.push_at(d, width)).collect();
+        out += &wrap.finish_at(width);
+        out
+    }
+
+    #[test]
+    fn words_are_wrapped() {
+        let text = "Some prose long enough to wrap:\n- a list item that wraps too\n```\nlet code = \"is never wrapped at all\";\n```";
+        let wrapped = "Some prose long\nenough to wrap:\n- a list item that\n  wraps too\n```\nlet code = \"is never wrapped at all\";\n```";
+        assert_eq!(wrap(&[text], 20), wrapped);
+        // Words split across deltas are held back until they end
+        let deltas: Vec<_> = text.split_inclusive(['o', ' ']).collect();
+        assert_eq!(wrap(&deltas, 20), wrapped);
+        assert_eq!(wrap(&[text], usize::MAX), text);
+    }
+
+    #[test]
+    fn long_words_are_left_to_the_terminal() {
+        assert_eq!(wrap(&["a verylongword b"], 5), "a\nverylongword\nb");
+        assert_eq!(wrap(&["  1. one two"], 10), "  1. one\n     two");
+    }
+}